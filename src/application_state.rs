@@ -8,28 +8,49 @@ use std::{
 };
 
 use circular_queue::CircularQueue;
-use iced::{exit, keyboard, Task};
+use clap::ValueEnum;
+use iced::{event, exit, keyboard, window, Event, Task};
 
 use crate::{
+    battery::{load_battery_save, save_path, write_battery_save},
     command_line_arguments::CommandLineArguments,
-    cpu::{interrupts::Interrupts, CPU},
-    instructions::decode::DecodedInstruction,
-    machine::Machine,
-    memory::{load_boot_rom, load_game_rom},
+    cpu::CPU,
+    gamepad::gamepad_events,
+    inputs::{Buttons, InputSource},
+    instructions::cache::InstructionCache,
+    keybindings::{normalize_key, Keybindings},
+    machine::{InstructionStep, Machine},
+    memory::{load_boot_rom, load_game_rom, print_opcode_scan},
     message::Message,
+    movie::{start_movie, Movie},
+    ppu::{TileMapCell, TilePaletteSelection},
+    preferences::Preferences,
+    symbols::SymbolTable,
 };
 
 const CPU_SNAPS_CAPACITY: usize = 5;
 const FRAME_TIME_NANOSECONDS: u32 = 16742;
 const LOG_PATH: &str = "log";
+// Roughly every 10 seconds, so that a crash does not lose much progress
+const SAVE_INTERVAL_FRAMES: u32 = 600;
 
 #[derive(Clone, Debug)]
 pub enum MapperType {
     ROMOnly,
     MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
     Other, // TODO
 }
 
+// Which console we behave as.  CGB games run in CGB mode, while DMG games never see CGB hardware.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ConsoleMode {
+    Dmg,
+    Cgb,
+}
+
 #[derive(Clone, Debug)]
 pub enum RAMSize {
     NoRAM,
@@ -40,19 +61,47 @@ pub enum RAMSize {
     Ram8banks8kb,
 }
 
+impl RAMSize {
+    pub fn byte_count(&self) -> usize {
+        match self {
+            RAMSize::NoRAM => 0,
+            RAMSize::Ram2kb => 0x800,
+            RAMSize::Ram8kb => 0x2000,
+            RAMSize::Ram4banks8kb => 0x8000,
+            RAMSize::Ram16banks8kb => 0x20000,
+            RAMSize::Ram8banks8kb => 0x10000,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ROMInformation {
+    pub console_mode: ConsoleMode,
+    pub global_checksum: u16,
+    // Whether the cartridge RAM (and clock) keep their contents when the console is off
+    pub has_battery: bool,
+    pub has_rtc: bool,
+    pub header_checksum_ok: bool,
+    pub licensee: String,
     pub mapper_type: MapperType,
     pub ram_size: RAMSize,
-    pub rom_banks: u8,
+    pub rom_banks: u16,
+    pub title: String,
 }
 
 impl ROMInformation {
     pub fn new() -> Self {
         ROMInformation {
+            console_mode: ConsoleMode::Dmg,
+            global_checksum: 0,
+            has_battery: false,
+            has_rtc: false,
+            header_checksum_ok: true,
+            licensee: String::new(),
             mapper_type: MapperType::ROMOnly,
             ram_size: RAMSize::NoRAM,
-            rom_banks: 0,
+            rom_banks: 2,
+            title: String::new(),
         }
     }
 }
@@ -60,10 +109,34 @@ impl ROMInformation {
 #[derive(Debug)]
 pub struct ApplicationState {
     pub breakpoints: Vec<u16>,
+    pub watchpoints: Vec<u16>,
+    // Memory accesses are only traced while set, see `Machine::memory_trace`
+    is_memory_trace_enabled: bool,
     pub output_file: Option<File>,
     pub paused: bool,
+    pub instruction_cache: InstructionCache,
+    // Rendering the tile maps is costly, so each of them can be turned off
+    pub is_tile_map0_shown: bool,
+    pub is_tile_map1_shown: bool,
+    pub tile_palette_selection: TilePaletteSelection,
+    pub hovered_tile_map_cell: Option<TileMapCell>,
+    pub is_fetcher_panel_expanded: bool,
+    pub is_joypad_panel_expanded: bool,
+    // Shows the frame being drawn rather than the last complete one
+    pub is_lcd_in_progress_shown: bool,
+    pub keybindings: Keybindings,
+    pub preferences: Preferences,
+    preferences_path: String,
+    // Frames are not paced while set
+    pub is_fast_forwarding: bool,
+    // Recording or playing back the buttons, until playback reaches the end
+    movie: Option<Movie>,
     pub snaps: CircularQueue<Machine>,
+    pub symbols: SymbolTable,
     target_frame_time: Duration,
+    // Only set for cartridges with a battery
+    save_path: Option<String>,
+    frames_since_save: u32,
 }
 
 enum PreserveHistory {
@@ -71,27 +144,58 @@ enum PreserveHistory {
     PreserveHistory,
 }
 
-pub struct MachineStep {
-    t_cycles: u128,
-    instruction_executed: Option<DecodedInstruction>,
-}
-
-pub struct InstructionStep {
-    t_cycles: u128,
-    _instruction_executed: DecodedInstruction,
+// Loads the ROMs given on the command line into a fresh machine.
+pub fn load_machine(args: &CommandLineArguments) -> Machine {
+    let boot_rom = load_boot_rom(&args.boot_rom).unwrap();
+    let (game_rom, mut rom_information) = load_game_rom(&args.game_rom).unwrap();
+    if let Some(console_mode) = args.mode {
+        rom_information.console_mode = console_mode;
+    }
+    println!("{:?}", rom_information);
+    if args.scan_opcodes {
+        print_opcode_scan(&game_rom);
+    }
+    let has_battery = rom_information.has_battery;
+    let mut machine = Machine::new(
+        boot_rom,
+        game_rom,
+        rom_information,
+        args.log_for_doctor,
+        args.strict_ppu,
+    );
+    machine.inputs.turbo_period = args.turbo_period;
+    machine.serial.is_console_enabled = args.serial_stdout;
+    if has_battery && !args.has_movie() {
+        if let Err(e) = load_battery_save(&mut machine, &save_path(&args.game_rom)) {
+            println!("[WARNING] Ignoring save file: {}", e);
+        }
+    }
+    machine
 }
 
 impl ApplicationState {
     pub fn new(args: &CommandLineArguments, breakpoints: &[u16]) -> Self {
         let mut queue = CircularQueue::with_capacity(CPU_SNAPS_CAPACITY);
-        let boot_rom = load_boot_rom(&args.boot_rom).unwrap();
-        let (game_rom, rom_information) = load_game_rom(&args.game_rom).unwrap();
-        println!("{:?}", rom_information);
-        let machine = Machine::new(boot_rom, game_rom, rom_information, args.log_for_doctor);
+        let mut machine = load_machine(args);
+        let has_battery = machine.rom_information.has_battery && !args.has_movie();
+        let movie = start_movie(args, &mut machine);
+        let is_memory_trace_enabled = !args.watchpoints.is_empty();
+        machine.memory_trace.is_enabled = is_memory_trace_enabled;
         queue.push(machine);
+        let symbols = match &args.sym {
+            Some(path) => SymbolTable::load(path)
+                .unwrap_or_else(|e| panic!("Could not load symbol file: {}", e)),
+            None => SymbolTable::new(),
+        };
         let target_frame_time = Duration::new(0, FRAME_TIME_NANOSECONDS);
+        let keybindings = Keybindings::load(&args.keybindings)
+            .unwrap_or_else(|e| panic!("Could not load keybindings: {}", e));
+        let preferences = Preferences::load(&args.preferences)
+            .unwrap_or_else(|e| panic!("Could not load preferences: {}", e));
         Self {
             breakpoints: breakpoints.into(),
+            watchpoints: args.watchpoints.clone(),
+            is_memory_trace_enabled,
             output_file: if args.log_for_doctor {
                 Some(
                     OpenOptions::new()
@@ -108,9 +212,29 @@ impl ApplicationState {
                 }
                 None
             },
+            instruction_cache: InstructionCache::new(),
+            is_tile_map0_shown: true,
+            is_tile_map1_shown: true,
+            tile_palette_selection: TilePaletteSelection::Background,
+            hovered_tile_map_cell: None,
+            is_fetcher_panel_expanded: false,
+            is_joypad_panel_expanded: false,
+            is_lcd_in_progress_shown: false,
+            keybindings,
+            preferences,
+            preferences_path: args.preferences.clone(),
+            is_fast_forwarding: false,
+            movie,
             paused: false,
             snaps: queue,
+            symbols,
             target_frame_time,
+            save_path: if has_battery {
+                Some(save_path(&args.game_rom))
+            } else {
+                None
+            },
+            frames_since_save: 0,
         }
     }
 
@@ -128,6 +252,37 @@ impl ApplicationState {
             .expect("current_machine_immut: no machine")
     }
 
+    fn render(&mut self) {
+        let (tile_palette_selection, render_tile_map0, render_tile_map1) = (
+            self.tile_palette_selection,
+            self.is_tile_map0_shown,
+            self.is_tile_map1_shown,
+        );
+        self.current_machine().ppu_mut().render(
+            tile_palette_selection,
+            render_tile_map0,
+            render_tile_map1,
+        );
+    }
+
+    fn write_battery_save(&mut self) {
+        let Some(path) = self.save_path.clone() else {
+            return;
+        };
+        if let Err(e) = write_battery_save(self.current_machine(), &path) {
+            println!("[WARNING] Could not write save file: {}", e);
+        }
+    }
+
+    pub fn title(&self) -> String {
+        let title = &self.current_machine_immut().rom_information.title;
+        if title.is_empty() {
+            String::from("YokoiBoy")
+        } else {
+            format!("YokoiBoy - {}", title)
+        }
+    }
+
     // TODO: move this elsewhere
     pub fn display_breakpoint(self: &Self, address: Wrapping<u16>) -> String {
         String::from(if self.breakpoints.contains(&address.0) {
@@ -137,107 +292,73 @@ impl ApplicationState {
         })
     }
 
-    // TODO: move in machine.rs
-    fn step_machine(machine: &mut Machine) -> MachineStep {
-        let mut instruction_executed = None;
-        let (mut t_cycles, mut _m_cycles) = Interrupts::handle_interrupts(machine);
-        if t_cycles == 0 {
-            (instruction_executed, (t_cycles, _m_cycles)) = CPU::execute_one_instruction(machine);
-        }
-        machine.timers.ticks(&mut machine.interrupts, t_cycles);
-        machine.ppu.ticks(
-            &mut machine.background_window_fetcher,
-            &mut machine.interrupts,
-            &mut machine.object_fetcher,
-            &mut machine.pixel_fetcher,
-            t_cycles,
-        );
-        machine.t_cycle_count += t_cycles as u64;
-
-        // // Print characters written to the Link cable on the terminal (useful for blargg w/o LCD)
-        // if machine.read_u8(Wrapping(0xFF02)).0 == 0x81 {
-        //     let char = machine.read_u8(Wrapping(0xFF01));
-        //     print!("{}", char.0 as char);
-        //     machine.write_u8(Wrapping(0xFF02), Wrapping(0x01));
-        // }
-
-        MachineStep {
-            t_cycles: t_cycles as u128,
-            instruction_executed,
-        }
-    }
-
-    // Steps cycles forward until an instruction is executed.  May take many tries when the console
-    // is in HALT and awaiting an interrupt to wake up and execute an instruction.
+    // Logs the state for gameboy-doctor, then lets the machine execute an instruction, on a copy of
+    // it when we want to be able to go back in history.
     fn execute_one_instruction(&mut self, preserve: PreserveHistory) -> InstructionStep {
         if !self.current_machine().is_dmg_boot_rom_on()
             && !self.current_machine().cpu().low_power_mode
+            && !self.current_machine().cpu().is_locked()
+            && !self.current_machine().stop_mode
         {
             let string = CPU::gbdoctor_string(self.current_machine());
             if let Some(output_file) = self.output_file.as_mut() {
                 write!(output_file, "{}\n", string).expect("write to log failed");
             }
         }
-        let current_machine = self.current_machine();
-        match preserve {
+        let step = match preserve {
             PreserveHistory::DontPreserveHistory => {
-                let machine = current_machine;
-                let mut executed_instruction = None;
-                let mut total_t_cycles: u128 = 0;
-
-                loop {
-                    match executed_instruction {
-                        Some(decoded_instruction) => {
-                            return InstructionStep {
-                                t_cycles: total_t_cycles,
-                                _instruction_executed: decoded_instruction,
-                            }
-                        }
-                        None => {
-                            let step = ApplicationState::step_machine(machine);
-                            executed_instruction = step.instruction_executed;
-                            total_t_cycles += step.t_cycles;
-                        }
-                    }
-                }
+                self.current_machine().execute_one_instruction()
             }
             PreserveHistory::PreserveHistory => {
-                let mut next_machine = current_machine.clone();
-                let mut executed_instruction = None;
-                let mut total_t_cycles = 0;
-
-                loop {
-                    match executed_instruction {
-                        Some(decoded_instruction) => {
-                            self.snaps.push(next_machine);
-                            return InstructionStep {
-                                t_cycles: total_t_cycles,
-                                _instruction_executed: decoded_instruction,
-                            };
-                        }
-                        None => {
-                            let step = ApplicationState::step_machine(&mut next_machine);
-                            executed_instruction = step.instruction_executed;
-                            total_t_cycles += step.t_cycles;
-                        }
-                    }
-                }
+                let mut next_machine = self.current_machine().clone();
+                let step = next_machine.execute_one_instruction();
+                self.snaps.push(next_machine);
+                step
+            }
+        };
+        if let Some(mut movie) = self.movie.take() {
+            if movie.update(self.current_machine()) {
+                self.movie = Some(movie);
             }
         }
+        if self.is_memory_trace_enabled {
+            self.check_watchpoints();
+        }
+        step
+    }
+
+    // Pauses on the first access to a watched address, once the instruction that made it is done.
+    // The trace is emptied either way, so that it only ever holds the last step's accesses.
+    fn check_watchpoints(&mut self) {
+        let accesses = self.current_machine().memory_trace.drain();
+        let Some(access) = accesses
+            .iter()
+            .find(|access| self.watchpoints.contains(&access.address.0))
+        else {
+            return;
+        };
+        println!(
+            "Watchpoint: {:?} of 0x{:02X} at 0x{:04X}",
+            access.kind, access.value, access.address
+        );
+        self.paused = true;
     }
 
+    // Keys go through the keybindings, see `Keybindings`.  Held keys repeat their presses, those
+    // are harmless since the button is already pressed.
     pub fn subscription(&self) -> iced::Subscription<Message> {
-        keyboard::on_key_press(|k, _m| match k {
-            keyboard::Key::Named(keyboard::key::Named::ArrowDown) => {
-                Some(Message::BeginRunUntilBreakpoint)
+        let key_presses =
+            keyboard::on_key_press(|k, _m| Some(Message::KeyPressed(normalize_key(k))));
+        let key_releases =
+            keyboard::on_key_release(|k, _m| Some(Message::KeyReleased(normalize_key(k))));
+        let focus_losses = event::listen_with(|event, _status, _id| match event {
+            Event::Window(window::Event::Unfocused) => {
+                Some(Message::ReleaseAllButtons(InputSource::Keyboard))
             }
-            keyboard::Key::Named(keyboard::key::Named::ArrowRight) => {
-                Some(Message::RunNextInstruction)
-            }
-            keyboard::Key::Named(keyboard::key::Named::Space) => Some(Message::Pause),
-            keyboard::Key::Named(keyboard::key::Named::Escape) => Some(Message::Quit),
             _ => None,
-        })
+        });
+        let gamepad = iced::Subscription::run(gamepad_events);
+        iced::Subscription::batch([key_presses, key_releases, focus_losses, gamepad])
     }
 
     pub fn update(&mut self, message: Message) -> Task<Message> {
@@ -251,12 +372,13 @@ impl ApplicationState {
                 if let Some(output_file) = self.output_file.as_mut() {
                     output_file.flush().expect("flush failed");
                 }
+                self.write_battery_save();
                 exit()
             }
 
             Message::RunNextInstruction => {
                 let _step = self.execute_one_instruction(PreserveHistory::PreserveHistory);
-                self.current_machine().ppu_mut().render();
+                self.render();
                 Task::none()
             }
 
@@ -276,7 +398,7 @@ impl ApplicationState {
                 while remaining_steps.0 > 0 && !self.paused && !self.breakpoints.contains(&pc.0) {
                     let step = self.execute_one_instruction(PreserveHistory::DontPreserveHistory);
                     remaining_steps -= step.t_cycles as u32;
-                    // self.current_machine().ppu_mut().render();
+                    // self.render();
                     // let final_frame_time = time::Instant::now() - initial_time;
                     // if final_frame_time > target_frame_time {
                     //     println!("Overslept {:?}", final_frame_time - target_frame_time);
@@ -287,11 +409,22 @@ impl ApplicationState {
                 }
 
                 if remaining_steps.0 == 0 {
+                    let machine = self.current_machine();
+                    machine.inputs.advance_frame(&mut machine.interrupts);
                     // If we're stopping for a frame, try to get accurate frame time
-                    self.current_machine().ppu_mut().render();
+                    self.render();
+                    self.frames_since_save += 1;
+                    if self.frames_since_save >= SAVE_INTERVAL_FRAMES {
+                        self.frames_since_save = 0;
+                        if self.current_machine().mmu.is_battery_ram_dirty {
+                            self.write_battery_save();
+                        }
+                    }
                     let final_time = time::Instant::now();
                     let frame_time = final_time - initial_time;
-                    if frame_time.as_nanos() < FRAME_TIME_NANOSECONDS as u128 {
+                    if frame_time.as_nanos() < FRAME_TIME_NANOSECONDS as u128
+                        && !self.is_fast_forwarding
+                    {
                         sleep(self.target_frame_time - frame_time);
                     }
                     // Note: I think technically we should save this time, so that we can account
@@ -303,6 +436,168 @@ impl ApplicationState {
                     Task::none()
                 }
             }
+
+            Message::ToggleTileMap0 => {
+                self.is_tile_map0_shown = !self.is_tile_map0_shown;
+                self.render();
+                Task::none()
+            }
+
+            Message::ToggleTileMap1 => {
+                self.is_tile_map1_shown = !self.is_tile_map1_shown;
+                self.render();
+                Task::none()
+            }
+
+            Message::SelectTilePalette(selection) => {
+                self.tile_palette_selection = selection;
+                self.render();
+                Task::none()
+            }
+
+            Message::HoverTileMapCell(cell) => {
+                self.hovered_tile_map_cell = Some(cell);
+                Task::none()
+            }
+
+            Message::LeaveTileMap => {
+                self.hovered_tile_map_cell = None;
+                Task::none()
+            }
+
+            Message::ToggleFetcherPanel => {
+                self.is_fetcher_panel_expanded = !self.is_fetcher_panel_expanded;
+                Task::none()
+            }
+
+            Message::ToggleJoypadPanel => {
+                self.is_joypad_panel_expanded = !self.is_joypad_panel_expanded;
+                Task::none()
+            }
+
+            Message::ToggleLCDInProgress => {
+                self.is_lcd_in_progress_shown = !self.is_lcd_in_progress_shown;
+                Task::none()
+            }
+
+            Message::ToggleInputDisplay => {
+                self.preferences.is_input_display_shown = !self.preferences.is_input_display_shown;
+                if let Err(e) = self.preferences.save(&self.preferences_path) {
+                    println!("[WARNING] Could not save preferences: {}", e);
+                }
+                Task::none()
+            }
+
+            Message::KeyPressed(key) => match self.keybindings.action(&key) {
+                Some(action) => self.update(action.on_press(InputSource::Keyboard)),
+                None => Task::none(),
+            },
+
+            Message::KeyReleased(key) => {
+                let source = InputSource::Keyboard;
+                let action = self.keybindings.action(&key);
+                match action.and_then(|a| a.on_release(source)) {
+                    Some(message) => self.update(message),
+                    None => Task::none(),
+                }
+            }
+
+            Message::GamepadPressed(button) => match self.keybindings.gamepad_action(&button) {
+                Some(action) => self.update(action.on_press(InputSource::Gamepad)),
+                None => Task::none(),
+            },
+
+            Message::GamepadReleased(button) => {
+                let source = InputSource::Gamepad;
+                let action = self.keybindings.gamepad_action(&button);
+                match action.and_then(|a| a.on_release(source)) {
+                    Some(message) => self.update(message),
+                    None => Task::none(),
+                }
+            }
+
+            Message::PressButton(source, button) => {
+                let machine = self.current_machine();
+                machine
+                    .inputs
+                    .set_button(source, button, true, &mut machine.interrupts);
+                Task::none()
+            }
+
+            Message::ReleaseButton(source, button) => {
+                let machine = self.current_machine();
+                machine
+                    .inputs
+                    .set_button(source, button, false, &mut machine.interrupts);
+                Task::none()
+            }
+
+            Message::PressTurboButton(source, button) => {
+                let machine = self.current_machine();
+                machine
+                    .inputs
+                    .set_turbo_button(source, button, true, &mut machine.interrupts);
+                Task::none()
+            }
+
+            Message::ReleaseTurboButton(source, button) => {
+                let machine = self.current_machine();
+                machine
+                    .inputs
+                    .set_turbo_button(source, button, false, &mut machine.interrupts);
+                Task::none()
+            }
+
+            Message::ReleaseAllButtons(source) => {
+                let machine = self.current_machine();
+                machine
+                    .inputs
+                    .set_buttons(source, Buttons::default(), &mut machine.interrupts);
+                machine.inputs.set_turbo_buttons(
+                    source,
+                    Buttons::default(),
+                    &mut machine.interrupts,
+                );
+                self.is_fast_forwarding = false;
+                Task::none()
+            }
+
+            Message::SetFastForward(is_fast_forwarding) => {
+                self.is_fast_forwarding = is_fast_forwarding;
+                Task::none()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::memory::tests::{cartridge, rom_file};
+
+    #[test]
+    fn the_mode_option_overrides_the_cartridge_header() {
+        let boot_rom = rom_file("mode-boot", &[0; 0x100]);
+        let dmg_game = rom_file("mode-dmg", &cartridge(&[]));
+        let cgb_game = rom_file("mode-cgb", &cartridge(&[(0x143, 0xC0)]));
+        for (game_rom, mode, console_mode) in [
+            (&dmg_game, None, ConsoleMode::Dmg),
+            (&dmg_game, Some("cgb"), ConsoleMode::Cgb),
+            (&cgb_game, None, ConsoleMode::Cgb),
+            (&cgb_game, Some("dmg"), ConsoleMode::Dmg),
+        ] {
+            let mut arguments = vec!["yokoyboi", "--boot-rom", &boot_rom, "--game-rom", game_rom];
+            arguments.extend(mode.iter().flat_map(|mode| ["--mode", mode]));
+            let machine = load_machine(&CommandLineArguments::parse_from(arguments));
+            assert_eq!(machine.console_mode(), console_mode, "{:?}", mode);
+            assert_eq!(machine.is_cgb_mode(), machine.ppu().is_cgb_mode);
+        }
+        for path in [boot_rom, dmg_game, cgb_game] {
+            fs::remove_file(path).unwrap();
         }
     }
 }
@@ -0,0 +1,178 @@
+use std::num::Wrapping;
+
+use crate::machine::Machine;
+
+// Only holds the sound registers for now, nothing is played yet.  Registers behave as plain bytes.
+#[derive(Clone, Debug, Hash)]
+pub struct APU {
+    pub nr10: Wrapping<u8>,
+    pub nr11: Wrapping<u8>,
+    pub nr12: Wrapping<u8>,
+    pub nr13: Wrapping<u8>,
+    pub nr14: Wrapping<u8>,
+
+    pub nr21: Wrapping<u8>,
+    pub nr22: Wrapping<u8>,
+    pub nr23: Wrapping<u8>,
+    pub nr24: Wrapping<u8>,
+
+    pub nr30: Wrapping<u8>,
+    pub nr31: Wrapping<u8>,
+    pub nr32: Wrapping<u8>,
+    pub nr33: Wrapping<u8>,
+    pub nr34: Wrapping<u8>,
+
+    pub nr50: Wrapping<u8>,
+    pub nr51: Wrapping<u8>,
+    pub nr52: Wrapping<u8>,
+
+    // Channel 4, and the holes between channels
+    pub register_ff15: Wrapping<u8>,
+    pub register_ff1f: Wrapping<u8>,
+    pub register_ff20: Wrapping<u8>,
+    pub register_ff21: Wrapping<u8>,
+    pub register_ff22: Wrapping<u8>,
+    pub register_ff23: Wrapping<u8>,
+    pub slice_ff27_ff2f: [Wrapping<u8>; 9],
+
+    pub wave_ram: [Wrapping<u8>; 16],
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            nr10: Wrapping(0),
+            nr11: Wrapping(0),
+            nr12: Wrapping(0),
+            nr13: Wrapping(0),
+            nr14: Wrapping(0),
+
+            nr21: Wrapping(0),
+            nr22: Wrapping(0),
+            nr23: Wrapping(0),
+            nr24: Wrapping(0),
+
+            nr30: Wrapping(0),
+            nr31: Wrapping(0),
+            nr32: Wrapping(0),
+            nr33: Wrapping(0),
+            nr34: Wrapping(0),
+
+            nr50: Wrapping(0),
+            nr51: Wrapping(0),
+            nr52: Wrapping(0),
+
+            register_ff15: Wrapping(0),
+            register_ff1f: Wrapping(0),
+            register_ff20: Wrapping(0),
+            register_ff21: Wrapping(0),
+            register_ff22: Wrapping(0),
+            register_ff23: Wrapping(0),
+            slice_ff27_ff2f: [Wrapping(0); 9],
+
+            wave_ram: [Wrapping(0); 16],
+        }
+    }
+
+    pub fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        match address.0 {
+            0xFF10 => self.nr10,
+            0xFF11 => self.nr11,
+            0xFF12 => self.nr12,
+            0xFF13 => self.nr13,
+            0xFF14 => self.nr14,
+            0xFF15 => self.register_ff15,
+            0xFF16 => self.nr21,
+            0xFF17 => self.nr22,
+            0xFF18 => self.nr23,
+            0xFF19 => self.nr24,
+            0xFF1A => self.nr30,
+            0xFF1B => self.nr31,
+            0xFF1C => self.nr32,
+            0xFF1D => self.nr33,
+            0xFF1E => self.nr34,
+            0xFF1F => self.register_ff1f,
+            0xFF20 => self.register_ff20,
+            0xFF21 => self.register_ff21,
+            0xFF22 => self.register_ff22,
+            0xFF23 => self.register_ff23,
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => self.nr52,
+            0xFF27..=0xFF2F => self.slice_ff27_ff2f[address.0 as usize - 0xFF27],
+            0xFF30..=0xFF3F => self.wave_ram[address.0 as usize - 0xFF30],
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        match address.0 {
+            0xFF10 => self.nr10 = value,
+            0xFF11 => self.nr11 = value,
+            0xFF12 => self.nr12 = value,
+            0xFF13 => self.nr13 = value,
+            0xFF14 => self.nr14 = value,
+            0xFF15 => self.register_ff15 = value,
+            0xFF16 => self.nr21 = value,
+            0xFF17 => self.nr22 = value,
+            0xFF18 => self.nr23 = value,
+            0xFF19 => self.nr24 = value,
+            0xFF1A => self.nr30 = value,
+            0xFF1B => self.nr31 = value,
+            0xFF1C => self.nr32 = value,
+            0xFF1D => self.nr33 = value,
+            0xFF1E => self.nr34 = value,
+            0xFF1F => self.register_ff1f = value,
+            0xFF20 => self.register_ff20 = value,
+            0xFF21 => self.register_ff21 = value,
+            0xFF22 => self.register_ff22 = value,
+            0xFF23 => self.register_ff23 = value,
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            0xFF26 => self.nr52 = value,
+            0xFF27..=0xFF2F => self.slice_ff27_ff2f[address.0 as usize - 0xFF27] = value,
+            0xFF30..=0xFF3F => self.wave_ram[address.0 as usize - 0xFF30] = value,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Machine {
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::tests::machine_with_program;
+
+    #[test]
+    fn every_register_reads_back_what_was_written() {
+        let mut machine = machine_with_program(&[]);
+        for value in [0x00, 0xFF] {
+            for address in 0xFF10..=0xFF3F {
+                machine.write_u8(Wrapping(address), Wrapping(value));
+                assert_eq!(machine.read_u8(Wrapping(address)), Wrapping(value));
+            }
+        }
+        // Each register holds its own byte
+        for address in 0xFF10..=0xFF3F {
+            machine.write_u8(Wrapping(address), Wrapping(address as u8));
+        }
+        for address in 0xFF10..=0xFF3F {
+            assert_eq!(
+                machine.read_u8(Wrapping(address)),
+                Wrapping(address as u8),
+                "0x{:04X}",
+                address
+            );
+        }
+        assert_eq!(machine.apu().nr52, Wrapping(0x26));
+        assert_eq!(machine.apu().wave_ram[15], Wrapping(0x3F));
+    }
+}
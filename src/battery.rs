@@ -0,0 +1,167 @@
+use std::{
+    fs,
+    io::{self, Error},
+    path::Path,
+};
+
+use crate::{machine::Machine, rtc::RTC_SAVE_SIZE};
+
+// Battery-backed cartridge RAM is kept next to the ROM, so that progress survives restarts.
+pub fn save_path(game_rom_path: &str) -> String {
+    format!("{}.sav", game_rom_path)
+}
+
+// Cartridges with a clock have its state appended after their RAM.
+fn expected_save_size(machine: &Machine) -> usize {
+    let rtc_size = if machine.rom_information.has_rtc {
+        RTC_SAVE_SIZE
+    } else {
+        0
+    };
+    machine.memory().game_ram.len() + rtc_size
+}
+
+// A missing save file is not an error, the game simply starts with blank RAM.  A save file that
+// does not match the cartridge is refused, rather than half-loaded.
+pub fn load_battery_save(machine: &mut Machine, path: &str) -> Result<(), io::Error> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    let bytes = fs::read(path)?;
+    let expected_size = expected_save_size(machine);
+    // Saves from emulators without RTC support lack the clock
+    let ram_size = machine.memory().game_ram.len();
+    if bytes.len() != expected_size && bytes.len() != ram_size {
+        return Err(Error::other(format!(
+            "Save file has 0x{:X} bytes, expected 0x{:X}",
+            bytes.len(),
+            expected_size
+        )));
+    }
+    let (ram, rtc) = bytes.split_at(ram_size);
+    machine.memory_mut().game_ram.copy_from_slice(ram);
+    if rtc.len() == RTC_SAVE_SIZE {
+        if let Some(clock) = machine.mmu.mapper.rtc_mut() {
+            clock.load_save_bytes(rtc);
+        }
+    }
+    Ok(())
+}
+
+pub fn write_battery_save(machine: &mut Machine, path: &str) -> Result<(), io::Error> {
+    let mut bytes = machine.memory().game_ram.clone();
+    if machine.rom_information.has_rtc {
+        if let Some(rtc) = machine.mmu.mapper.rtc() {
+            bytes.extend(rtc.to_save_bytes());
+        }
+    }
+    fs::write(path, bytes)?;
+    machine.mmu.is_battery_ram_dirty = false;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, num::Wrapping};
+
+    use super::*;
+    use crate::application_state::{MapperType, RAMSize, ROMInformation};
+
+    fn mbc3_machine_with_clock() -> Machine {
+        let rom_information = ROMInformation {
+            has_battery: true,
+            has_rtc: true,
+            mapper_type: MapperType::MBC3,
+            ram_size: RAMSize::Ram4banks8kb,
+            ..ROMInformation::new()
+        };
+        Machine::new(
+            vec![0; 0x100],
+            vec![0; 0x8000],
+            rom_information,
+            false,
+            false,
+        )
+    }
+
+    fn temporary_save_path(name: &str) -> String {
+        let file_name = format!("yokoyboi-test-{}-{}.sav", std::process::id(), name);
+        env::temp_dir()
+            .join(file_name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    // Latches the clock, then reads one of its registers, the way games do.
+    fn read_rtc(machine: &mut Machine, register: u8) -> u8 {
+        machine.write_u8(Wrapping(0x4000), Wrapping(register));
+        machine.write_u8(Wrapping(0x6000), Wrapping(0x00));
+        machine.write_u8(Wrapping(0x6000), Wrapping(0x01));
+        machine.read_u8(Wrapping(0xA000)).0
+    }
+
+    #[test]
+    fn ram_and_clock_survive_a_round_trip() {
+        let path = temporary_save_path("round-trip");
+        let mut saved = mbc3_machine_with_clock();
+        for (index, byte) in saved.memory_mut().game_ram.iter_mut().enumerate() {
+            *byte = (index * 7) as u8;
+        }
+        saved.write_u8(Wrapping(0x0000), Wrapping(0x0A));
+        // Halted, so that the time it takes to run the test does not show
+        for (register, value) in [(0x08, 42), (0x09, 17), (0x0A, 5), (0x0C, 0x41)] {
+            saved.write_u8(Wrapping(0x4000), Wrapping(register));
+            saved.write_u8(Wrapping(0xA000), Wrapping(value));
+        }
+        saved.mmu.is_battery_ram_dirty = true;
+        write_battery_save(&mut saved, &path).unwrap();
+        assert!(!saved.mmu.is_battery_ram_dirty);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            0x8000 + RTC_SAVE_SIZE
+        );
+
+        let mut loaded = mbc3_machine_with_clock();
+        load_battery_save(&mut loaded, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.memory().game_ram, saved.memory().game_ram);
+        loaded.write_u8(Wrapping(0x0000), Wrapping(0x0A));
+        assert_eq!(read_rtc(&mut loaded, 0x08), 42);
+        for register in 0x08..=0x0C {
+            assert_eq!(
+                read_rtc(&mut loaded, register),
+                read_rtc(&mut saved, register),
+                "0x{:02X}",
+                register
+            );
+        }
+    }
+
+    #[test]
+    fn save_without_the_clock_is_loaded() {
+        let path = temporary_save_path("no-clock");
+        fs::write(&path, vec![0x42; 0x8000]).unwrap();
+        let mut machine = mbc3_machine_with_clock();
+        load_battery_save(&mut machine, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(machine.memory().game_ram.iter().all(|byte| *byte == 0x42));
+    }
+
+    #[test]
+    fn save_of_the_wrong_size_is_refused() {
+        let path = temporary_save_path("wrong-size");
+        fs::write(&path, vec![0x42; 0x2000]).unwrap();
+        let mut machine = mbc3_machine_with_clock();
+        assert!(load_battery_save(&mut machine, &path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(machine.memory().game_ram.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn missing_save_leaves_ram_blank() {
+        let mut machine = mbc3_machine_with_clock();
+        load_battery_save(&mut machine, &temporary_save_path("missing")).unwrap();
+        assert!(machine.memory().game_ram.iter().all(|byte| *byte == 0));
+    }
+}
@@ -0,0 +1,123 @@
+use std::num::Wrapping;
+
+use crate::{
+    cpu::{interrupts::Interrupts, CPU},
+    memory_trace::MemoryObserver,
+    registers::{Registers, R8},
+};
+
+// Everything an instruction needs to execute: the CPU itself, the interrupt registers, and a view
+// of the address space.  `Machine` is the real implementation, `FlatBus` lets us run the CPU alone.
+pub trait Bus {
+    fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8>;
+    fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>);
+
+    fn cpu(&self) -> &CPU;
+    fn cpu_mut(&mut self) -> &mut CPU;
+    fn interrupts(&self) -> &Interrupts;
+    fn interrupts_mut(&mut self) -> &mut Interrupts;
+
+    // Called by STOP, only the full machine has clocks to stop.
+    fn enter_stop_mode(&mut self) {}
+
+    // Lets the rest of the machine run for some t-cycles, in the middle of an instruction.
+    fn advance_cycles(&mut self, _t_cycles: u8) {}
+
+    // Whoever wants to see the memory accesses made by instructions, if anyone.  When nobody is
+    // watching, accesses only pay for this check.
+    fn memory_observer(&mut self) -> Option<&mut dyn MemoryObserver> {
+        None
+    }
+
+    // Memory accesses made by an executing instruction take one M-cycle each.  We let the rest of
+    // the machine catch up first, so that reads observe registers like LY or DIV as they are at the
+    // time of the access.
+    fn cycle_read_u8(&mut self, address: Wrapping<u16>) -> Wrapping<u8> {
+        self.advance_cycles(4);
+        let value = self.read_u8(address);
+        if let Some(observer) = self.memory_observer() {
+            observer.on_read(address, value);
+        }
+        value
+    }
+
+    fn cycle_write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        self.advance_cycles(4);
+        if let Some(observer) = self.memory_observer() {
+            observer.on_write(address, value);
+        }
+        self.write_u8(address, value)
+    }
+
+    // Wraps around 0xFFFF like the CPU would, so that the result always has `size` bytes.
+    fn read_range(&self, address: Wrapping<u16>, size: usize) -> Vec<Wrapping<u8>> {
+        let mut res = Vec::new();
+        for offset in 0..size {
+            res.push(self.read_u8(address + Wrapping(offset as u16)));
+        }
+        res
+    }
+
+    fn registers(&self) -> &Registers {
+        self.cpu().registers()
+    }
+
+    fn registers_mut(&mut self) -> &mut Registers {
+        self.cpu_mut().registers_mut()
+    }
+
+    // Used very frequently
+    fn read_r8(&self, r8: &R8) -> Wrapping<u8> {
+        self.registers().read_r8(r8)
+    }
+
+    // Used very frequently
+    fn write_r8(&mut self, r8: &R8, value: Wrapping<u8>) -> &mut Self {
+        self.registers_mut().write_r8(r8, value);
+        self
+    }
+}
+
+// The whole address space is plain RAM, there is no mapper, no IO, and no boot ROM.
+#[derive(Clone, Debug)]
+pub struct FlatBus {
+    cpu: CPU,
+    interrupts: Interrupts,
+    pub memory: Box<[u8; 0x10000]>,
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        FlatBus {
+            cpu: CPU::new(),
+            interrupts: Interrupts::new(),
+            memory: Box::new([0; 0x10000]),
+        }
+    }
+}
+
+impl Bus for FlatBus {
+    fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        Wrapping(self.memory[address.0 as usize])
+    }
+
+    fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        self.memory[address.0 as usize] = value.0;
+    }
+
+    fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    fn interrupts(&self) -> &Interrupts {
+        &self.interrupts
+    }
+
+    fn interrupts_mut(&mut self) -> &mut Interrupts {
+        &mut self.interrupts
+    }
+}
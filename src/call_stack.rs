@@ -0,0 +1,119 @@
+use std::num::Wrapping;
+
+use crate::instructions::{decode::DecodedInstruction, type_def::Instruction};
+
+// Games that never return (or that reset SP below their frames) would otherwise grow this forever
+const MAX_FRAMES: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct CallFrame {
+    pub call_site: Wrapping<u16>,
+    pub target: Wrapping<u16>,
+    // Value of SP right after the return address was pushed
+    pub stack_pointer: Wrapping<u16>,
+}
+
+// Best-effort tracking of CALL/RST and interrupt dispatch, for display purposes only.  Rather than
+// matching RET instructions, frames are dropped once SP rises above the return address they pushed,
+// so that code manipulating the stack by hand does not leave stale frames behind.
+#[derive(Clone, Debug)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack { frames: Vec::new() }
+    }
+
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn push(&mut self, frame: CallFrame) {
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // Records a frame if the instruction was a call that was taken
+    pub fn track_instruction(
+        &mut self,
+        instruction: &DecodedInstruction,
+        sp_before: Wrapping<u16>,
+        sp_after: Wrapping<u16>,
+        pc_after: Wrapping<u16>,
+    ) {
+        let is_call = matches!(
+            instruction.instruction,
+            Instruction::CALL_a16(_) | Instruction::CALL_cc_u16(_, _) | Instruction::RST(_)
+        );
+        if is_call && sp_after == sp_before - Wrapping(2) {
+            self.push(CallFrame {
+                call_site: instruction.address,
+                target: pc_after,
+                stack_pointer: sp_after,
+            });
+        }
+    }
+
+    pub fn unwind(&mut self, sp: Wrapping<u16>) {
+        while let Some(frame) = self.frames.last() {
+            if frame.stack_pointer >= sp {
+                break;
+            }
+            self.frames.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instructions::asm::*,
+        machine::{
+            tests::{machine_with_program, run_until},
+            Machine,
+        },
+        registers::R16,
+    };
+
+    fn frames(machine: &Machine) -> Vec<(u16, u16, u16)> {
+        machine
+            .call_stack
+            .frames()
+            .iter()
+            .map(|frame| (frame.call_site.0, frame.target.0, frame.stack_pointer.0))
+            .collect()
+    }
+
+    #[test]
+    fn nested_calls_are_tracked_until_they_return() {
+        let mut code = program![ld_r16_u16(R16::SP, 0xFFFE), call(0x0110), jr(-2)];
+        code.resize(0x10, 0);
+        code.extend(program![call(0x0120), ret()]);
+        code.resize(0x20, 0);
+        code.extend(program![rst(0x38), ret()]);
+        let mut machine = machine_with_program(&code);
+        machine.mmu.memory.game_rom[0x38..0x39].copy_from_slice(&ret());
+        run_until(&mut machine, 0x0100);
+        let outer = (0x0103, 0x0110, 0xFFFC);
+        let inner = (0x0110, 0x0120, 0xFFFA);
+        let rst = (0x0120, 0x0038, 0xFFF8);
+        let expected_after_each_step = [
+            vec![],
+            vec![outer],
+            vec![outer, inner],
+            vec![outer, inner, rst],
+            vec![outer, inner],
+            vec![outer],
+            vec![],
+            vec![],
+        ];
+        for expected in expected_after_each_step {
+            machine.execute_one_instruction();
+            assert_eq!(frames(&machine), expected);
+        }
+    }
+}
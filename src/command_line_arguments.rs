@@ -1,5 +1,7 @@
 use clap::Parser;
 
+use crate::application_state::ConsoleMode;
+
 #[derive(Clone, Debug, Parser)]
 #[command(version, about, long_about = None)]
 pub struct CommandLineArguments {
@@ -9,4 +11,53 @@ pub struct CommandLineArguments {
     pub game_rom: String,
     #[arg(short, long, default_value_t = false)]
     pub log_for_doctor: bool,
+    // RGBDS symbol file, used to show labels in the debugger
+    #[arg(long)]
+    pub sym: Option<String>,
+    // Report the illegal opcodes found anywhere in the ROM, before starting
+    #[arg(long, default_value_t = false)]
+    pub scan_opcodes: bool,
+    // Panic when a scanline does not finish drawing in time, instead of recovering from it
+    #[arg(long, default_value_t = false)]
+    pub strict_ppu: bool,
+    // Overrides the console mode detected from the cartridge header
+    #[arg(long, value_enum)]
+    pub mode: Option<ConsoleMode>,
+    // Runs this many frames without a window, then prints a hash of the last one.  Meant to check
+    // test ROMs like dmg-acid2 against a known good frame.
+    #[arg(long)]
+    pub headless_frames: Option<u32>,
+    // Prints what the game sends over the link cable, which is how test ROMs report results
+    #[arg(long, default_value_t = false)]
+    pub serial_stdout: bool,
+    // Keys for the joypad and the debugger, defaults are used when the file does not exist
+    #[arg(long, default_value = "keybindings.toml")]
+    pub keybindings: String,
+    // Where choices made in the GUI are kept between runs
+    #[arg(long, default_value = "preferences.toml")]
+    pub preferences: String,
+    // Frames that turbo buttons stay pressed, then released, while held
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub turbo_period: u32,
+    // Records the buttons of every frame to this file, starting from power-on
+    #[arg(long, conflicts_with = "play_movie")]
+    pub record_movie: Option<String>,
+    // Plays back a movie made with --record-movie, instead of the player's buttons
+    #[arg(long)]
+    pub play_movie: Option<String>,
+    // Pauses after an instruction reads or writes this address, in hexadecimal.  Can be repeated.
+    #[arg(long = "watchpoint", value_parser = parse_address)]
+    pub watchpoints: Vec<u16>,
+}
+
+fn parse_address(address: &str) -> Result<u16, String> {
+    let digits = address.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|e| format!("Not an address: {}", e))
+}
+
+impl CommandLineArguments {
+    // Movies start from power-on, so the battery save is neither loaded nor written
+    pub fn has_movie(&self) -> bool {
+        self.record_movie.is_some() || self.play_movie.is_some()
+    }
 }
@@ -2,7 +2,7 @@ use core::fmt;
 
 use crate::{cpu::CPU, registers::Flag};
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug, Hash, PartialEq)]
 pub enum Condition {
     C,
     Z,
@@ -4,31 +4,31 @@ pub mod timers;
 use std::num::Wrapping;
 
 use crate::{
-    application_state::ROMInformation,
+    bus::Bus,
     instructions::{
         decode::{decode_instruction_at_address, DecodedInstruction},
         type_def::Immediate16,
     },
     machine::Machine,
-    memory::Memory,
     registers::{Registers, R16},
 };
 
 #[derive(Clone, Debug, Hash)]
 pub struct CPU {
     // CPU state
+    // The illegal opcode that locked the CPU, nothing but a reset gets the CPU out of it
+    pub locked_by: Option<u8>,
     pub low_power_mode: bool,
 
     // Subsystems
-    memory: Memory,
     registers: Registers,
 }
 
 impl CPU {
-    pub fn new(boot_rom: Vec<u8>, game_rom: Vec<u8>, rom_information: &ROMInformation) -> Self {
+    pub fn new() -> Self {
         CPU {
+            locked_by: None,
             low_power_mode: false,
-            memory: Memory::new(boot_rom, game_rom, rom_information),
             registers: Registers::new(),
         }
     }
@@ -36,17 +36,18 @@ impl CPU {
     pub fn execute_one_instruction(
         machine: &mut Machine,
     ) -> (Option<DecodedInstruction>, (u8, u8)) {
-        if machine.cpu_mut().low_power_mode {
-            if machine.interrupts.is_interrupt_pending() {
-                machine.cpu_mut().low_power_mode = false;
-                // Fall through on wakeup to execute one instruction
-            } else {
-                // Otherwise, force the other components to move forward
-                return (None, (4, 1));
-            }
+        if machine.cpu().is_locked() {
+            // The CPU is stuck for good, but the other components keep moving forward
+            return (None, (4, 1));
+        }
+        if machine.cpu().low_power_mode {
+            // Waking up is handled by `Machine::step`, force the other components to move forward
+            return (None, (4, 1));
         }
         let next_instruction = decode_instruction_at_address(machine, machine.cpu().registers.pc);
         // println!("About to execute {}", next_instruction);
+        // Fetching each byte of the instruction takes one M-cycle
+        machine.advance_cycles(4 * next_instruction.instruction_size);
         // This will be the default PC, unless instruction semantics overwrite it
         machine.cpu_mut().registers.pc =
             machine.cpu_mut().registers.pc + Wrapping(next_instruction.instruction_size as u16);
@@ -54,10 +55,10 @@ impl CPU {
         (Some(next_instruction), cycles)
     }
 
-    pub fn pop_r16<'a>(machine: &'a mut Machine, r16: &R16) -> &'a mut Machine {
-        let lower = machine.read_u8(machine.cpu().registers.sp);
+    pub fn pop_r16<'a, B: Bus>(machine: &'a mut B, r16: &R16) -> &'a mut B {
+        let lower = machine.cycle_read_u8(machine.cpu().registers.sp);
         machine.cpu_mut().registers.sp += 1;
-        let higher = machine.read_u8(machine.cpu().registers.sp);
+        let higher = machine.cycle_read_u8(machine.cpu().registers.sp);
         machine.cpu_mut().registers.sp += 1;
         let imm16 = Immediate16 {
             lower_byte: lower,
@@ -68,14 +69,31 @@ impl CPU {
     }
 
     // Note: pushes the higher byte goes to higher address!!!
-    pub fn push_imm16<'a>(machine: &'a mut Machine, imm16: Immediate16) -> &'a mut Machine {
+    // Every push (PUSH, CALL, RST, interrupt dispatch) spends one internal M-cycle decrementing SP
+    // before writing.
+    pub fn push_imm16<'a, B: Bus>(machine: &'a mut B, imm16: Immediate16) -> &'a mut B {
+        machine.advance_cycles(4);
         machine.cpu_mut().registers.sp -= 1;
-        machine.write_u8(machine.cpu().registers.sp, imm16.higher_byte);
+        machine.cycle_write_u8(machine.cpu().registers.sp, imm16.higher_byte);
         machine.cpu_mut().registers.sp -= 1;
-        machine.write_u8(machine.cpu().registers.sp, imm16.lower_byte);
+        machine.cycle_write_u8(machine.cpu().registers.sp, imm16.lower_byte);
         machine
     }
 
+    pub fn is_locked(&self) -> bool {
+        self.locked_by.is_some()
+    }
+
+    pub fn status(&self) -> String {
+        if let Some(opcode) = self.locked_by {
+            format!("LOCKED (0x{:02X})", opcode)
+        } else if self.low_power_mode {
+            String::from("HALTED")
+        } else {
+            String::from("RUNNING")
+        }
+    }
+
     pub fn gbdoctor_string(machine: &Machine) -> String {
         let cpu = &machine.cpu();
         let mut res = String::new();
@@ -100,37 +118,118 @@ impl CPU {
         res
     }
 
-    pub fn memory(&self) -> &Memory {
-        &self.memory
+    pub fn registers(&self) -> &Registers {
+        &self.registers
     }
 
-    pub fn memory_mut(&mut self) -> &mut Memory {
-        &mut self.memory
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
     }
+}
 
+impl Machine {
     pub fn registers(&self) -> &Registers {
-        &self.registers
+        &self.cpu().registers
     }
 
     pub fn registers_mut(&mut self) -> &mut Registers {
-        &mut self.registers
+        &mut self.cpu_mut().registers
     }
 }
 
-impl Machine {
-    pub fn memory(&self) -> &Memory {
-        &self.cpu().memory
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application_state::{MapperType, ROMInformation},
+        instructions::asm::*,
+        machine::tests::{machine_with_cartridge, machine_with_program, run_until},
+    };
+
+    #[test]
+    fn pop_af_then_push_af_round_trips_without_the_low_nibble() {
+        let mut machine = machine_with_program(&program![
+            ld_r16_u16(R16::SP, 0xFFFE),
+            ld_r16_u16(R16::BC, 0x12FF),
+            push(R16::BC),
+            pop(R16::AF),
+            push(R16::AF),
+            pop(R16::DE),
+            // 0x010A:
+            jr(-2),
+        ]);
+        run_until(&mut machine, 0x010A);
+        assert_eq!(machine.registers().af, Wrapping(0x12F0));
+        assert_eq!(machine.registers().de, Wrapping(0x12F0));
+        assert_eq!(machine.read_u8(Wrapping(0xFFFC)), Wrapping(0xF0));
     }
 
-    pub fn memory_mut(&mut self) -> &mut Memory {
-        &mut self.cpu_mut().memory
+    #[test]
+    fn push_wraps_sp_below_0x0000() {
+        let mut machine = machine_with_program(&program![
+            ld_r16_u16(R16::SP, 0x0001),
+            ld_r16_u16(R16::BC, 0xABCD),
+            push(R16::BC),
+            // 0x0107:
+            jr(-2),
+        ]);
+        run_until(&mut machine, 0x0107);
+        assert_eq!(machine.registers().sp, Wrapping(0xFFFF));
+        // The high byte goes to ROM, where it is dropped, the low one to IE
+        assert_eq!(machine.read_u8(Wrapping(0x0000)), Wrapping(0x00));
+        assert_eq!(machine.read_u8(Wrapping(0xFFFF)), Wrapping(0xCD));
     }
 
-    pub fn registers(&self) -> &Registers {
-        &self.cpu().registers
+    #[test]
+    fn pop_wraps_sp_past_0xffff() {
+        let mut machine = machine_with_program(&program![
+            ld_a_u8(0x34),
+            ldh_u8_a(0xFE),
+            ld_a_u8(0x12),
+            ldh_u8_a(0xFF),
+            ld_r16_u16(R16::SP, 0xFFFE),
+            pop(R16::DE),
+            // 0x010C:
+            ld_r16_u16(R16::SP, 0xFFFF),
+            pop(R16::HL),
+            // 0x0110:
+            jr(-2),
+        ]);
+        // The last byte of HRAM, then IE
+        run_until(&mut machine, 0x010C);
+        assert_eq!(machine.registers().de, Wrapping(0x1234));
+        assert_eq!(machine.registers().sp, Wrapping(0x0000));
+        // IE, then the first byte of ROM
+        run_until(&mut machine, 0x0110);
+        assert_eq!(machine.registers().hl, Wrapping(0x0012));
+        assert_eq!(machine.registers().sp, Wrapping(0x0001));
     }
 
-    pub fn registers_mut(&mut self) -> &mut Registers {
-        &mut self.cpu_mut().registers
+    #[test]
+    fn pushes_into_rom_program_the_mapper_and_pops_read_rom() {
+        let rom_information = ROMInformation {
+            mapper_type: MapperType::MBC1,
+            rom_banks: 8,
+            ..ROMInformation::new()
+        };
+        let mut machine = machine_with_cartridge(
+            &program![
+                ld_r16_u16(R16::SP, 0x3000),
+                ld_r16_u16(R16::BC, 0x0502),
+                push(R16::BC),
+                pop(R16::DE),
+                // 0x0108:
+                jr(-2),
+            ],
+            rom_information,
+        );
+        machine.mmu.memory.game_rom[0x2FFE..0x3000].copy_from_slice(&[0x11, 0x22]);
+        let game_rom = machine.mmu.memory.game_rom.clone();
+        run_until(&mut machine, 0x0108);
+        // Both bytes went to the ROM bank register, the last one sticks
+        assert_eq!(machine.mmu.mapper.rom_bank(), 2);
+        assert_eq!(machine.mmu.memory.game_rom, game_rom);
+        assert_eq!(machine.registers().de, Wrapping(0x2211));
+        assert_eq!(machine.registers().sp, Wrapping(0x3000));
     }
 }
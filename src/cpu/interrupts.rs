@@ -1,6 +1,8 @@
 use std::num::Wrapping;
 
-use crate::{instructions::type_def::Immediate16, machine::Machine};
+use crate::{
+    bus::Bus, call_stack::CallFrame, instructions::type_def::Immediate16, machine::Machine,
+};
 
 use super::CPU;
 
@@ -45,17 +47,42 @@ impl Interrupts {
     }
 
     pub fn handle_interrupts(machine: &mut Machine) -> (u8, u8) {
-        if let Some(interrupt) = machine.interrupts.should_handle_interrupt() {
-            machine.interrupts.interrupt_flag =
-                machine.interrupts.interrupt_flag & Wrapping(!(1 << interrupt));
+        // A locked CPU never services interrupts
+        if machine.cpu().is_locked() {
+            return (0, 0);
+        }
+        if machine.interrupts.should_handle_interrupt().is_some() {
             machine.interrupts.interrupt_master_enable = false;
             // Here the CPU:
             // - NOPs twice (2 M-cycles)
             // - PUSHes PC (2 M-cycles)
             // - sets PC to the handle (1 M-cycle)
-            // Currently simulating this whole thing at once, but might need granularity
-            CPU::push_imm16(machine, Immediate16::from_u16(machine.cpu().registers.pc));
-            machine.cpu_mut().registers.pc = interrupt_handler_offset(interrupt);
+            // The PUSH is spelled out below, since it can affect which interrupt gets serviced.
+            machine.advance_cycles(8);
+            let call_site = machine.cpu().registers.pc;
+            let call_site_imm16 = Immediate16::from_u16(call_site);
+            machine.cpu_mut().registers.sp -= 1;
+            machine.cycle_write_u8(machine.cpu().registers.sp, call_site_imm16.higher_byte);
+            // The interrupt to service is only chosen now, so pushing the upper byte of PC onto IE
+            // may cancel it.  In that case, the CPU ends up at 0x0000 instead.
+            let interrupt = machine.interrupts.highest_priority_pending_interrupt();
+            machine.cpu_mut().registers.sp -= 1;
+            machine.cycle_write_u8(machine.cpu().registers.sp, call_site_imm16.lower_byte);
+            let target = match interrupt {
+                Some(interrupt) => {
+                    machine.interrupts.interrupt_flag =
+                        machine.interrupts.interrupt_flag & Wrapping(!(1 << interrupt));
+                    interrupt_handler_offset(interrupt)
+                }
+                None => Wrapping(0x0000),
+            };
+            machine.cpu_mut().registers.pc = target;
+            machine.advance_cycles(4);
+            machine.call_stack.push(CallFrame {
+                call_site,
+                target,
+                stack_pointer: machine.cpu().registers.sp,
+            });
             // Execute the first instruction of the interrupt handler to match GB doctor
             let (_, (t_cycles, m_cycles)) = CPU::execute_one_instruction(machine);
             (20 + t_cycles, 5 + m_cycles)
@@ -79,6 +106,10 @@ impl Interrupts {
         if !self.interrupt_master_enable {
             return None;
         }
+        self.highest_priority_pending_interrupt()
+    }
+
+    fn highest_priority_pending_interrupt(&self) -> Option<u8> {
         let masked_ie = self.interrupt_enable.0 & 0x1F;
         let masked_if = self.interrupt_flag.0 & 0x1F;
         let conjoined = masked_ie & masked_if;
@@ -101,3 +132,159 @@ impl Machine {
         &mut self.interrupts
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instructions::asm::*,
+        machine::tests::{machine_with_program, run_until},
+        registers::R16,
+    };
+
+    // Enables and requests the timer interrupt, then runs `sequence` from 0x109.
+    fn machine_with_pending_timer_interrupt(sequence: &[u8]) -> Machine {
+        let program = program![
+            ld_r16_u16(R16::SP, 0xFFFE),
+            ld_a_u8(1 << TIMER_INTERRUPT_BIT),
+            ldh_u8_a(0xFF),
+            ldh_u8_a(0x0F),
+            sequence.to_vec(),
+            // Spins here
+            jr(-2),
+        ];
+        let mut machine = machine_with_program(&program);
+        run_until(&mut machine, 0x109);
+        machine
+    }
+
+    fn is_timer_interrupt_requested(machine: &Machine) -> bool {
+        machine.interrupts.interrupt_flag.0 & (1 << TIMER_INTERRUPT_BIT) != 0
+    }
+
+    #[test]
+    fn ei_enables_interrupts_after_the_next_instruction() {
+        let mut machine = machine_with_pending_timer_interrupt(&program![ei(), nop(), nop()]);
+        machine.execute_one_instruction();
+        assert!(!machine.interrupts.interrupt_master_enable);
+        machine.execute_one_instruction();
+        assert!(machine.interrupts.interrupt_master_enable);
+        assert!(is_timer_interrupt_requested(&machine));
+        // Dispatches, then returns from the handler to the second NOP
+        machine.step();
+        assert!(!is_timer_interrupt_requested(&machine));
+        assert_eq!(machine.registers().pc, Wrapping(0x10B));
+    }
+
+    #[test]
+    fn ei_then_di_never_dispatches() {
+        let mut machine = machine_with_pending_timer_interrupt(&program![ei(), di(), nop()]);
+        run_until(&mut machine, 0x10C);
+        assert!(!machine.interrupts.interrupt_master_enable);
+        assert!(is_timer_interrupt_requested(&machine));
+    }
+
+    #[test]
+    fn ei_twice_then_di_dispatches_before_di() {
+        let mut machine = machine_with_pending_timer_interrupt(&program![ei(), ei(), di(), nop()]);
+        run_until(&mut machine, 0x10B);
+        assert!(machine.interrupts.interrupt_master_enable);
+        machine.step();
+        assert!(!is_timer_interrupt_requested(&machine));
+        // RETI leaves IME set, until DI runs
+        assert_eq!(machine.registers().pc, Wrapping(0x10B));
+        run_until(&mut machine, 0x10D);
+        assert!(!machine.interrupts.interrupt_master_enable);
+    }
+
+    #[test]
+    fn halt_with_ime_off_resumes_after_4_more_dots() {
+        let program = program![
+            ld_r16_u16(R16::SP, 0xFFFE),
+            di(),
+            ld_a_u8(1 << TIMER_INTERRUPT_BIT),
+            ldh_u8_a(0xFF),
+            // 0x108
+            halt(),
+            nop(),
+            jr(-2),
+        ];
+        let mut machine = machine_with_program(&program);
+        run_until(&mut machine, 0x109);
+        assert!(machine.cpu().low_power_mode);
+        // Halted, nothing gets executed
+        let step = machine.step();
+        assert_eq!(step.t_cycles, 4);
+        assert!(step.instruction_executed.is_none());
+        machine.interrupts.request(TIMER_INTERRUPT_BIT);
+        let t_cycle_count = machine.t_cycle_count;
+        let step = machine.step();
+        // Waking up, then the NOP right after HALT
+        assert_eq!(step.t_cycles, 4 + 4);
+        assert_eq!(machine.t_cycle_count - t_cycle_count, 4 + 4);
+        assert_eq!(step.instruction_executed.unwrap().address, Wrapping(0x109));
+        assert_eq!(machine.registers().pc, Wrapping(0x10A));
+        // With IME off, the interrupt is left pending
+        assert!(!machine.cpu().low_power_mode);
+        assert!(is_timer_interrupt_requested(&machine));
+    }
+
+    // Has DIV go from 0 to 1 `dots` into the step that dispatches the timer interrupt, and returns
+    // what the handler reads from DIV with its first instruction.
+    fn div_read_by_the_timer_handler(dots: u8) -> u8 {
+        let mut machine = machine_with_program(&[]);
+        run_until(&mut machine, 0x100);
+        machine.registers_mut().sp = Wrapping(0xFFFE);
+        machine.mmu.memory.game_rom[0x50..0x52].copy_from_slice(&program![ldh_a_u8(0x04)]);
+        machine.interrupts.interrupt_master_enable = true;
+        machine.interrupts.interrupt_enable = Wrapping(1 << TIMER_INTERRUPT_BIT);
+        machine.interrupts.request(TIMER_INTERRUPT_BIT);
+        machine.timers_mut().reset_divide_register();
+        machine.advance_cycles((256 - dots as u16) as u8);
+        machine.step();
+        assert_eq!(machine.registers().pc, Wrapping(0x52));
+        machine.registers().read_a().0
+    }
+
+    #[test]
+    fn dispatch_takes_5_m_cycles_before_the_handler_runs() {
+        // The dispatch takes 20 dots, then LDH A, (0x04) reads on its third M-cycle
+        assert_eq!(div_read_by_the_timer_handler(32), 1);
+        assert_eq!(div_read_by_the_timer_handler(33), 0);
+    }
+
+    // Requests the timer interrupt with PC at `pc` and SP at 0x0000, so that pushing the upper
+    // byte of PC overwrites IE.
+    fn machine_pushing_onto_ie(pc: u16) -> Machine {
+        let mut machine = machine_with_program(&[]);
+        run_until(&mut machine, 0x100);
+        machine.registers_mut().pc = Wrapping(pc);
+        machine.registers_mut().sp = Wrapping(0x0000);
+        machine.interrupts.interrupt_master_enable = true;
+        machine.interrupts.interrupt_enable = Wrapping(1 << TIMER_INTERRUPT_BIT);
+        machine.interrupts.request(TIMER_INTERRUPT_BIT);
+        machine
+    }
+
+    #[test]
+    fn pushing_onto_ie_cancels_the_interrupt() {
+        let mut machine = machine_pushing_onto_ie(0x0100);
+        machine.step();
+        assert_eq!(machine.interrupts.interrupt_enable, Wrapping(0x01));
+        assert!(!machine.interrupts.interrupt_master_enable);
+        // The interrupt was not taken, so it stays requested
+        assert!(is_timer_interrupt_requested(&machine));
+        // Ends up at 0x0000, where the first instruction, a NOP, has been executed too
+        assert_eq!(machine.registers().pc, Wrapping(0x0001));
+    }
+
+    #[test]
+    fn pushing_onto_ie_keeps_the_interrupt_if_still_enabled() {
+        let mut machine = machine_pushing_onto_ie(0x0500);
+        machine.step();
+        assert_eq!(machine.interrupts.interrupt_enable, Wrapping(0x05));
+        assert!(!is_timer_interrupt_requested(&machine));
+        // The handler's RETI has returned
+        assert_eq!(machine.registers().pc, Wrapping(0x0500));
+    }
+}
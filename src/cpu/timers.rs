@@ -9,16 +9,25 @@ const TIMER_COUNTER_ADDRESS: u16 = 0xFF05;
 const TIMER_MODULO_ADDRESS: u16 = 0xFF06;
 const TIMER_CONTROL_ADDRESS: u16 = 0xFF07;
 
+// When TIMA overflows, it reads as 0x00 for one M-cycle, and only gets reloaded from TMA (raising
+// the timer interrupt) at the end of that M-cycle.  During the following M-cycle, TIMA still
+// reflects writes to TMA.
+const TIMER_RELOAD_DELAY_DOTS: u8 = 4;
+
+#[derive(Clone, Debug, Hash, PartialEq)]
+enum TimerCounterState {
+    Counting,
+    Overflowed(u8), // Dots left before the reload
+    Reloading(u8),  // Dots left in the reload M-cycle
+}
+
 #[derive(Clone, Debug, Hash)]
 pub struct Timers {
-    pub divide_register: Wrapping<u8>,
-    divide_register_dots: u16,
-    // When we reset this, we must account for the fact that the reset would happen at the end of
-    // the resetting instruction, rather than the beginning.  So we mark this to know to reset it
-    // later.
-    divide_register_to_be_reset: bool,
+    /// Internal 16-bit counter incremented every dot.  DIV is its upper byte, and TIMA is clocked
+    /// by the falling edges of one of its bits.
+    system_counter: Wrapping<u16>,
     pub timer_counter: Wrapping<u8>,
-    timer_counter_dots: u16,
+    timer_counter_state: TimerCounterState,
     pub timer_modulo: Wrapping<u8>,
     pub timer_control: Wrapping<u8>,
 }
@@ -26,45 +35,79 @@ pub struct Timers {
 impl Timers {
     pub fn new() -> Self {
         Timers {
-            divide_register: Wrapping(0),
-            divide_register_to_be_reset: false,
-            divide_register_dots: 0,
+            system_counter: Wrapping(0),
             timer_counter: Wrapping(0),
-            timer_counter_dots: 0,
+            timer_counter_state: TimerCounterState::Counting,
             timer_modulo: Wrapping(0),
             timer_control: Wrapping(0),
         }
     }
 
-    fn get_timer_counter_threshold(&self) -> u16 {
+    pub fn divide_register(&self) -> Wrapping<u8> {
+        Wrapping((self.system_counter.0 >> 8) as u8)
+    }
+
+    // The bit of the system counter whose falling edges clock TIMA, i.e. TIMA increments every
+    // 1024, 16, 64, or 256 dots respectively.
+    fn get_timer_counter_bit(&self) -> u8 {
         match self.timer_control.0 & 0x3 {
-            0b00 => 1024,
-            0b01 => 16,
-            0b10 => 64,
-            0b11 => 256,
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            0b11 => 7,
             _ => unreachable!(),
         }
     }
 
+    // The selected bit goes through an AND gate with the enable bit before the edge detector.
+    fn timer_counter_signal(&self) -> bool {
+        let is_enabled = (self.timer_control.0 & 0b100) != 0;
+        is_enabled && (self.system_counter.0 >> self.get_timer_counter_bit()) & 1 != 0
+    }
+
+    fn increment_timer_counter(&mut self) {
+        self.timer_counter += 1;
+        if self.timer_counter.0 == 0 {
+            self.timer_counter_state = TimerCounterState::Overflowed(TIMER_RELOAD_DELAY_DOTS);
+        }
+    }
+
+    // Used by STOP and by writes to DIV.  Since the whole system counter is reset, this is seen as
+    // a falling edge if the selected bit was set, and TIMA gets an extra increment.
+    pub fn reset_divide_register(&mut self) {
+        let signal_before = self.timer_counter_signal();
+        self.system_counter = Wrapping(0);
+        if signal_before {
+            self.increment_timer_counter();
+        }
+    }
+
     pub fn tick(&mut self, interrupts: &mut Interrupts) {
-        // TODO: Reset this on STOP
-        // TODO: Freeze this while in STOP mode
-        self.divide_register_dots += 1;
-        if self.divide_register_dots == 256 {
-            self.divide_register_dots = 0;
-            self.divide_register += 1;
-        }
-
-        if (self.timer_control.0 & 0b100) != 0 {
-            self.timer_counter_dots += 1;
-            if self.timer_counter_dots == self.get_timer_counter_threshold() {
-                self.timer_counter_dots = 0;
-                self.timer_counter += 1;
-                if self.timer_counter.0 == 0 {
+        match self.timer_counter_state {
+            TimerCounterState::Counting => {}
+            TimerCounterState::Overflowed(dots_left) => {
+                if dots_left == 1 {
                     self.timer_counter = self.timer_modulo;
                     interrupts.request(TIMER_INTERRUPT_BIT);
+                    self.timer_counter_state =
+                        TimerCounterState::Reloading(TIMER_RELOAD_DELAY_DOTS);
+                } else {
+                    self.timer_counter_state = TimerCounterState::Overflowed(dots_left - 1);
                 }
             }
+            TimerCounterState::Reloading(dots_left) => {
+                self.timer_counter_state = if dots_left == 1 {
+                    TimerCounterState::Counting
+                } else {
+                    TimerCounterState::Reloading(dots_left - 1)
+                };
+            }
+        }
+
+        let signal_before = self.timer_counter_signal();
+        self.system_counter += 1;
+        if signal_before && !self.timer_counter_signal() {
+            self.increment_timer_counter();
         }
     }
 
@@ -72,15 +115,11 @@ impl Timers {
         for _ in 0..dots {
             self.tick(interrupts);
         }
-        if self.divide_register_to_be_reset {
-            self.divide_register_to_be_reset = false;
-            self.divide_register = Wrapping(0);
-        }
     }
 
     pub fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
         match address.0 {
-            DIVIDE_REGISTER_ADDRESS => self.divide_register,
+            DIVIDE_REGISTER_ADDRESS => self.divide_register(),
             TIMER_COUNTER_ADDRESS => self.timer_counter,
             TIMER_MODULO_ADDRESS => self.timer_modulo,
             TIMER_CONTROL_ADDRESS => self.timer_control,
@@ -90,16 +129,34 @@ impl Timers {
 
     pub fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
         match address.0 {
-            DIVIDE_REGISTER_ADDRESS => {
-                // Writing any value to this register resets it.  However, if we were to reset it
-                // here for a 4 t-cycle instruction, it would have started counting 4 by the time
-                // where it should actually be reset.  So instead we mark it to be reset after
-                // simulating the current instruction's t-cycles.
-                self.divide_register_to_be_reset = true;
+            // Writing any value to this register resets it.  Writes happen at the right cycle
+            // within their instruction, so the reset can be immediate.
+            DIVIDE_REGISTER_ADDRESS => self.reset_divide_register(),
+            TIMER_COUNTER_ADDRESS => match self.timer_counter_state {
+                // Writing during the overflow M-cycle cancels the reload and the interrupt
+                TimerCounterState::Overflowed(_) => {
+                    self.timer_counter = value;
+                    self.timer_counter_state = TimerCounterState::Counting;
+                }
+                // Writing during the reload M-cycle is ignored, TMA wins
+                TimerCounterState::Reloading(_) => {}
+                TimerCounterState::Counting => self.timer_counter = value,
+            },
+            TIMER_MODULO_ADDRESS => {
+                self.timer_modulo = value;
+                // TIMA is still being loaded from TMA during the reload M-cycle
+                if let TimerCounterState::Reloading(_) = self.timer_counter_state {
+                    self.timer_counter = value;
+                }
+            }
+            // Changing the selected bit, or disabling the timer, can also produce a falling edge.
+            TIMER_CONTROL_ADDRESS => {
+                let signal_before = self.timer_counter_signal();
+                self.timer_control = value;
+                if signal_before && !self.timer_counter_signal() {
+                    self.increment_timer_counter();
+                }
             }
-            TIMER_COUNTER_ADDRESS => self.timer_counter = value,
-            TIMER_MODULO_ADDRESS => self.timer_modulo = value,
-            TIMER_CONTROL_ADDRESS => self.timer_control = value,
             _ => unreachable!(),
         }
     }
@@ -113,3 +170,146 @@ impl Machine {
         &mut self.timers
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(timers: &mut Timers, address: u16, value: u8) {
+        timers.write_u8(Wrapping(address), Wrapping(value));
+    }
+
+    fn is_timer_interrupt_requested(interrupts: &Interrupts) -> bool {
+        interrupts.interrupt_flag.0 & (1 << TIMER_INTERRUPT_BIT) != 0
+    }
+
+    // Ticks a timer counting every 16 dots until TIMA overflows, TMA holds 0x23.
+    fn overflowed_timers(interrupts: &mut Interrupts) -> Timers {
+        let mut timers = Timers::new();
+        write(&mut timers, TIMER_CONTROL_ADDRESS, 0b101);
+        write(&mut timers, TIMER_MODULO_ADDRESS, 0x23);
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0xFF);
+        for _ in 0..16 {
+            timers.tick(interrupts);
+        }
+        assert_eq!(timers.timer_counter, Wrapping(0));
+        timers
+    }
+
+    #[test]
+    fn tima_reads_zero_for_an_m_cycle_before_the_reload() {
+        let mut interrupts = Interrupts::new();
+        let mut timers = overflowed_timers(&mut interrupts);
+        for _ in 0..3 {
+            timers.tick(&mut interrupts);
+            assert_eq!(timers.timer_counter, Wrapping(0));
+            assert!(!is_timer_interrupt_requested(&interrupts));
+        }
+        timers.tick(&mut interrupts);
+        assert_eq!(timers.timer_counter, Wrapping(0x23));
+        assert!(is_timer_interrupt_requested(&interrupts));
+    }
+
+    #[test]
+    fn writing_tima_before_the_reload_cancels_it() {
+        let mut interrupts = Interrupts::new();
+        let mut timers = overflowed_timers(&mut interrupts);
+        timers.tick(&mut interrupts);
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0x42);
+        for _ in 0..8 {
+            timers.tick(&mut interrupts);
+        }
+        assert_eq!(timers.timer_counter, Wrapping(0x42));
+        assert!(!is_timer_interrupt_requested(&interrupts));
+    }
+
+    #[test]
+    fn writes_during_the_reload_load_tma() {
+        let mut interrupts = Interrupts::new();
+        let mut timers = overflowed_timers(&mut interrupts);
+        for _ in 0..4 {
+            timers.tick(&mut interrupts);
+        }
+        write(&mut timers, TIMER_MODULO_ADDRESS, 0x77);
+        assert_eq!(timers.timer_counter, Wrapping(0x77));
+        // TMA wins over writes to TIMA
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0x11);
+        assert_eq!(timers.timer_counter, Wrapping(0x77));
+        // Once the reload is over, TIMA is written as usual
+        for _ in 0..4 {
+            timers.tick(&mut interrupts);
+        }
+        write(&mut timers, TIMER_COUNTER_ADDRESS, 0x11);
+        assert_eq!(timers.timer_counter, Wrapping(0x11));
+    }
+
+    // Counts every 16 dots, i.e. on falling edges of bit 3 of the system counter.
+    fn timers_at(system_counter: u16) -> Timers {
+        let mut timers = Timers::new();
+        write(&mut timers, TIMER_CONTROL_ADDRESS, 0b101);
+        timers.system_counter = Wrapping(system_counter);
+        timers
+    }
+
+    #[test]
+    fn writing_div_with_the_selected_bit_set_increments_tima() {
+        let mut timers = timers_at(0x0008);
+        write(&mut timers, DIVIDE_REGISTER_ADDRESS, 0x42);
+        assert_eq!(timers.divide_register(), Wrapping(0));
+        assert_eq!(timers.timer_counter, Wrapping(1));
+    }
+
+    #[test]
+    fn writing_div_with_the_selected_bit_clear_leaves_tima() {
+        let mut timers = timers_at(0xFFF7);
+        write(&mut timers, DIVIDE_REGISTER_ADDRESS, 0x42);
+        assert_eq!(timers.divide_register(), Wrapping(0));
+        assert_eq!(timers.timer_counter, Wrapping(0));
+    }
+
+    #[test]
+    fn writing_div_while_the_timer_is_disabled_leaves_tima() {
+        let mut timers = Timers::new();
+        write(&mut timers, TIMER_CONTROL_ADDRESS, 0b001);
+        timers.system_counter = Wrapping(0x0008);
+        write(&mut timers, DIVIDE_REGISTER_ADDRESS, 0x42);
+        assert_eq!(timers.timer_counter, Wrapping(0));
+    }
+
+    #[test]
+    fn changing_the_frequency_increments_tima_on_a_falling_edge() {
+        // The system counter bit selected by each frequency, in TAC order
+        let selected_bits = [9, 3, 5, 7];
+        for old_frequency in 0..4u8 {
+            for new_frequency in 0..4u8 {
+                for system_counter in [0x0000, 0x0008, 0x0020, 0x0080, 0x0200, 0x02A8, 0xFFFF] {
+                    let mut timers = Timers::new();
+                    write(&mut timers, TIMER_CONTROL_ADDRESS, 0b100 | old_frequency);
+                    timers.system_counter = Wrapping(system_counter);
+                    write(&mut timers, TIMER_CONTROL_ADDRESS, 0b100 | new_frequency);
+                    let is_bit_set = |frequency: u8| {
+                        (system_counter >> selected_bits[frequency as usize]) & 1 != 0
+                    };
+                    let expected = is_bit_set(old_frequency) && !is_bit_set(new_frequency);
+                    assert_eq!(
+                        timers.timer_counter,
+                        Wrapping(expected as u8),
+                        "0x{:04X}: {} -> {}",
+                        system_counter,
+                        old_frequency,
+                        new_frequency
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn disabling_the_timer_increments_tima_if_the_selected_bit_is_set() {
+        for (system_counter, expected) in [(0x0008, 1), (0xFFF7, 0)] {
+            let mut timers = timers_at(system_counter);
+            write(&mut timers, TIMER_CONTROL_ADDRESS, 0b001);
+            assert_eq!(timers.timer_counter, Wrapping(expected));
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::num::Wrapping;
+
+// One byte gets copied every M-cycle, so that the whole transfer takes 640 dots.
+const DOTS_PER_COPIED_BYTE: u8 = 4;
+const OAM_DMA_LENGTH: u8 = 0xA0;
+
+// OAM DMA copies 160 bytes from page 0xXX00 (with XX written to FF46) to OAM, in the background.
+#[derive(Clone, Debug, Hash)]
+pub struct DMA {
+    source_page: Wrapping<u8>,
+    /// Index of the next byte to copy, while a transfer is in progress.
+    next_byte: Option<u8>,
+    copy_dots: u8,
+    /// Sources past WRAM are unusual enough to warn about, but only the first time.
+    has_warned_about_source: bool,
+}
+
+impl DMA {
+    pub fn new() -> Self {
+        DMA {
+            source_page: Wrapping(0),
+            next_byte: None,
+            copy_dots: 0,
+            has_warned_about_source: false,
+        }
+    }
+
+    // FF46 reads back the last page written to it.
+    pub fn source_page(&self) -> Wrapping<u8> {
+        self.source_page
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.next_byte.is_some()
+    }
+
+    fn source_warning(&mut self, source_page: Wrapping<u8>) -> Option<String> {
+        if source_page.0 <= 0xDF || self.has_warned_about_source {
+            return None;
+        }
+        self.has_warned_about_source = true;
+        Some(format!(
+            "WARNING: OAM DMA transfer from page 0x{:02X}, past WRAM",
+            source_page.0
+        ))
+    }
+
+    // Writing to FF46 starts a transfer, restarting it from the first byte if one was in progress.
+    pub fn start(&mut self, source_page: Wrapping<u8>) {
+        if let Some(warning) = self.source_warning(source_page) {
+            println!("{}", warning);
+        }
+        self.source_page = source_page;
+        self.next_byte = Some(0);
+        self.copy_dots = 0;
+    }
+
+    // Returns the source address and the OAM offset of the byte to copy on this dot, if any.  The
+    // copy itself is left to the caller, which has access to the whole address space.
+    pub fn tick(&mut self) -> Option<(Wrapping<u16>, usize)> {
+        let byte = self.next_byte?;
+        self.copy_dots += 1;
+        if self.copy_dots < DOTS_PER_COPIED_BYTE {
+            return None;
+        }
+        self.copy_dots = 0;
+        self.next_byte = if byte + 1 < OAM_DMA_LENGTH {
+            Some(byte + 1)
+        } else {
+            None
+        };
+        let source_address = ((self.source_page.0 as u16) << 8) | byte as u16;
+        Some((Wrapping(source_address), byte as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_past_wram_are_only_warned_about_once() {
+        let mut dma = DMA::new();
+        assert!(dma.source_warning(Wrapping(0xC1)).is_none());
+        assert!(dma.source_warning(Wrapping(0xE1)).is_some());
+        assert!(dma.source_warning(Wrapping(0xFF)).is_none());
+        assert!(dma.source_warning(Wrapping(0xE1)).is_none());
+    }
+}
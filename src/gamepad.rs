@@ -0,0 +1,56 @@
+use std::{
+    collections::{HashMap, HashSet},
+    thread,
+};
+
+use gilrs::{Button, EventType, GamepadId, Gilrs};
+use iced::futures::{channel::mpsc, Stream};
+
+use crate::message::Message;
+
+// Gilrs cannot move across threads on every platform, so it lives on a thread of its own, which
+// blocks on gamepad events and forwards button presses to the application.  Gamepads can come and
+// go at any time, Gilrs keeps track of them for us.
+pub fn gamepad_events() -> impl Stream<Item = Message> {
+    let (sender, receiver) = mpsc::unbounded();
+    thread::spawn(move || {
+        let mut gilrs = match Gilrs::new() {
+            Ok(gilrs) => gilrs,
+            Err(e) => {
+                println!("[WARNING] Gamepads are not available: {}", e);
+                return;
+            }
+        };
+        // All gamepads drive the same buttons, so we remember who holds what in order to only
+        // release, on disconnection, the buttons that no other gamepad is still holding.
+        let mut held_buttons: HashMap<GamepadId, HashSet<Button>> = HashMap::new();
+        while let Some(event) = gilrs.next_event_blocking(None) {
+            let messages = match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    held_buttons.entry(event.id).or_default().insert(button);
+                    vec![Message::GamepadPressed(button)]
+                }
+                EventType::ButtonReleased(button, _) => {
+                    held_buttons.entry(event.id).or_default().remove(&button);
+                    vec![Message::GamepadReleased(button)]
+                }
+                EventType::Disconnected => {
+                    let released = held_buttons.remove(&event.id).unwrap_or_default();
+                    released
+                        .into_iter()
+                        .filter(|button| !held_buttons.values().any(|held| held.contains(button)))
+                        .map(Message::GamepadReleased)
+                        .collect()
+                }
+                _ => continue,
+            };
+            for message in messages {
+                // The application is gone
+                if sender.unbounded_send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
@@ -0,0 +1,73 @@
+// Runs test ROMs without a window for a fixed number of frames, and compares the hash of the last
+// frame with the one recorded under golden_frames/.  The ROMs are not part of the repository, so
+// these tests are ignored by default, and are given the ROMs through environment variables:
+//
+//     YOKOYBOI_BOOT_ROM=dmg_boot.bin YOKOYBOI_DMG_ACID2=dmg-acid2.gb \
+//     YOKOYBOI_CPU_INSTRS_01=01-special.gb cargo test golden_frames -- --ignored
+//
+// When a change is meant to alter what they show, check the new frames by eye in the GUI, then run
+// the same command with YOKOYBOI_REGENERATE_GOLDEN_FRAMES=1 to record their hashes.
+
+use std::{env, fs, path::PathBuf};
+
+use crate::{
+    machine::Machine,
+    memory::{load_boot_rom, load_game_rom},
+    utils::fnv1a_hash,
+};
+
+const BOOT_ROM_VARIABLE: &str = "YOKOYBOI_BOOT_ROM";
+const REGENERATE_VARIABLE: &str = "YOKOYBOI_REGENERATE_GOLDEN_FRAMES";
+
+fn rom_path(variable: &str) -> String {
+    env::var(variable).unwrap_or_else(|_| panic!("{} should point at the ROM to run", variable))
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("golden_frames")
+        .join(format!("{}.hash", name))
+}
+
+fn check_golden_frame(name: &str, rom_variable: &str, frames: u32) {
+    let boot_rom = load_boot_rom(&rom_path(BOOT_ROM_VARIABLE)).unwrap();
+    let (game_rom, rom_information) = load_game_rom(&rom_path(rom_variable)).unwrap();
+    let mut machine = Machine::new(boot_rom, game_rom, rom_information, false, false);
+    for _ in 0..frames {
+        machine.run_frame();
+    }
+    let hash = format!("{:016X}\n", fnv1a_hash(&machine.ppu().front_buffer));
+
+    let reference_path = reference_path(name);
+    if env::var_os(REGENERATE_VARIABLE).is_some() {
+        fs::create_dir_all(reference_path.parent().unwrap()).unwrap();
+        fs::write(&reference_path, &hash).unwrap();
+        println!("Recorded {}", reference_path.display());
+        return;
+    }
+    let reference = fs::read_to_string(&reference_path).unwrap_or_else(|e| {
+        panic!(
+            "No reference frame for {} ({}), record it with {}=1",
+            name, e, REGENERATE_VARIABLE
+        )
+    });
+    assert_eq!(
+        hash, reference,
+        "{} does not draw the frame it used to",
+        name
+    );
+}
+
+#[test]
+#[ignore]
+fn dmg_acid2() {
+    check_golden_frame("dmg-acid2", "YOKOYBOI_DMG_ACID2", 300);
+}
+
+// The result screen of blargg's first CPU test, which only passes with the right flags for the
+// instructions it exercises.
+#[test]
+#[ignore]
+fn cpu_instrs_01_special() {
+    check_golden_frame("cpu_instrs-01-special", "YOKOYBOI_CPU_INSTRS_01", 900);
+}
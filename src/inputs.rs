@@ -1,23 +1,589 @@
-use std::num::Wrapping;
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
+
+use crate::cpu::interrupts::{Interrupts, JOYPAD_INTERRUPT_BIT};
+
+// P1 select bits of interest, they are active-low too
+const SELECT_DIRECTION_BUTTONS_BIT: u8 = 4;
+const SELECT_ACTION_BUTTONS_BIT: u8 = 5;
+const SELECT_BITS: u8 = 1 << SELECT_DIRECTION_BUTTONS_BIT | 1 << SELECT_ACTION_BUTTONS_BIT;
+const UNUSED_BITS: u8 = 0xC0;
+
+// Physical state of the buttons, `true` meaning pressed.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Buttons {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+// Where button presses come from.  Each source is tracked on its own, so that releasing a key does
+// not release the same button held on the gamepad.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum InputSource {
+    Keyboard,
+    Gamepad,
+}
+
+impl Buttons {
+    // Pressed on either side
+    fn or(&self, other: &Buttons) -> Buttons {
+        Buttons {
+            right: self.right || other.right,
+            left: self.left || other.left,
+            up: self.up || other.up,
+            down: self.down || other.down,
+            a: self.a || other.a,
+            b: self.b || other.b,
+            select: self.select || other.select,
+            start: self.start || other.start,
+        }
+    }
+
+    // One bit per button, directions in the low nibble like on the lines
+    pub fn to_byte(&self) -> u8 {
+        [
+            self.right,
+            self.left,
+            self.up,
+            self.down,
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |byte, (bit, is_pressed)| {
+            byte | (*is_pressed as u8) << bit
+        })
+    }
+
+    pub fn from_byte(byte: u8) -> Buttons {
+        let is_pressed = |bit: u8| byte & (1 << bit) != 0;
+        Buttons {
+            right: is_pressed(0),
+            left: is_pressed(1),
+            up: is_pressed(2),
+            down: is_pressed(3),
+            a: is_pressed(4),
+            b: is_pressed(5),
+            select: is_pressed(6),
+            start: is_pressed(7),
+        }
+    }
+
+    fn button_mut(&mut self, button: Button) -> &mut bool {
+        match button {
+            Button::Right => &mut self.right,
+            Button::Left => &mut self.left,
+            Button::Up => &mut self.up,
+            Button::Down => &mut self.down,
+            Button::A => &mut self.a,
+            Button::B => &mut self.b,
+            Button::Select => &mut self.select,
+            Button::Start => &mut self.start,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Inputs {
-    pub inputs_register: Wrapping<u8>,
+    keyboard_buttons: Buttons,
+    gamepad_buttons: Buttons,
+    /// Buttons held for turbo, which are pressed only every other turbo period
+    keyboard_turbo_buttons: Buttons,
+    gamepad_turbo_buttons: Buttons,
+    /// How many frames turbo buttons stay pressed, then released
+    pub turbo_period: u32,
+    turbo_frames: u32,
+    is_turbo_pressed: bool,
+    /// Set while a movie plays or records, replaces the buttons from all sources
+    overridden_buttons: Option<Buttons>,
+    /// Whether the joypad interrupt was requested during the current frame, and the one before,
+    /// for the debugger
+    is_interrupt_requested_this_frame: bool,
+    was_interrupt_requested_last_frame: bool,
+    /// Only the select bits are stored, the lower nibble is computed from the buttons on reads.
+    inputs_register: Wrapping<u8>,
+}
+
+// The interrupt flags are only there for the debugger, they never change what the game sees.
+impl Hash for Inputs {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.keyboard_buttons.hash(state);
+        self.gamepad_buttons.hash(state);
+        self.keyboard_turbo_buttons.hash(state);
+        self.gamepad_turbo_buttons.hash(state);
+        self.turbo_period.hash(state);
+        self.turbo_frames.hash(state);
+        self.is_turbo_pressed.hash(state);
+        self.overridden_buttons.hash(state);
+        self.inputs_register.hash(state);
+    }
 }
 
 impl Inputs {
     pub fn new() -> Self {
         Inputs {
+            keyboard_buttons: Buttons::default(),
+            gamepad_buttons: Buttons::default(),
+            keyboard_turbo_buttons: Buttons::default(),
+            gamepad_turbo_buttons: Buttons::default(),
+            turbo_period: 1,
+            turbo_frames: 0,
+            is_turbo_pressed: false,
+            overridden_buttons: None,
+            is_interrupt_requested_this_frame: false,
+            was_interrupt_requested_last_frame: false,
             inputs_register: Wrapping(0),
         }
     }
 
+    // What the game sees
+    pub fn buttons(&self) -> Buttons {
+        self.overridden_buttons
+            .unwrap_or_else(|| self.held_buttons())
+    }
+
+    // What the player holds, from all sources together
+    pub fn held_buttons(&self) -> Buttons {
+        let buttons = self.keyboard_buttons.or(&self.gamepad_buttons);
+        if self.is_turbo_pressed {
+            buttons.or(&self.turbo_buttons())
+        } else {
+            buttons
+        }
+    }
+
+    fn turbo_buttons(&self) -> Buttons {
+        self.keyboard_turbo_buttons.or(&self.gamepad_turbo_buttons)
+    }
+
+    fn source_buttons_mut(&mut self, source: InputSource) -> &mut Buttons {
+        match source {
+            InputSource::Keyboard => &mut self.keyboard_buttons,
+            InputSource::Gamepad => &mut self.gamepad_buttons,
+        }
+    }
+
+    fn source_turbo_buttons_mut(&mut self, source: InputSource) -> &mut Buttons {
+        match source {
+            InputSource::Keyboard => &mut self.keyboard_turbo_buttons,
+            InputSource::Gamepad => &mut self.gamepad_turbo_buttons,
+        }
+    }
+
+    // Each line is pulled low by a pressed button from any of the selected groups.
+    fn lines(&self) -> u8 {
+        let buttons = self.buttons();
+        let directions = group_lines([buttons.right, buttons.left, buttons.up, buttons.down]);
+        let actions = group_lines([buttons.a, buttons.b, buttons.select, buttons.start]);
+        match (self.are_directions_selected(), self.are_actions_selected()) {
+            // Nothing drives the lines, they all read as released
+            (false, false) => 0x0F,
+            (true, false) => directions,
+            (false, true) => actions,
+            // Both groups share the lines, a button from either one pulls its line low
+            (true, true) => directions & actions,
+        }
+    }
+
+    pub fn are_directions_selected(&self) -> bool {
+        self.inputs_register.0 & (1 << SELECT_DIRECTION_BUTTONS_BIT) == 0
+    }
+
+    pub fn are_actions_selected(&self) -> bool {
+        self.inputs_register.0 & (1 << SELECT_ACTION_BUTTONS_BIT) == 0
+    }
+
+    pub fn is_interrupt_requested_this_frame(&self) -> bool {
+        self.is_interrupt_requested_this_frame
+    }
+
+    pub fn was_interrupt_requested_last_frame(&self) -> bool {
+        self.was_interrupt_requested_last_frame
+    }
+
+    // Bits 6 and 7 are not wired to anything and always read as 1.
     pub fn read(&self) -> Wrapping<u8> {
-        self.inputs_register
+        Wrapping(UNUSED_BITS | self.inputs_register.0 | self.lines())
+    }
+
+    // Lines are active-low, so any 0 in the lower nibble is a pressed button.
+    pub fn is_any_line_low(&self) -> bool {
+        self.lines() != 0x0F
+    }
+
+    // The joypad interrupt is requested when any line goes from high to low.  This can happen
+    // either because a button got pressed, or because a group with a pressed button got selected.
+    fn update_lines(&mut self, lines_before: u8, interrupts: &mut Interrupts) {
+        if lines_before & !self.lines() != 0 {
+            interrupts.request(JOYPAD_INTERRUPT_BIT);
+            self.is_interrupt_requested_this_frame = true;
+        }
+    }
+
+    pub fn set_buttons(
+        &mut self,
+        source: InputSource,
+        buttons: Buttons,
+        interrupts: &mut Interrupts,
+    ) {
+        let lines_before = self.lines();
+        *self.source_buttons_mut(source) = buttons;
+        self.update_lines(lines_before, interrupts);
+    }
+
+    pub fn set_button(
+        &mut self,
+        source: InputSource,
+        button: Button,
+        is_pressed: bool,
+        interrupts: &mut Interrupts,
+    ) {
+        let mut buttons = *self.source_buttons_mut(source);
+        *buttons.button_mut(button) = is_pressed;
+        self.set_buttons(source, buttons, interrupts);
+    }
+
+    // Turbo buttons start out pressed, so that a short tap still registers.
+    pub fn set_turbo_buttons(
+        &mut self,
+        source: InputSource,
+        buttons: Buttons,
+        interrupts: &mut Interrupts,
+    ) {
+        let lines_before = self.lines();
+        if self.turbo_buttons() == Buttons::default() {
+            self.turbo_frames = 0;
+            self.is_turbo_pressed = true;
+        }
+        *self.source_turbo_buttons_mut(source) = buttons;
+        self.update_lines(lines_before, interrupts);
+    }
+
+    pub fn set_turbo_button(
+        &mut self,
+        source: InputSource,
+        button: Button,
+        is_held: bool,
+        interrupts: &mut Interrupts,
+    ) {
+        let mut buttons = *self.source_turbo_buttons_mut(source);
+        *buttons.button_mut(button) = is_held;
+        self.set_turbo_buttons(source, buttons, interrupts);
+    }
+
+    // Called on frame boundaries by the emulation loop, rather than on a timer, so that turbo is
+    // deterministic and replays the same way as recorded inputs.
+    pub fn advance_frame(&mut self, interrupts: &mut Interrupts) {
+        self.was_interrupt_requested_last_frame = self.is_interrupt_requested_this_frame;
+        self.is_interrupt_requested_this_frame = false;
+        if self.turbo_buttons() == Buttons::default() {
+            return;
+        }
+        self.turbo_frames += 1;
+        if self.turbo_frames >= self.turbo_period {
+            let lines_before = self.lines();
+            self.turbo_frames = 0;
+            self.is_turbo_pressed = !self.is_turbo_pressed;
+            self.update_lines(lines_before, interrupts);
+        }
+    }
+
+    pub fn set_overridden_buttons(
+        &mut self,
+        buttons: Option<Buttons>,
+        interrupts: &mut Interrupts,
+    ) {
+        let lines_before = self.lines();
+        self.overridden_buttons = buttons;
+        self.update_lines(lines_before, interrupts);
+    }
+
+    pub fn write(&mut self, value: Wrapping<u8>, interrupts: &mut Interrupts) {
+        let lines_before = self.lines();
+        // Only the select bits can be written, the lines are read-only
+        self.inputs_register = Wrapping(value.0 & SELECT_BITS);
+        self.update_lines(lines_before, interrupts);
+    }
+}
+
+// The lines of a group of 4 buttons, each pulled low while its button is pressed.
+fn group_lines(pressed: [bool; 4]) -> u8 {
+    pressed
+        .iter()
+        .enumerate()
+        .fold(0x0F, |lines, (line, is_pressed)| {
+            if *is_pressed {
+                lines & !(1 << line)
+            } else {
+                lines
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sources_pressing_the_same_button() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.set_button(InputSource::Keyboard, Button::A, true, &mut interrupts);
+        inputs.set_button(InputSource::Gamepad, Button::A, true, &mut interrupts);
+        assert!(inputs.buttons().a);
+        inputs.set_button(InputSource::Keyboard, Button::A, false, &mut interrupts);
+        assert!(inputs.buttons().a, "still held on the gamepad");
+        inputs.set_button(InputSource::Gamepad, Button::A, false, &mut interrupts);
+        assert!(!inputs.buttons().a);
+    }
+
+    #[test]
+    fn releasing_on_one_source_keeps_the_other_sources_buttons() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.set_button(InputSource::Keyboard, Button::Left, true, &mut interrupts);
+        inputs.set_button(InputSource::Gamepad, Button::Start, true, &mut interrupts);
+        inputs.set_buttons(InputSource::Gamepad, Buttons::default(), &mut interrupts);
+        let buttons = inputs.buttons();
+        assert!(buttons.left);
+        assert!(!buttons.start);
+    }
+
+    #[test]
+    fn releasing_a_button_that_was_not_pressed_changes_nothing() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.set_button(InputSource::Gamepad, Button::B, true, &mut interrupts);
+        inputs.set_button(InputSource::Keyboard, Button::B, false, &mut interrupts);
+        assert!(inputs.buttons().b);
+    }
+
+    // Right is on line 0, B on line 1, and Start on line 3.
+    fn inputs_with_right_b_and_start(interrupts: &mut Interrupts) -> Inputs {
+        let mut inputs = Inputs::new();
+        for button in [Button::Right, Button::B, Button::Start] {
+            inputs.set_button(InputSource::Keyboard, button, true, interrupts);
+        }
+        inputs
+    }
+
+    #[test]
+    fn each_select_configuration_reads_its_group() {
+        let mut interrupts = Interrupts::new();
+        let mut inputs = inputs_with_right_b_and_start(&mut interrupts);
+        for (select_bits, lines) in [(0x30, 0x0F), (0x20, 0x0E), (0x10, 0x05), (0x00, 0x04)] {
+            inputs.write(Wrapping(select_bits), &mut interrupts);
+            assert_eq!(
+                inputs.read().0 & 0x0F,
+                lines,
+                "select bits 0x{:02X}",
+                select_bits
+            );
+        }
+    }
+
+    fn is_joypad_interrupt_requested(interrupts: &Interrupts) -> bool {
+        interrupts.interrupt_flag.0 & (1 << JOYPAD_INTERRUPT_BIT) != 0
+    }
+
+    #[test]
+    fn pressing_a_selected_button_interrupts() {
+        let directions = [Button::Right, Button::Left, Button::Up, Button::Down];
+        let actions = [Button::A, Button::B, Button::Select, Button::Start];
+        for (select_bits, interrupting) in [
+            (0x30, vec![]),
+            (0x20, directions.to_vec()),
+            (0x10, actions.to_vec()),
+            (0x00, [directions, actions].concat()),
+        ] {
+            for button in directions.into_iter().chain(actions) {
+                let mut inputs = Inputs::new();
+                let mut interrupts = Interrupts::new();
+                inputs.write(Wrapping(select_bits), &mut interrupts);
+                inputs.set_button(InputSource::Keyboard, button, true, &mut interrupts);
+                assert_eq!(
+                    is_joypad_interrupt_requested(&interrupts),
+                    interrupting.contains(&button),
+                    "{:?} with select bits 0x{:02X}",
+                    button,
+                    select_bits
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn selecting_a_group_with_a_pressed_button_interrupts() {
+        // Going from the first select bits to the second ones, with Right, B and Start pressed
+        for (from, to, is_interrupting) in [
+            (0x30, 0x20, true),
+            (0x30, 0x10, true),
+            (0x30, 0x00, true),
+            (0x20, 0x10, true),
+            (0x10, 0x20, true),
+            // Line 0 only goes low for Right, lines 1 and 3 are already low
+            (0x10, 0x00, true),
+            // Lines 1 and 3 only go low for B and Start, line 0 is already low
+            (0x20, 0x00, true),
+            // Deselecting only releases lines
+            (0x00, 0x20, false),
+            (0x00, 0x10, false),
+            (0x20, 0x30, false),
+        ] {
+            let mut interrupts = Interrupts::new();
+            let mut inputs = inputs_with_right_b_and_start(&mut interrupts);
+            inputs.write(Wrapping(from), &mut interrupts);
+            interrupts.interrupt_flag = Wrapping(0);
+            inputs.write(Wrapping(to), &mut interrupts);
+            assert_eq!(
+                is_joypad_interrupt_requested(&interrupts),
+                is_interrupting,
+                "select bits 0x{:02X} then 0x{:02X}",
+                from,
+                to
+            );
+        }
+    }
+
+    #[test]
+    fn both_groups_selected_share_the_lines() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.write(Wrapping(0x00), &mut interrupts);
+        inputs.set_button(InputSource::Keyboard, Button::Left, true, &mut interrupts);
+        assert!(is_joypad_interrupt_requested(&interrupts));
+        interrupts.interrupt_flag = Wrapping(0);
+        // B is on line 1 as well, which Left already holds low
+        inputs.set_button(InputSource::Gamepad, Button::B, true, &mut interrupts);
+        assert!(!is_joypad_interrupt_requested(&interrupts));
+        // Releasing Left keeps the line low, so pressing it again does not interrupt either
+        inputs.set_button(InputSource::Keyboard, Button::Left, false, &mut interrupts);
+        inputs.set_button(InputSource::Keyboard, Button::Left, true, &mut interrupts);
+        assert!(!is_joypad_interrupt_requested(&interrupts));
+        assert_eq!(inputs.read().0 & 0x0F, 0x0D);
+    }
+
+    #[test]
+    fn pressing_then_releasing_a_button() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        // Actions selected
+        inputs.write(Wrapping(0x10), &mut interrupts);
+        inputs.set_button(InputSource::Keyboard, Button::A, true, &mut interrupts);
+        assert_eq!(inputs.read().0 & 0x0F, 0x0E);
+        assert!(is_joypad_interrupt_requested(&interrupts));
+        interrupts.interrupt_flag = Wrapping(0);
+        inputs.set_button(InputSource::Keyboard, Button::A, false, &mut interrupts);
+        assert_eq!(inputs.read().0 & 0x0F, 0x0F);
+        // Only high to low transitions interrupt
+        assert!(!is_joypad_interrupt_requested(&interrupts));
+    }
+
+    #[test]
+    fn key_repeats_do_not_press_again() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.write(Wrapping(0x20), &mut interrupts);
+        inputs.set_button(InputSource::Keyboard, Button::Up, true, &mut interrupts);
+        interrupts.interrupt_flag = Wrapping(0);
+        for _ in 0..3 {
+            inputs.set_button(InputSource::Keyboard, Button::Up, true, &mut interrupts);
+        }
+        assert!(!is_joypad_interrupt_requested(&interrupts));
+        inputs.set_button(InputSource::Keyboard, Button::Up, false, &mut interrupts);
+        assert!(!inputs.buttons().up);
+    }
+
+    #[test]
+    fn releasing_all_of_a_source_on_focus_loss() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.set_button(InputSource::Keyboard, Button::Down, true, &mut interrupts);
+        inputs.set_button(InputSource::Keyboard, Button::Select, true, &mut interrupts);
+        inputs.set_button(InputSource::Gamepad, Button::B, true, &mut interrupts);
+        inputs.set_buttons(InputSource::Keyboard, Buttons::default(), &mut interrupts);
+        assert_eq!(
+            inputs.buttons(),
+            Buttons {
+                b: true,
+                ..Buttons::default()
+            }
+        );
+    }
+
+    #[test]
+    fn unused_bits_read_as_one_and_only_select_bits_are_stored() {
+        let mut interrupts = Interrupts::new();
+        let released = Inputs::new();
+        let pressed = inputs_with_right_b_and_start(&mut interrupts);
+        // Written, then read back with nothing pressed, and with Right, B and Start pressed
+        let cases = [
+            (0x30, 0xFF, 0xFF),
+            (0x20, 0xEF, 0xEE),
+            (0x10, 0xDF, 0xD5),
+            (0x00, 0xCF, 0xC4),
+            // Writes to the other bits are dropped
+            (0xFF, 0xFF, 0xFF),
+            (0xCA, 0xCF, 0xC4),
+            (0x25, 0xEF, 0xEE),
+        ];
+        for (mut inputs, is_pressed) in [(released, false), (pressed, true)] {
+            for (written, read_released, read_pressed) in cases {
+                inputs.write(Wrapping(written), &mut interrupts);
+                let expected = if is_pressed {
+                    read_pressed
+                } else {
+                    read_released
+                };
+                assert_eq!(inputs.read().0, expected, "0x{:02X} written", written);
+            }
+        }
+    }
+
+    // Whether A is pressed on each of the 10 frames turbo A is held
+    fn turbo_a_over_10_frames(turbo_period: u32) -> Vec<bool> {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        inputs.turbo_period = turbo_period;
+        inputs.set_turbo_button(InputSource::Keyboard, Button::A, true, &mut interrupts);
+        let mut pressed = Vec::new();
+        for _ in 0..10 {
+            pressed.push(inputs.buttons().a);
+            inputs.advance_frame(&mut interrupts);
+        }
+        inputs.set_turbo_button(InputSource::Keyboard, Button::A, false, &mut interrupts);
+        assert!(!inputs.buttons().a);
+        pressed
     }
 
-    pub fn write(&mut self, value: Wrapping<u8>) {
-        // Lower nibble is read-only
-        self.inputs_register = Wrapping((value.0 & 0xF0) | (self.inputs_register.0 & 0x0F));
+    #[test]
+    fn turbo_alternates_every_period() {
+        let (t, f) = (true, false);
+        assert_eq!(turbo_a_over_10_frames(1), [t, f, t, f, t, f, t, f, t, f]);
+        assert_eq!(turbo_a_over_10_frames(2), [t, t, f, f, t, t, f, f, t, t]);
+        assert_eq!(turbo_a_over_10_frames(3), [t, t, t, f, f, f, t, t, t, f]);
     }
 }
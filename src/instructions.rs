@@ -1,4 +1,10 @@
+#[cfg(test)]
+pub mod asm;
+pub mod cache;
+mod carry;
 pub mod decode;
 mod display;
 mod semantics;
+#[cfg(test)]
+mod single_step_tests;
 pub mod type_def;
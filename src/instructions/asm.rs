@@ -0,0 +1,335 @@
+// Encodes instructions for tests, so that test programs can be written as instructions rather than
+// as opcode bytes.  Only the instructions that tests use are here, add more as needed, along with
+// a round-trip test below.
+
+use crate::{
+    conditions::Condition,
+    registers::{R16, R8},
+};
+
+// Concatenates instructions into a program.  With an origin, the program is preceded by as many
+// zeros (NOPs) as needed for its first instruction to sit at that address.
+macro_rules! program {
+    (origin = $origin:expr; $($instruction:expr),* $(,)?) => {{
+        let mut bytes: Vec<u8> = vec![0; $origin];
+        $(bytes.extend($instruction);)*
+        bytes
+    }};
+    ($($instruction:expr),* $(,)?) => {
+        program!(origin = 0; $($instruction),*)
+    };
+}
+pub(crate) use program;
+
+// The 3 bits that select a register in most opcodes.  (HL) takes the place of F, it has its own
+// helpers.
+fn r8_index(r8: &R8) -> u8 {
+    match r8 {
+        R8::B => 0,
+        R8::C => 1,
+        R8::D => 2,
+        R8::E => 3,
+        R8::H => 4,
+        R8::L => 5,
+        R8::A => 7,
+        R8::F => panic!("F cannot be an operand"),
+    }
+}
+
+fn condition_index(condition: &Condition) -> u8 {
+    match condition {
+        Condition::NZ => 0,
+        Condition::Z => 1,
+        Condition::NC => 2,
+        Condition::C => 3,
+    }
+}
+
+// AF only exists for PUSH and POP, SP for everything else.
+fn r16_index(r16: &R16) -> u8 {
+    match r16 {
+        R16::BC => 0,
+        R16::DE => 1,
+        R16::HL => 2,
+        R16::AF | R16::SP => 3,
+        R16::PC => panic!("PC cannot be an operand"),
+    }
+}
+
+fn with_u16(opcode: u8, value: u16) -> Vec<u8> {
+    let [lower, higher] = value.to_le_bytes();
+    vec![opcode, lower, higher]
+}
+
+pub fn nop() -> Vec<u8> {
+    vec![0x00]
+}
+
+pub fn stop() -> Vec<u8> {
+    vec![0x10, 0x00]
+}
+
+pub fn halt() -> Vec<u8> {
+    vec![0x76]
+}
+
+pub fn di() -> Vec<u8> {
+    vec![0xF3]
+}
+
+pub fn ei() -> Vec<u8> {
+    vec![0xFB]
+}
+
+pub fn daa() -> Vec<u8> {
+    vec![0x27]
+}
+
+pub fn scf() -> Vec<u8> {
+    vec![0x37]
+}
+
+pub fn ccf() -> Vec<u8> {
+    vec![0x3F]
+}
+
+pub fn ld_r8_u8(r8: R8, value: u8) -> Vec<u8> {
+    vec![0x06 | r8_index(&r8) << 3, value]
+}
+
+pub fn ld_a_u8(value: u8) -> Vec<u8> {
+    ld_r8_u8(R8::A, value)
+}
+
+pub fn ld_r8_r8(destination: R8, source: R8) -> Vec<u8> {
+    vec![0x40 | r8_index(&destination) << 3 | r8_index(&source)]
+}
+
+pub fn ld_r16_u16(r16: R16, value: u16) -> Vec<u8> {
+    with_u16(0x01 | r16_index(&r16) << 4, value)
+}
+
+pub fn ld_mhl_a() -> Vec<u8> {
+    vec![0x77]
+}
+
+pub fn ld_mhlinc_a() -> Vec<u8> {
+    vec![0x22]
+}
+
+pub fn ld_a_mhl() -> Vec<u8> {
+    vec![0x7E]
+}
+
+pub fn ld_r8_mhl(r8: R8) -> Vec<u8> {
+    vec![0x46 | r8_index(&r8) << 3]
+}
+
+pub fn ldh_u8_a(offset: u8) -> Vec<u8> {
+    vec![0xE0, offset]
+}
+
+pub fn ldh_a_u8(offset: u8) -> Vec<u8> {
+    vec![0xF0, offset]
+}
+
+pub fn ld_mu16_a(address: u16) -> Vec<u8> {
+    with_u16(0xEA, address)
+}
+
+pub fn ld_a_mu16(address: u16) -> Vec<u8> {
+    with_u16(0xFA, address)
+}
+
+pub fn inc_r8(r8: R8) -> Vec<u8> {
+    vec![0x04 | r8_index(&r8) << 3]
+}
+
+pub fn dec_r8(r8: R8) -> Vec<u8> {
+    vec![0x05 | r8_index(&r8) << 3]
+}
+
+pub fn add_a_r8(r8: R8) -> Vec<u8> {
+    vec![0x80 | r8_index(&r8)]
+}
+
+pub fn sub_a_r8(r8: R8) -> Vec<u8> {
+    vec![0x90 | r8_index(&r8)]
+}
+
+pub fn xor_a_r8(r8: R8) -> Vec<u8> {
+    vec![0xA8 | r8_index(&r8)]
+}
+
+pub fn add_a_u8(value: u8) -> Vec<u8> {
+    vec![0xC6, value]
+}
+
+pub fn sub_a_u8(value: u8) -> Vec<u8> {
+    vec![0xD6, value]
+}
+
+pub fn cp_a_u8(value: u8) -> Vec<u8> {
+    vec![0xFE, value]
+}
+
+pub fn push(r16: R16) -> Vec<u8> {
+    vec![0xC5 | r16_index(&r16) << 4]
+}
+
+pub fn pop(r16: R16) -> Vec<u8> {
+    vec![0xC1 | r16_index(&r16) << 4]
+}
+
+// Offsets are relative to the end of the JR instruction.
+pub fn jr(offset: i8) -> Vec<u8> {
+    vec![0x18, offset as u8]
+}
+
+pub fn jr_cc(condition: Condition, offset: i8) -> Vec<u8> {
+    vec![0x20 | condition_index(&condition) << 3, offset as u8]
+}
+
+pub fn jr_nz(offset: i8) -> Vec<u8> {
+    jr_cc(Condition::NZ, offset)
+}
+
+pub fn jr_z(offset: i8) -> Vec<u8> {
+    jr_cc(Condition::Z, offset)
+}
+
+pub fn jp(address: u16) -> Vec<u8> {
+    with_u16(0xC3, address)
+}
+
+pub fn call(address: u16) -> Vec<u8> {
+    with_u16(0xCD, address)
+}
+
+pub fn ret() -> Vec<u8> {
+    vec![0xC9]
+}
+
+pub fn reti() -> Vec<u8> {
+    vec![0xD9]
+}
+
+// The vector is one of 0x00, 0x08, ..., 0x38.
+pub fn rst(vector: u8) -> Vec<u8> {
+    assert_eq!(vector & !0x38, 0, "No RST to 0x{:02X}", vector);
+    vec![0xC7 | vector]
+}
+
+pub fn bit(bit: u8, r8: R8) -> Vec<u8> {
+    vec![0xCB, 0x40 | bit << 3 | r8_index(&r8)]
+}
+
+pub fn swap(r8: R8) -> Vec<u8> {
+    vec![0xCB, 0x30 | r8_index(&r8)]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use super::*;
+    use crate::{
+        bus::FlatBus,
+        instructions::{
+            decode::decode_instruction_at_address,
+            type_def::{Immediate16, Instruction},
+        },
+    };
+
+    fn imm16(value: u16) -> Immediate16 {
+        Immediate16::from_u16(Wrapping(value))
+    }
+
+    #[test]
+    fn helpers_agree_with_the_decoder() {
+        let cases = [
+            (nop(), Instruction::NOP),
+            (stop(), Instruction::STOP),
+            (halt(), Instruction::HALT),
+            (di(), Instruction::DI),
+            (ei(), Instruction::EI),
+            (daa(), Instruction::DAA),
+            (scf(), Instruction::SCF),
+            (ccf(), Instruction::CCF),
+            (ld_a_u8(0x42), Instruction::LD_r8_u8(R8::A, Wrapping(0x42))),
+            (
+                ld_r8_u8(R8::L, 0x99),
+                Instruction::LD_r8_u8(R8::L, Wrapping(0x99)),
+            ),
+            (ld_r8_r8(R8::B, R8::A), Instruction::LD_r8_r8(R8::B, R8::A)),
+            (ld_r8_r8(R8::A, R8::H), Instruction::LD_r8_r8(R8::A, R8::H)),
+            (
+                ld_r16_u16(R16::HL, 0xC000),
+                Instruction::LD_r16_d16(R16::HL, imm16(0xC000)),
+            ),
+            (
+                ld_r16_u16(R16::SP, 0xFFFE),
+                Instruction::LD_SP_u16(imm16(0xFFFE)),
+            ),
+            (ld_mhl_a(), Instruction::LD_mr16_r8(R16::HL, R8::A)),
+            (ld_mhlinc_a(), Instruction::LD_mHLinc_A),
+            (ld_a_mhl(), Instruction::LD_A_mr16(R16::HL)),
+            (ld_r8_mhl(R8::H), Instruction::LD_H_mHL),
+            (ld_r8_mhl(R8::L), Instruction::LD_L_mHL),
+            (ld_r8_mhl(R8::B), Instruction::LD_r8_mr16(R8::B, R16::HL)),
+            (ldh_u8_a(0x40), Instruction::LD_FFu8_A(Wrapping(0x40))),
+            (ldh_a_u8(0x44), Instruction::LD_A_FFu8(Wrapping(0x44))),
+            (ld_mu16_a(0x2000), Instruction::LD_mu16_A(imm16(0x2000))),
+            (ld_a_mu16(0xA000), Instruction::LD_A_mu16(imm16(0xA000))),
+            (inc_r8(R8::C), Instruction::INC_r8(R8::C)),
+            (dec_r8(R8::A), Instruction::DEC_r8(R8::A)),
+            (add_a_r8(R8::B), Instruction::ADD_A_r8(R8::B)),
+            (sub_a_r8(R8::E), Instruction::SUB_A_r8(R8::E)),
+            (xor_a_r8(R8::A), Instruction::XOR_A_r8(R8::A)),
+            (add_a_u8(0x01), Instruction::ADD_A_u8(Wrapping(0x01))),
+            (sub_a_u8(0x10), Instruction::SUB_A_u8(Wrapping(0x10))),
+            (cp_a_u8(0x90), Instruction::CP_A_u8(Wrapping(0x90))),
+            (push(R16::AF), Instruction::PUSH_r16(R16::AF)),
+            (pop(R16::DE), Instruction::POP_r16(R16::DE)),
+            (jr(-2), Instruction::JR_i8(Wrapping(-2))),
+            (
+                jr_nz(-5),
+                Instruction::JR_cc_i8(Condition::NZ, Wrapping(-5)),
+            ),
+            (jr_z(3), Instruction::JR_cc_i8(Condition::Z, Wrapping(3))),
+            (
+                jr_cc(Condition::C, 0x10),
+                Instruction::JR_cc_i8(Condition::C, Wrapping(0x10)),
+            ),
+            (jp(0x0150), Instruction::JP_u16(imm16(0x0150))),
+            (call(0x1234), Instruction::CALL_a16(imm16(0x1234))),
+            (ret(), Instruction::RET),
+            (reti(), Instruction::RETI),
+            (rst(0x38), Instruction::RST(imm16(0x0038))),
+            (bit(7, R8::H), Instruction::BIT_u3_r8(7, R8::H)),
+            (swap(R8::A), Instruction::SWAP_r8(R8::A)),
+        ];
+        for (bytes, expected) in cases {
+            let mut bus = FlatBus::new();
+            bus.memory[0x100..0x100 + bytes.len()].copy_from_slice(&bytes);
+            let decoded = decode_instruction_at_address(&bus, Wrapping(0x100));
+            assert_eq!(decoded.instruction, expected, "{:02X?}", bytes);
+            assert_eq!(
+                decoded.instruction_size as usize,
+                bytes.len(),
+                "{:?}",
+                expected
+            );
+            assert_eq!(expected.size() as usize, bytes.len(), "{:?}", expected);
+        }
+    }
+
+    #[test]
+    fn program_pads_to_its_origin() {
+        let bytes = program!(origin = 0x100; nop(), jp(0x0150));
+        assert_eq!(bytes.len(), 0x104);
+        assert!(bytes[..0x100].iter().all(|byte| *byte == 0));
+        assert_eq!(bytes[0x100..], [0x00, 0xC3, 0x50, 0x01]);
+        assert_eq!(program![ei(), halt()], [0xFB, 0x76]);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{cell::RefCell, collections::HashMap, num::Wrapping};
+
+use crate::{machine::Machine, memory::Memory};
+
+use super::decode::{decode_instruction_at_address, DecodedInstruction};
+
+// The debugger decodes the same instructions over and over, so we remember the ones that come from
+// ROM.  Entries are keyed by (bank, address): switching banks simply makes for different keys.
+#[derive(Debug)]
+pub struct InstructionCache {
+    decoded: RefCell<HashMap<(u16, u16), DecodedInstruction>>,
+}
+
+impl InstructionCache {
+    pub fn new() -> Self {
+        InstructionCache {
+            decoded: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Only ROM is immutable, anything else (including the boot ROM, which gets unmapped) must be
+    // decoded every time.  Instructions that straddle the end of bank 0 depend on the mapped bank.
+    fn key(machine: &Machine, address: Wrapping<u16>) -> Option<(u16, u16)> {
+        if machine.is_dmg_boot_rom_on() && address.0 <= 0xFF {
+            return None;
+        }
+        match address.0 {
+            0x0000..=0x3FFD => Some((machine.current_low_rom_bank(), address.0)),
+            0x3FFE..=0x7FFD => Some((machine.current_rom_bank(), address.0)),
+            _ => None,
+        }
+    }
+
+    pub fn decode_instruction_at(
+        &self,
+        machine: &Machine,
+        address: Wrapping<u16>,
+    ) -> DecodedInstruction {
+        let Some(key) = InstructionCache::key(machine, address) else {
+            return decode_instruction_at_address(machine, address);
+        };
+        self.decoded
+            .borrow_mut()
+            .entry(key)
+            .or_insert_with(|| decode_instruction_at_address(machine, address))
+            .clone()
+    }
+
+    pub fn decode_instructions_at(
+        &self,
+        machine: &Machine,
+        address: Wrapping<u16>,
+        how_many: u8,
+    ) -> Vec<DecodedInstruction> {
+        Memory::decode_instructions_with(address, how_many, |pc| {
+            self.decode_instruction_at(machine, pc)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application_state::{MapperType, ROMInformation},
+        instructions::{asm::*, type_def::Instruction},
+        machine::tests::machine_with_cartridge,
+        registers::R8,
+    };
+
+    #[test]
+    fn switching_rom_banks_decodes_the_new_bank() {
+        let rom_information = ROMInformation {
+            mapper_type: MapperType::MBC1,
+            rom_banks: 4,
+            ..ROMInformation::new()
+        };
+        let mut machine = machine_with_cartridge(&[], rom_information);
+        let game_rom = &mut machine.mmu.memory.game_rom;
+        game_rom[0x4000..0x4001].copy_from_slice(&nop());
+        game_rom[0x8000..0x8001].copy_from_slice(&inc_r8(R8::A));
+        let cache = InstructionCache::new();
+        let decode = |machine: &Machine| cache.decode_instruction_at(machine, Wrapping(0x4000));
+        assert_eq!(decode(&machine).instruction, Instruction::NOP);
+        assert_eq!(decode(&machine).instruction, Instruction::NOP);
+        machine.write_u8(Wrapping(0x2000), Wrapping(0x02));
+        assert_eq!(decode(&machine).instruction, Instruction::INC_r8(R8::A));
+        machine.write_u8(Wrapping(0x2000), Wrapping(0x01));
+        assert_eq!(decode(&machine).instruction, Instruction::NOP);
+    }
+}
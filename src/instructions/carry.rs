@@ -0,0 +1,91 @@
+// Both helpers take `b` as an i32 so that signed operands (ADD SP, i8 and LD HL, SP+i8) go through
+// the same code path as unsigned ones: masking a negative i32 keeps its two's complement low bits,
+// which is exactly what the hardware adds.
+
+// Checks whether adding a and b with bitsize (bit - 1) would produce a carry (1) at position bit.
+// Assumes bit <= 16, so that all operations can be carried without loss as i32.
+pub fn add_produces_carry(a: impl Into<u16>, b: impl Into<i32>, c: bool, bit: u8) -> bool {
+    let a = a.into() as i32;
+    let b = b.into();
+    let bit_mask = 1 << bit;
+    let input_mask = bit_mask - 1;
+    ((a & input_mask) + (b & input_mask) + c as i32) & bit_mask == bit_mask
+}
+
+// Checks whether subtracting b from a with bitsize (bit - 1) would produce a borrow at position
+// bit.  Assumes bit <= 16, so that all operations can be carried without loss as i32.
+pub fn sub_borrows(a: impl Into<u16>, b: impl Into<i32>, c: bool, bit: u8) -> bool {
+    let a = a.into() as i32;
+    let b = b.into();
+    let bit_mask = 1 << bit;
+    let input_mask = bit_mask - 1;
+    // Put a 1 in borrowable position, then borrow occured if it became a 0
+    ((bit_mask | (a & input_mask)) - (b & input_mask) - (c as i32)) & bit_mask == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Operands as the hardware sees them: signed ones are sign-extended to 16 bits.
+    fn operands() -> impl Iterator<Item = (u16, i32)> {
+        let bs: Vec<i32> = (-128..=0xFF).chain((0..=0xFFFF).step_by(0x3FF)).collect();
+        (0..=0xFFFF)
+            .step_by(37)
+            .chain(0xFFF0..=0xFFFF)
+            .flat_map(move |a| bs.clone().into_iter().map(move |b| (a, b)))
+    }
+
+    // The bits that received a carry are the ones where the sum differs from the plain XOR of the
+    // operands.
+    fn carries(a: u16, b: i32, c: bool) -> u32 {
+        let b = b as u16 as u32;
+        let sum = a as u32 + b + c as u32;
+        a as u32 ^ b ^ sum
+    }
+
+    // Same for borrows, with the difference.
+    fn borrows(a: u16, b: i32, c: bool) -> u32 {
+        let b = b as u16 as u32;
+        let difference = (a as u32).wrapping_sub(b).wrapping_sub(c as u32);
+        a as u32 ^ b ^ difference
+    }
+
+    #[test]
+    fn add_produces_carry_agrees_with_full_width_arithmetic() {
+        for (a, b) in operands() {
+            for c in [false, true] {
+                for bit in [4, 8, 12, 16] {
+                    assert_eq!(
+                        add_produces_carry(a, b, c, bit),
+                        carries(a, b, c) & 1 << bit != 0,
+                        "0x{:04X} + {} + {} at bit {}",
+                        a,
+                        b,
+                        c as u8,
+                        bit
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sub_borrows_agrees_with_full_width_arithmetic() {
+        for (a, b) in operands() {
+            for c in [false, true] {
+                for bit in [4, 8, 12, 16] {
+                    assert_eq!(
+                        sub_borrows(a, b, c, bit),
+                        borrows(a, b, c) & 1 << bit != 0,
+                        "0x{:04X} - {} - {} at bit {}",
+                        a,
+                        b,
+                        c as u8,
+                        bit
+                    );
+                }
+            }
+        }
+    }
+}
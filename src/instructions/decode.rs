@@ -1,8 +1,8 @@
 use std::{fmt, num::Wrapping};
 
 use crate::{
+    bus::Bus,
     conditions::Condition,
-    machine::Machine,
     registers::{R16, R8},
 };
 
@@ -32,8 +32,8 @@ impl DecodedInstruction {
     }
 }
 
-pub fn decode_instruction_at_address(
-    machine: &Machine,
+pub fn decode_instruction_at_address<B: Bus>(
+    machine: &B,
     address: Wrapping<u16>,
 ) -> DecodedInstruction {
     let mut bytes_read: u16 = 0;
@@ -70,7 +70,11 @@ pub fn decode_instruction_at_address(
         0x0E => Instruction::LD_r8_u8(R8::C, next_u8(&mut bytes_read)),
         0x0F => Instruction::RRCA,
 
-        0x10 => Instruction::STOP,
+        0x10 => {
+            // STOP is a 2-byte instruction, the second byte is ignored
+            next_u8(&mut bytes_read);
+            Instruction::STOP
+        }
         0x11 => Instruction::LD_r16_d16(R16::DE, next_imm16(&mut bytes_read)),
         0x12 => Instruction::LD_mr16_r8(R16::DE, R8::A),
         0x13 => Instruction::INC_r16(R16::DE),
@@ -161,7 +165,7 @@ pub fn decode_instruction_at_address(
         0x63 => Instruction::LD_r8_r8(R8::H, R8::E),
         0x64 => Instruction::LD_r8_r8(R8::H, R8::H),
         0x65 => Instruction::LD_r8_r8(R8::H, R8::L),
-        0x66 => Instruction::LD_r8_mr16(R8::H, R16::HL),
+        0x66 => Instruction::LD_H_mHL,
         0x67 => Instruction::LD_r8_r8(R8::H, R8::A),
         0x68 => Instruction::LD_r8_r8(R8::L, R8::B),
         0x69 => Instruction::LD_r8_r8(R8::L, R8::C),
@@ -169,7 +173,7 @@ pub fn decode_instruction_at_address(
         0x6B => Instruction::LD_r8_r8(R8::L, R8::E),
         0x6C => Instruction::LD_r8_r8(R8::L, R8::H),
         0x6D => Instruction::LD_r8_r8(R8::L, R8::L),
-        0x6E => Instruction::LD_r8_mr16(R8::L, R16::HL),
+        0x6E => Instruction::LD_L_mHL,
         0x6F => Instruction::LD_r8_r8(R8::L, R8::A),
 
         0x70 => Instruction::LD_mr16_r8(R16::HL, R8::B),
@@ -601,6 +605,91 @@ pub fn decode_instruction_at_address(
         address: address,
         instruction: i,
         instruction_size: bytes_read as u8,
-        raw: machine.read_range(address, bytes_read as usize).into(),
+        raw: machine.read_range(address, bytes_read as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+
+    // The opcodes that lock up the CPU
+    const ILLEGAL_OPCODES: [u8; 11] = [
+        0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+    ];
+
+    fn bus_with(address: u16, bytes: &[u8]) -> FlatBus {
+        let mut bus = FlatBus::new();
+        for (offset, byte) in bytes.iter().enumerate() {
+            bus.write_u8(Wrapping(address) + Wrapping(offset as u16), Wrapping(*byte));
+        }
+        bus
+    }
+
+    #[test]
+    fn every_opcode_decodes() {
+        for opcode in 0x00..=0xFF {
+            let bus = bus_with(0x100, &[opcode, 0x34, 0x12]);
+            let decoded = decode_instruction_at_address(&bus, Wrapping(0x100));
+            if ILLEGAL_OPCODES.contains(&opcode) {
+                assert_eq!(decoded.instruction, Instruction::Illegal(opcode));
+            } else {
+                assert!(
+                    !matches!(decoded.instruction, Instruction::Illegal(_)),
+                    "0x{:02X} decoded as {:?}",
+                    opcode,
+                    decoded.instruction
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_prefixed_opcode_decodes() {
+        for opcode in 0x00..=0xFF {
+            let bus = bus_with(0x100, &[0xCB, opcode]);
+            let decoded = decode_instruction_at_address(&bus, Wrapping(0x100));
+            assert!(
+                !matches!(decoded.instruction, Instruction::Illegal(_)),
+                "0xCB 0x{:02X} decoded as {:?}",
+                opcode,
+                decoded.instruction
+            );
+            assert_eq!(decoded.instruction_size, 2);
+        }
+    }
+
+    #[test]
+    fn sizes_agree_with_the_bytes_decoded() {
+        let opcodes = (0x00..=0xFF).map(|opcode| [opcode, 0x34, 0x12]);
+        let prefixed = (0x00..=0xFF).map(|opcode| [0xCB, opcode, 0x00]);
+        for bytes in opcodes.chain(prefixed) {
+            let decoded = decode_instruction_at_address(&bus_with(0x100, &bytes), Wrapping(0x100));
+            assert_eq!(
+                decoded.instruction_size,
+                decoded.instruction.size(),
+                "{:?}",
+                decoded.instruction
+            );
+            assert_eq!(decoded.raw.len(), decoded.instruction_size as usize);
+        }
+    }
+
+    #[test]
+    fn instructions_wrap_around_0xffff() {
+        for address in [0xFFFD, 0xFFFE, 0xFFFF] {
+            let bus = bus_with(address, &[0x01, 0x78, 0x56]);
+            let decoded = decode_instruction_at_address(&bus, Wrapping(address));
+            assert_eq!(
+                decoded.instruction,
+                Instruction::LD_r16_d16(R16::BC, Immediate16::from_u16(Wrapping(0x5678)))
+            );
+            assert_eq!(decoded.raw.len(), decoded.instruction_size as usize);
+            assert_eq!(
+                decoded.raw,
+                [Wrapping(0x01), Wrapping(0x78), Wrapping(0x56)]
+            );
+        }
     }
 }
@@ -2,6 +2,68 @@ use std::num::Wrapping;
 
 use super::{decode::DecodedInstruction, type_def::Instruction};
 
+// Names of the IO registers, as used in the Pan Docs.
+fn io_register_name(address: u16) -> Option<&'static str> {
+    Some(match address {
+        0xFF00 => "P1",
+        0xFF01 => "SB",
+        0xFF02 => "SC",
+        0xFF04 => "DIV",
+        0xFF05 => "TIMA",
+        0xFF06 => "TMA",
+        0xFF07 => "TAC",
+        0xFF0F => "IF",
+        0xFF10 => "NR10",
+        0xFF11 => "NR11",
+        0xFF12 => "NR12",
+        0xFF13 => "NR13",
+        0xFF14 => "NR14",
+        0xFF16 => "NR21",
+        0xFF17 => "NR22",
+        0xFF18 => "NR23",
+        0xFF19 => "NR24",
+        0xFF1A => "NR30",
+        0xFF1B => "NR31",
+        0xFF1C => "NR32",
+        0xFF1D => "NR33",
+        0xFF1E => "NR34",
+        0xFF20 => "NR41",
+        0xFF21 => "NR42",
+        0xFF22 => "NR43",
+        0xFF23 => "NR44",
+        0xFF24 => "NR50",
+        0xFF25 => "NR51",
+        0xFF26 => "NR52",
+        0xFF40 => "LCDC",
+        0xFF41 => "STAT",
+        0xFF42 => "SCY",
+        0xFF43 => "SCX",
+        0xFF44 => "LY",
+        0xFF45 => "LYC",
+        0xFF46 => "DMA",
+        0xFF47 => "BGP",
+        0xFF48 => "OBP0",
+        0xFF49 => "OBP1",
+        0xFF4A => "WY",
+        0xFF4B => "WX",
+        0xFF4D => "KEY1",
+        0xFF50 => "BOOT",
+        0xFFFF => "IE",
+        _ => return None,
+    })
+}
+
+fn display_address(address: Wrapping<u16>) -> String {
+    match io_register_name(address.0) {
+        Some(name) => String::from(name),
+        None => format!("0x{:04X}", address),
+    }
+}
+
+fn display_ffu8(u8: &Wrapping<u8>) -> String {
+    display_address(Wrapping(0xFF00) + Wrapping(u8.0 as u16))
+}
+
 impl DecodedInstruction {
     fn resolve_relative(&self, i8: Wrapping<i8>) -> u16 {
         (self.address + Wrapping(self.instruction_size as u16))
@@ -9,6 +71,21 @@ impl DecodedInstruction {
             .wrapping_add_signed(i8.0 as i16)
     }
 
+    // Where this instruction may transfer control to, when known statically
+    pub fn jump_target(&self) -> Option<Wrapping<u16>> {
+        match &self.instruction {
+            Instruction::CALL_a16(imm16)
+            | Instruction::CALL_cc_u16(_, imm16)
+            | Instruction::JP_cc_u16(_, imm16)
+            | Instruction::JP_u16(imm16)
+            | Instruction::RST(imm16) => Some(imm16.as_u16()),
+            Instruction::JR_cc_i8(_, i8) | Instruction::JR_i8(i8) => {
+                Some(Wrapping(self.resolve_relative(*i8)))
+            }
+            _ => None,
+        }
+    }
+
     pub fn as_string(&self) -> String {
         match &self.instruction {
             Instruction::ADC_A_mHL => String::from("ADC A [HL]"),
@@ -18,7 +95,7 @@ impl DecodedInstruction {
             Instruction::ADD_A_r8(r8) => format!("ADD A, {}", r8),
             Instruction::ADD_A_u8(u8) => format!("ADD A, 0x{:02X}", u8),
             Instruction::ADD_HL_r16(r16) => format!("ADD HL, {}", r16),
-            Instruction::ADD_SP_i8(i8) => format!("ADD SP, 0x{:02X}", i8),
+            Instruction::ADD_SP_i8(i8) => format!("ADD SP, {} (= 0x{:02X})", i8, i8),
             Instruction::AND_A_mHL => String::from("AND A, [HL]"),
             Instruction::AND_A_r8(r8) => format!("AND A, {}", r8),
             Instruction::AND_u8(u8) => format!("AND A, 0x{:02X}", u8),
@@ -38,7 +115,8 @@ impl DecodedInstruction {
             Instruction::DI => String::from("DI"),
             Instruction::EI => String::from("EI"),
             Instruction::HALT => String::from("HALT"),
-            Instruction::Illegal(opcode) => format!("ILLEGAL 0x{:02X}", opcode),
+            // Not an instruction, most likely data: show it as such
+            Instruction::Illegal(opcode) => format!("DB 0x{:02X}", opcode),
             Instruction::INC_mHL => String::from("INC [HL]"),
             Instruction::INC_r16(r16) => format!("INC {}", r16),
             Instruction::INC_r8(r8) => format!("INC {}", r8),
@@ -48,16 +126,9 @@ impl DecodedInstruction {
             Instruction::JR_cc_i8(cc, i8) => {
                 format!("JR {}, {} (=0x{:04X})", cc, i8, self.resolve_relative(*i8))
             }
-            Instruction::JR_i8(i8) => format!("JR 0x{:02X}", i8),
-            Instruction::JR_r8(r8) => format!("JP {}", r8),
-            Instruction::LD_FFu8_A(u8) => format!(
-                "LD [0x{:04X}], A",
-                Wrapping(0xFF00) + Wrapping((*u8).0 as u16)
-            ),
-            Instruction::LD_A_FFu8(u8) => format!(
-                "LD A, [0x{:04X}]",
-                Wrapping(0xFF00) + Wrapping((*u8).0 as u16)
-            ),
+            Instruction::JR_i8(i8) => format!("JR {} (=0x{:04X})", i8, self.resolve_relative(*i8)),
+            Instruction::LD_FFu8_A(u8) => format!("LD [{}], A", display_ffu8(u8)),
+            Instruction::LD_A_FFu8(u8) => format!("LD A, [{}]", display_ffu8(u8)),
             Instruction::LD_A_FFC => String::from("LD A, [0xFF00 + C]"),
             Instruction::LD_r16_d16(r16, imm16) => {
                 format!("LD {} 0x{:04X} (= {})", r16, imm16.as_u16(), imm16.as_u16())
@@ -68,16 +139,20 @@ impl DecodedInstruction {
             Instruction::LD_A_mr16(r16) => format!("LD A [{}]", r16),
             Instruction::LD_A_mHLdec => String::from("LD A [HL-]"),
             Instruction::LD_A_mHLinc => String::from("LD A [HL+]"),
-            Instruction::LD_A_mu16(imm16) => format!("LD A, [0x{:04X}]", imm16.as_u16()),
+            Instruction::LD_A_mu16(imm16) => {
+                format!("LD A, [{}]", display_address(imm16.as_u16()))
+            }
             Instruction::LD_FFC_A => String::from("LD [0xFF00 + C], A"),
             Instruction::LD_H_mHL => String::from("LD H, [HL]"),
-            Instruction::LD_HL_SP_i8(i8) => format!("LD HL, SP+{:02X}", i8),
+            Instruction::LD_HL_SP_i8(i8) => format!("LD HL, SP{:+} (= 0x{:02X})", i8, i8),
             Instruction::LD_L_mHL => String::from("LD L, [HL]"),
             Instruction::LD_mHL_u8(u8) => format!("LD [HL], 0x{:02X}", u8),
             Instruction::LD_mHLdec_A => String::from("LD [HL-], A"),
             Instruction::LD_mHLinc_A => String::from("LD [HL+], A"),
             Instruction::LD_mr16_r8(mr16, r8) => format!("LD [{}] {}", mr16, r8),
-            Instruction::LD_mu16_A(imm16) => format!("LD [0x{:04X}], A", imm16.as_u16()),
+            Instruction::LD_mu16_A(imm16) => {
+                format!("LD [{}], A", display_address(imm16.as_u16()))
+            }
             Instruction::LD_mu16_SP(imm16) => format!("LD [0x{:04X}] SP", imm16.as_u16()),
             Instruction::LD_r8_mr16(r8, mr16) => format!("LD {} [{}]", r8, mr16),
             Instruction::LD_r8_r8(r8a, r8b) => format!("LD {}, {}", r8a, r8b),
@@ -131,3 +206,87 @@ impl DecodedInstruction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::{Bus, FlatBus},
+        conditions::Condition,
+        instructions::{asm::*, decode::decode_instruction_at_address},
+    };
+
+    fn decode(address: u16, bytes: &[u8]) -> DecodedInstruction {
+        let mut bus = FlatBus::new();
+        for (offset, byte) in bytes.iter().enumerate() {
+            bus.write_u8(Wrapping(address) + Wrapping(offset as u16), Wrapping(*byte));
+        }
+        decode_instruction_at_address(&bus, Wrapping(address))
+    }
+
+    #[test]
+    fn jr_shows_the_target_address() {
+        // Targets are relative to the end of the 2-byte instruction, at 0x0152
+        let cases = [
+            (0x18, jr(0x10), 0x0162, "JR 16 (=0x0162)"),
+            (0x20, jr_nz(-2), 0x0150, "JR NZ, -2 (=0x0150)"),
+            (0x28, jr_z(-128), 0x00D2, "JR Z, -128 (=0x00D2)"),
+            (
+                0x30,
+                jr_cc(Condition::NC, 127),
+                0x01D1,
+                "JR NC, 127 (=0x01D1)",
+            ),
+            (
+                0x38,
+                jr_cc(Condition::C, -82),
+                0x0100,
+                "JR C, -82 (=0x0100)",
+            ),
+        ];
+        for (opcode, bytes, target, expected) in cases {
+            assert_eq!(bytes[0], opcode);
+            let decoded = decode(0x0150, &bytes);
+            assert_eq!(decoded.jump_target(), Some(Wrapping(target)));
+            assert_eq!(decoded.as_string(), expected);
+        }
+    }
+
+    #[test]
+    fn jr_targets_wrap_around_the_address_space() {
+        let cases = [
+            (0x0200, jr(5), "JR 5 (=0x0207)"),
+            (0x0233, jr(-9), "JR -9 (=0x022C)"),
+            (0xFFF0, jr(32), "JR 32 (=0x0012)"),
+            (0x0001, jr(-8), "JR -8 (=0xFFFB)"),
+            (0xFFFE, jr_nz(0), "JR NZ, 0 (=0x0000)"),
+        ];
+        for (address, bytes, expected) in cases {
+            assert_eq!(decode(address, &bytes).as_string(), expected);
+        }
+    }
+
+    #[test]
+    fn operands_show_signed_values_and_io_register_names() {
+        let cases = [
+            (vec![0xE8, 0xFE], "ADD SP, -2 (= 0xFE)"),
+            (vec![0xE8, 0x7F], "ADD SP, 127 (= 0x7F)"),
+            (vec![0xF8, 0x05], "LD HL, SP+5 (= 0x05)"),
+            (vec![0xF8, 0x80], "LD HL, SP-128 (= 0x80)"),
+            (ldh_u8_a(0x40), "LD [LCDC], A"),
+            (ldh_a_u8(0x44), "LD A, [LY]"),
+            (ldh_a_u8(0x00), "LD A, [P1]"),
+            (ldh_u8_a(0x50), "LD [BOOT], A"),
+            // Unnamed ones, HRAM included
+            (ldh_u8_a(0x4C), "LD [0xFF4C], A"),
+            (ldh_a_u8(0x80), "LD A, [0xFF80]"),
+            (ld_mu16_a(0xFFFF), "LD [IE], A"),
+            (ld_a_mu16(0xFF0F), "LD A, [IF]"),
+            (ld_mu16_a(0xC000), "LD [0xC000], A"),
+            (ld_a_mu16(0xFF4D), "LD A, [KEY1]"),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(decode(0x0150, &bytes).as_string(), expected);
+        }
+    }
+}
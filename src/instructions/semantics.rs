@@ -1,33 +1,15 @@
 use std::num::Wrapping;
 
 use crate::{
+    bus::Bus,
     cpu::CPU,
-    machine::Machine,
     registers::{Flag, R16},
 };
 
-use super::type_def::{Immediate16, Instruction};
-
-// Checks whether adding a and b with bitsize (bit - 1) would produce a carry (1) at position bit.
-// Assumes bit < 16, so that all operations can be carried without loss as u32.
-fn add_produces_carry(a: impl Into<u16>, b: impl Into<i32>, c: bool, bit: u8) -> bool {
-    let a = a.into() as i32;
-    let b = b.into();
-    let bit_mask = 1 << bit;
-    let input_mask = bit_mask - 1;
-    ((a & input_mask) + (b & input_mask) + c as i32) & bit_mask == bit_mask
-}
-
-// Checks whether subtracting b from a with bitsize (bit - 1) would produce a borrow at position
-// bit.  Assumes bit < 16, so that all operations can be carried without loss as u32.
-fn sub_borrows(a: impl Into<u16>, b: impl Into<u16>, c: bool, bit: u8) -> bool {
-    let a = a.into() as u32;
-    let b = b.into() as u32;
-    let bit_mask = 1 << bit;
-    let input_mask = (1 << bit) - 1;
-    // Put a 1 in borrowable position, then borrow occured if it became a 0
-    ((bit_mask | (a & input_mask)) - (b & input_mask) - (c as u32)) & bit_mask == 0
-}
+use super::{
+    carry::{add_produces_carry, sub_borrows},
+    type_def::{Immediate16, Instruction},
+};
 
 fn compare(cpu: &mut CPU, a: &Wrapping<u8>, b: &Wrapping<u8>) {
     cpu.registers_mut().znhc(
@@ -98,25 +80,23 @@ fn xor(cpu: &mut CPU, a: &Wrapping<u8>, b: &Wrapping<u8>) {
         .znhc(res.0 == 0, false, false, false);
 }
 
-fn call(machine: &mut Machine, address: Wrapping<u16>) {
+fn call<B: Bus>(machine: &mut B, address: Wrapping<u16>) {
     let pc = machine.registers().pc;
     CPU::push_imm16(machine, Immediate16::from_u16(pc));
     machine.registers_mut().pc = address;
 }
 
 impl Instruction {
-    pub fn execute(self: &Instruction, machine: &mut Machine) -> (u8, u8) {
-        // EI effects are delayed by one instruction, we resolve it here
-        if machine.interrupts().interrupt_master_enable_delayed {
-            machine.interrupts_mut().interrupt_master_enable_delayed = false;
-            machine.interrupts_mut().interrupt_master_enable = true;
-        }
+    pub fn execute<B: Bus>(self: &Instruction, machine: &mut B) -> (u8, u8) {
+        // EI effects are delayed by one instruction: remember whether an EI was pending before this
+        // instruction, so that we only commit it once this instruction has fully executed.
+        let was_enable_pending = machine.interrupts().interrupt_master_enable_delayed;
 
-        match self {
+        let cycles = match self {
             Instruction::ADC_A_mHL => {
                 let a = machine.registers().read_a();
                 let hl = machine.registers().hl;
-                let b = machine.read_u8(hl);
+                let b = machine.cycle_read_u8(hl);
                 let c = machine.registers().read_flag(Flag::C);
                 adc(machine.cpu_mut(), &a, &b, c);
                 (8, 2)
@@ -139,7 +119,7 @@ impl Instruction {
 
             Instruction::ADD_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cycle_read_u8(machine.registers().hl);
                 add(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -184,7 +164,7 @@ impl Instruction {
 
             Instruction::AND_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cycle_read_u8(machine.registers().hl);
                 and(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -204,7 +184,7 @@ impl Instruction {
 
             Instruction::BIT_u3_mHL(bit_position) => {
                 let address = machine.registers().hl;
-                let value = ((machine.read_u8(address).0 >> bit_position) & 0x1) == 0x1;
+                let value = ((machine.cycle_read_u8(address).0 >> bit_position) & 0x1) == 0x1;
                 bit_complement(machine.cpu_mut(), value);
                 (12, 3)
             }
@@ -255,7 +235,7 @@ impl Instruction {
             Instruction::CP_A_mHL => {
                 let a = machine.registers().read_a();
                 let address = machine.registers().read_r16(&R16::HL);
-                let b = machine.read_u8(address);
+                let b = machine.cycle_read_u8(address);
                 compare(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -271,44 +251,49 @@ impl Instruction {
             }
 
             Instruction::DAA => {
-                let mut data = Wrapping(machine.registers().read_a().0 as u16);
+                let a = machine.registers().read_a();
                 let subtraction_flag = machine.registers().read_flag(Flag::N);
-                let mut half_carry = machine.registers().read_flag(Flag::H);
+                let half_carry = machine.registers().read_flag(Flag::H);
                 let mut carry = machine.registers().read_flag(Flag::C);
+                let mut adjustment = Wrapping(0x00);
                 if subtraction_flag {
-                    // post-subtraction
+                    // post-subtraction: only undo what the flags say was borrowed
                     if half_carry {
-                        data -= Wrapping(0x06);
+                        adjustment += Wrapping(0x06);
                     }
                     if carry {
-                        data -= Wrapping(0x60);
+                        adjustment += Wrapping(0x60);
                     }
                 } else {
-                    // post-addition
-                    if half_carry || ((data.0 & 0x0F) > 0x09) {
-                        data += Wrapping(0x06);
-                        half_carry = true; // set in case we entered because of the right condition
+                    // post-addition: both conditions must look at the *unadjusted* value
+                    if half_carry || ((a.0 & 0x0F) > 0x09) {
+                        adjustment += Wrapping(0x06);
                     }
-                    if carry || ((data.0 & 0x1FF) > 0x9F) {
-                        data += Wrapping(0x60);
+                    if carry || (a.0 > 0x99) {
+                        adjustment += Wrapping(0x60);
                         carry = true; // set in case we entered because of the right condition
                     }
                 }
+                let res = if subtraction_flag {
+                    a - adjustment
+                } else {
+                    a + adjustment
+                };
 
                 machine
                     .registers_mut()
-                    .write_a(Wrapping(data.0 as u8))
-                    .write_flag(Flag::Z, data.0 == 0)
-                    .write_flag(Flag::H, half_carry)
+                    .write_a(res)
+                    .write_flag(Flag::Z, res.0 == 0)
+                    .write_flag(Flag::H, false)
                     .write_flag(Flag::C, carry);
 
                 (4, 1)
             }
 
             Instruction::DEC_mHL => {
-                let a = machine.read_u8(machine.registers().hl);
+                let a = machine.cycle_read_u8(machine.registers().hl);
                 let res = dec(machine.cpu_mut(), &a);
-                machine.write_u8(machine.registers().hl, res);
+                machine.cycle_write_u8(machine.registers().hl, res);
                 (12, 3)
             }
 
@@ -328,10 +313,11 @@ impl Instruction {
 
             Instruction::DI => {
                 machine.interrupts_mut().interrupt_master_enable = false;
+                machine.interrupts_mut().interrupt_master_enable_delayed = false;
                 (4, 1)
             }
 
-            // NOTE: This sets up IME in one instruction
+            // NOTE: This sets up IME after the next instruction
             Instruction::EI => {
                 machine.interrupts_mut().interrupt_master_enable_delayed = true;
                 (4, 1)
@@ -351,8 +337,12 @@ impl Instruction {
                 (4, 1)
             }
 
+            // NOTE: On hardware, illegal opcodes hard-lock the CPU.  We keep the rest of the
+            // machine running so that the screen freezes, and the debugger can still inspect the
+            // state.
             Instruction::Illegal(opcode) => {
-                panic!("Attempted to execute an illegal opcode: 0x{:02X}", opcode)
+                machine.cpu_mut().locked_by = Some(*opcode);
+                (4, 1)
             }
 
             Instruction::INC_r8(r8) => {
@@ -375,13 +365,11 @@ impl Instruction {
             }
 
             Instruction::INC_mHL => {
-                let res = machine.read_u8(machine.registers().hl) + Wrapping(1);
-                machine.write_u8(machine.registers().hl, res);
+                let res = machine.cycle_read_u8(machine.registers().hl) + Wrapping(1);
+                machine.cycle_write_u8(machine.registers().hl, res);
                 (12, 3)
             }
 
-            Instruction::JR_r8(_) => todo!(),
-
             Instruction::JP_u16(imm16) => {
                 machine.registers_mut().pc = imm16.as_u16();
                 (16, 4)
@@ -419,14 +407,14 @@ impl Instruction {
 
             Instruction::LD_A_mr16(r16) => {
                 let address = machine.registers().read_r16(r16);
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 machine.registers_mut().write_a(a);
                 (8, 2)
             }
 
             Instruction::LD_A_mHLdec => {
                 let hl = machine.registers().hl;
-                let a = machine.read_u8(hl);
+                let a = machine.cycle_read_u8(hl);
                 machine.registers_mut().write_a(a);
                 machine.registers_mut().hl -= 1;
                 (8, 2)
@@ -434,14 +422,14 @@ impl Instruction {
 
             Instruction::LD_A_mHLinc => {
                 let hl = machine.registers().hl;
-                let a = machine.read_u8(hl);
+                let a = machine.cycle_read_u8(hl);
                 machine.registers_mut().write_a(a);
                 machine.registers_mut().hl += 1;
                 (8, 2)
             }
 
             Instruction::LD_FFu8_A(u8) => {
-                machine.write_u8(
+                machine.cycle_write_u8(
                     Wrapping(0xFF00 + (*u8).0 as u16),
                     machine.registers().read_a(),
                 );
@@ -462,24 +450,36 @@ impl Instruction {
             }
 
             Instruction::LD_mu16_A(imm16) => {
-                machine.write_u8(imm16.as_u16(), machine.registers().read_a());
+                machine.cycle_write_u8(imm16.as_u16(), machine.registers().read_a());
                 (16, 4)
             }
 
             Instruction::LD_mu16_SP(imm16) => {
                 let sp = Immediate16::from_u16(machine.registers().sp);
                 let address = imm16.as_u16();
-                machine.write_u8(address, sp.lower_byte);
-                machine.write_u8(address + Wrapping(1), sp.higher_byte);
+                machine.cycle_write_u8(address, sp.lower_byte);
+                machine.cycle_write_u8(address + Wrapping(1), sp.higher_byte);
                 (20, 5)
             }
 
-            Instruction::LD_H_mHL => todo!(),
+            // NOTE: H and L are halves of the address register, so we must read through the old HL
+            // before overwriting either half.
+            Instruction::LD_H_mHL => {
+                let hl = machine.registers().hl;
+                let h = machine.cycle_read_u8(hl);
+                machine.registers_mut().write_h(h);
+                (8, 2)
+            }
 
-            Instruction::LD_L_mHL => todo!(),
+            Instruction::LD_L_mHL => {
+                let hl = machine.registers().hl;
+                let l = machine.cycle_read_u8(hl);
+                machine.registers_mut().write_l(l);
+                (8, 2)
+            }
 
             Instruction::LD_FFC_A => {
-                machine.write_u8(
+                machine.cycle_write_u8(
                     Wrapping(0xFF00) + Wrapping(machine.registers().read_c().0 as u16),
                     machine.registers().read_a(),
                 );
@@ -498,42 +498,42 @@ impl Instruction {
             }
 
             Instruction::LD_mr16_r8(mr16, r8) => {
-                machine.write_u8(machine.registers().read_r16(mr16), machine.read_r8(r8));
+                machine.cycle_write_u8(machine.registers().read_r16(mr16), machine.read_r8(r8));
                 (8, 2)
             }
 
             Instruction::LD_mHL_u8(u8) => {
-                machine.write_u8(machine.registers().hl, *u8);
+                machine.cycle_write_u8(machine.registers().hl, *u8);
                 (12, 3)
             }
 
             Instruction::LD_mHLdec_A => {
-                machine.write_u8(machine.registers().hl, machine.registers().read_a());
+                machine.cycle_write_u8(machine.registers().hl, machine.registers().read_a());
                 machine.registers_mut().hl -= 1;
                 (8, 2)
             }
 
             Instruction::LD_mHLinc_A => {
-                machine.write_u8(machine.registers().hl, machine.registers().read_a());
+                machine.cycle_write_u8(machine.registers().hl, machine.registers().read_a());
                 machine.registers_mut().hl += 1;
                 (8, 2)
             }
 
             Instruction::LD_A_FFC => {
                 let c = machine.registers().read_c();
-                let a = machine.read_u8(Wrapping(0xFF00) + Wrapping(c.0 as u16));
+                let a = machine.cycle_read_u8(Wrapping(0xFF00) + Wrapping(c.0 as u16));
                 machine.registers_mut().write_a(a);
                 (8, 2)
             }
 
             Instruction::LD_A_FFu8(u8) => {
-                let a = machine.read_u8(Wrapping(0xFF00) + Wrapping((*u8).0 as u16));
+                let a = machine.cycle_read_u8(Wrapping(0xFF00) + Wrapping((*u8).0 as u16));
                 machine.registers_mut().write_a(a);
                 (12, 3)
             }
 
             Instruction::LD_A_mu16(imm16) => {
-                let a = machine.read_u8(imm16.as_u16());
+                let a = machine.cycle_read_u8(imm16.as_u16());
                 machine.registers_mut().write_a(a);
                 (16, 4)
             }
@@ -545,7 +545,7 @@ impl Instruction {
 
             Instruction::LD_r8_mr16(r8, r16) => {
                 let address = machine.registers().read_r16(r16);
-                let val = machine.read_u8(address);
+                let val = machine.cycle_read_u8(address);
                 machine.registers_mut().write_r8(r8, val);
                 (8, 2)
             }
@@ -564,7 +564,7 @@ impl Instruction {
 
             Instruction::OR_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cycle_read_u8(machine.registers().hl);
                 or(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -584,29 +584,20 @@ impl Instruction {
 
             Instruction::POP_r16(r16) => {
                 CPU::pop_r16(machine, r16);
-                // Only the flag bits of F are restored
-                if *r16 == R16::AF {
-                    let masked_af = machine.registers().read_r16(r16) & Wrapping(0xFFF0);
-                    machine.registers_mut().write_r16(r16, masked_af);
-                }
                 (12, 3)
             }
 
             Instruction::PUSH_r16(r16) => {
-                let mut byte_to_push = machine.registers().read_r16(r16);
-                // Only the flag bits of F are pushed
-                if *r16 == R16::AF {
-                    byte_to_push = byte_to_push & Wrapping(0xFFF0);
-                }
-                CPU::push_imm16(machine, Immediate16::from_u16(byte_to_push));
+                let value = machine.registers().read_r16(r16);
+                CPU::push_imm16(machine, Immediate16::from_u16(value));
                 (16, 4)
             }
 
             Instruction::RES_u3_mHL(u8) => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = bit_reset(&a, u8);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -639,9 +630,9 @@ impl Instruction {
 
             Instruction::RL_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = rotate_left_through_carry(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -672,9 +663,9 @@ impl Instruction {
 
             Instruction::RLC_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = rotate_left(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -696,9 +687,9 @@ impl Instruction {
 
             Instruction::RR_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = rotate_right_through_carry(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -720,9 +711,9 @@ impl Instruction {
 
             Instruction::RRC_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = rotate_right(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -741,7 +732,7 @@ impl Instruction {
 
             Instruction::SBC_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cycle_read_u8(machine.registers().hl);
                 let c = machine.registers().read_flag(Flag::C);
                 subc(machine.cpu_mut(), &a, &b, c);
                 (8, 2)
@@ -773,9 +764,9 @@ impl Instruction {
 
             Instruction::SET_u3_mHL(u8) => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = bit_set(&a, u8);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -788,9 +779,9 @@ impl Instruction {
 
             Instruction::SLA_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = rotate_left_with(machine.cpu_mut(), &a, false);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -803,9 +794,9 @@ impl Instruction {
 
             Instruction::SRA_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = shift_right_arithmetically(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -818,9 +809,9 @@ impl Instruction {
 
             Instruction::SRL_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = shift_right_logically(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -831,14 +822,16 @@ impl Instruction {
                 (8, 2)
             }
 
+            // TODO: CGB speed switching when KEY1 is armed
+            // NOTE: STOP is 2 bytes long, so fetching it already takes 2 M-cycles
             Instruction::STOP => {
-                // TODO
-                (4, 1)
+                machine.enter_stop_mode();
+                (8, 2)
             }
 
             Instruction::SUB_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cycle_read_u8(machine.registers().hl);
                 sub(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
@@ -858,9 +851,9 @@ impl Instruction {
 
             Instruction::SWAP_mHL => {
                 let address = machine.registers().hl;
-                let a = machine.read_u8(address);
+                let a = machine.cycle_read_u8(address);
                 let res = swap(machine.cpu_mut(), &a);
-                machine.write_u8(address, res);
+                machine.cycle_write_u8(address, res);
                 (16, 4)
             }
 
@@ -886,11 +879,19 @@ impl Instruction {
 
             Instruction::XOR_A_mHL => {
                 let a = machine.registers().read_a();
-                let b = machine.read_u8(machine.registers().hl);
+                let b = machine.cycle_read_u8(machine.registers().hl);
                 xor(machine.cpu_mut(), &a, &b);
                 (8, 2)
             }
+        };
+
+        // NOTE: DI clears the pending flag, so that `EI; DI` never opens a window for interrupts.
+        if was_enable_pending && machine.interrupts().interrupt_master_enable_delayed {
+            machine.interrupts_mut().interrupt_master_enable_delayed = false;
+            machine.interrupts_mut().interrupt_master_enable = true;
         }
+
+        cycles
     }
 }
 
@@ -969,3 +970,95 @@ pub fn bit_reset(value: &Wrapping<u8>, bit_position: &u8) -> Wrapping<u8> {
 pub fn bit_set(value: &Wrapping<u8>, bit_position: &u8) -> Wrapping<u8> {
     Wrapping(value.0 | (1 << bit_position))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::FlatBus,
+        instructions::{asm::*, decode::decode_instruction_at_address},
+        registers::R8,
+    };
+
+    // Places the instruction at PC and executes it, the way the CPU would.
+    fn execute(bus: &mut FlatBus, bytes: &[u8]) -> (u8, u8) {
+        let pc = bus.registers().pc;
+        bus.memory[pc.0 as usize..pc.0 as usize + bytes.len()].copy_from_slice(bytes);
+        let decoded = decode_instruction_at_address(bus, pc);
+        bus.registers_mut().pc += decoded.instruction_size as u16;
+        decoded.instruction.execute(bus)
+    }
+
+    #[test]
+    fn ld_h_mhl_reads_through_the_old_hl() {
+        let mut bus = FlatBus::new();
+        bus.registers_mut().pc = Wrapping(0x100);
+        bus.registers_mut().hl = Wrapping(0xC012);
+        bus.memory[0xC012] = 0xD0;
+        // Would be read if H was written first
+        bus.memory[0xD012] = 0x55;
+        assert_eq!(execute(&mut bus, &ld_r8_mhl(R8::H)), (8, 2));
+        assert_eq!(bus.registers().hl, Wrapping(0xD012));
+    }
+
+    #[test]
+    fn ld_l_mhl_reads_through_the_old_hl() {
+        let mut bus = FlatBus::new();
+        bus.registers_mut().pc = Wrapping(0x100);
+        bus.registers_mut().hl = Wrapping(0xC012);
+        bus.memory[0xC012] = 0x34;
+        bus.memory[0xC034] = 0x55;
+        assert_eq!(execute(&mut bus, &ld_r8_mhl(R8::L)), (8, 2));
+        assert_eq!(bus.registers().hl, Wrapping(0xC034));
+    }
+
+    // A, N, H, C before DAA
+    type DAAInput = (u8, bool, bool, bool);
+    // A, Z, C after DAA.  N is left alone and H is always cleared.
+    type DAAOutput = (u8, bool, bool);
+
+    const DAA_CASES: [(DAAInput, DAAOutput); 13] = [
+        // After an addition
+        ((0x45, false, false, false), (0x45, false, false)),
+        ((0x0A, false, false, false), (0x10, false, false)),
+        ((0x9A, false, false, false), (0x00, true, true)),
+        ((0xA0, false, false, false), (0x00, true, true)),
+        ((0xC5, false, false, false), (0x25, false, true)),
+        ((0x13, false, true, false), (0x19, false, false)),
+        ((0x20, false, false, true), (0x80, false, true)),
+        ((0x00, false, true, true), (0x66, false, true)),
+        // After a subtraction
+        ((0x42, true, false, false), (0x42, false, false)),
+        ((0x0F, true, true, false), (0x09, false, false)),
+        ((0x06, true, true, false), (0x00, true, false)),
+        ((0xA0, true, false, true), (0x40, false, true)),
+        ((0x9A, true, true, true), (0x34, false, true)),
+    ];
+
+    #[test]
+    fn daa_adjusts_for_every_flag_combination() {
+        for ((a, n, h, c), (expected_a, expected_z, expected_c)) in DAA_CASES {
+            let mut bus = FlatBus::new();
+            bus.registers_mut()
+                .write_a(Wrapping(a))
+                .znhc(false, n, h, c);
+            assert_eq!(execute(&mut bus, &daa()), (4, 1));
+            let registers = bus.registers();
+            let case = format!("A=0x{:02X} N={} H={} C={}", a, n, h, c);
+            assert_eq!(registers.read_a(), Wrapping(expected_a), "{}", case);
+            assert_eq!(registers.read_flag(Flag::Z), expected_z, "{}", case);
+            assert_eq!(registers.read_flag(Flag::N), n, "{}", case);
+            assert!(!registers.read_flag(Flag::H), "{}", case);
+            assert_eq!(registers.read_flag(Flag::C), expected_c, "{}", case);
+        }
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_the_cpu() {
+        let mut bus = FlatBus::new();
+        bus.registers_mut().pc = Wrapping(0x100);
+        assert_eq!(execute(&mut bus, &[0xED]), (4, 1));
+        assert_eq!(bus.cpu().locked_by, Some(0xED));
+        assert_eq!(bus.cpu().status(), "LOCKED (0xED)");
+    }
+}
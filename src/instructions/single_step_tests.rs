@@ -0,0 +1,333 @@
+// Runs the SM83 single-step test vectors (https://github.com/SingleStepTests/sm83) against the
+// instruction semantics, on a `FlatBus`.  The vectors are not part of the repository: point the
+// SM83_TESTS_DIR environment variable at the directory holding the `v1/*.json` files to run them,
+// otherwise these tests pass without checking anything.
+//
+// Each file holds the test cases of one opcode, each test case an initial and a final state, and
+// the bus activity of every M-cycle in between.
+
+use std::{env, fs, num::Wrapping, path::Path};
+
+use crate::{
+    bus::{Bus, FlatBus},
+    instructions::decode::decode_instruction_at_address,
+    registers::{u16_from_u8s, Registers},
+};
+
+const TESTS_DIR_VARIABLE: &str = "SM83_TESTS_DIR";
+
+// Just enough JSON for the test vectors.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn field(&self, key: &str) -> &Json {
+        self.get(key)
+            .unwrap_or_else(|| panic!("Missing field {} in {:?}", key, self))
+    }
+
+    fn as_number(&self) -> i64 {
+        match self {
+            Json::Number(n) => *n,
+            _ => panic!("Expected a number, got {:?}", self),
+        }
+    }
+
+    fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => panic!("Expected an array, got {:?}", self),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Json::String(s) => s,
+            _ => panic!("Expected a string, got {:?}", self),
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn parse(text: &'a str) -> Json {
+        let mut parser = JsonParser {
+            bytes: text.as_bytes(),
+            position: 0,
+        };
+        parser.value()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.position < self.bytes.len() && self.bytes[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> u8 {
+        self.skip_whitespace();
+        self.bytes[self.position]
+    }
+
+    fn expect(&mut self, byte: u8) {
+        assert_eq!(
+            self.peek() as char,
+            byte as char,
+            "at offset {}",
+            self.position
+        );
+        self.position += 1;
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Json) -> Json {
+        assert!(self.bytes[self.position..].starts_with(keyword.as_bytes()));
+        self.position += keyword.len();
+        value
+    }
+
+    // The vectors only hold integers and plain ASCII strings, booleans are read as 0 or 1.
+    fn value(&mut self) -> Json {
+        match self.peek() {
+            b'n' => self.keyword("null", Json::Null),
+            b't' => self.keyword("true", Json::Number(1)),
+            b'f' => self.keyword("false", Json::Number(0)),
+            b'"' => Json::String(self.string()),
+            b'[' => {
+                let mut items = Vec::new();
+                self.sequence(b'[', b']', |parser| items.push(parser.value()));
+                Json::Array(items)
+            }
+            b'{' => {
+                let mut fields = Vec::new();
+                self.sequence(b'{', b'}', |parser| {
+                    let key = parser.string();
+                    parser.expect(b':');
+                    fields.push((key, parser.value()));
+                });
+                Json::Object(fields)
+            }
+            _ => {
+                let start = self.position;
+                while self.position < self.bytes.len()
+                    && (self.bytes[self.position] == b'-'
+                        || self.bytes[self.position].is_ascii_digit())
+                {
+                    self.position += 1;
+                }
+                let digits = std::str::from_utf8(&self.bytes[start..self.position]).unwrap();
+                Json::Number(digits.parse().unwrap())
+            }
+        }
+    }
+
+    fn sequence(&mut self, open: u8, close: u8, mut item: impl FnMut(&mut Self)) {
+        self.expect(open);
+        if self.peek() == close {
+            self.position += 1;
+            return;
+        }
+        loop {
+            item(self);
+            if self.peek() == close {
+                self.position += 1;
+                return;
+            }
+            self.expect(b',');
+        }
+    }
+
+    fn string(&mut self) -> String {
+        self.expect(b'"');
+        let start = self.position;
+        while self.bytes[self.position] != b'"' {
+            self.position += 1;
+        }
+        self.position += 1;
+        String::from_utf8(self.bytes[start..self.position - 1].to_vec()).unwrap()
+    }
+}
+
+fn byte(state: &Json, key: &str) -> Wrapping<u8> {
+    Wrapping(state.field(key).as_number() as u8)
+}
+
+fn registers(state: &Json) -> Registers {
+    let mut registers = Registers::new();
+    registers.af = u16_from_u8s(byte(state, "a"), byte(state, "f"));
+    registers.bc = u16_from_u8s(byte(state, "b"), byte(state, "c"));
+    registers.de = u16_from_u8s(byte(state, "d"), byte(state, "e"));
+    registers.hl = u16_from_u8s(byte(state, "h"), byte(state, "l"));
+    registers.sp = Wrapping(state.field("sp").as_number() as u16);
+    registers.pc = Wrapping(state.field("pc").as_number() as u16);
+    registers
+}
+
+fn ram(state: &Json) -> Vec<(u16, u8)> {
+    state
+        .field("ram")
+        .as_array()
+        .iter()
+        .map(|cell| {
+            let cell = cell.as_array();
+            (cell[0].as_number() as u16, cell[1].as_number() as u8)
+        })
+        .collect()
+}
+
+// Returns a description of what went wrong, if anything.
+fn run_test_case(test_case: &Json) -> Option<String> {
+    let initial = test_case.field("initial");
+    let expected = test_case.field("final");
+
+    let mut bus = FlatBus::new();
+    *bus.registers_mut() = registers(initial);
+    bus.interrupts_mut().interrupt_master_enable = initial.field("ime").as_number() != 0;
+    if let Some(ie) = initial.get("ie") {
+        bus.interrupts_mut().interrupt_enable = Wrapping(ie.as_number() as u8);
+    }
+    for (address, value) in ram(initial) {
+        bus.memory[address as usize] = value;
+    }
+
+    // Same as `CPU::execute_one_instruction`
+    let decoded = decode_instruction_at_address(&bus, bus.registers().pc);
+    bus.registers_mut().pc += decoded.instruction_size as u16;
+    let (t_cycles, _m_cycles) = decoded.instruction.execute(&mut bus);
+
+    let mut errors = Vec::new();
+    let expected_registers = registers(expected);
+    if *bus.registers() != expected_registers {
+        errors.push(format!(
+            "registers {:?}, expected {:?}",
+            bus.registers(),
+            expected_registers
+        ));
+    }
+    // EI only takes effect once the next instruction is done, which the vectors do not run
+    let interrupts = bus.interrupts();
+    let ime = interrupts.interrupt_master_enable || interrupts.interrupt_master_enable_delayed;
+    if ime != (expected.field("ime").as_number() != 0) {
+        errors.push(format!("IME {}, expected {}", ime, !ime));
+    }
+    for (address, value) in ram(expected) {
+        if bus.memory[address as usize] != value {
+            errors.push(format!(
+                "[0x{:04X}] = 0x{:02X}, expected 0x{:02X}",
+                address, bus.memory[address as usize], value
+            ));
+        }
+    }
+    // One entry per M-cycle, including the fetch
+    let expected_m_cycles = test_case.field("cycles").as_array().len();
+    if t_cycles as usize != 4 * expected_m_cycles {
+        errors.push(format!(
+            "{} t-cycles, expected {}",
+            t_cycles,
+            4 * expected_m_cycles
+        ));
+    }
+
+    if errors.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "{} ({}): {}",
+            test_case.field("name").as_str(),
+            decoded,
+            errors.join(", ")
+        ))
+    }
+}
+
+// Runs the vectors of each opcode, as found in files named like `3e.json` or `cb 3e.json`.
+fn run_opcodes(file_names: impl Iterator<Item = String>) {
+    let Ok(tests_dir) = env::var(TESTS_DIR_VARIABLE) else {
+        println!(
+            "{} is not set, skipping the SM83 test vectors",
+            TESTS_DIR_VARIABLE
+        );
+        return;
+    };
+    let mut failures = Vec::new();
+    for file_name in file_names {
+        let path = Path::new(&tests_dir).join(&file_name);
+        // Illegal opcodes have no vectors
+        let Ok(contents) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let test_cases = JsonParser::parse(&contents);
+        let opcode_failures: Vec<String> = test_cases
+            .as_array()
+            .iter()
+            .filter_map(run_test_case)
+            .collect();
+        if let Some(first_failure) = opcode_failures.first() {
+            failures.push(format!(
+                "{}: {} failures, first one {}",
+                file_name,
+                opcode_failures.len(),
+                first_failure
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+fn unprefixed(opcodes: impl Iterator<Item = u8>) -> impl Iterator<Item = String> {
+    opcodes.map(|opcode| format!("{:02x}.json", opcode))
+}
+
+#[test]
+fn loads() {
+    // HALT waits for the rest of the machine
+    run_opcodes(unprefixed((0x40..=0x7F).filter(|opcode| *opcode != 0x76)));
+}
+
+#[test]
+fn arithmetic_and_logic() {
+    run_opcodes(unprefixed(0x80..=0xBF));
+}
+
+#[test]
+fn others() {
+    // STOP waits for the rest of the machine, 0xCB is the prefix
+    run_opcodes(unprefixed(
+        (0x00..=0x3F)
+            .chain(0xC0..=0xFF)
+            .filter(|opcode| ![0x10, 0xCB].contains(opcode)),
+    ));
+}
+
+#[test]
+fn prefixed() {
+    run_opcodes((0x00..=0xFF).map(|opcode| format!("cb {:02x}.json", opcode)));
+}
+
+#[test]
+fn json_parser() {
+    let json = JsonParser::parse(r#"{"name": "00 0000", "ram": [[1, 255]], "x": null, "y": [] }"#);
+    assert_eq!(json.field("name").as_str(), "00 0000");
+    assert_eq!(
+        json.field("ram").as_array()[0].as_array()[1].as_number(),
+        255
+    );
+    assert!(matches!(json.field("x"), Json::Null));
+    assert!(json.field("y").as_array().is_empty());
+}
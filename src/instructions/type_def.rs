@@ -1,12 +1,12 @@
 use std::num::Wrapping;
 
 use crate::{
+    bus::Bus,
     conditions::Condition,
-    machine::Machine,
     registers::{R16, R8},
 };
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Immediate16 {
     pub lower_byte: Wrapping<u8>,
     pub higher_byte: Wrapping<u8>,
@@ -25,7 +25,7 @@ impl Immediate16 {
     }
 
     // In ROM, immediate 16-bit values are stored lower-byte-first.
-    pub fn from_memory(machine: &Machine, address: Wrapping<u16>) -> Immediate16 {
+    pub fn from_memory<B: Bus>(machine: &B, address: Wrapping<u16>) -> Immediate16 {
         Immediate16 {
             lower_byte: machine.read_u8(address),
             higher_byte: machine.read_u8(address + Wrapping(1)),
@@ -33,7 +33,7 @@ impl Immediate16 {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum Instruction {
     ADC_A_mHL,
@@ -72,7 +72,6 @@ pub enum Instruction {
     JP_u16(Immediate16),
     JR_cc_i8(Condition, Wrapping<i8>),
     JR_i8(Wrapping<i8>),
-    JR_r8(R8),
     LD_A_FFC,
     LD_A_FFu8(Wrapping<u8>),
     LD_A_mHLdec,
@@ -142,3 +141,124 @@ pub enum Instruction {
     XOR_A_r8(R8),
     XOR_A_u8(Wrapping<u8>),
 }
+
+impl Instruction {
+    // Number of bytes this instruction occupies in memory, including its opcode (and the 0xCB
+    // prefix for extended instructions).
+    pub fn size(&self) -> u8 {
+        match self {
+            Instruction::ADC_A_mHL
+            | Instruction::ADC_A_r8(_)
+            | Instruction::ADD_A_mHL
+            | Instruction::ADD_A_r8(_)
+            | Instruction::ADD_HL_r16(_)
+            | Instruction::AND_A_mHL
+            | Instruction::AND_A_r8(_)
+            | Instruction::CCF
+            | Instruction::CP_A_mHL
+            | Instruction::CP_A_r8(_)
+            | Instruction::CPL
+            | Instruction::DAA
+            | Instruction::DEC_mHL
+            | Instruction::DEC_r16(_)
+            | Instruction::DEC_r8(_)
+            | Instruction::DI
+            | Instruction::EI
+            | Instruction::HALT
+            | Instruction::Illegal(_)
+            | Instruction::INC_mHL
+            | Instruction::INC_r16(_)
+            | Instruction::INC_r8(_)
+            | Instruction::JP_HL
+            | Instruction::LD_A_FFC
+            | Instruction::LD_A_mHLdec
+            | Instruction::LD_A_mHLinc
+            | Instruction::LD_A_mr16(_)
+            | Instruction::LD_FFC_A
+            | Instruction::LD_H_mHL
+            | Instruction::LD_L_mHL
+            | Instruction::LD_mHLdec_A
+            | Instruction::LD_mHLinc_A
+            | Instruction::LD_mr16_r8(_, _)
+            | Instruction::LD_r8_mr16(_, _)
+            | Instruction::LD_r8_r8(_, _)
+            | Instruction::LD_SP_HL
+            | Instruction::NOP
+            | Instruction::OR_A_mHL
+            | Instruction::OR_A_r8(_)
+            | Instruction::POP_r16(_)
+            | Instruction::PUSH_r16(_)
+            | Instruction::RET_cc(_)
+            | Instruction::RET
+            | Instruction::RETI
+            | Instruction::RLA
+            | Instruction::RLCA
+            | Instruction::RRA
+            | Instruction::RRCA
+            | Instruction::RST(_)
+            | Instruction::SBC_A_mHL
+            | Instruction::SBC_A_r8(_)
+            | Instruction::SCF
+            | Instruction::SUB_A_mHL
+            | Instruction::SUB_A_r8(_)
+            | Instruction::XOR_A_mHL
+            | Instruction::XOR_A_r8(_) => 1,
+
+            // Instructions with an 8-bit immediate
+            Instruction::ADC_A_u8(_)
+            | Instruction::ADD_A_u8(_)
+            | Instruction::ADD_SP_i8(_)
+            | Instruction::AND_u8(_)
+            | Instruction::CP_A_u8(_)
+            | Instruction::JR_cc_i8(_, _)
+            | Instruction::JR_i8(_)
+            | Instruction::LD_A_FFu8(_)
+            | Instruction::LD_FFu8_A(_)
+            | Instruction::LD_HL_SP_i8(_)
+            | Instruction::LD_mHL_u8(_)
+            | Instruction::LD_r8_u8(_, _)
+            | Instruction::OR_A_u8(_)
+            | Instruction::SBC_A_u8(_)
+            | Instruction::SUB_A_u8(_)
+            | Instruction::XOR_A_u8(_) => 2,
+
+            // STOP is followed by a byte that is ignored
+            Instruction::STOP => 2,
+
+            // Instructions prefixed with 0xCB
+            Instruction::BIT_u3_mHL(_)
+            | Instruction::BIT_u3_r8(_, _)
+            | Instruction::RES_u3_mHL(_)
+            | Instruction::RES_u3_r8(_, _)
+            | Instruction::RL_mHL
+            | Instruction::RL_r8(_)
+            | Instruction::RLC_mHL
+            | Instruction::RLC_r8(_)
+            | Instruction::RR_mHL
+            | Instruction::RR_r8(_)
+            | Instruction::RRC_mHL
+            | Instruction::RRC_r8(_)
+            | Instruction::SET_u3_mHL(_)
+            | Instruction::SET_u3_r8(_, _)
+            | Instruction::SLA_mHL
+            | Instruction::SLA_r8(_)
+            | Instruction::SRA_mHL
+            | Instruction::SRA_r8(_)
+            | Instruction::SRL_mHL
+            | Instruction::SRL_r8(_)
+            | Instruction::SWAP_mHL
+            | Instruction::SWAP_r8(_) => 2,
+
+            // Instructions with a 16-bit immediate
+            Instruction::CALL_a16(_)
+            | Instruction::CALL_cc_u16(_, _)
+            | Instruction::JP_cc_u16(_, _)
+            | Instruction::JP_u16(_)
+            | Instruction::LD_A_mu16(_)
+            | Instruction::LD_mu16_A(_)
+            | Instruction::LD_mu16_SP(_)
+            | Instruction::LD_r16_d16(_, _)
+            | Instruction::LD_SP_u16(_) => 3,
+        }
+    }
+}
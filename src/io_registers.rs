@@ -0,0 +1,103 @@
+use std::num::Wrapping;
+
+// (address, readable bits, writable bits) of the plain registers that only exist in CGB mode.
+// Bits that cannot be read read as 1.  KEY1's bit 7 reports the current speed, always normal since
+// we do not switch speeds.  FF74 is only writable in CGB mode, which is the only mode it exists in
+// here.
+const CGB_REGISTERS: [(u16, u8, u8); 5] = [
+    (0xFF4D, 0x81, 0x01),
+    (0xFF72, 0xFF, 0xFF),
+    (0xFF73, 0xFF, 0xFF),
+    (0xFF74, 0xFF, 0xFF),
+    (0xFF75, 0x70, 0x70),
+];
+
+// The IO registers that hold a byte and nothing else.  Other addresses routed here (FF03, and
+// FF08-FF0E) do not exist on any model, they read as 0xFF and ignore writes, like the registers
+// above in DMG mode.
+#[derive(Clone, Debug, Hash)]
+pub struct IORegisters {
+    is_cgb_mode: bool,
+    values: [u8; CGB_REGISTERS.len()],
+}
+
+impl IORegisters {
+    pub fn new(is_cgb_mode: bool) -> Self {
+        IORegisters {
+            is_cgb_mode,
+            values: [0; CGB_REGISTERS.len()],
+        }
+    }
+
+    fn index(&self, address: Wrapping<u16>) -> Option<usize> {
+        if !self.is_cgb_mode {
+            return None;
+        }
+        CGB_REGISTERS
+            .iter()
+            .position(|(register_address, _, _)| *register_address == address.0)
+    }
+
+    pub fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        match self.index(address) {
+            Some(index) => {
+                let (_, readable_bits, _) = CGB_REGISTERS[index];
+                Wrapping(self.values[index] & readable_bits | !readable_bits)
+            }
+            None => Wrapping(0xFF),
+        }
+    }
+
+    pub fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        if let Some(index) = self.index(address) {
+            let (_, _, writable_bits) = CGB_REGISTERS[index];
+            self.values[index] = self.values[index] & !writable_bits | value.0 & writable_bits;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (address, read back after writing 0x00, then after writing 0xFF), in CGB mode.  In DMG mode,
+    // all of them read as 0xFF.
+    const CGB_READ_BACKS: [(u16, u8, u8); 14] = [
+        (0xFF03, 0xFF, 0xFF),
+        (0xFF08, 0xFF, 0xFF),
+        (0xFF09, 0xFF, 0xFF),
+        (0xFF0A, 0xFF, 0xFF),
+        (0xFF0B, 0xFF, 0xFF),
+        (0xFF0C, 0xFF, 0xFF),
+        (0xFF0D, 0xFF, 0xFF),
+        (0xFF0E, 0xFF, 0xFF),
+        (0xFF4D, 0x7E, 0x7F),
+        (0xFF72, 0x00, 0xFF),
+        (0xFF73, 0x00, 0xFF),
+        (0xFF74, 0x00, 0xFF),
+        (0xFF75, 0x8F, 0xFF),
+        // Not one of them
+        (0xFF76, 0xFF, 0xFF),
+    ];
+
+    #[test]
+    fn registers_read_back_through_their_masks() {
+        for is_cgb_mode in [false, true] {
+            let mut io_registers = IORegisters::new(is_cgb_mode);
+            for (address, after_0x00, after_0xff) in CGB_READ_BACKS {
+                for (value, expected) in [(0x00, after_0x00), (0xFF, after_0xff)] {
+                    io_registers.write_u8(Wrapping(address), Wrapping(value));
+                    let expected = if is_cgb_mode { expected } else { 0xFF };
+                    assert_eq!(
+                        io_registers.read_u8(Wrapping(address)),
+                        Wrapping(expected),
+                        "0x{:04X} after writing 0x{:02X}, CGB mode: {}",
+                        address,
+                        value,
+                        is_cgb_mode
+                    );
+                }
+            }
+        }
+    }
+}
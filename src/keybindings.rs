@@ -0,0 +1,406 @@
+use std::{collections::HashMap, io, path::Path};
+
+use gilrs::Button as GamepadButton;
+use iced::keyboard::{key::Named, Key};
+
+use crate::{
+    inputs::{Button, InputSource},
+    message::Message,
+};
+
+// The bindings used for actions the file leaves out, or when there is no file at all.
+const DEFAULT_KEYBINDINGS: &str = include_str!("../keybindings.toml");
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Action {
+    Joypad(Button),
+    Turbo(Button),
+    Pause,
+    Step,
+    Run,
+    Quit,
+    FastForward,
+    ToggleTileMap0,
+    ToggleTileMap1,
+    ToggleInputDisplay,
+}
+
+const ACTION_NAMES: &[(&str, Action)] = &[
+    ("up", Action::Joypad(Button::Up)),
+    ("down", Action::Joypad(Button::Down)),
+    ("left", Action::Joypad(Button::Left)),
+    ("right", Action::Joypad(Button::Right)),
+    ("a", Action::Joypad(Button::A)),
+    ("b", Action::Joypad(Button::B)),
+    ("start", Action::Joypad(Button::Start)),
+    ("select", Action::Joypad(Button::Select)),
+    ("turbo_a", Action::Turbo(Button::A)),
+    ("turbo_b", Action::Turbo(Button::B)),
+    ("pause", Action::Pause),
+    ("step", Action::Step),
+    ("run", Action::Run),
+    ("quit", Action::Quit),
+    ("fast_forward", Action::FastForward),
+    ("toggle_tile_map_0", Action::ToggleTileMap0),
+    ("toggle_tile_map_1", Action::ToggleTileMap1),
+    ("toggle_input_display", Action::ToggleInputDisplay),
+];
+
+const NAMED_KEYS: &[(&str, Named)] = &[
+    ("ArrowUp", Named::ArrowUp),
+    ("ArrowDown", Named::ArrowDown),
+    ("ArrowLeft", Named::ArrowLeft),
+    ("ArrowRight", Named::ArrowRight),
+    ("Enter", Named::Enter),
+    ("Space", Named::Space),
+    ("Escape", Named::Escape),
+    ("Backspace", Named::Backspace),
+    ("Tab", Named::Tab),
+    ("Shift", Named::Shift),
+    ("Control", Named::Control),
+    ("Alt", Named::Alt),
+    ("F1", Named::F1),
+    ("F2", Named::F2),
+    ("F3", Named::F3),
+    ("F4", Named::F4),
+    ("F5", Named::F5),
+    ("F6", Named::F6),
+    ("F7", Named::F7),
+    ("F8", Named::F8),
+    ("F9", Named::F9),
+    ("F10", Named::F10),
+    ("F11", Named::F11),
+    ("F12", Named::F12),
+];
+
+impl Action {
+    pub fn on_press(&self, source: InputSource) -> Message {
+        match self {
+            Action::Joypad(button) => Message::PressButton(source, *button),
+            Action::Turbo(button) => Message::PressTurboButton(source, *button),
+            Action::Pause => Message::Pause,
+            Action::Step => Message::RunNextInstruction,
+            Action::Run => Message::BeginRunUntilBreakpoint,
+            Action::Quit => Message::Quit,
+            Action::FastForward => Message::SetFastForward(true),
+            Action::ToggleTileMap0 => Message::ToggleTileMap0,
+            Action::ToggleTileMap1 => Message::ToggleTileMap1,
+            Action::ToggleInputDisplay => Message::ToggleInputDisplay,
+        }
+    }
+
+    // Only the joypad, turbo, and fast-forward care about keys being held.
+    pub fn on_release(&self, source: InputSource) -> Option<Message> {
+        match self {
+            Action::Joypad(button) => Some(Message::ReleaseButton(source, *button)),
+            Action::Turbo(button) => Some(Message::ReleaseTurboButton(source, *button)),
+            Action::FastForward => Some(Message::SetFastForward(false)),
+            _ => None,
+        }
+    }
+}
+
+// Letters are matched regardless of Shift.
+pub fn normalize_key(key: Key) -> Key {
+    match key {
+        Key::Character(c) => Key::Character(c.to_lowercase().into()),
+        key => key,
+    }
+}
+
+fn parse_key(name: &str) -> Option<Key> {
+    if let Some((_, named)) = NAMED_KEYS.iter().find(|(key_name, _)| *key_name == name) {
+        return Some(Key::Named(*named));
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphanumeric() => {
+            Some(Key::Character(c.to_ascii_lowercase().to_string().into()))
+        }
+        _ => None,
+    }
+}
+
+// Gamepad buttons are named after their position, so South is A on a Xbox pad but B on a Nintendo
+// one.
+const GAMEPAD_BUTTON_NAMES: &[(&str, GamepadButton)] = &[
+    ("South", GamepadButton::South),
+    ("East", GamepadButton::East),
+    ("North", GamepadButton::North),
+    ("West", GamepadButton::West),
+    ("Start", GamepadButton::Start),
+    ("Select", GamepadButton::Select),
+    ("Mode", GamepadButton::Mode),
+    ("DPadUp", GamepadButton::DPadUp),
+    ("DPadDown", GamepadButton::DPadDown),
+    ("DPadLeft", GamepadButton::DPadLeft),
+    ("DPadRight", GamepadButton::DPadRight),
+    ("LeftTrigger", GamepadButton::LeftTrigger),
+    ("LeftTrigger2", GamepadButton::LeftTrigger2),
+    ("RightTrigger", GamepadButton::RightTrigger),
+    ("RightTrigger2", GamepadButton::RightTrigger2),
+];
+
+// Actions are bound to a key, and separately to a gamepad button with a `gamepad_` prefix.
+#[derive(Clone, Debug)]
+enum Input {
+    Key(Key),
+    Gamepad(GamepadButton),
+}
+
+impl Input {
+    fn is_gamepad(&self) -> bool {
+        matches!(self, Input::Gamepad(_))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Keybindings {
+    actions: HashMap<Key, Action>,
+    gamepad_actions: HashMap<GamepadButton, Action>,
+}
+
+impl Keybindings {
+    // A missing file is not an error, the defaults are used instead.
+    pub fn load(path: &str) -> Result<Self, io::Error> {
+        if !Path::new(path).exists() {
+            return Ok(Keybindings::parse(""));
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(Keybindings::parse(&contents))
+    }
+
+    // Lines look like `action = "key"`, and `#` starts a comment.  Malformed lines are skipped,
+    // and the actions they would have bound keep their default key.
+    pub fn parse(contents: &str) -> Self {
+        let (bindings, warnings) = parse_bindings(contents);
+        for warning in warnings {
+            println!("[WARNING] {}", warning);
+        }
+        let mut all_bindings: Vec<(Action, Input)> = parse_bindings(DEFAULT_KEYBINDINGS)
+            .0
+            .into_iter()
+            .filter(|(action, input)| {
+                !bindings.iter().any(|(bound, bound_input)| {
+                    bound == action && bound_input.is_gamepad() == input.is_gamepad()
+                })
+            })
+            .collect();
+        all_bindings.extend(bindings);
+        // When two actions share a key, the one from the file wins over a default
+        let mut actions = HashMap::new();
+        let mut gamepad_actions = HashMap::new();
+        for (action, input) in all_bindings {
+            let other_action = match &input {
+                Input::Key(key) => actions.insert(key.clone(), action),
+                Input::Gamepad(button) => gamepad_actions.insert(*button, action),
+            };
+            if let Some(other_action) = other_action {
+                println!(
+                    "[WARNING] {:?} is bound to both {:?} and {:?}",
+                    input, other_action, action
+                );
+            }
+        }
+        Keybindings {
+            actions,
+            gamepad_actions,
+        }
+    }
+
+    pub fn action(&self, key: &Key) -> Option<Action> {
+        self.actions.get(key).copied()
+    }
+
+    pub fn gamepad_action(&self, button: &GamepadButton) -> Option<Action> {
+        self.gamepad_actions.get(button).copied()
+    }
+}
+
+fn parse_input(name: &str, is_gamepad: bool) -> Option<Input> {
+    if is_gamepad {
+        GAMEPAD_BUTTON_NAMES
+            .iter()
+            .find(|(button_name, _)| *button_name == name)
+            .map(|(_, button)| Input::Gamepad(*button))
+    } else {
+        parse_key(name).map(Input::Key)
+    }
+}
+
+// Returns what each action is bound to, and what was wrong with the lines that were skipped.  An
+// action bound twice gets the last key.
+fn parse_bindings(contents: &str) -> (Vec<(Action, Input)>, Vec<String>) {
+    let mut bindings: Vec<(Action, Input)> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut seen_on_line = HashMap::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line_number = line_number + 1;
+        let line = match line.split_once('#') {
+            Some((before_comment, _)) => before_comment,
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((name, input_name)) = line.split_once('=') else {
+            warnings.push(format!(
+                "Ignoring malformed keybinding on line {}: {}",
+                line_number, line
+            ));
+            continue;
+        };
+        let name = name.trim();
+        let input_name = input_name.trim().trim_matches('"');
+        let (action_name, is_gamepad) = match name.strip_prefix("gamepad_") {
+            Some(action_name) => (action_name, true),
+            None => (name, false),
+        };
+        let Some((_, action)) = ACTION_NAMES.iter().find(|(name, _)| *name == action_name) else {
+            warnings.push(format!(
+                "Ignoring unknown action on line {}: {}",
+                line_number, name
+            ));
+            continue;
+        };
+        let Some(input) = parse_input(input_name, is_gamepad) else {
+            warnings.push(format!(
+                "Ignoring unknown key name on line {}: {}",
+                line_number, input_name
+            ));
+            continue;
+        };
+        if let Some(first_line) = seen_on_line.insert(name, line_number) {
+            warnings.push(format!(
+                "Action {} is bound again on line {}, replacing line {}",
+                name, line_number, first_line
+            ));
+            bindings.retain(|(bound, bound_input)| {
+                !(bound == action && bound_input.is_gamepad() == is_gamepad)
+            });
+        }
+        bindings.push((*action, input));
+    }
+    (bindings, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> Key {
+        parse_key(name).unwrap()
+    }
+
+    #[test]
+    fn a_valid_file_overrides_the_defaults() {
+        let contents = "\
+# Comments and blank lines are skipped
+
+a = \"k\"  # So are trailing comments
+pause = \"F1\"
+gamepad_start = \"Mode\"
+";
+        let (_, warnings) = parse_bindings(contents);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let keybindings = Keybindings::parse(contents);
+        assert_eq!(
+            keybindings.action(&key("k")),
+            Some(Action::Joypad(Button::A))
+        );
+        assert_eq!(keybindings.action(&key("x")), None);
+        assert_eq!(keybindings.action(&key("F1")), Some(Action::Pause));
+        assert_eq!(keybindings.action(&key("Space")), None);
+        assert_eq!(
+            keybindings.gamepad_action(&GamepadButton::Mode),
+            Some(Action::Joypad(Button::Start))
+        );
+        assert_eq!(keybindings.gamepad_action(&GamepadButton::Start), None);
+        // Actions left out keep their default key
+        assert_eq!(
+            keybindings.action(&key("z")),
+            Some(Action::Joypad(Button::B))
+        );
+    }
+
+    #[test]
+    fn an_action_bound_twice_keeps_the_last_key() {
+        let contents = "step = \"F2\"\nrun = \"F3\"\nstep = \"F4\"\n";
+        let (_, warnings) = parse_bindings(contents);
+        assert_eq!(
+            warnings,
+            ["Action step is bound again on line 3, replacing line 1"]
+        );
+        let keybindings = Keybindings::parse(contents);
+        assert_eq!(keybindings.action(&key("F2")), None);
+        assert_eq!(keybindings.action(&key("F4")), Some(Action::Step));
+        assert_eq!(keybindings.action(&key("F3")), Some(Action::Run));
+    }
+
+    #[test]
+    fn unknown_names_are_reported_with_their_line() {
+        let contents = "\
+up = \"w\"
+down = \"PageDown\"
+jump = \"Space\"
+gamepad_a = \"Trigger\"
+left
+";
+        let (bindings, warnings) = parse_bindings(contents);
+        assert_eq!(
+            warnings,
+            [
+                "Ignoring unknown key name on line 2: PageDown",
+                "Ignoring unknown action on line 3: jump",
+                "Ignoring unknown key name on line 4: Trigger",
+                "Ignoring malformed keybinding on line 5: left",
+            ]
+        );
+        assert_eq!(bindings.len(), 1);
+        // The actions of the skipped lines keep their default key
+        let keybindings = Keybindings::parse(contents);
+        assert_eq!(
+            keybindings.action(&key("w")),
+            Some(Action::Joypad(Button::Up))
+        );
+        assert_eq!(
+            keybindings.action(&key("ArrowDown")),
+            Some(Action::Joypad(Button::Down))
+        );
+        assert_eq!(
+            keybindings.gamepad_action(&GamepadButton::East),
+            Some(Action::Joypad(Button::A))
+        );
+    }
+
+    #[test]
+    fn without_a_file_the_defaults_are_used() {
+        let keybindings = Keybindings::load("no/such/keybindings.toml").unwrap();
+        let (defaults, warnings) = parse_bindings(DEFAULT_KEYBINDINGS);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        // Every action has a key
+        for (_, action) in ACTION_NAMES {
+            assert!(
+                defaults
+                    .iter()
+                    .any(|(bound, input)| bound == action && !input.is_gamepad()),
+                "{:?}",
+                action
+            );
+        }
+        for (action, input) in defaults {
+            match input {
+                Input::Key(key) => assert_eq!(keybindings.action(&key), Some(action)),
+                Input::Gamepad(button) => {
+                    assert_eq!(keybindings.gamepad_action(&button), Some(action))
+                }
+            }
+        }
+        assert_eq!(keybindings.action(&key("Space")), Some(Action::Pause));
+        assert_eq!(
+            keybindings.action(&key("x")),
+            Some(Action::Joypad(Button::A))
+        );
+    }
+}
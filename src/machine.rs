@@ -1,93 +1,71 @@
-use std::num::Wrapping;
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
 
 use crate::{
-    application_state::{MapperType, ROMInformation},
+    application_state::{ConsoleMode, ROMInformation},
+    apu::APU,
+    bus::Bus,
+    call_stack::CallStack,
     cpu::{interrupts::Interrupts, timers::Timers, CPU},
+    dma::DMA,
     inputs::Inputs,
+    instructions::decode::DecodedInstruction,
+    io_registers::IORegisters,
+    memory::Memory,
+    memory_trace::{MemoryObserver, MemoryTrace},
+    mmu::MMU,
     pixel_fetcher::{
         background_or_window::BackgroundOrWindowFetcher, object::ObjectFetcher, Fetcher,
     },
     ppu::PPU,
+    serial::Serial,
+    utils::Fnv1aHasher,
 };
 
-#[derive(Clone, Debug, PartialEq)]
-enum BankingMode {
-    Ram,
-    Rom,
+// A frame lasts 154 scanlines of 456 dots.
+const T_CYCLES_PER_FRAME: u128 = 154 * 456;
+
+pub struct MachineStep {
+    pub t_cycles: u128,
+    pub instruction_executed: Option<DecodedInstruction>,
 }
 
-// TODO: separate MMU from Machine?
+pub struct InstructionStep {
+    pub t_cycles: u128,
+    pub instruction_executed: Option<DecodedInstruction>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Machine {
     // Machine state
-    banking_mode: BankingMode,
-    pub is_ram_enabled: bool,
-    pub loram_bank: u8,
-    pub ram_or_hiram_bank: u8,
     pub rom_information: ROMInformation,
+    pub stop_mode: bool, // Set by STOP, the clocks are frozen until a joypad line goes low
     pub t_cycle_count: u64,
+    t_cycles_ticked_in_step: u8, // Cycles already given to timers/PPU during the current step
+    // One bit per IO register in 0xFF00-0xFF7F, so that unmapped ones only get warned about once
+    warned_unmapped_io_writes: u128,
 
     // Subsystems
+    pub apu: APU,
     pub background_window_fetcher: BackgroundOrWindowFetcher,
+    pub call_stack: CallStack,
     pub cpu: CPU,
+    pub dma: DMA,
     pub inputs: Inputs,
     pub interrupts: Interrupts,
+    pub io_registers: IORegisters,
+    pub memory_trace: MemoryTrace,
+    pub mmu: MMU,
     pub object_fetcher: ObjectFetcher,
     pub pixel_fetcher: Fetcher,
     pub ppu: PPU,
+    pub serial: Serial,
     pub timers: Timers,
 
     // Special registers
     pub dmg_boot_rom: Wrapping<u8>,
-
-    // TODO: These should go in audio or other modules
-    pub nr10: Wrapping<u8>,
-    pub nr11: Wrapping<u8>,
-    pub nr12: Wrapping<u8>,
-    pub nr13: Wrapping<u8>,
-    pub nr14: Wrapping<u8>,
-
-    pub nr21: Wrapping<u8>,
-    pub nr22: Wrapping<u8>,
-    pub nr23: Wrapping<u8>,
-    pub nr24: Wrapping<u8>,
-
-    pub nr30: Wrapping<u8>,
-    pub nr31: Wrapping<u8>,
-    pub nr32: Wrapping<u8>,
-    pub nr33: Wrapping<u8>,
-    pub nr34: Wrapping<u8>,
-
-    pub nr50: Wrapping<u8>,
-    pub nr51: Wrapping<u8>,
-    pub nr52: Wrapping<u8>,
-
-    pub register_ff03: Wrapping<u8>,
-    pub register_ff08: Wrapping<u8>,
-    pub register_ff09: Wrapping<u8>,
-    pub register_ff15: Wrapping<u8>,
-    pub register_ff1f: Wrapping<u8>,
-    pub register_ff20: Wrapping<u8>,
-    pub register_ff21: Wrapping<u8>,
-    pub register_ff22: Wrapping<u8>,
-    pub register_ff23: Wrapping<u8>,
-    pub slice_ff27_ff2f: [Wrapping<u8>; 9],
-    pub slice_ff30_ff3f: [Wrapping<u8>; 16],
-    pub register_ff0a: Wrapping<u8>,
-    pub register_ff0b: Wrapping<u8>,
-    pub register_ff0c: Wrapping<u8>,
-    pub register_ff0d: Wrapping<u8>,
-    pub register_ff0e: Wrapping<u8>,
-    pub register_ff4d: Wrapping<u8>,
-    pub register_ff72: Wrapping<u8>,
-    pub register_ff73: Wrapping<u8>,
-    pub register_ff75: Wrapping<u8>,
-
-    // TODO: move these in PPU?
-    pub sb: Wrapping<u8>,
-    pub sc: Wrapping<u8>,
-    pub wram_bank: Wrapping<u8>,
 }
 
 impl Machine {
@@ -96,375 +74,380 @@ impl Machine {
         game_rom: Vec<u8>,
         rom_information: ROMInformation,
         fix_ly: bool,
+        strict_ppu: bool,
     ) -> Self {
-        let cpu = CPU::new(boot_rom, game_rom, &rom_information);
+        let memory = Memory::new(boot_rom, game_rom, &rom_information);
+        let mmu = MMU::new(memory, &rom_information);
+        let is_cgb_mode = rom_information.console_mode == ConsoleMode::Cgb;
         Machine {
-            banking_mode: BankingMode::Rom,
-            is_ram_enabled: false,
-            loram_bank: 1,
-            ram_or_hiram_bank: 0,
             rom_information,
+            stop_mode: false,
             t_cycle_count: 0,
+            t_cycles_ticked_in_step: 0,
+            warned_unmapped_io_writes: 0,
             dmg_boot_rom: Wrapping(0),
 
+            apu: APU::new(),
             background_window_fetcher: BackgroundOrWindowFetcher::new(),
-            cpu,
+            call_stack: CallStack::new(),
+            cpu: CPU::new(),
+            dma: DMA::new(),
             inputs: Inputs::new(),
             interrupts: Interrupts::new(),
+            io_registers: IORegisters::new(is_cgb_mode),
+            memory_trace: MemoryTrace::new(),
+            mmu,
             object_fetcher: ObjectFetcher::new(),
             pixel_fetcher: Fetcher::new(),
-            ppu: PPU::new(fix_ly),
+            ppu: PPU::new(fix_ly, is_cgb_mode, strict_ppu),
+            serial: Serial::new(),
             timers: Timers::new(),
+        }
+    }
+
+    // Runs the timers and the PPU, the CPU is responsible for calling this as it goes.
+    pub fn advance_cycles(&mut self, t_cycles: u8) {
+        self.t_cycles_ticked_in_step += t_cycles;
+        self.t_cycle_count += t_cycles as u64;
+        self.timers.ticks(&mut self.interrupts, t_cycles);
+        self.serial.ticks(&mut self.interrupts, t_cycles);
+        self.mmu.mapper.ticks(t_cycles);
+        self.tick_oam_dma(t_cycles);
+        self.ppu.ticks(
+            &mut self.background_window_fetcher,
+            &mut self.interrupts,
+            &mut self.object_fetcher,
+            &mut self.pixel_fetcher,
+            t_cycles,
+        );
+    }
 
-            nr10: Wrapping(0),
-            nr11: Wrapping(0),
-            nr12: Wrapping(0),
-            nr13: Wrapping(0),
-            nr14: Wrapping(0),
-
-            nr21: Wrapping(0),
-            nr22: Wrapping(0),
-            nr23: Wrapping(0),
-            nr24: Wrapping(0),
-
-            nr30: Wrapping(0),
-            nr31: Wrapping(0),
-            nr32: Wrapping(0),
-            nr33: Wrapping(0),
-            nr34: Wrapping(0),
-
-            nr50: Wrapping(0),
-            nr51: Wrapping(0),
-            nr52: Wrapping(0),
-
-            register_ff03: Wrapping(0),
-            register_ff08: Wrapping(0),
-            register_ff09: Wrapping(0),
-            register_ff15: Wrapping(0),
-            register_ff1f: Wrapping(0),
-            register_ff20: Wrapping(0),
-            register_ff21: Wrapping(0),
-            register_ff22: Wrapping(0),
-            register_ff23: Wrapping(0),
-            slice_ff27_ff2f: [Wrapping(0); 9],
-            slice_ff30_ff3f: [Wrapping(0); 16],
-            register_ff0a: Wrapping(0),
-            register_ff0b: Wrapping(0),
-            register_ff0c: Wrapping(0),
-            register_ff0d: Wrapping(0),
-            register_ff0e: Wrapping(0),
-            register_ff4d: Wrapping(0),
-            register_ff72: Wrapping(0),
-            register_ff73: Wrapping(0),
-            register_ff75: Wrapping(0),
-
-            sb: Wrapping(0),
-            sc: Wrapping(0),
-            wram_bank: Wrapping(0),
+    fn tick_oam_dma(&mut self, dots: u8) {
+        for _ in 0..dots {
+            if let Some((source_address, oam_offset)) = self.dma.tick() {
+                let byte = match source_address.0 {
+                    // Sources from 0xE000 see echo RAM, but there is nothing to copy past it
+                    0xFE00..=0xFFFF => Wrapping(0xFF),
+                    _ => self.read_u8_raw(source_address),
+                };
+                self.ppu.object_attribute_memory[oam_offset] = byte.0;
+            }
         }
     }
 
+    pub fn step(&mut self) -> MachineStep {
+        if self.stop_mode {
+            if self.inputs.is_any_line_low() {
+                self.stop_mode = false;
+            } else {
+                // Both the CPU and the PPU/timers clocks are stopped
+                return MachineStep {
+                    t_cycles: 4,
+                    instruction_executed: None,
+                };
+            }
+        }
+
+        self.t_cycles_ticked_in_step = 0;
+
+        // Leaving HALT takes an extra M-cycle, whether IME lets the interrupt be serviced or not.
+        // When it does not, the interrupt stays pending and execution resumes after HALT.
+        let mut wake_up_t_cycles = 0;
+        if self.cpu.low_power_mode && self.interrupts.is_interrupt_pending() {
+            self.cpu.low_power_mode = false;
+            wake_up_t_cycles = 4;
+            self.advance_cycles(wake_up_t_cycles);
+        }
+
+        let sp_before = self.registers().sp;
+        let mut instruction_executed = None;
+        let (mut t_cycles, mut _m_cycles) = Interrupts::handle_interrupts(self);
+        if t_cycles == 0 {
+            (instruction_executed, (t_cycles, _m_cycles)) = CPU::execute_one_instruction(self);
+        }
+        t_cycles += wake_up_t_cycles;
+        if let Some(instruction) = &instruction_executed {
+            let (sp_after, pc_after) = (self.registers().sp, self.registers().pc);
+            self.call_stack
+                .track_instruction(instruction, sp_before, sp_after, pc_after);
+        }
+        self.call_stack.unwind(self.registers().sp);
+        // Memory accesses have already advanced the machine, only internal cycles remain.  If this
+        // instruction was STOP, the clocks stop right away.
+        debug_assert!(self.t_cycles_ticked_in_step <= t_cycles);
+        if !self.stop_mode {
+            self.advance_cycles(t_cycles - self.t_cycles_ticked_in_step);
+        }
+
+        MachineStep {
+            t_cycles: t_cycles as u128,
+            instruction_executed,
+        }
+    }
+
+    // Steps cycles forward until an instruction is executed.  May take many tries when the console
+    // is in HALT and awaiting an interrupt to wake up and execute an instruction.  When the CPU is
+    // locked or stopped, we return after a single step so that the application stays responsive.
+    pub fn execute_one_instruction(&mut self) -> InstructionStep {
+        let mut total_t_cycles: u128 = 0;
+        loop {
+            let step = self.step();
+            total_t_cycles += step.t_cycles;
+            if step.instruction_executed.is_some() || self.cpu().is_locked() || self.stop_mode {
+                return InstructionStep {
+                    t_cycles: total_t_cycles,
+                    instruction_executed: step.instruction_executed,
+                };
+            }
+        }
+    }
+
+    // Runs instructions until the PPU completes a frame, so that frames can be produced without the
+    // GUI.  When the LCD is off, no frame ever completes, so we give up after a frame's worth of
+    // cycles.  Returns the number of t-cycles that were run.
+    pub fn run_frame(&mut self) -> u128 {
+        let frame_count = self.ppu().completed_frame_count();
+        let mut total_t_cycles: u128 = 0;
+        while self.ppu().completed_frame_count() == frame_count
+            && total_t_cycles < T_CYCLES_PER_FRAME
+        {
+            total_t_cycles += self.execute_one_instruction().t_cycles;
+        }
+        self.inputs.advance_frame(&mut self.interrupts);
+        total_t_cycles
+    }
+
+    // Hashes everything that decides how emulation goes from here, so that two runs can be checked
+    // to be in the same state.  Debugging aids (call stack, memory trace, warnings already given,
+    // console output, joypad interrupt history) are left out, as is the bookkeeping of the current
+    // step, which is always complete between instructions.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1aHasher::new();
+        self.stop_mode.hash(&mut hasher);
+        self.t_cycle_count.hash(&mut hasher);
+        self.dmg_boot_rom.hash(&mut hasher);
+
+        self.apu.hash(&mut hasher);
+        self.background_window_fetcher.hash(&mut hasher);
+        self.cpu.hash(&mut hasher);
+        self.dma.hash(&mut hasher);
+        self.inputs.hash(&mut hasher);
+        self.interrupts.hash(&mut hasher);
+        self.io_registers.hash(&mut hasher);
+        self.mmu.hash(&mut hasher);
+        self.object_fetcher.hash(&mut hasher);
+        self.pixel_fetcher.hash(&mut hasher);
+        self.ppu.hash(&mut hasher);
+        self.serial.hash(&mut hasher);
+        self.timers.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // What the game printed over the link cable, see `Serial::is_console_enabled`.
+    pub fn serial_output(&self) -> &str {
+        self.serial.console_output()
+    }
+
+    // The ROM bank currently mapped at 0x0000-0x3FFF
+    pub fn current_low_rom_bank(&self) -> u16 {
+        self.mmu.mapper.low_rom_bank()
+    }
+
+    // The ROM bank currently mapped at 0x4000-0x7FFF
+    pub fn current_rom_bank(&self) -> u16 {
+        self.mmu.mapper.rom_bank()
+    }
+
+    pub fn console_mode(&self) -> ConsoleMode {
+        self.rom_information.console_mode
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.console_mode() == ConsoleMode::Cgb
+    }
+
     pub fn is_dmg_boot_rom_on(&self) -> bool {
         self.dmg_boot_rom.0 == 0
     }
 
+    // Whether the CPU is currently locked out of this address, by OAM DMA or by the PPU.
+    fn is_locked_out(&self, address: Wrapping<u16>) -> bool {
+        match address.0 {
+            // IO registers and HRAM remain reachable, games wait for OAM DMA to finish from there
+            0xFF00..=0xFFFF => false,
+            _ if self.dma.is_active() => true,
+            0x8000..=0x9FFF => !self.ppu.is_vram_accessible(),
+            0xFE00..=0xFE9F => !self.ppu.is_oam_accessible(),
+            _ => false,
+        }
+    }
+
+    // Blocked reads see the bus floating high.
     pub fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        if self.is_locked_out(address) {
+            return Wrapping(0xFF);
+        }
+        self.read_u8_raw(address)
+    }
+
+    // Reads memory regardless of OAM DMA and of what the PPU is doing, for the debugger views and
+    // OAM DMA itself.
+    pub fn read_u8_raw(&self, address: Wrapping<u16>) -> Wrapping<u8> {
         if self.is_dmg_boot_rom_on() && address.0 <= 0xFF {
             return self.memory().read_boot_rom(address);
         }
         match address.0 {
-            0x0000..=0x3FFF => Wrapping(self.memory().game_rom[address.0 as usize]),
-            0x4000..=0x7FFF => match self.rom_information.mapper_type {
-                crate::application_state::MapperType::ROMOnly => {
-                    Wrapping(self.memory().game_rom[address.0 as usize])
-                }
-                crate::application_state::MapperType::MBC1 => {
-                    let mut bank_number = self.loram_bank;
-                    if self.banking_mode == BankingMode::Rom {
-                        bank_number |= self.ram_or_hiram_bank << 5;
-                    }
-                    let base_address = bank_number as usize * 0x4000;
-                    Wrapping(self.memory().game_rom[base_address + address.0 as usize - 0x4000])
-                }
-                crate::application_state::MapperType::Other => todo!(),
-            },
             0x8000..=0x9FFF => self.ppu.read_vram(address - Wrapping(0x8000)),
-
-            0xA000..=0xBFFF => {
-                Wrapping(self.memory().game_ram[(address - Wrapping(0xA000)).0 as usize])
-            }
-            0xC000..=0xCFFF => self.ppu.read_wram_0(address - Wrapping(0xC000)),
-            0xD000..=0xDFFF => self.ppu.read_wram_1(address - Wrapping(0xD000)),
-            0xE000..=0xFDFF => self.read_u8(address - Wrapping(0x2000)),
-
             0xFE00..=0xFE9F => {
                 Wrapping(self.ppu.object_attribute_memory[address.0 as usize - 0xFE00])
             }
             0xFEA0..=0xFEFF => Wrapping(0xFF),
+            0xFF00..=0xFF7F => self.read_io(address),
+            0xFFFF..=0xFFFF => self.interrupts().interrupt_enable,
+            // The cartridge, WRAM, and HRAM
+            _ => self.mmu.read_u8(address),
+        }
+    }
 
+    fn read_io(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        match address.0 {
             0xFF00..=0xFF00 => self.inputs.read(),
-            0xFF01..=0xFF01 => self.sb,
-            0xFF02..=0xFF02 => self.sc,
-            0xFF03..=0xFF03 => self.register_ff03,
+            0xFF01..=0xFF02 => self.serial.read_u8(address),
+            0xFF03..=0xFF03 => self.io_registers.read_u8(address),
             0xFF04..=0xFF07 => self.timers().read_u8(address),
-            0xFF08..=0xFF08 => self.register_ff08,
-            0xFF09..=0xFF09 => self.register_ff09,
-            0xFF0A..=0xFF0A => self.register_ff0a,
-            0xFF0B..=0xFF0B => self.register_ff0b,
-            0xFF0C..=0xFF0C => self.register_ff0c,
-            0xFF0D..=0xFF0D => self.register_ff0d,
-            0xFF0E..=0xFF0E => self.register_ff0e,
-            0xFF0F..=0xFF0F => self.interrupts().interrupt_flag,
-
-            0xFF10..=0xFF10 => self.nr10,
-            0xFF11..=0xFF11 => self.nr11,
-            0xFF12..=0xFF12 => self.nr12,
-            0xFF13..=0xFF13 => self.nr13,
-            0xFF14..=0xFF14 => self.nr14,
-            0xFF15..=0xFF15 => self.register_ff15,
-            0xFF16..=0xFF16 => self.nr21,
-            0xFF17..=0xFF17 => self.nr22,
-            0xFF18..=0xFF18 => self.nr23,
-            0xFF19..=0xFF19 => self.nr24,
-            0xFF1A..=0xFF1A => self.nr30,
-            0xFF1B..=0xFF1B => self.nr31,
-            0xFF1C..=0xFF1C => self.nr32,
-            0xFF1D..=0xFF1D => self.nr33,
-            0xFF1E..=0xFF1E => self.nr34,
-            0xFF1F..=0xFF1F => self.register_ff1f,
-            0xFF20..=0xFF20 => self.register_ff20,
-            0xFF21..=0xFF21 => self.register_ff21,
-            0xFF22..=0xFF22 => self.register_ff22,
-            0xFF23..=0xFF23 => self.register_ff23,
-            0xFF24..=0xFF24 => self.nr50,
-            0xFF25..=0xFF25 => self.nr51,
-            0xFF26..=0xFF26 => self.nr52,
-            0xFF27..=0xFF2F => self.slice_ff27_ff2f[address.0 as usize - 0xFF27],
-
-            // Wave RAM
-            0xFF30..=0xFF3F => self.slice_ff30_ff3f[address.0 as usize - 0xFF30],
+            0xFF08..=0xFF0E => self.io_registers.read_u8(address),
+            // Only 5 interrupts exist, the upper bits are unused and read as 1
+            0xFF0F..=0xFF0F => self.interrupts().interrupt_flag | Wrapping(0xE0),
+
+            0xFF10..=0xFF3F => self.apu().read_u8(address),
 
             0xFF40..=0xFF40 => self.ppu.read_lcdc(),
-            0xFF41..=0xFF41 => self.ppu.lcd_status,
+            0xFF41..=0xFF41 => self.ppu.read_stat(),
             0xFF42..=0xFF42 => self.ppu.scy,
             0xFF43..=0xFF43 => self.ppu.scx,
             0xFF44..=0xFF44 => self.ppu.read_ly(),
             0xFF45..=0xFF45 => self.ppu.lcd_y_compare,
-            0xFF46..=0xFF46 => {
-                print!("WARNING: Faking read attempt of 0xFF46");
-                Wrapping(0xFF)
-            }
+            0xFF46..=0xFF46 => self.dma.source_page(),
             0xFF47..=0xFF47 => Wrapping(self.ppu.background_palette_data),
             0xFF48..=0xFF48 => Wrapping(self.ppu.object_palette_0),
             0xFF49..=0xFF49 => Wrapping(self.ppu.object_palette_1),
             0xFF4A..=0xFF4A => self.ppu.window_y,
             0xFF4B..=0xFF4B => self.ppu.window_x7,
-            0xFF4D..=0xFF4D => self.register_ff4d,
-            0xFF4F..=0xFF4F => self.ppu.vram_bank,
+            0xFF4D..=0xFF4D => self.io_registers.read_u8(address),
+            0xFF4F..=0xFF4F if self.is_cgb_mode() => self.ppu.vram_bank,
 
             0xFF50..=0xFF50 => self.dmg_boot_rom,
 
-            0xFF68..=0xFF68 => self.ppu.cgb_background_palette_spec,
-            0xFF69..=0xFF69 => self.ppu.cgb_background_palette_data,
-            0xFF6A..=0xFF6A => self.ppu.object_palette_spec,
-            0xFF6B..=0xFF6B => self.ppu.object_palette_data,
+            0xFF68..=0xFF68 if self.is_cgb_mode() => self.ppu.cgb_background_palettes.read_spec(),
+            0xFF69..=0xFF69 if self.is_cgb_mode() => self.ppu.cgb_background_palettes.read_data(),
+            0xFF6A..=0xFF6A if self.is_cgb_mode() => self.ppu.cgb_object_palettes.read_spec(),
+            0xFF6B..=0xFF6B if self.is_cgb_mode() => self.ppu.cgb_object_palettes.read_data(),
 
-            0xFF70..=0xFF70 => self.wram_bank,
-            0xFF72..=0xFF72 => self.register_ff72,
-            0xFF73..=0xFF73 => self.register_ff73,
-            0xFF74..=0xFF74 => Wrapping(0xFF),
-            0xFF75..=0xFF75 => self.register_ff75,
+            0xFF70..=0xFF70 if self.is_cgb_mode() => self.mmu.wram_bank,
+            0xFF72..=0xFF75 => self.io_registers.read_u8(address),
 
-            0xFF80..=0xFFFE => Wrapping(self.memory().hram[address.0 as usize - 0xFF80]),
-            0xFFFF..=0xFFFF => self.interrupts().interrupt_enable,
-            _ => panic!(
-                "Memory read at address {:04X} needs to be handled (at PC 0x{:04X})",
-                address,
-                self.registers().pc
-            ),
-        }
-    }
-
-    pub fn read_range(&self, address: Wrapping<u16>, size: usize) -> Vec<Wrapping<u8>> {
-        let address = address.0;
-        let mut res = Vec::new();
-        for a in address..address.saturating_add(size as u16) {
-            res.push(self.read_u8(Wrapping(a)));
+            // Unmapped IO registers read as all ones
+            _ => Wrapping(0xFF),
         }
-        res
     }
 
     pub fn request_interrupt(&mut self, interrupt_bit: u8) {
         self.interrupts_mut().request(interrupt_bit);
     }
 
+    // NOTE: The boot ROM is only overlaid for reads, writes to 0x0000-0x00FF still reach the
+    // cartridge, like any other ROM write.
     pub fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
-        if self.is_dmg_boot_rom_on() && address.0 <= 0xFF {
-            panic!("Attempted write in boot ROM")
+        if self.is_locked_out(address) {
+            return;
         }
         match address.0 {
-            0x0000..=0x1FFF => match self.rom_information.mapper_type {
-                MapperType::ROMOnly => {
-                    print!("WARNING: Ignoring write at 0x{:04X}", address.0)
-                }
-                MapperType::MBC1 => {
-                    self.is_ram_enabled = value.0 & 0x0F == 0x0A;
-                }
-                MapperType::Other => todo!(),
-            },
-            0x2000..=0x3FFF => match self.rom_information.mapper_type {
-                MapperType::ROMOnly => {
-                    println!("WARNING: Ignoring write at 0x{:04X}", address.0)
-                }
-                MapperType::MBC1 => {
-                    self.loram_bank = value.0 & 0x1F;
-                }
-                MapperType::Other => todo!(),
-            },
-            0x4000..=0x5FFF => match self.rom_information.mapper_type {
-                MapperType::ROMOnly => {
-                    print!("WARNING: Ignoring write at 0x{:04X}", address.0)
-                }
-                MapperType::MBC1 => {
-                    self.ram_or_hiram_bank = value.0 & 0b11;
-                }
-                MapperType::Other => todo!(),
-            },
-            0x6000..=0x7FFF => match self.rom_information.mapper_type {
-                MapperType::ROMOnly => {
-                    print!("WARNING: Ignoring write at 0x{:04X}", address.0)
-                }
-                MapperType::MBC1 => {
-                    self.banking_mode = if value.0 & 1 == 0 {
-                        BankingMode::Rom
-                    } else {
-                        BankingMode::Ram
-                    }
-                }
-                MapperType::Other => todo!(),
-            },
             0x8000..=0x9FFF => PPU::write_vram(&mut self.ppu, address - Wrapping(0x8000), value),
-
-            0xA000..=0xBFFF => match self.rom_information.ram_size {
-                crate::application_state::RAMSize::NoRAM => {
-                    println!(
-                        "WARNING: Ignoring write to non-existing RAM at 0x{:04X}",
-                        address
-                    )
-                }
-                _ => self.memory_mut().game_ram[address.0 as usize - 0xA000] = value.0,
-            },
-            0xC000..=0xCFFF => PPU::write_wram_0(&mut self.ppu, address - Wrapping(0xC000), value),
-            0xD000..=0xDFFF => PPU::write_wram_1(&mut self.ppu, address - Wrapping(0xD000), value),
-            0xE000..=0xFDFF => self.write_u8(Wrapping(address.0 - 0x2000), value),
-
             0xFE00..=0xFE9F => {
                 self.ppu.object_attribute_memory[address.0 as usize - 0xFE00] = value.0
             }
             0xFEA0..=0xFEFF => {
                 // println!("[WARNING] Ignoring write to 0x{:04X}", address.0)
             }
+            0xFF00..=0xFF7F => self.write_io(address, value),
+            // Unlike IF, all 8 bits of IE are stored, some games use the upper bits as scratch
+            0xFFFF..=0xFFFF => self.interrupts_mut().interrupt_enable = value,
+            // The cartridge (including its mapper registers), WRAM, and HRAM
+            _ => self.mmu.write_u8(address, value),
+        }
+    }
 
-            0xFF00..=0xFF00 => self.inputs.write(value),
-            0xFF01..=0xFF01 => self.sb = value,
-            0xFF02..=0xFF02 => self.sc = value,
-            0xFF03..=0xFF03 => self.register_ff03 = value,
+    fn write_io(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        match address.0 {
+            0xFF00..=0xFF00 => self.inputs.write(value, &mut self.interrupts),
+            0xFF01..=0xFF02 => self.serial.write_u8(address, value),
+            0xFF03..=0xFF03 => self.io_registers.write_u8(address, value),
             0xFF04..=0xFF07 => self.timers_mut().write_u8(address, value),
-            0xFF08..=0xFF08 => self.register_ff08 = value,
-            0xFF09..=0xFF09 => self.register_ff09 = value,
-            0xFF0A..=0xFF0A => self.register_ff0a = value,
-            0xFF0B..=0xFF0B => self.register_ff0b = value,
-            0xFF0C..=0xFF0C => self.register_ff0c = value,
-            0xFF0D..=0xFF0D => self.register_ff0d = value,
-            0xFF0E..=0xFF0E => self.register_ff0e = value,
-            0xFF0F..=0xFF0F => self.interrupts_mut().interrupt_flag = value,
-
-            // AUDIO
-            0xFF10..=0xFF10 => self.nr10 = value,
-            0xFF11..=0xFF11 => self.nr11 = value,
-            0xFF12..=0xFF12 => self.nr12 = value,
-            0xFF13..=0xFF13 => self.nr13 = value,
-            0xFF14..=0xFF14 => self.nr14 = value,
-            0xFF15..=0xFF15 => self.register_ff15 = value,
-            0xFF16..=0xFF16 => self.nr21 = value,
-            0xFF17..=0xFF17 => self.nr22 = value,
-            0xFF18..=0xFF18 => self.nr23 = value,
-            0xFF19..=0xFF19 => self.nr24 = value,
-            0xFF1A..=0xFF1A => self.nr30 = value,
-            0xFF1B..=0xFF1B => self.nr31 = value,
-            0xFF1C..=0xFF1C => self.nr32 = value,
-            0xFF1D..=0xFF1D => self.nr33 = value,
-            0xFF1E..=0xFF1E => self.nr34 = value,
-            0xFF1F..=0xFF1F => self.register_ff1f = value,
-
-            0xFF20..=0xFF20 => self.register_ff20 = value,
-            0xFF21..=0xFF21 => self.register_ff21 = value,
-            0xFF22..=0xFF22 => self.register_ff22 = value,
-            0xFF23..=0xFF23 => self.register_ff23 = value,
-            0xFF24..=0xFF24 => self.nr50 = value,
-            0xFF25..=0xFF25 => self.nr51 = value,
-            0xFF26..=0xFF26 => self.nr52 = value,
-            0xFF27..=0xFF2F => self.slice_ff27_ff2f[address.0 as usize - 0xFF27] = value,
-
-            // WAVE RAM
-            0xFF30..=0xFF3F => self.slice_ff30_ff3f[address.0 as usize - 0xFF30] = value,
-
-            0xFF40..=0xFF40 => self.ppu.write_lcdc(value),
-            0xFF41..=0xFF41 => self.ppu.lcd_status = value,
+            0xFF08..=0xFF0E => self.io_registers.write_u8(address, value),
+            0xFF0F..=0xFF0F => self.interrupts_mut().interrupt_flag = value & Wrapping(0x1F),
+
+            0xFF10..=0xFF3F => self.apu_mut().write_u8(address, value),
+
+            0xFF40..=0xFF40 => self.ppu.write_lcdc(
+                value,
+                &mut self.background_window_fetcher,
+                &mut self.object_fetcher,
+                &mut self.interrupts,
+            ),
+            0xFF41..=0xFF41 => self.ppu.write_stat(value, &mut self.interrupts),
             0xFF42..=0xFF42 => self.ppu.scy = value,
             0xFF43..=0xFF43 => self.ppu.scx = value,
             0xFF44..=0xFF44 => {
-                panic!("Something attempted to write to LY")
-            }
-            0xFF45..=0xFF45 => self.ppu.lcd_y_compare = value,
-            0xFF46..=0xFF46 => {
-                // TODO: extract
-                // OAM DMA transfer (should take 640 dots)
-                if value.0 > 0xDF {
-                    panic!("OAM DMA transfer outside of valid range!");
-                }
-                let base_source_address = (value.0 as u16) << 8;
-                for offset in 0..=0x9F {
-                    let byte = self.read_u8(Wrapping(base_source_address | offset));
-                    self.write_u8(Wrapping(0xFE00 + offset), byte)
-                }
+                // LY is read-only
             }
+            0xFF45..=0xFF45 => self.ppu.write_lyc(value, &mut self.interrupts),
+            0xFF46..=0xFF46 => self.dma.start(value),
             0xFF47..=0xFF47 => self.ppu.background_palette_data = value.0,
             0xFF48..=0xFF48 => self.ppu.object_palette_0 = value.0,
             0xFF49..=0xFF49 => self.ppu.object_palette_1 = value.0,
             0xFF4A..=0xFF4A => self.ppu.window_y = value,
             0xFF4B..=0xFF4B => self.ppu.window_x7 = value,
-            0xFF4D..=0xFF4D => self.register_ff4d = value,
-            0xFF4F..=0xFF4F => self.ppu.vram_bank = value,
+            0xFF4D..=0xFF4D => self.io_registers.write_u8(address, value),
+            0xFF4F..=0xFF4F if self.is_cgb_mode() => self.ppu.vram_bank = value,
 
             0xFF50..=0xFF50 => self.dmg_boot_rom = value,
 
-            0xFF68..=0xFF68 => self.ppu.cgb_background_palette_spec = value,
-            0xFF69..=0xFF69 => self.ppu.cgb_background_palette_data = value,
-            0xFF6A..=0xFF6A => self.ppu.object_palette_spec = value,
-            0xFF6B..=0xFF6B => self.ppu.object_palette_data = value,
+            0xFF68..=0xFF68 if self.is_cgb_mode() => {
+                self.ppu.cgb_background_palettes.write_spec(value)
+            }
+            0xFF69..=0xFF69 if self.is_cgb_mode() => {
+                self.ppu.cgb_background_palettes.write_data(value)
+            }
+            0xFF6A..=0xFF6A if self.is_cgb_mode() => self.ppu.cgb_object_palettes.write_spec(value),
+            0xFF6B..=0xFF6B if self.is_cgb_mode() => self.ppu.cgb_object_palettes.write_data(value),
 
-            0xFF70..=0xFF70 => self.wram_bank = value,
-            0xFF72..=0xFF72 => self.register_ff72 = value,
-            0xFF73..=0xFF73 => self.register_ff73 = value,
-            0xFF74..=0xFF74 => {}
-            0xFF75..=0xFF75 => self.register_ff75 = Wrapping(value.0 & 0x07),
+            0xFF70..=0xFF70 if self.is_cgb_mode() => self.mmu.wram_bank = value,
+            0xFF72..=0xFF75 => self.io_registers.write_u8(address, value),
             0xFF7F..=0xFF7F => {
                 // println!("[WARNING] Ignoring write to 0x{:04X}", address.0)
             }
 
-            0xFF80..=0xFFFE => self.memory_mut().hram[address.0 as usize - 0xFF80] = value.0,
-            0xFFFF..=0xFFFF => self.interrupts_mut().interrupt_enable = value,
-            _ => panic!(
-                "Memory write at address {:04X} needs to be handle (at PC 0x{:04X})",
-                address,
-                self.registers().pc
-            ),
+            // CGB registers do not exist in DMG mode
+            0xFF4F | 0xFF68..=0xFF6B | 0xFF70 => {}
+
+            // Unmapped IO registers ignore writes.  Games tend to write the same ones over and
+            // over, e.g. when clearing the IO area, so we only warn the first time.
+            _ => {
+                let bit = 1 << (address.0 & 0x7F);
+                if self.warned_unmapped_io_writes & bit == 0 {
+                    self.warned_unmapped_io_writes |= bit;
+                    println!(
+                        "WARNING: Ignoring write to unmapped address 0x{:04X} (at PC 0x{:04X})",
+                        address,
+                        self.registers().pc
+                    )
+                }
+            }
         }
     }
 
     pub fn show_memory_row(&self, from: Wrapping<u16>) -> String {
-        let range = self.read_range(from, 8);
+        let range = self.hex_dump(from, 1)[0].bytes;
         format!(
             "{:04x}: {:02X} {:02X} {:02X} {:02X}  {:02X} {:02X} {:02X} {:02X}",
             from, range[0], range[1], range[2], range[3], range[4], range[5], range[6], range[7]
@@ -495,3 +478,556 @@ impl Machine {
         &mut self.ppu
     }
 }
+
+impl Bus for Machine {
+    fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        Machine::read_u8(self, address)
+    }
+
+    fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        Machine::write_u8(self, address, value)
+    }
+
+    fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    fn interrupts(&self) -> &Interrupts {
+        &self.interrupts
+    }
+
+    fn interrupts_mut(&mut self) -> &mut Interrupts {
+        &mut self.interrupts
+    }
+
+    fn memory_observer(&mut self) -> Option<&mut dyn MemoryObserver> {
+        if self.memory_trace.is_enabled {
+            Some(&mut self.memory_trace)
+        } else {
+            None
+        }
+    }
+
+    fn enter_stop_mode(&mut self) {
+        self.timers_mut().reset_divide_register();
+        self.stop_mode = true;
+    }
+
+    fn advance_cycles(&mut self, t_cycles: u8) {
+        Machine::advance_cycles(self, t_cycles)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use crate::{
+        bus::FlatBus,
+        inputs::{Button, Buttons, InputSource},
+        instructions::asm::*,
+        registers::{R16, R8},
+    };
+
+    // A ROM-only machine running `program` from 0x100, once a boot ROM that does nothing but unmap
+    // itself is done.  Interrupt handlers return right away.
+    pub fn machine_with_program(program: &[u8]) -> Machine {
+        machine_with_cartridge(program, ROMInformation::new())
+    }
+
+    // Same, with the cartridge described by `rom_information`.
+    pub fn machine_with_cartridge(program: &[u8], rom_information: ROMInformation) -> Machine {
+        let mut boot_rom = vec![0; 0x100];
+        // LD A, 0x01; LDH (0x50), A
+        boot_rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let mut game_rom = vec![0; rom_information.rom_banks as usize * 0x4000];
+        for handler in [0x40, 0x48, 0x50, 0x58, 0x60] {
+            game_rom[handler] = 0xD9; // RETI
+        }
+        game_rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        Machine::new(boot_rom, game_rom, rom_information, false, false)
+    }
+
+    // Executes instructions until PC reaches `address`, which must happen within a frame's worth.
+    pub fn run_until(machine: &mut Machine, address: u16) {
+        let mut t_cycles = 0;
+        while machine.registers().pc.0 != address {
+            assert!(
+                t_cycles < T_CYCLES_PER_FRAME,
+                "Never reached 0x{:04X}",
+                address
+            );
+            t_cycles += machine.execute_one_instruction().t_cycles;
+        }
+    }
+
+    // Turns the LCD on, then keeps adding the action buttons to B, and storing B to HRAM.
+    pub fn joypad_program() -> Vec<u8> {
+        program![
+            ld_r16_u16(R16::SP, 0xFFFE),
+            ld_a_u8(0x91),
+            ldh_u8_a(0x40),
+            // loop:
+            ld_a_u8(0x10),
+            ldh_u8_a(0x00),
+            ldh_a_u8(0x00),
+            add_a_r8(R8::B),
+            ld_r8_r8(R8::B, R8::A),
+            ldh_u8_a(0x80),
+            jr(-12),
+        ]
+    }
+
+    fn scripted_buttons(frame: usize) -> Buttons {
+        Buttons::from_byte((frame * 37) as u8)
+    }
+
+    // Runs frame by frame from power-on, optionally going through a copy of the machine for every
+    // instruction the way the debugger does to keep history.
+    fn run_scripted(frame_count: usize, is_history_preserved: bool) -> Vec<u64> {
+        let mut machine = machine_with_program(&joypad_program());
+        let mut hashes = Vec::new();
+        for frame in 0..frame_count {
+            machine.inputs.set_buttons(
+                InputSource::Keyboard,
+                scripted_buttons(frame),
+                &mut machine.interrupts,
+            );
+            if is_history_preserved {
+                let completed_frames = machine.ppu().completed_frame_count();
+                while machine.ppu().completed_frame_count() == completed_frames {
+                    let mut next_machine = machine.clone();
+                    next_machine.execute_one_instruction();
+                    machine = next_machine;
+                }
+                machine.inputs.advance_frame(&mut machine.interrupts);
+            } else {
+                machine.run_frame();
+            }
+            hashes.push(machine.state_hash());
+        }
+        hashes
+    }
+
+    #[test]
+    fn runs_from_power_on_are_deterministic() {
+        let first = run_scripted(20, false);
+        let second = run_scripted(20, false);
+        assert_eq!(first, second);
+        // The program does run and see the buttons
+        assert_ne!(first[18], first[19]);
+    }
+
+    #[test]
+    fn preserving_history_does_not_change_the_run() {
+        // Copying the machine for every instruction is slow, a few frames will do
+        assert_eq!(run_scripted(3, false), run_scripted(3, true));
+    }
+
+    #[test]
+    fn state_hash_ignores_debugging_aids() {
+        let mut machine = machine_with_program(&joypad_program());
+        let hash = machine.state_hash();
+        machine.memory_trace.is_enabled = true;
+        machine.call_stack = CallStack::new();
+        machine.inputs.advance_frame(&mut machine.interrupts);
+        assert_eq!(hash, machine.state_hash());
+    }
+
+    // Selects the action buttons, then stops until one is pressed.
+    fn stop_program() -> Vec<u8> {
+        program![
+            ld_r16_u16(R16::SP, 0xFFFE),
+            ld_a_u8(0x10),
+            ldh_u8_a(0x00),
+            // 0x107
+            stop(),
+            // 0x109
+            ldh_a_u8(0x04),
+            jr(-4),
+        ]
+    }
+
+    #[test]
+    fn stop_resets_div() {
+        let mut machine = machine_with_program(&stop_program());
+        run_until(&mut machine, 0x107);
+        assert_ne!(machine.read_u8(Wrapping(0xFF04)), Wrapping(0));
+        machine.execute_one_instruction();
+        assert!(machine.stop_mode);
+        assert_eq!(machine.read_u8(Wrapping(0xFF04)), Wrapping(0));
+    }
+
+    #[test]
+    fn a_joypad_press_resumes_from_stop() {
+        let mut machine = machine_with_program(&stop_program());
+        run_until(&mut machine, 0x107);
+        machine.execute_one_instruction();
+        for _ in 0..1000 {
+            machine.step();
+        }
+        // The byte after STOP is skipped, and nothing runs while stopped
+        assert!(machine.stop_mode);
+        assert_eq!(machine.registers().pc, Wrapping(0x109));
+        assert_eq!(machine.read_u8(Wrapping(0xFF04)), Wrapping(0));
+        machine.inputs.set_button(
+            InputSource::Keyboard,
+            Button::Start,
+            true,
+            &mut machine.interrupts,
+        );
+        machine.execute_one_instruction();
+        assert!(!machine.stop_mode);
+        assert_eq!(machine.registers().pc, Wrapping(0x10B));
+    }
+
+    #[test]
+    fn every_step_advances_the_machine_by_its_cycles() {
+        let program = program![
+            ld_r16_u16(R16::SP, 0xFFFE),
+            ld_r16_u16(R16::HL, 0xC000),
+            ld_a_u8(0x91),
+            ldh_u8_a(0x40),
+            // loop:
+            push(R16::HL),
+            call(0x011A),
+            pop(R16::DE),
+            ld_mhlinc_a(),
+            ld_a_mhl(),
+            ld_mu16_a(0xC100),
+            ldh_a_u8(0x44),
+            swap(R8::A),
+            jr(-16),
+            // 0x011A
+            ret(),
+        ];
+        let mut machine = machine_with_program(&program);
+        for _ in 0..10_000 {
+            let t_cycle_count = machine.t_cycle_count;
+            let step = machine.step();
+            assert_eq!(machine.t_cycle_count - t_cycle_count, step.t_cycles as u64);
+            if let Some(decoded) = step.instruction_executed {
+                let mut bus = FlatBus::new();
+                let (t_cycles, m_cycles) = decoded.instruction.execute(&mut bus);
+                assert_eq!(step.t_cycles, t_cycles as u128, "{:?}", decoded.instruction);
+                assert_eq!(t_cycles, 4 * m_cycles, "{:?}", decoded.instruction);
+            }
+        }
+        // The loop did run
+        assert!(machine.registers().hl.0 > 0xC100);
+    }
+
+    #[test]
+    fn reads_see_ly_at_the_time_of_the_access() {
+        let program = program![
+            ld_a_u8(0x91),
+            ldh_u8_a(0x40),
+            // 0x104
+            ldh_a_u8(0x44),
+            // The loop takes 28 t-cycles, so that it drifts against the 456 of a scanline
+            nop(),
+            jr(-5),
+        ];
+        let mut machine = machine_with_program(&program);
+        let mut boundaries_crossed = 0;
+        for _ in 0..2_000 {
+            run_until(&mut machine, 0x104);
+            // Fetching the instruction takes 2 M-cycles, the read happens during the third
+            let mut at_read = machine.clone();
+            at_read.advance_cycles(12);
+            let ly_at_read = at_read.ppu.read_ly();
+            if ly_at_read != machine.ppu.read_ly() {
+                boundaries_crossed += 1;
+            }
+            machine.execute_one_instruction();
+            assert_eq!(machine.registers().read_a(), ly_at_read);
+        }
+        assert!(boundaries_crossed > 0);
+    }
+
+    // A boot ROM going through the same motions as the real one: it clears VRAM, copies the logo
+    // from the cartridge header into it, shows it for 3 frames, and locks up unless the logo
+    // matches its own copy, which it keeps at 0x0004-0x0033.
+    fn logo_checking_boot_rom(logo: &[u8]) -> Vec<u8> {
+        let mut boot_rom = program![jr(0x32), nop(), nop()];
+        boot_rom.extend(logo);
+        boot_rom.extend(program![
+            ld_r16_u16(R16::SP, 0xFFFE),
+            ld_r16_u16(R16::HL, 0x8000),
+            // Until HL reaches 0xA000
+            xor_a_r8(R8::A),
+            ld_mhlinc_a(),
+            bit(5, R8::H),
+            jr_z(-6),
+            // The logo lands at 0x8104, so that only H differs from its address in the header
+            ld_r16_u16(R16::HL, 0x0104),
+            ld_a_mhl(),
+            ld_r8_u8(R8::H, 0x81),
+            ld_mhl_a(),
+            ld_r8_u8(R8::H, 0x01),
+            inc_r8(R8::L),
+            ld_r8_r8(R8::A, R8::L),
+            cp_a_u8(0x34),
+            jr_nz(-12),
+            ld_a_u8(0xFC),
+            ldh_u8_a(0x47),
+            ld_a_u8(0x91),
+            ldh_u8_a(0x40),
+            // Waits for VBlank to start, 3 times
+            ld_r8_u8(R8::B, 3),
+            ldh_a_u8(0x44),
+            cp_a_u8(144),
+            jr_nz(-6),
+            ldh_a_u8(0x44),
+            cp_a_u8(144),
+            jr_z(-6),
+            dec_r8(R8::B),
+            jr_nz(-15),
+            // Compares the header with the copy at 0x0004
+            ld_r16_u16(R16::HL, 0x0104),
+            ld_a_mhl(),
+            ld_r8_r8(R8::B, R8::A),
+            ld_r8_u8(R8::H, 0x00),
+            ld_a_mhl(),
+            sub_a_r8(R8::B),
+            // Locks up
+            jr_nz(-2),
+            ld_r8_u8(R8::H, 0x01),
+            inc_r8(R8::L),
+            ld_r8_r8(R8::A, R8::L),
+            cp_a_u8(0x34),
+            jr_nz(-16),
+        ]);
+        // NOPs until the boot ROM unmaps itself, from 0x00FC like the real one
+        boot_rom.resize(0xFC, 0x00);
+        boot_rom.extend(program![ld_a_u8(0x01), ldh_u8_a(0x50)]);
+        boot_rom
+    }
+
+    // Boots a cartridge with `header_logo` in its header, until it runs or 20 frames went by.
+    fn boot_with_logo(boot_logo: &[u8], header_logo: &[u8]) -> Machine {
+        let mut game_rom = vec![0; 0x8000];
+        game_rom[0x104..0x134].copy_from_slice(header_logo);
+        let mut machine = Machine::new(
+            logo_checking_boot_rom(boot_logo),
+            game_rom,
+            ROMInformation::new(),
+            false,
+            false,
+        );
+        let mut t_cycles = 0;
+        while machine.registers().pc.0 != 0x100 && t_cycles < 20 * T_CYCLES_PER_FRAME {
+            t_cycles += machine.execute_one_instruction().t_cycles;
+        }
+        machine
+    }
+
+    #[test]
+    fn booting_shows_the_logo_then_hands_over_to_the_cartridge() {
+        let logo: Vec<u8> = (0..48)
+            .map(|index: u8| index.wrapping_mul(37) ^ 0x5A)
+            .collect();
+        let machine = boot_with_logo(&logo, &logo);
+        assert_eq!(machine.registers().pc.0, 0x100);
+        assert!(!machine.is_dmg_boot_rom_on());
+        assert!(machine.ppu().is_lcd_ppu_on());
+        assert!(machine.ppu().completed_frame_count() >= 3);
+        assert_eq!(machine.ppu().vram[0x104..0x134], logo);
+        assert!(machine.ppu().vram[..0x104].iter().all(|byte| *byte == 0));
+        assert_eq!(machine.read_u8(Wrapping(0x0000)), Wrapping(0x00));
+
+        // Any difference in the logo keeps the cartridge from running
+        let mut bad_logo = logo.clone();
+        bad_logo[47] ^= 0x01;
+        let mut machine = boot_with_logo(&logo, &bad_logo);
+        let lockup = machine.registers().pc;
+        machine.execute_one_instruction();
+        assert_eq!(machine.registers().pc, lockup);
+        assert!(machine.is_dmg_boot_rom_on());
+    }
+
+    #[test]
+    fn every_address_can_be_read() {
+        let mut machine = machine_with_program(&[]);
+        for address in 0x0000..=0xFFFF {
+            machine.read_u8(Wrapping(address));
+        }
+        run_until(&mut machine, 0x100);
+        for address in 0x0000..=0xFFFF {
+            machine.read_u8(Wrapping(address));
+        }
+    }
+
+    #[test]
+    fn io_holes_read_as_0xff_and_ignore_writes() {
+        let mut machine = machine_with_program(&[]);
+        let holes = [
+            0xFF4C, 0xFF4E, 0xFF51, 0xFF67, 0xFF6C, 0xFF6F, 0xFF71, 0xFF76, 0xFF7E,
+        ];
+        for address in holes {
+            machine.write_u8(Wrapping(address), Wrapping(0x00));
+            assert_eq!(
+                machine.read_u8(Wrapping(address)),
+                Wrapping(0xFF),
+                "0x{:04X}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn memory_views_wrap_around_0xffff() {
+        let mut machine = machine_with_program(&[]);
+        run_until(&mut machine, 0x100);
+        for (address, value) in [
+            (0xFFFC, 0x01),
+            (0xFFFD, 0x02),
+            (0xFFFE, 0x12),
+            (0xFFFF, 0x1F),
+        ] {
+            machine.write_u8(Wrapping(address), Wrapping(value));
+        }
+        let range = machine.read_range(Wrapping(0xFFFE), 8);
+        assert_eq!(range.len(), 8);
+        assert_eq!(range[..2], [Wrapping(0x12), Wrapping(0x1F)]);
+        assert_eq!(range[2..], machine.read_range(Wrapping(0x0000), 6));
+        assert_eq!(
+            machine.show_memory_row(Wrapping(0xFFFC)),
+            "fffc: 01 02 12 1F  00 00 00 00"
+        );
+    }
+
+    #[test]
+    fn unused_if_bits_read_as_one() {
+        let mut machine = machine_with_program(&[]);
+        for (written, read) in [(0x00, 0xE0), (0x1F, 0xFF), (0xFF, 0xFF), (0x05, 0xE5)] {
+            machine.write_u8(Wrapping(0xFF0F), Wrapping(written));
+            assert_eq!(machine.read_u8(Wrapping(0xFF0F)), Wrapping(read));
+            assert_eq!(machine.interrupts.interrupt_flag, Wrapping(written & 0x1F));
+        }
+    }
+
+    #[test]
+    fn ie_keeps_all_its_bits() {
+        let mut machine = machine_with_program(&[]);
+        for value in [0x00, 0x1F, 0xE0, 0xA5, 0xFF] {
+            machine.write_u8(Wrapping(0xFFFF), Wrapping(value));
+            assert_eq!(machine.read_u8(Wrapping(0xFFFF)), Wrapping(value));
+        }
+    }
+
+    // A machine running NOPs from HRAM, the only memory the CPU can reach during OAM DMA, with
+    // a different pattern on each page of WRAM.
+    fn machine_ready_for_dma() -> Machine {
+        let mut machine = machine_with_program(&[]);
+        run_until(&mut machine, 0x100);
+        for offset in 0..0x2000 {
+            let byte = (offset % 251) as u8;
+            machine.write_u8(Wrapping(0xC000 + offset), Wrapping(byte));
+        }
+        for address in 0xFF80..0xFFFF {
+            machine.write_u8(Wrapping(address), Wrapping(0x00));
+        }
+        machine
+    }
+
+    // Runs the NOPs in HRAM for `dots`, there are enough of them for 508.
+    fn run_from_hram(machine: &mut Machine, dots: u128) {
+        machine.registers_mut().pc = Wrapping(0xFF80);
+        let mut t_cycles = 0;
+        while t_cycles < dots {
+            t_cycles += machine.step().t_cycles;
+        }
+        assert_eq!(t_cycles, dots);
+    }
+
+    fn oam(machine: &Machine) -> Vec<u8> {
+        machine.ppu.object_attribute_memory.to_vec()
+    }
+
+    #[test]
+    fn oam_dma_copies_a_byte_per_m_cycle() {
+        let mut machine = machine_ready_for_dma();
+        let source = (0xC100..0xC1A0)
+            .map(|address| machine.read_u8(Wrapping(address)).0)
+            .collect::<Vec<_>>();
+        machine.write_u8(Wrapping(0xFF46), Wrapping(0xC1));
+        run_from_hram(&mut machine, 320);
+        assert_eq!(oam(&machine)[..80], source[..80]);
+        assert!(oam(&machine)[80..].iter().all(|byte| *byte == 0));
+
+        // Only HRAM and the IO registers can be reached in the meantime
+        assert_eq!(machine.read_u8(Wrapping(0xC000)), Wrapping(0xFF));
+        machine.write_u8(Wrapping(0xC000), Wrapping(0x42));
+        machine.write_u8(Wrapping(0xFFF0), Wrapping(0x42));
+        assert_eq!(machine.read_u8(Wrapping(0xFFF0)), Wrapping(0x42));
+
+        run_from_hram(&mut machine, 320);
+        assert_eq!(oam(&machine), source);
+        assert_eq!(machine.read_u8(Wrapping(0xC000)), Wrapping(0x00));
+    }
+
+    #[test]
+    fn ff46_reads_back_the_last_source_page() {
+        let mut machine = machine_ready_for_dma();
+        machine.write_u8(Wrapping(0xFF46), Wrapping(0xC1));
+        assert_eq!(machine.read_u8(Wrapping(0xFF46)), Wrapping(0xC1));
+        // Including in the copies made to keep history, during and after the transfer
+        assert_eq!(machine.clone().read_u8(Wrapping(0xFF46)), Wrapping(0xC1));
+        run_from_hram(&mut machine, 320);
+        run_from_hram(&mut machine, 320);
+        assert!(!machine.dma.is_active());
+        assert_eq!(machine.clone().read_u8(Wrapping(0xFF46)), Wrapping(0xC1));
+    }
+
+    #[test]
+    fn oam_dma_past_wram_sees_echo_ram_then_0xff() {
+        let mut from_wram = machine_ready_for_dma();
+        from_wram.write_u8(Wrapping(0xFF46), Wrapping(0xC1));
+        let mut from_echo_ram = machine_ready_for_dma();
+        from_echo_ram.write_u8(Wrapping(0xFF46), Wrapping(0xE1));
+        let mut past_echo_ram = machine_ready_for_dma();
+        past_echo_ram.write_u8(Wrapping(0xFF46), Wrapping(0xFF));
+        for machine in [&mut from_wram, &mut from_echo_ram, &mut past_echo_ram] {
+            run_from_hram(machine, 320);
+            run_from_hram(machine, 320);
+        }
+        assert_eq!(oam(&from_echo_ram), oam(&from_wram));
+        assert!(oam(&past_echo_ram).iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn dmg_mode_hides_the_cgb_registers() {
+        let mut machine = machine_with_program(&[]);
+        let fresh = machine.clone();
+        for address in [0xFF4D, 0xFF4F, 0xFF68, 0xFF69, 0xFF6A, 0xFF6B, 0xFF70] {
+            machine.write_u8(Wrapping(address), Wrapping(0x01));
+            assert_eq!(
+                machine.read_u8(Wrapping(address)),
+                Wrapping(0xFF),
+                "0x{:04X}",
+                address
+            );
+        }
+        // None of the writes reached the CGB hardware
+        assert_eq!(machine.ppu.vram_bank, fresh.ppu.vram_bank);
+        assert_eq!(machine.mmu.wram_bank, fresh.mmu.wram_bank);
+        for (palettes, fresh_palettes) in [
+            (
+                &machine.ppu.cgb_background_palettes,
+                &fresh.ppu.cgb_background_palettes,
+            ),
+            (
+                &machine.ppu.cgb_object_palettes,
+                &fresh.ppu.cgb_object_palettes,
+            ),
+        ] {
+            assert_eq!(palettes.read_spec(), fresh_palettes.read_spec());
+            assert_eq!(palettes.read_data(), fresh_palettes.read_data());
+        }
+    }
+}
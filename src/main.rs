@@ -1,23 +1,43 @@
 pub mod application_state;
+pub mod apu;
+pub mod battery;
+pub mod bus;
+pub mod call_stack;
 pub mod command_line_arguments;
 pub mod conditions;
 pub mod cpu;
+pub mod dma;
+pub mod gamepad;
+#[cfg(test)]
+mod golden_frames;
 pub mod inputs;
 pub mod instructions;
+pub mod io_registers;
+pub mod keybindings;
 pub mod machine;
+pub mod mapper;
 pub mod memory;
+pub mod memory_trace;
 pub mod message;
+pub mod mmu;
+pub mod movie;
 pub mod pixel_fetcher;
 pub mod ppu;
+pub mod preferences;
 pub mod registers;
+pub mod rtc;
+pub mod serial;
+pub mod symbols;
 pub mod utils;
 pub mod view;
 
-use application_state::ApplicationState;
+use application_state::{load_machine, ApplicationState};
 use clap::Parser;
 use command_line_arguments::CommandLineArguments;
 use iced::{self, advanced::graphics::core::font, Settings, Size, Task};
 use message::Message;
+use movie::start_movie;
+use utils::fnv1a_hash;
 
 const BREAKPOINTS: &[u16] = &[
     // 0x00F1, // passed logo check
@@ -31,19 +51,49 @@ const BREAKPOINTS: &[u16] = &[
     // 0xDEF8,
 ];
 
+// Runs without a window, so that the frames a ROM produces can be checked against reference ones.
+// The hash to compare against is obtained by running the same command on a known good build.  With
+// --play-movie, the frames can depend on recorded inputs.
+fn run_headless(args: &CommandLineArguments, frames: u32) {
+    let mut machine = load_machine(args);
+    let mut movie = start_movie(args, &mut machine);
+    for _ in 0..frames {
+        machine.run_frame();
+        if let Some(m) = movie.as_mut() {
+            if !m.update(&mut machine) {
+                movie = None;
+            }
+        }
+    }
+    println!(
+        "Frame {} hash: {:016X}",
+        machine.ppu().completed_frame_count(),
+        fnv1a_hash(&machine.ppu().front_buffer)
+    );
+}
+
 fn main() -> Result<(), iced::Error> {
     let args = CommandLineArguments::parse();
 
+    if let Some(frames) = args.headless_frames {
+        run_headless(&args, frames);
+        return Ok(());
+    }
+
     let mut settings = Settings::default();
     settings.default_font = font::Font::MONOSPACE;
-    iced::application("YokoiBoy", ApplicationState::update, ApplicationState::view)
-        .subscription(ApplicationState::subscription)
-        .settings(settings)
-        .window_size(Size::new(1600.0, 1100.0))
-        .run_with(move || {
-            (
-                ApplicationState::new(&args, BREAKPOINTS),
-                Task::done(Message::BeginRunUntilBreakpoint),
-            )
-        })
+    iced::application(
+        ApplicationState::title,
+        ApplicationState::update,
+        ApplicationState::view,
+    )
+    .subscription(ApplicationState::subscription)
+    .settings(settings)
+    .window_size(Size::new(1600.0, 1100.0))
+    .run_with(move || {
+        (
+            ApplicationState::new(&args, BREAKPOINTS),
+            Task::done(Message::BeginRunUntilBreakpoint),
+        )
+    })
 }
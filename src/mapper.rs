@@ -0,0 +1,104 @@
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rom_only;
+
+use std::{fmt::Debug, hash::Hasher, num::Wrapping};
+
+use mbc1::MBC1;
+use mbc2::MBC2;
+use mbc3::MBC3;
+use mbc5::MBC5;
+use rom_only::ROMOnly;
+
+use crate::{application_state::MapperType, rtc::RTC};
+
+// The cartridge hardware deciding which ROM and RAM banks the CPU sees.  Games program it by
+// writing to the ROM area, 0x0000-0x7FFF.
+pub trait Mapper: Debug {
+    // The ROM bank mapped at 0x0000-0x3FFF
+    fn low_rom_bank(&self) -> u16 {
+        0
+    }
+
+    // The ROM bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> u16;
+
+    fn write_register(&mut self, address: Wrapping<u16>, value: Wrapping<u8>);
+
+    // The RAM bank mapped at 0xA000-0xBFFF, if any.  Mappers only expose their RAM after it has
+    // been enabled.
+    fn ram_bank(&self) -> Option<usize>;
+
+    // Where an access to 0xA000-0xBFFF lands in the cartridge RAM, if anywhere.
+    fn ram_index(&self, game_ram: &[u8], address: Wrapping<u16>) -> Option<usize> {
+        if game_ram.is_empty() {
+            return None;
+        }
+        let bank = self.ram_bank()?;
+        // Smaller RAMs are mirrored across the window, MBC2's 512 nibbles included
+        Some((bank * 0x2000 + (address.0 as usize - 0xA000)) % game_ram.len())
+    }
+
+    fn read_ram(&self, game_ram: &[u8], address: Wrapping<u16>) -> Wrapping<u8> {
+        match self.ram_index(game_ram, address) {
+            Some(index) => Wrapping(game_ram[index]),
+            // Open bus
+            None => Wrapping(0xFF),
+        }
+    }
+
+    // Returns whether anything was written, so that battery-backed RAM gets saved.
+    fn write_ram(
+        &mut self,
+        game_ram: &mut [u8],
+        address: Wrapping<u16>,
+        value: Wrapping<u8>,
+    ) -> bool {
+        match self.ram_index(game_ram, address) {
+            Some(index) => {
+                game_ram[index] = value.0;
+                true
+            }
+            // Non-existing or disabled RAM, ignored
+            None => false,
+        }
+    }
+
+    // Only MBC3 has a clock, which needs to know how much time passed
+    fn ticks(&mut self, _t_cycles: u8) {}
+
+    fn rtc(&self) -> Option<&RTC> {
+        None
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut RTC> {
+        None
+    }
+
+    // Lets `Machine` be cloned for the history, whatever mapper it has.
+    fn box_clone(&self) -> Box<dyn Mapper>;
+
+    // `Hash` cannot be used on trait objects, see `Machine::state_hash`.
+    fn hash_state(&self, state: &mut dyn Hasher);
+}
+
+impl Clone for Box<dyn Mapper> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+pub fn new_mapper(mapper_type: &MapperType) -> Box<dyn Mapper> {
+    match mapper_type {
+        MapperType::ROMOnly => Box::new(ROMOnly::new()),
+        MapperType::MBC1 => Box::new(MBC1::new()),
+        MapperType::MBC2 => Box::new(MBC2::new()),
+        MapperType::MBC3 => Box::new(MBC3::new()),
+        MapperType::MBC5 => Box::new(MBC5::new()),
+        // We already warned about it when loading the ROM, the game may still get somewhere
+        // without banking.
+        MapperType::Other => Box::new(ROMOnly::new()),
+    }
+}
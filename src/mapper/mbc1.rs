@@ -0,0 +1,90 @@
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
+
+use super::Mapper;
+
+#[derive(Clone, Debug, Hash, PartialEq)]
+enum BankingMode {
+    Ram,
+    Rom,
+}
+
+#[derive(Clone, Debug, Hash)]
+pub struct MBC1 {
+    banking_mode: BankingMode,
+    is_ram_enabled: bool,
+    loram_bank: u8,
+    /// The RAM bank in RAM banking mode, and always the upper 2 bits of the ROM bank
+    ram_or_hiram_bank: u8,
+}
+
+impl MBC1 {
+    pub fn new() -> Self {
+        MBC1 {
+            banking_mode: BankingMode::Rom,
+            is_ram_enabled: false,
+            loram_bank: 1,
+            ram_or_hiram_bank: 0,
+        }
+    }
+}
+
+impl Mapper for MBC1 {
+    // MBC1 maps another bank there from its upper bank bits, in RAM banking mode.  Those only
+    // matter to 1MB ROMs and up, smaller ones wrap back to bank 0.
+    fn low_rom_bank(&self) -> u16 {
+        match self.banking_mode {
+            BankingMode::Ram => (self.ram_or_hiram_bank as u16) << 5,
+            BankingMode::Rom => 0,
+        }
+    }
+
+    // The upper bank bits apply here in both banking modes
+    fn rom_bank(&self) -> u16 {
+        (self.ram_or_hiram_bank << 5 | self.loram_bank) as u16
+    }
+
+    fn write_register(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        match address.0 {
+            0x0000..=0x1FFF => self.is_ram_enabled = value.0 & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Bank 0 is already at 0x0000-0x3FFF, selecting it maps bank 1 instead.  Only the
+                // lower 5 bits are checked, so banks 0x20, 0x40 and 0x60 map 0x21, 0x41 and 0x61
+                // instead.
+                self.loram_bank = match value.0 & 0x1F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_or_hiram_bank = value.0 & 0b11,
+            _ => {
+                self.banking_mode = if value.0 & 1 == 0 {
+                    BankingMode::Rom
+                } else {
+                    BankingMode::Ram
+                }
+            }
+        }
+    }
+
+    // In ROM banking mode, the upper bank bits go to the ROM instead
+    fn ram_bank(&self) -> Option<usize> {
+        if !self.is_ram_enabled {
+            return None;
+        }
+        match self.banking_mode {
+            BankingMode::Ram => Some(self.ram_or_hiram_bank as usize),
+            BankingMode::Rom => Some(0),
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
+
+use super::Mapper;
+
+// MBC2 has 512 nibbles of RAM built in.  We keep one nibble per byte.
+#[derive(Clone, Debug, Hash)]
+pub struct MBC2 {
+    is_ram_enabled: bool,
+    loram_bank: u8,
+}
+
+impl MBC2 {
+    pub fn new() -> Self {
+        MBC2 {
+            is_ram_enabled: false,
+            loram_bank: 1,
+        }
+    }
+}
+
+impl Mapper for MBC2 {
+    fn rom_bank(&self) -> u16 {
+        self.loram_bank as u16
+    }
+
+    // MBC2 has both of its registers in 0x0000-0x3FFF, bit 8 of the address picks which one.
+    fn write_register(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        if address.0 >= 0x4000 {
+            return;
+        }
+        if address.0 & 0x0100 == 0 {
+            self.is_ram_enabled = value.0 & 0x0F == 0x0A;
+        } else {
+            // Bank 0 is already at 0x0000-0x3FFF, selecting it maps bank 1 instead
+            self.loram_bank = match value.0 & 0x0F {
+                0 => 1,
+                bank => bank,
+            };
+        }
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        self.is_ram_enabled.then_some(0)
+    }
+
+    // The upper nibble reads as open bus
+    fn read_ram(&self, game_ram: &[u8], address: Wrapping<u16>) -> Wrapping<u8> {
+        match self.ram_index(game_ram, address) {
+            Some(index) => Wrapping(0xF0 | game_ram[index]),
+            None => Wrapping(0xFF),
+        }
+    }
+
+    // Only the lower nibble is stored
+    fn write_ram(
+        &mut self,
+        game_ram: &mut [u8],
+        address: Wrapping<u16>,
+        value: Wrapping<u8>,
+    ) -> bool {
+        match self.ram_index(game_ram, address) {
+            Some(index) => {
+                game_ram[index] = value.0 & 0x0F;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
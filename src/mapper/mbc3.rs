@@ -0,0 +1,195 @@
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
+
+use super::Mapper;
+use crate::rtc::RTC;
+
+#[derive(Clone, Debug, Hash)]
+pub struct MBC3 {
+    /// Also enables the RTC registers
+    is_ram_enabled: bool,
+    loram_bank: u8,
+    /// 0x00-0x03 select a RAM bank, 0x08-0x0C an RTC register
+    ram_bank_or_rtc_register: u8,
+    rtc: RTC,
+}
+
+impl MBC3 {
+    pub fn new() -> Self {
+        MBC3 {
+            is_ram_enabled: false,
+            loram_bank: 1,
+            ram_bank_or_rtc_register: 0,
+            rtc: RTC::new(),
+        }
+    }
+
+    // The RTC register mapped at 0xA000-0xBFFF, if one is selected.
+    fn mapped_rtc_register(&self) -> Option<u8> {
+        (self.is_ram_enabled && RTC::is_register(self.ram_bank_or_rtc_register))
+            .then_some(self.ram_bank_or_rtc_register)
+    }
+}
+
+impl Mapper for MBC3 {
+    fn rom_bank(&self) -> u16 {
+        self.loram_bank as u16
+    }
+
+    fn write_register(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        match address.0 {
+            0x0000..=0x1FFF => self.is_ram_enabled = value.0 & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Bank 0 is already at 0x0000-0x3FFF, selecting it maps bank 1 instead
+                self.loram_bank = match value.0 & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank_or_rtc_register = value.0,
+            _ => self.rtc.write_latch(value.0),
+        }
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        if !self.is_ram_enabled {
+            return None;
+        }
+        match self.ram_bank_or_rtc_register {
+            bank @ 0..=3 => Some(bank as usize),
+            // Either an RTC register or nothing at all
+            _ => None,
+        }
+    }
+
+    fn read_ram(&self, game_ram: &[u8], address: Wrapping<u16>) -> Wrapping<u8> {
+        if let Some(register) = self.mapped_rtc_register() {
+            return Wrapping(self.rtc.read(register));
+        }
+        match self.ram_index(game_ram, address) {
+            Some(index) => Wrapping(game_ram[index]),
+            None => Wrapping(0xFF),
+        }
+    }
+
+    fn write_ram(
+        &mut self,
+        game_ram: &mut [u8],
+        address: Wrapping<u16>,
+        value: Wrapping<u8>,
+    ) -> bool {
+        if let Some(register) = self.mapped_rtc_register() {
+            self.rtc.write(register, value.0);
+            return true;
+        }
+        match self.ram_index(game_ram, address) {
+            Some(index) => {
+                game_ram[index] = value.0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn ticks(&mut self, t_cycles: u8) {
+        self.rtc.ticks(t_cycles)
+    }
+
+    fn rtc(&self) -> Option<&RTC> {
+        Some(&self.rtc)
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut RTC> {
+        Some(&mut self.rtc)
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(mbc3: &mut MBC3, address: u16, value: u8) {
+        mbc3.write_register(Wrapping(address), Wrapping(value));
+    }
+
+    // The clock runs at 4194304 t-cycles per second.
+    fn run_seconds(mbc3: &mut MBC3, seconds: u32) {
+        for _ in 0..seconds * 4_194_304 / 128 {
+            mbc3.ticks(128);
+        }
+    }
+
+    #[test]
+    fn rom_bank_uses_7_bits_and_never_maps_bank_0() {
+        let mut mbc3 = MBC3::new();
+        assert_eq!(mbc3.rom_bank(), 1);
+        for (written, bank) in [
+            (0x00, 1),
+            (0x01, 1),
+            (0x05, 5),
+            (0x7F, 0x7F),
+            (0x80, 1),
+            (0x85, 5),
+        ] {
+            write(&mut mbc3, 0x2000, written);
+            assert_eq!(mbc3.rom_bank(), bank, "0x{:02X}", written);
+        }
+        // The whole 0x2000-0x3FFF range selects the bank
+        write(&mut mbc3, 0x3FFF, 0x42);
+        assert_eq!(mbc3.rom_bank(), 0x42);
+    }
+
+    #[test]
+    fn ram_banks_are_only_mapped_once_enabled() {
+        let mut mbc3 = MBC3::new();
+        let mut game_ram = vec![0; 0x8000];
+        write(&mut mbc3, 0x4000, 0x02);
+        assert_eq!(mbc3.ram_bank(), None);
+        assert!(!mbc3.write_ram(&mut game_ram, Wrapping(0xA000), Wrapping(0x42)));
+        write(&mut mbc3, 0x0000, 0x0A);
+        for bank in 0..4 {
+            write(&mut mbc3, 0x4000, bank);
+            assert_eq!(mbc3.ram_bank(), Some(bank as usize));
+            mbc3.write_ram(&mut game_ram, Wrapping(0xA123), Wrapping(0x10 + bank));
+        }
+        for bank in 0..4 {
+            write(&mut mbc3, 0x4000, bank);
+            let value = mbc3.read_ram(&game_ram, Wrapping(0xA123));
+            assert_eq!(value, Wrapping(0x10 + bank));
+            assert_eq!(game_ram[bank as usize * 0x2000 + 0x123], 0x10 + bank);
+        }
+        write(&mut mbc3, 0x0000, 0x00);
+        assert_eq!(mbc3.read_ram(&game_ram, Wrapping(0xA123)), Wrapping(0xFF));
+    }
+
+    #[test]
+    fn rtc_registers_show_the_latched_clock() {
+        let mut mbc3 = MBC3::new();
+        let mut game_ram = vec![0; 0x2000];
+        write(&mut mbc3, 0x0000, 0x0A);
+        // Seconds
+        write(&mut mbc3, 0x4000, 0x08);
+        mbc3.write_ram(&mut game_ram, Wrapping(0xA000), Wrapping(30));
+        // Only 0x00 then 0x01 latches, so the second counts go unseen at first
+        run_seconds(&mut mbc3, 2);
+        write(&mut mbc3, 0x6000, 0x01);
+        assert_eq!(mbc3.read_ram(&game_ram, Wrapping(0xA000)), Wrapping(30));
+        write(&mut mbc3, 0x6000, 0x00);
+        assert_eq!(mbc3.read_ram(&game_ram, Wrapping(0xA000)), Wrapping(30));
+        write(&mut mbc3, 0x6000, 0x01);
+        assert_eq!(mbc3.read_ram(&game_ram, Wrapping(0xA000)), Wrapping(32));
+        // The latched value stays put while the clock runs
+        run_seconds(&mut mbc3, 1);
+        assert_eq!(mbc3.read_ram(&game_ram, Wrapping(0xA000)), Wrapping(32));
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
+
+use super::Mapper;
+
+#[derive(Clone, Debug, Hash)]
+pub struct MBC5 {
+    is_ram_enabled: bool,
+    loram_bank: u8,
+    /// The 9th bit of the ROM bank
+    hirom_bank: u8,
+    selected_ram_bank: u8,
+}
+
+impl MBC5 {
+    pub fn new() -> Self {
+        MBC5 {
+            is_ram_enabled: false,
+            loram_bank: 1,
+            hirom_bank: 0,
+            selected_ram_bank: 0,
+        }
+    }
+}
+
+impl Mapper for MBC5 {
+    // Unlike the other mappers, bank 0 can be mapped here
+    fn rom_bank(&self) -> u16 {
+        (self.hirom_bank as u16) << 8 | self.loram_bank as u16
+    }
+
+    fn write_register(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        match address.0 {
+            0x0000..=0x1FFF => self.is_ram_enabled = value.0 & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.loram_bank = value.0,
+            0x3000..=0x3FFF => self.hirom_bank = value.0 & 1,
+            0x4000..=0x5FFF => self.selected_ram_bank = value.0 & 0x0F,
+            // No banking mode register
+            _ => {}
+        }
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        self.is_ram_enabled
+            .then_some(self.selected_ram_bank as usize)
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
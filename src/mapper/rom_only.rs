@@ -0,0 +1,69 @@
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
+
+use super::Mapper;
+
+// 32KB of ROM, and at most 8KB of RAM, always mapped.
+#[derive(Clone, Debug, Hash)]
+pub struct ROMOnly {
+    /// Games write to ROM by mistake over and over, only the first write gets warned about.
+    has_warned_about_writes: bool,
+}
+
+impl ROMOnly {
+    pub fn new() -> Self {
+        ROMOnly {
+            has_warned_about_writes: false,
+        }
+    }
+
+    fn write_warning(&mut self, address: Wrapping<u16>) -> Option<String> {
+        if self.has_warned_about_writes {
+            return None;
+        }
+        self.has_warned_about_writes = true;
+        Some(format!(
+            "WARNING: Ignoring write at 0x{:04X}, and any further ROM writes",
+            address.0
+        ))
+    }
+}
+
+impl Mapper for ROMOnly {
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    fn write_register(&mut self, address: Wrapping<u16>, _value: Wrapping<u8>) {
+        if let Some(warning) = self.write_warning(address) {
+            println!("{}", warning);
+        }
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_writes_are_only_warned_about_once() {
+        let mut rom_only = ROMOnly::new();
+        assert!(rom_only.write_warning(Wrapping(0x2000)).is_some());
+        assert!(rom_only.write_warning(Wrapping(0x2000)).is_none());
+        assert!(rom_only.write_warning(Wrapping(0x7FFF)).is_none());
+    }
+}
@@ -1,15 +1,25 @@
 use std::{
+    collections::BTreeMap,
     io::{self, Error},
     num::Wrapping,
 };
 
 use crate::{
-    application_state::{MapperType, RAMSize, ROMInformation},
-    instructions::decode::{decode_instruction_at_address, DecodedInstruction},
+    application_state::{ConsoleMode, MapperType, RAMSize, ROMInformation},
+    bus::FlatBus,
+    instructions::{
+        decode::{decode_instruction_at_address, DecodedInstruction},
+        type_def::Instruction,
+    },
     machine::Machine,
 };
 
 const HRAM_SIZE: usize = 0x7F;
+const WRAM_BANK_SIZE: usize = 0x1000;
+// DMG only uses the first 2 banks, CGB can switch the second half of WRAM between banks 1 to 7.
+const WRAM_BANK_COUNT: usize = 8;
+const MBC2_RAM_SIZE: usize = 0x200;
+const HEX_ROW_SIZE: usize = 16;
 
 #[derive(Clone, Debug, Hash)]
 pub struct Memory {
@@ -17,6 +27,7 @@ pub struct Memory {
     pub game_rom: Vec<u8>,
     pub game_ram: Vec<u8>,
     pub hram: [u8; HRAM_SIZE],
+    wram: Box<[[u8; WRAM_BANK_SIZE]; WRAM_BANK_COUNT]>,
 }
 
 impl Memory {
@@ -24,41 +35,67 @@ impl Memory {
         decode_instruction_at_address(machine, address)
     }
 
+    // Decodes up to `how_many` instructions.  Stops early after an illegal opcode (we are most
+    // likely looking at data), or when running past the end of the address space.
     pub fn decode_instructions_at(
         machine: &Machine,
         address: Wrapping<u16>,
         how_many: u8,
+    ) -> Vec<DecodedInstruction> {
+        Memory::decode_instructions_with(address, how_many, |pc| {
+            decode_instruction_at_address(machine, pc)
+        })
+    }
+
+    pub fn decode_instructions_with(
+        address: Wrapping<u16>,
+        how_many: u8,
+        decode: impl Fn(Wrapping<u16>) -> DecodedInstruction,
     ) -> Vec<DecodedInstruction> {
         let mut res = Vec::new();
-        let mut pc = address;
+        let mut pc = address.0;
         for _ in 0..how_many {
-            let instr = decode_instruction_at_address(machine, pc);
-            pc = pc + Wrapping(instr.instruction_size as u16);
+            let instr = decode(Wrapping(pc));
+            let is_illegal = matches!(instr.instruction, Instruction::Illegal(_));
+            let next_pc = pc.checked_add(instr.instruction_size as u16);
             res.push(instr);
+            match next_pc {
+                Some(next_pc) if !is_illegal => pc = next_pc,
+                _ => break,
+            }
         }
         res
     }
 
     pub fn new(boot_rom: Vec<u8>, game_rom: Vec<u8>, rom_information: &ROMInformation) -> Self {
-        let game_ram = match rom_information.ram_size {
-            RAMSize::NoRAM => Vec::new(),
-            RAMSize::Ram2kb => Vec::from([0; 0x800]),
-            RAMSize::Ram8kb => Vec::from([0; 0x2000]),
-            RAMSize::Ram4banks8kb => todo!(),
-            RAMSize::Ram16banks8kb => todo!(),
-            RAMSize::Ram8banks8kb => todo!(),
+        let game_ram_size = match rom_information.mapper_type {
+            // MBC2 has RAM built in, and the header reports none.  We keep one nibble per byte.
+            MapperType::MBC2 => MBC2_RAM_SIZE,
+            _ => rom_information.ram_size.byte_count(),
         };
+        // Allocated on the heap directly, the larger RAMs do not belong on the stack
+        let game_ram = vec![0; game_ram_size];
         Memory {
             boot_rom,
             game_rom,
             game_ram,
             hram: [0; HRAM_SIZE],
+            wram: Box::new([[0; WRAM_BANK_SIZE]; WRAM_BANK_COUNT]),
         }
     }
 
     pub fn read_boot_rom(&self, address: Wrapping<u16>) -> Wrapping<u8> {
         Wrapping(self.boot_rom[address.0 as usize])
     }
+
+    // `offset` is relative to the start of the bank
+    pub fn read_wram(&self, bank: usize, offset: Wrapping<u16>) -> Wrapping<u8> {
+        Wrapping(self.wram[bank][offset.0 as usize])
+    }
+
+    pub fn write_wram(&mut self, bank: usize, offset: Wrapping<u16>, value: Wrapping<u8>) {
+        self.wram[bank][offset.0 as usize] = value.0;
+    }
 }
 
 // TODO: move somewhere
@@ -75,29 +112,45 @@ pub fn load_boot_rom(path: &String) -> Result<Vec<u8>, io::Error> {
 
 pub fn load_game_rom(path: &String) -> Result<(Vec<u8>, ROMInformation), io::Error> {
     let bytes = std::fs::read(path)?;
-    let byte_length = bytes.len();
-    if byte_length > 0x8000 {
-        println!("[WARNING] ROM larger than 0x8000 bytes, errors may occur.");
+    if bytes.len() < 0x150 {
+        return Err(Error::other("ROM too small to hold a cartridge header."));
     }
 
     println!("MBC: 0x{:02X}", bytes[0x147]);
     // Now compute ROM information
+    let console_mode = match bytes[0x143] {
+        // 0x80 also runs on a DMG, but a CGB still switches to CGB mode for it
+        0x80 | 0xC0 => ConsoleMode::Cgb,
+        _ => ConsoleMode::Dmg,
+    };
     let mapper_type = match bytes[0x147] {
         0x00 => MapperType::ROMOnly,
         0x01..=0x03 => MapperType::MBC1,
+        0x05 | 0x06 => MapperType::MBC2,
+        // 0x0F and 0x10 come with the real-time clock
+        0x0F..=0x13 => MapperType::MBC3,
+        // 0x1C-0x1E also drive a rumble motor, which we do not emulate
+        0x19..=0x1E => MapperType::MBC5,
         byte => {
             println!("Unhandled mapper type: 0x{:02X}", byte);
             MapperType::Other
         }
     };
-    let rom_banks = match bytes[0x148] {
-        0x00 => 0,
-        0x01 => 4,
-        0x02 => 8,
-        0x03 => 16,
-        0x04 => 32,
+    // From 32KB (2 banks) to 8MB (512 banks)
+    let rom_banks: u16 = match bytes[0x148] {
+        byte @ 0x00..=0x08 => 2 << byte,
         byte => panic!("Unhandled ROM bank size: 0x{:02X}", byte),
     };
+    // Bank numbers wrap around the actual ROM, so we can keep going with a bad dump
+    let declared_length = rom_banks as usize * 0x4000;
+    if bytes.len() != declared_length {
+        println!(
+            "[WARNING] ROM has 0x{:X} bytes, but its header declares 0x{:X}",
+            bytes.len(),
+            declared_length
+        );
+    }
+    // Per Pan Docs, 0x04 is 16 banks and 0x05 only 8.  0x01 is unused by licensed cartridges.
     let ram_size = match bytes[0x149] {
         0x00 => RAMSize::NoRAM,
         0x01 => RAMSize::Ram2kb,
@@ -107,13 +160,394 @@ pub fn load_game_rom(path: &String) -> Result<(Vec<u8>, ROMInformation), io::Err
         0x05 => RAMSize::Ram8banks8kb,
         byte => panic!("Unhandled RAM size: 0x{:02X}", byte),
     };
+    let has_battery = matches!(
+        bytes[0x147],
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E
+    );
+    let has_rtc = matches!(bytes[0x147], 0x0F | 0x10);
+    // CGB cartridges use the last byte of the title as their CGB flag
+    let title_end = match console_mode {
+        ConsoleMode::Cgb => 0x143,
+        ConsoleMode::Dmg => 0x144,
+    };
+    let title_bytes = &bytes[0x134..title_end];
+    let title_length = title_bytes
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(title_bytes.len());
+    let title = String::from_utf8_lossy(&title_bytes[..title_length])
+        .trim()
+        .to_string();
+    // 0x33 means the licensee is given by the two ASCII characters of the newer code instead
+    let licensee = match bytes[0x14B] {
+        0x33 => String::from_utf8_lossy(&bytes[0x144..0x146]).to_string(),
+        code => format!("{:02X}", code),
+    };
+    let header_checksum_ok = header_checksum(&bytes) == bytes[0x14D];
+    if !header_checksum_ok {
+        println!(
+            "[WARNING] Header checksum mismatch (0x{:02X} computed, 0x{:02X} in header), the \
+             boot ROM would refuse to boot this cartridge.",
+            header_checksum(&bytes),
+            bytes[0x14D]
+        );
+    }
+    // Big-endian, unlike everything else.  Nothing checks it, not even the boot ROM.
+    let global_checksum = u16::from_be_bytes([bytes[0x14E], bytes[0x14F]]);
 
     Ok((
         bytes,
         ROMInformation {
+            console_mode,
+            global_checksum,
+            has_battery,
+            has_rtc,
+            header_checksum_ok,
+            licensee,
             mapper_type,
             ram_size,
             rom_banks,
+            title,
         },
     ))
 }
+
+// The boot ROM computes this over 0x134-0x14C, and locks up if it does not match 0x14D.
+pub fn header_checksum(bytes: &[u8]) -> u8 {
+    bytes[0x134..=0x14C].iter().fold(0u8, |checksum, byte| {
+        checksum.wrapping_sub(*byte).wrapping_sub(1)
+    })
+}
+
+// Decodes at every offset of the ROM, the same way the CPU would, and returns the illegal opcodes
+// we ran into, along with how many times and where we first found them.  Each bank is decoded as if
+// it was the one mapped, so that operands read across 0x3FFF/0x7FFF see what the CPU would see.
+pub fn scan_opcodes(game_rom: &[u8]) -> BTreeMap<u8, (usize, usize)> {
+    let mut illegal_opcodes: BTreeMap<u8, (usize, usize)> = BTreeMap::new();
+    let mut bus = FlatBus::new();
+    for (bank, bank_bytes) in game_rom.chunks(0x4000).enumerate() {
+        let bank_start = if bank == 0 { 0x0000 } else { 0x4000 };
+        bus.memory[bank_start..bank_start + bank_bytes.len()].copy_from_slice(bank_bytes);
+        for offset in 0..bank_bytes.len() {
+            let address = Wrapping((bank_start + offset) as u16);
+            let decoded = decode_instruction_at_address(&bus, address);
+            if let Instruction::Illegal(opcode) = decoded.instruction {
+                let rom_offset = bank * 0x4000 + offset;
+                illegal_opcodes
+                    .entry(opcode)
+                    .and_modify(|(count, _)| *count += 1)
+                    .or_insert((1, rom_offset));
+            }
+        }
+    }
+    illegal_opcodes
+}
+
+pub fn print_opcode_scan(game_rom: &[u8]) {
+    let illegal_opcodes = scan_opcodes(game_rom);
+    if illegal_opcodes.is_empty() {
+        println!("Opcode scan: no illegal opcodes found");
+    }
+    for (opcode, (count, first_offset)) in illegal_opcodes {
+        println!(
+            "Opcode scan: 0x{:02X} found {} times, first at ROM offset 0x{:06X}",
+            opcode, count, first_offset
+        );
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HexRow {
+    pub address: Wrapping<u16>,
+    pub bytes: [u8; HEX_ROW_SIZE],
+    /// Printable characters as themselves, anything else as '.'
+    pub ascii: String,
+}
+
+impl Machine {
+    // Rows of 16 bytes starting at `from`, wrapping around 0xFFFF.  Reads ignore OAM DMA and the
+    // PPU, so that the dump shows what memory actually holds.
+    pub fn hex_dump(&self, from: Wrapping<u16>, rows: usize) -> Vec<HexRow> {
+        (0..rows)
+            .map(|row| {
+                let address = from + Wrapping((row * HEX_ROW_SIZE) as u16);
+                let mut bytes = [0; HEX_ROW_SIZE];
+                for (offset, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.read_u8_raw(address + Wrapping(offset as u16)).0;
+                }
+                let ascii = bytes
+                    .iter()
+                    .map(|&byte| {
+                        if byte.is_ascii_graphic() || byte == b' ' {
+                            byte as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                HexRow {
+                    address,
+                    bytes,
+                    ascii,
+                }
+            })
+            .collect()
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.mmu.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.mmu.memory
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::{
+        instructions::asm::*,
+        machine::tests::{machine_with_program, run_until},
+        registers::R8,
+    };
+
+    // Past the boot ROM, so that the cartridge shows at 0x0000.
+    fn booted_machine() -> Machine {
+        let mut machine = machine_with_program(&[]);
+        run_until(&mut machine, 0x100);
+        machine
+    }
+
+    // A 32KB ROM-only cartridge with these header bytes, and the header checksum to match.
+    pub fn cartridge(header: &[(usize, u8)]) -> Vec<u8> {
+        let mut bytes = vec![0; 0x8000];
+        for &(offset, byte) in header {
+            bytes[offset] = byte;
+        }
+        bytes[0x14D] = header_checksum(&bytes);
+        bytes
+    }
+
+    // Writes `bytes` to a file of its own, for the loaders to read.
+    pub fn rom_file(name: &str, bytes: &[u8]) -> String {
+        let file_name = format!("yokoyboi-test-{}-{}.gb", process::id(), name);
+        let path = env::temp_dir().join(file_name);
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn load_cartridge(name: &str, bytes: &[u8]) -> (Vec<u8>, ROMInformation) {
+        let path = rom_file(name, bytes);
+        let loaded = load_game_rom(&path);
+        fs::remove_file(&path).unwrap();
+        loaded.unwrap()
+    }
+
+    #[test]
+    fn cgb_flags_select_the_console_mode() {
+        for (flag, console_mode) in [
+            (0x00, ConsoleMode::Dmg),
+            (0x80, ConsoleMode::Cgb),
+            (0xC0, ConsoleMode::Cgb),
+        ] {
+            let bytes = cartridge(&[(0x143, flag)]);
+            let (_, rom_information) = load_cartridge(&format!("cgb-flag-{:02X}", flag), &bytes);
+            assert_eq!(rom_information.console_mode, console_mode, "0x{:02X}", flag);
+        }
+    }
+
+    #[test]
+    fn rom_size_codes_give_the_bank_count() {
+        for code in 0x00..=0x08 {
+            let rom_banks = 2 << code;
+            let mut bytes = cartridge(&[(0x148, code)]);
+            bytes.resize(rom_banks * 0x4000, 0);
+            let (game_rom, rom_information) =
+                load_cartridge(&format!("rom-size-{:02X}", code), &bytes);
+            assert_eq!(
+                rom_information.rom_banks, rom_banks as u16,
+                "0x{:02X}",
+                code
+            );
+            assert_eq!(game_rom.len(), bytes.len());
+        }
+    }
+
+    #[test]
+    fn roms_of_the_wrong_size_are_loaded_whole() {
+        // Declares 128KB
+        let mut bytes = cartridge(&[(0x148, 0x02)]);
+        for length in [0x8000, 0x28000] {
+            bytes.resize(length, 0);
+            let (game_rom, rom_information) = load_cartridge("rom-size-mismatch", &bytes);
+            assert_eq!(rom_information.rom_banks, 8);
+            assert_eq!(game_rom.len(), length);
+        }
+    }
+
+    #[test]
+    fn ram_size_codes_allocate_the_whole_ram() {
+        for (code, length) in [
+            (0x00, 0),
+            (0x01, 0x800),
+            (0x02, 0x2000),
+            (0x03, 0x8000),
+            (0x04, 0x20000),
+            (0x05, 0x10000),
+        ] {
+            // MBC5+RAM+BATTERY
+            let bytes = cartridge(&[(0x147, 0x1B), (0x149, code)]);
+            let (game_rom, rom_information) =
+                load_cartridge(&format!("ram-size-{:02X}", code), &bytes);
+            assert_eq!(rom_information.ram_size.byte_count(), length);
+            let memory = Memory::new(vec![0; 0x100], game_rom, &rom_information);
+            assert_eq!(memory.game_ram.len(), length, "0x{:02X}", code);
+        }
+    }
+
+    // The title, then whatever else the test needs
+    fn header_with_title(title: &[u8], fields: &[(usize, u8)]) -> Vec<(usize, u8)> {
+        let title_bytes = title
+            .iter()
+            .enumerate()
+            .map(|(offset, byte)| (0x134 + offset, *byte));
+        title_bytes.chain(fields.iter().copied()).collect()
+    }
+
+    #[test]
+    fn header_fields_are_parsed() {
+        let header = header_with_title(b"TETRIS", &[(0x14B, 0x01), (0x14E, 0xBE), (0x14F, 0xEF)]);
+        let (_, rom_information) = load_cartridge("header-fields", &cartridge(&header));
+        assert_eq!(rom_information.title, "TETRIS");
+        assert_eq!(rom_information.licensee, "01");
+        assert!(rom_information.header_checksum_ok);
+        assert_eq!(rom_information.global_checksum, 0xBEEF);
+        assert_eq!(rom_information.console_mode, ConsoleMode::Dmg);
+    }
+
+    #[test]
+    fn cgb_titles_leave_out_the_cgb_flag() {
+        let header = header_with_title(
+            b"ABCDEFGHIJKLMNO\x80",
+            &[(0x144, b'A'), (0x145, b'4'), (0x14B, 0x33)],
+        );
+        let (_, rom_information) = load_cartridge("header-cgb-title", &cartridge(&header));
+        assert_eq!(rom_information.title, "ABCDEFGHIJKLMNO");
+        assert_eq!(rom_information.console_mode, ConsoleMode::Cgb);
+        // 0x33 points at the new licensee code
+        assert_eq!(rom_information.licensee, "A4");
+    }
+
+    #[test]
+    fn header_checksum_mismatches_are_reported() {
+        // Each of the 25 bytes counts for one more than its value
+        assert_eq!(header_checksum(&[0; 0x150]), 0u8.wrapping_sub(25));
+        let mut bytes = cartridge(&header_with_title(b"TETRIS", &[]));
+        assert_eq!(bytes[0x14D], header_checksum(&bytes));
+        bytes[0x14D] ^= 0x01;
+        // The cartridge still loads
+        let (_, rom_information) = load_cartridge("header-checksum", &bytes);
+        assert!(!rom_information.header_checksum_ok);
+        assert_eq!(rom_information.title, "TETRIS");
+    }
+
+    fn write_bytes(machine: &mut Machine, from: u16, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            machine.write_u8(Wrapping(from) + Wrapping(offset as u16), Wrapping(*byte));
+        }
+    }
+
+    #[test]
+    fn hex_dump_shows_printable_characters() {
+        let mut machine = booted_machine();
+        write_bytes(&mut machine, 0xC000, b"Hi there~\x7F\x00\x80\xFF\t\n!");
+        let rows = machine.hex_dump(Wrapping(0xC000), 1);
+        assert_eq!(rows[0].address, Wrapping(0xC000));
+        assert_eq!(&rows[0].bytes[..9], b"Hi there~");
+        assert_eq!(rows[0].ascii, "Hi there~......!");
+    }
+
+    #[test]
+    fn hex_dump_wraps_around_0xffff() {
+        let mut machine = booted_machine();
+        write_bytes(&mut machine, 0xFFF8, &[1, 2, 3, 4, 5, 6, 7, 0x1F]);
+        let rows = machine.hex_dump(Wrapping(0xFFF8), 2);
+        assert_eq!(rows[0].bytes[..8], [1, 2, 3, 4, 5, 6, 7, 0x1F]);
+        // Then the start of the cartridge
+        assert_eq!(rows[0].bytes[8..], [0; 8]);
+        assert_eq!(rows[1].address, Wrapping(0x0008));
+    }
+
+    #[test]
+    fn hex_dump_of_echo_ram_shows_wram() {
+        let mut machine = booted_machine();
+        write_bytes(&mut machine, 0xC120, b"echo");
+        let rows = machine.hex_dump(Wrapping(0xE120), 1);
+        assert_eq!(rows[0].address, Wrapping(0xE120));
+        assert_eq!(&rows[0].bytes[..4], b"echo");
+        assert_eq!(rows[0].ascii, "echo............");
+        assert_eq!(
+            rows[0].bytes,
+            machine.hex_dump(Wrapping(0xC120), 1)[0].bytes
+        );
+    }
+
+    #[test]
+    fn data_disassembles_as_db() {
+        let mut machine = booted_machine();
+        write_bytes(&mut machine, 0xC000, &[0xFD; 16]);
+        for address in 0xC000..0xC010 {
+            let instructions = Memory::decode_instructions_at(&machine, Wrapping(address), 10);
+            assert_eq!(instructions.len(), 1);
+            assert_eq!(instructions[0].as_string(), "DB 0xFD");
+        }
+    }
+
+    #[test]
+    fn lookahead_stops_at_the_first_illegal_opcode() {
+        let mut machine = booted_machine();
+        write_bytes(
+            &mut machine,
+            0xC000,
+            &program![ld_a_u8(0x01), inc_r8(R8::A)],
+        );
+        write_bytes(&mut machine, 0xC003, &[0xFD; 8]);
+        let instructions = Memory::decode_instructions_at(&machine, Wrapping(0xC000), 10);
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(instructions[2].address, Wrapping(0xC003));
+        assert_eq!(instructions[2].instruction, Instruction::Illegal(0xFD));
+    }
+
+    #[test]
+    fn lookahead_stops_at_the_end_of_memory() {
+        let mut machine = booted_machine();
+        // NOPs, IE included
+        write_bytes(&mut machine, 0xFFFC, &[0x00; 4]);
+        let instructions = Memory::decode_instructions_at(&machine, Wrapping(0xFFFC), 10);
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(instructions[3].address, Wrapping(0xFFFF));
+    }
+
+    #[test]
+    fn opcode_scan_reports_illegal_opcodes_in_every_bank() {
+        let mut game_rom = vec![0; 0x10000];
+        game_rom[0x0150] = 0xD3;
+        for offset in [0x0200, 0x5000, 0xFFFF] {
+            game_rom[offset] = 0xFD;
+        }
+        // Every offset is decoded, so operands that happen to be illegal opcodes are reported too
+        game_rom[0x9000..0x9002].copy_from_slice(&ld_a_u8(0xE4));
+        let illegal_opcodes = scan_opcodes(&game_rom);
+        assert_eq!(
+            illegal_opcodes.into_iter().collect::<Vec<_>>(),
+            [
+                (0xD3, (1, 0x0150)),
+                (0xE4, (1, 0x9001)),
+                (0xFD, (3, 0x0200))
+            ]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::num::Wrapping;
+
+// Enough to cover a few instructions, for watchpoints checked after every step.
+const MEMORY_TRACE_CAPACITY: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: Wrapping<u16>,
+    pub value: Wrapping<u8>,
+}
+
+// Gets to see the memory accesses made by executing instructions, see `Bus::memory_observer`.
+pub trait MemoryObserver {
+    fn on_read(&mut self, address: Wrapping<u16>, value: Wrapping<u8>);
+    fn on_write(&mut self, address: Wrapping<u16>, value: Wrapping<u8>);
+}
+
+// Keeps the most recent accesses, overwriting the oldest ones when full.  The buffer is allocated
+// once, recording an access never allocates.
+#[derive(Clone, Debug)]
+pub struct MemoryTrace {
+    // Nothing is recorded until this is set
+    pub is_enabled: bool,
+    accesses: [MemoryAccess; MEMORY_TRACE_CAPACITY],
+    // Index where the next access goes
+    next: usize,
+    len: usize,
+}
+
+impl MemoryTrace {
+    pub fn new() -> Self {
+        MemoryTrace {
+            is_enabled: false,
+            accesses: [MemoryAccess {
+                kind: AccessKind::Read,
+                address: Wrapping(0),
+                value: Wrapping(0),
+            }; MEMORY_TRACE_CAPACITY],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    fn record(&mut self, kind: AccessKind, address: Wrapping<u16>, value: Wrapping<u8>) {
+        self.accesses[self.next] = MemoryAccess {
+            kind,
+            address,
+            value,
+        };
+        self.next = (self.next + 1) % MEMORY_TRACE_CAPACITY;
+        self.len = (self.len + 1).min(MEMORY_TRACE_CAPACITY);
+    }
+
+    // Returns the recorded accesses, oldest first, and empties the trace.
+    pub fn drain(&mut self) -> Vec<MemoryAccess> {
+        let start = (self.next + MEMORY_TRACE_CAPACITY - self.len) % MEMORY_TRACE_CAPACITY;
+        let res = (0..self.len)
+            .map(|offset| self.accesses[(start + offset) % MEMORY_TRACE_CAPACITY])
+            .collect();
+        self.len = 0;
+        res
+    }
+}
+
+impl MemoryObserver for MemoryTrace {
+    fn on_read(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        self.record(AccessKind::Read, address, value)
+    }
+
+    fn on_write(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        self.record(AccessKind::Write, address, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, num::Wrapping, time::Instant};
+
+    use super::*;
+    use crate::{
+        bus::{Bus, FlatBus},
+        cpu::{interrupts::Interrupts, CPU},
+        instructions::{asm::*, decode::decode_instruction_at_address},
+        registers::{R16, R8},
+    };
+
+    #[test]
+    fn drain_returns_the_most_recent_accesses_oldest_first() {
+        let mut trace = MemoryTrace::new();
+        for address in 0..MEMORY_TRACE_CAPACITY + 2 {
+            trace.on_write(Wrapping(address as u16), Wrapping(0));
+        }
+        let accesses = trace.drain();
+        assert_eq!(accesses.len(), MEMORY_TRACE_CAPACITY);
+        assert_eq!(accesses[0].address, Wrapping(2));
+        assert_eq!(
+            accesses[MEMORY_TRACE_CAPACITY - 1].address,
+            Wrapping(MEMORY_TRACE_CAPACITY as u16 + 1)
+        );
+        assert!(trace.drain().is_empty());
+    }
+
+    // Observes accesses the same way `Machine` does.
+    struct TracedBus {
+        bus: FlatBus,
+        trace: MemoryTrace,
+    }
+
+    impl Bus for TracedBus {
+        fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+            self.bus.read_u8(address)
+        }
+
+        fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+            self.bus.write_u8(address, value)
+        }
+
+        fn cpu(&self) -> &CPU {
+            self.bus.cpu()
+        }
+
+        fn cpu_mut(&mut self) -> &mut CPU {
+            self.bus.cpu_mut()
+        }
+
+        fn interrupts(&self) -> &Interrupts {
+            self.bus.interrupts()
+        }
+
+        fn interrupts_mut(&mut self) -> &mut Interrupts {
+            self.bus.interrupts_mut()
+        }
+
+        fn memory_observer(&mut self) -> Option<&mut dyn MemoryObserver> {
+            if self.trace.is_enabled {
+                Some(&mut self.trace)
+            } else {
+                None
+            }
+        }
+    }
+
+    // Keeps incrementing a byte in WRAM, so that most instructions access memory.
+    fn load_program<B: Bus>(bus: &mut B) {
+        let program = program![
+            ld_r16_u16(R16::HL, 0xC000),
+            // loop:
+            ld_a_mhl(),
+            inc_r8(R8::A),
+            ld_mhl_a(),
+            jr(-5),
+        ];
+        for (address, byte) in program.iter().enumerate() {
+            bus.write_u8(Wrapping(address as u16), Wrapping(*byte));
+        }
+    }
+
+    // Returns how long it took, in seconds.
+    fn time_instructions<B: Bus>(bus: &mut B, instruction_count: usize) -> f64 {
+        load_program(bus);
+        let start = Instant::now();
+        for _ in 0..instruction_count {
+            let decoded = decode_instruction_at_address(bus, bus.registers().pc);
+            bus.registers_mut().pc += decoded.instruction_size as u16;
+            black_box(decoded.instruction.execute(bus));
+        }
+        start.elapsed().as_secs_f64()
+    }
+
+    // Timing is only meaningful in release builds: cargo test --release -- --ignored trace_overhead
+    // Each setup runs a few times, interleaved, and keeps its best time, to smooth out warm-up and
+    // noise.
+    #[test]
+    #[ignore]
+    fn trace_overhead() {
+        const INSTRUCTION_COUNT: usize = 10_000_000;
+        let mut untraced = f64::MAX;
+        let mut disabled = f64::MAX;
+        let mut enabled = f64::MAX;
+        for _ in 0..3 {
+            untraced = untraced.min(time_instructions(&mut FlatBus::new(), INSTRUCTION_COUNT));
+            let mut traced = TracedBus {
+                bus: FlatBus::new(),
+                trace: MemoryTrace::new(),
+            };
+            disabled = disabled.min(time_instructions(&mut traced, INSTRUCTION_COUNT));
+            traced.trace.is_enabled = true;
+            enabled = enabled.min(time_instructions(&mut traced, INSTRUCTION_COUNT));
+        }
+        println!(
+            "No observer: {:.3}s, trace disabled: {:.3}s, trace enabled: {:.3}s",
+            untraced, disabled, enabled
+        );
+        assert!(disabled < untraced * 1.1, "disabled trace costs too much");
+    }
+}
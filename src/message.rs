@@ -1,3 +1,11 @@
+use gilrs::Button as GamepadButton;
+use iced::keyboard::Key;
+
+use crate::{
+    inputs::{Button, InputSource},
+    ppu::{TileMapCell, TilePaletteSelection},
+};
+
 #[derive(Clone, Debug, Hash)]
 pub enum Message {
     Pause,
@@ -5,4 +13,25 @@ pub enum Message {
     RunNextInstruction,
     BeginRunUntilBreakpoint,
     ContinueRunUntilBreakpoint,
+    ToggleTileMap0,
+    ToggleTileMap1,
+    SelectTilePalette(TilePaletteSelection),
+    HoverTileMapCell(TileMapCell),
+    LeaveTileMap,
+    ToggleFetcherPanel,
+    ToggleJoypadPanel,
+    ToggleLCDInProgress,
+    ToggleInputDisplay,
+    KeyPressed(Key),
+    KeyReleased(Key),
+    GamepadPressed(GamepadButton),
+    GamepadReleased(GamepadButton),
+    PressButton(InputSource, Button),
+    ReleaseButton(InputSource, Button),
+    PressTurboButton(InputSource, Button),
+    ReleaseTurboButton(InputSource, Button),
+    // Sent when the window loses focus, since the key releases will go elsewhere, and when a
+    // gamepad is disconnected
+    ReleaseAllButtons(InputSource),
+    SetFastForward(bool),
 }
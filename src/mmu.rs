@@ -0,0 +1,383 @@
+use std::{
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
+
+use crate::{
+    application_state::{ConsoleMode, ROMInformation},
+    mapper::{new_mapper, Mapper},
+    memory::Memory,
+};
+
+// The parts of the address space backed by memory: the cartridge (through its mapper), WRAM, and
+// HRAM.  Everything else belongs to the components `Machine` owns, and it decodes those itself.
+#[derive(Clone, Debug)]
+pub struct MMU {
+    pub mapper: Box<dyn Mapper>,
+    pub memory: Memory,
+    /// Set when cartridge RAM or the RTC changed since the last save
+    pub is_battery_ram_dirty: bool,
+    /// SVBK, only used in CGB mode
+    pub wram_bank: Wrapping<u8>,
+    is_cgb_mode: bool,
+}
+
+// Whether the battery save is due is the host's business, not the emulated machine's.
+impl Hash for MMU {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mapper.hash_state(state);
+        self.memory.hash(state);
+        self.wram_bank.hash(state);
+        self.is_cgb_mode.hash(state);
+    }
+}
+
+impl MMU {
+    pub fn new(memory: Memory, rom_information: &ROMInformation) -> Self {
+        MMU {
+            mapper: new_mapper(&rom_information.mapper_type),
+            memory,
+            is_battery_ram_dirty: false,
+            wram_bank: Wrapping(0),
+            is_cgb_mode: rom_information.console_mode == ConsoleMode::Cgb,
+        }
+    }
+
+    // Banks past the end of the ROM wrap around, like the unconnected high bank lines would.
+    fn rom_index(&self, bank: u16, address: Wrapping<u16>) -> usize {
+        let index = bank as usize * 0x4000 + (address.0 as usize & 0x3FFF);
+        index % self.memory.game_rom.len()
+    }
+
+    // The WRAM bank mapped at 0xD000-0xDFFF.  In CGB mode, it is selected with SVBK, where 0 also
+    // selects bank 1.  DMG always has bank 1 there.
+    fn switchable_wram_bank(&self) -> usize {
+        if !self.is_cgb_mode {
+            return 1;
+        }
+        match self.wram_bank.0 & 0x07 {
+            0 => 1,
+            bank => bank as usize,
+        }
+    }
+
+    pub fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        match address.0 {
+            0x0000..=0x3FFF => {
+                let index = self.rom_index(self.mapper.low_rom_bank(), address);
+                Wrapping(self.memory.game_rom[index])
+            }
+            0x4000..=0x7FFF => {
+                let index = self.rom_index(self.mapper.rom_bank(), address);
+                Wrapping(self.memory.game_rom[index])
+            }
+            0xA000..=0xBFFF => self.mapper.read_ram(&self.memory.game_ram, address),
+            0xC000..=0xCFFF => self.memory.read_wram(0, address - Wrapping(0xC000)),
+            0xD000..=0xDFFF => {
+                let bank = self.switchable_wram_bank();
+                self.memory.read_wram(bank, address - Wrapping(0xD000))
+            }
+            0xE000..=0xFDFF => self.read_u8(address - Wrapping(0x2000)),
+            0xFF80..=0xFFFE => Wrapping(self.memory.hram[address.0 as usize - 0xFF80]),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        match address.0 {
+            0x0000..=0x7FFF => self.mapper.write_register(address, value),
+            0xA000..=0xBFFF => {
+                if self
+                    .mapper
+                    .write_ram(&mut self.memory.game_ram, address, value)
+                {
+                    self.is_battery_ram_dirty = true;
+                }
+            }
+            0xC000..=0xCFFF => self.memory.write_wram(0, address - Wrapping(0xC000), value),
+            0xD000..=0xDFFF => {
+                let bank = self.switchable_wram_bank();
+                self.memory
+                    .write_wram(bank, address - Wrapping(0xD000), value)
+            }
+            0xE000..=0xFDFF => self.write_u8(address - Wrapping(0x2000), value),
+            0xFF80..=0xFFFE => self.memory.hram[address.0 as usize - 0xFF80] = value.0,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application_state::{MapperType, RAMSize};
+
+    // Each ROM bank starts with its own number, so that reads tell which bank is mapped.
+    fn mmu_with(mapper_type: MapperType, rom_banks: usize, ram_size: RAMSize) -> MMU {
+        let mut game_rom = vec![0; rom_banks * 0x4000];
+        for bank in 0..rom_banks {
+            game_rom[bank * 0x4000..][..2].copy_from_slice(&(bank as u16).to_le_bytes());
+        }
+        let rom_information = ROMInformation {
+            mapper_type,
+            ram_size,
+            rom_banks: rom_banks as u16,
+            ..ROMInformation::new()
+        };
+        let memory = Memory::new(vec![0; 0x100], game_rom, &rom_information);
+        MMU::new(memory, &rom_information)
+    }
+
+    fn write(mmu: &mut MMU, address: u16, value: u8) {
+        mmu.write_u8(Wrapping(address), Wrapping(value));
+    }
+
+    fn read(mmu: &MMU, address: u16) -> u8 {
+        mmu.read_u8(Wrapping(address)).0
+    }
+
+    // The ROM bank mapped at the 16KB window starting at `address`
+    fn bank_at(mmu: &MMU, address: u16) -> u16 {
+        u16::from_le_bytes([read(mmu, address), read(mmu, address + 1)])
+    }
+
+    #[test]
+    fn mbc5_maps_any_of_512_banks() {
+        let mut mmu = mmu_with(MapperType::MBC5, 512, RAMSize::NoRAM);
+        assert_eq!(bank_at(&mmu, 0x4000), 1);
+        // Unlike the other mappers, bank 0 is selectable
+        write(&mut mmu, 0x2000, 0x00);
+        assert_eq!(bank_at(&mmu, 0x4000), 0);
+        write(&mut mmu, 0x2000, 0x01);
+        assert_eq!(bank_at(&mmu, 0x4000), 1);
+        write(&mut mmu, 0x3000, 0x01);
+        write(&mut mmu, 0x2000, 0x00);
+        assert_eq!(bank_at(&mmu, 0x4000), 0x100);
+        write(&mut mmu, 0x2FFF, 0xFF);
+        assert_eq!(bank_at(&mmu, 0x4000), 0x1FF);
+        // 0x0000-0x3FFF always has bank 0
+        assert_eq!(bank_at(&mmu, 0x0000), 0);
+    }
+
+    #[test]
+    fn mbc5_banks_past_the_end_of_the_rom_wrap_around() {
+        let mut mmu = mmu_with(MapperType::MBC5, 128, RAMSize::NoRAM);
+        write(&mut mmu, 0x3000, 0x01);
+        write(&mut mmu, 0x2000, 0xA5);
+        assert_eq!(bank_at(&mmu, 0x4000), 0x25);
+    }
+
+    #[test]
+    fn mbc5_reaches_each_of_16_ram_banks() {
+        let mut mmu = mmu_with(MapperType::MBC5, 2, RAMSize::Ram16banks8kb);
+        write(&mut mmu, 0x0000, 0x0A);
+        for bank in 0..16 {
+            write(&mut mmu, 0x4000, bank);
+            write(&mut mmu, 0xA000, bank + 0x40);
+            write(&mut mmu, 0xBFFF, bank + 0x80);
+        }
+        for bank in 0..16 {
+            assert_eq!(mmu.memory.game_ram[bank * 0x2000], bank as u8 + 0x40);
+            assert_eq!(
+                mmu.memory.game_ram[bank * 0x2000 + 0x1FFF],
+                bank as u8 + 0x80
+            );
+        }
+    }
+
+    #[test]
+    fn mbc2_address_bit_8_picks_the_register() {
+        let mut mmu = mmu_with(MapperType::MBC2, 16, RAMSize::NoRAM);
+        // Bit 8 clear: RAM enable, the bank stays
+        write(&mut mmu, 0x2000, 0x0A);
+        assert_eq!(bank_at(&mmu, 0x4000), 1);
+        write(&mut mmu, 0xA000, 0x05);
+        assert_eq!(read(&mmu, 0xA000), 0xF5);
+        // Bit 8 set: ROM bank, RAM stays enabled
+        write(&mut mmu, 0x2100, 0x07);
+        assert_eq!(bank_at(&mmu, 0x4000), 7);
+        assert_eq!(read(&mmu, 0xA000), 0xF5);
+        write(&mut mmu, 0x0100, 0x00);
+        assert_eq!(bank_at(&mmu, 0x4000), 1);
+        write(&mut mmu, 0x3EFF, 0x00);
+        assert_eq!(read(&mmu, 0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc2_ram_holds_512_mirrored_nibbles() {
+        let mut mmu = mmu_with(MapperType::MBC2, 16, RAMSize::NoRAM);
+        write(&mut mmu, 0x0000, 0x0A);
+        write(&mut mmu, 0xA000, 0xAB);
+        write(&mut mmu, 0xA1FF, 0x3C);
+        // Only the lower nibble is kept, the upper one reads as open bus
+        assert_eq!(read(&mmu, 0xA000), 0xFB);
+        assert_eq!(read(&mmu, 0xA1FF), 0xFC);
+        for mirror in [0xA200, 0xA400, 0xBE00] {
+            assert_eq!(read(&mmu, mirror), 0xFB);
+            assert_eq!(read(&mmu, mirror + 0x1FF), 0xFC);
+        }
+        write(&mut mmu, 0xA200, 0x01);
+        assert_eq!(read(&mmu, 0xA000), 0xF1);
+    }
+
+    #[test]
+    fn mbc1_ram_banking_mode_selects_the_ram_bank() {
+        let mut mmu = mmu_with(MapperType::MBC1, 4, RAMSize::Ram4banks8kb);
+        write(&mut mmu, 0x0000, 0x0A);
+        write(&mut mmu, 0x6000, 0x01);
+        for bank in 0..4 {
+            write(&mut mmu, 0x4000, bank);
+            write(&mut mmu, 0xA000, 0x10 + bank);
+            write(&mut mmu, 0xBFFF, 0x20 + bank);
+        }
+        for bank in 0..4 {
+            write(&mut mmu, 0x4000, bank);
+            assert_eq!(read(&mmu, 0xA000), 0x10 + bank);
+            assert_eq!(read(&mmu, 0xBFFF), 0x20 + bank);
+        }
+    }
+
+    #[test]
+    fn mbc1_rom_banking_mode_always_maps_ram_bank_0() {
+        let mut mmu = mmu_with(MapperType::MBC1, 4, RAMSize::Ram4banks8kb);
+        write(&mut mmu, 0x0000, 0x0A);
+        write(&mut mmu, 0x6000, 0x01);
+        write(&mut mmu, 0x4000, 0x00);
+        write(&mut mmu, 0xA000, 0x42);
+        write(&mut mmu, 0x4000, 0x02);
+        write(&mut mmu, 0xA000, 0x43);
+        // Back to ROM banking mode, the upper bits no longer reach the RAM
+        write(&mut mmu, 0x6000, 0x00);
+        assert_eq!(read(&mmu, 0xA000), 0x42);
+        write(&mut mmu, 0xA000, 0x44);
+        write(&mut mmu, 0x6000, 0x01);
+        assert_eq!(read(&mmu, 0xA000), 0x43);
+        write(&mut mmu, 0x4000, 0x00);
+        assert_eq!(read(&mmu, 0xA000), 0x44);
+    }
+
+    // Selects a bank the way games do, the lower 5 bits then the upper 2.
+    fn select_mbc1_rom_bank(mmu: &mut MMU, bank: u8) {
+        write(mmu, 0x2000, bank & 0x1F);
+        write(mmu, 0x4000, bank >> 5);
+    }
+
+    #[test]
+    fn mbc1_banks_wrap_around_a_64kb_rom() {
+        let mut mmu = mmu_with(MapperType::MBC1, 4, RAMSize::NoRAM);
+        for (selected, mapped) in [
+            (0x00, 1),
+            (0x01, 1),
+            (0x03, 3),
+            (0x04, 0),
+            (0x05, 1),
+            (0x1F, 3),
+        ] {
+            write(&mut mmu, 0x2000, selected);
+            assert_eq!(bank_at(&mmu, 0x4000), mapped, "0x{:02X}", selected);
+        }
+        // The upper bits select banks past the end of the ROM
+        for (selected, mapped) in [(0x20, 1), (0x42, 2), (0x7F, 3)] {
+            select_mbc1_rom_bank(&mut mmu, selected);
+            assert_eq!(bank_at(&mmu, 0x4000), mapped, "0x{:02X}", selected);
+        }
+    }
+
+    #[test]
+    fn mbc1_cannot_map_banks_0x20_0x40_and_0x60() {
+        let mut mmu = mmu_with(MapperType::MBC1, 128, RAMSize::NoRAM);
+        for (selected, mapped) in [
+            (0x1F, 0x1F),
+            (0x20, 0x21),
+            (0x40, 0x41),
+            (0x60, 0x61),
+            (0x7F, 0x7F),
+        ] {
+            select_mbc1_rom_bank(&mut mmu, selected);
+            assert_eq!(bank_at(&mmu, 0x4000), mapped, "0x{:02X}", selected);
+        }
+        // A 1MB ROM only has half of those banks
+        let mut mmu = mmu_with(MapperType::MBC1, 64, RAMSize::NoRAM);
+        for (selected, mapped) in [(0x20, 0x21), (0x3F, 0x3F), (0x40, 0x01), (0x61, 0x21)] {
+            select_mbc1_rom_bank(&mut mmu, selected);
+            assert_eq!(bank_at(&mmu, 0x4000), mapped, "0x{:02X}", selected);
+        }
+    }
+
+    #[test]
+    fn mbc1_ram_banking_mode_remaps_bank_0_on_large_roms() {
+        let mut mmu = mmu_with(MapperType::MBC1, 64, RAMSize::NoRAM);
+        select_mbc1_rom_bank(&mut mmu, 0x25);
+        assert_eq!(bank_at(&mmu, 0x0000), 0x00);
+        write(&mut mmu, 0x6000, 0x01);
+        assert_eq!(bank_at(&mmu, 0x0000), 0x20);
+        assert_eq!(bank_at(&mmu, 0x4000), 0x25);
+        // Smaller ROMs wrap back to bank 0
+        let mut mmu = mmu_with(MapperType::MBC1, 32, RAMSize::NoRAM);
+        write(&mut mmu, 0x6000, 0x01);
+        write(&mut mmu, 0x4000, 0x01);
+        assert_eq!(bank_at(&mmu, 0x0000), 0x00);
+    }
+
+    fn mmu_as(console_mode: ConsoleMode) -> MMU {
+        let rom_information = ROMInformation {
+            console_mode,
+            ..ROMInformation::new()
+        };
+        let memory = Memory::new(vec![0; 0x100], vec![0; 0x8000], &rom_information);
+        MMU::new(memory, &rom_information)
+    }
+
+    #[test]
+    fn svbk_maps_a_wram_bank_at_0xd000() {
+        let mut mmu = mmu_as(ConsoleMode::Cgb);
+        for bank in 0..8 {
+            mmu.wram_bank = Wrapping(bank);
+            write(&mut mmu, 0xD123, 0x10 + bank);
+            write(&mut mmu, 0xC123, 0x20 + bank);
+        }
+        // Selecting bank 0 maps bank 1, and only the lower 3 bits count
+        for (svbk, value) in [
+            (0, 0x11),
+            (1, 0x11),
+            (2, 0x12),
+            (7, 0x17),
+            (0x0B, 0x13),
+            (0xF8, 0x11),
+        ] {
+            mmu.wram_bank = Wrapping(svbk);
+            assert_eq!(read(&mmu, 0xD123), value, "SVBK 0x{:02X}", svbk);
+            // 0xC000-0xCFFF always has bank 0
+            assert_eq!(read(&mmu, 0xC123), 0x27, "SVBK 0x{:02X}", svbk);
+        }
+    }
+
+    #[test]
+    fn echo_ram_follows_the_selected_wram_bank() {
+        let mut mmu = mmu_as(ConsoleMode::Cgb);
+        for bank in 1..8 {
+            mmu.wram_bank = Wrapping(bank);
+            write(&mut mmu, 0xF123, 0x30 + bank);
+        }
+        for bank in 1..8 {
+            mmu.wram_bank = Wrapping(bank);
+            assert_eq!(read(&mmu, 0xD123), 0x30 + bank, "bank {}", bank);
+            write(&mut mmu, 0xD456, 0x40 + bank);
+            assert_eq!(read(&mmu, 0xF456), 0x40 + bank, "bank {}", bank);
+        }
+        write(&mut mmu, 0xE123, 0x50);
+        assert_eq!(read(&mmu, 0xC123), 0x50);
+    }
+
+    #[test]
+    fn dmg_always_maps_wram_bank_1() {
+        let mut mmu = mmu_as(ConsoleMode::Dmg);
+        write(&mut mmu, 0xD123, 0x11);
+        mmu.wram_bank = Wrapping(2);
+        assert_eq!(read(&mmu, 0xD123), 0x11);
+        write(&mut mmu, 0xD123, 0x22);
+        mmu.wram_bank = Wrapping(0);
+        assert_eq!(read(&mmu, 0xD123), 0x22);
+        assert_eq!(read(&mmu, 0xF123), 0x22);
+    }
+}
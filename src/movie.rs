@@ -0,0 +1,195 @@
+use std::{
+    fs::{self, File},
+    io::{self, Error, ErrorKind, Write},
+};
+
+use crate::{command_line_arguments::CommandLineArguments, inputs::Buttons, machine::Machine};
+
+// Movie files start with this, then the ROM's global checksum, then one byte of buttons per
+// frame, see `Buttons::to_byte`.  Movies always start from power-on.
+const MOVIE_MAGIC: &[u8; 4] = b"YBMV";
+const MOVIE_HEADER_SIZE: usize = MOVIE_MAGIC.len() + 2;
+
+#[derive(Debug)]
+enum MovieMode {
+    Recording(File),
+    Playing { frames: Vec<u8>, next: usize },
+}
+
+// Records or plays back the buttons of every frame.  Both modes only change the buttons when the
+// PPU completes a frame, so that a movie plays back the same regardless of host timing.  Frame 0
+// lasts from power-on to the first VBlank.
+#[derive(Debug)]
+pub struct Movie {
+    mode: MovieMode,
+    /// The frame whose buttons are currently applied
+    frame_count: u64,
+}
+
+impl Movie {
+    // Overwrites any existing file.  While recording, the game sees the buttons held when the
+    // frame started, rather than as they change, so that playback matches exactly.
+    pub fn record(path: &str, machine: &mut Machine) -> Result<Self, io::Error> {
+        let mut file = File::create(path)?;
+        file.write_all(MOVIE_MAGIC)?;
+        file.write_all(&machine.rom_information.global_checksum.to_be_bytes())?;
+        let mut movie = Movie {
+            mode: MovieMode::Recording(file),
+            frame_count: machine.ppu().completed_frame_count(),
+        };
+        movie.next_frame(machine)?;
+        Ok(movie)
+    }
+
+    pub fn play(path: &str, machine: &mut Machine) -> Result<Self, io::Error> {
+        let bytes = fs::read(path)?;
+        if bytes.len() < MOVIE_HEADER_SIZE || &bytes[..MOVIE_MAGIC.len()] != MOVIE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a movie file"));
+        }
+        let checksum = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if checksum != machine.rom_information.global_checksum {
+            println!(
+                "[WARNING] Movie was recorded with ROM checksum 0x{:04X}, this ROM's is 0x{:04X}",
+                checksum, machine.rom_information.global_checksum
+            );
+        }
+        let mut movie = Movie {
+            mode: MovieMode::Playing {
+                frames: bytes[MOVIE_HEADER_SIZE..].to_vec(),
+                next: 0,
+            },
+            frame_count: machine.ppu().completed_frame_count(),
+        };
+        movie.next_frame(machine)?;
+        Ok(movie)
+    }
+
+    // To be called after every instruction.  Returns whether the movie is still going, playback
+    // stops at the end of the file and hands the buttons back to the player.
+    pub fn update(&mut self, machine: &mut Machine) -> bool {
+        let frame_count = machine.ppu().completed_frame_count();
+        if frame_count == self.frame_count {
+            return true;
+        }
+        self.frame_count = frame_count;
+        match self.next_frame(machine) {
+            Ok(()) => true,
+            Err(e) => {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    println!("Movie ended at frame {}", frame_count);
+                } else {
+                    println!("[WARNING] Stopping movie at frame {}: {}", frame_count, e);
+                }
+                machine
+                    .inputs
+                    .set_overridden_buttons(None, &mut machine.interrupts);
+                false
+            }
+        }
+    }
+
+    fn next_frame(&mut self, machine: &mut Machine) -> Result<(), io::Error> {
+        let buttons = match &mut self.mode {
+            MovieMode::Recording(file) => {
+                let buttons = machine.inputs.held_buttons();
+                file.write_all(&[buttons.to_byte()])?;
+                buttons
+            }
+            MovieMode::Playing { frames, next } => {
+                let Some(byte) = frames.get(*next) else {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "End of movie"));
+                };
+                *next += 1;
+                Buttons::from_byte(*byte)
+            }
+        };
+        machine
+            .inputs
+            .set_overridden_buttons(Some(buttons), &mut machine.interrupts);
+        Ok(())
+    }
+}
+
+// Starts the movie asked for on the command line, if any.
+pub fn start_movie(args: &CommandLineArguments, machine: &mut Machine) -> Option<Movie> {
+    let movie = if let Some(path) = &args.record_movie {
+        Movie::record(path, machine)
+    } else if let Some(path) = &args.play_movie {
+        Movie::play(path, machine)
+    } else {
+        return None;
+    };
+    Some(movie.unwrap_or_else(|e| panic!("Could not start movie: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::{
+        inputs::InputSource,
+        machine::tests::{joypad_program, machine_with_program},
+    };
+
+    const FRAME_COUNT: usize = 12;
+
+    // Runs frames the way headless mode does, pressing different buttons on every frame.
+    fn run_frames(machine: &mut Machine, movie: &mut Movie, is_pressing_buttons: bool) {
+        for frame in 0..FRAME_COUNT {
+            if is_pressing_buttons {
+                let buttons = Buttons::from_byte((frame * 37) as u8);
+                machine
+                    .inputs
+                    .set_buttons(InputSource::Keyboard, buttons, &mut machine.interrupts);
+            }
+            machine.run_frame();
+            assert!(movie.update(machine));
+        }
+    }
+
+    #[test]
+    fn played_back_movie_reaches_the_recorded_state() {
+        let path = env::temp_dir().join(format!("yokoyboi-test-{}.ybmv", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut recorded = machine_with_program(&joypad_program());
+        let mut movie = Movie::record(path, &mut recorded).unwrap();
+        run_frames(&mut recorded, &mut movie, true);
+        drop(movie);
+        // What the player holds is not part of the movie, only what the game saw
+        recorded.inputs.set_buttons(
+            InputSource::Keyboard,
+            Buttons::default(),
+            &mut recorded.interrupts,
+        );
+
+        let mut played = machine_with_program(&joypad_program());
+        let mut movie = Movie::play(path, &mut played).unwrap();
+        run_frames(&mut played, &mut movie, false);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(recorded.state_hash(), played.state_hash());
+        assert_ne!(
+            played.state_hash(),
+            machine_with_program(&joypad_program()).state_hash()
+        );
+    }
+
+    #[test]
+    fn playback_stops_at_the_end_of_the_movie() {
+        let path = env::temp_dir().join(format!("yokoyboi-end-{}.ybmv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut bytes = MOVIE_MAGIC.to_vec();
+        bytes.extend([0, 0, 0xFF]);
+        fs::write(path, bytes).unwrap();
+
+        let mut machine = machine_with_program(&joypad_program());
+        let mut movie = Movie::play(path, &mut machine).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(machine.inputs.buttons(), Buttons::from_byte(0xFF));
+        machine.run_frame();
+        assert!(!movie.update(&mut machine));
+        assert_eq!(machine.inputs.buttons(), Buttons::default());
+    }
+}
@@ -6,8 +6,8 @@ use object::ObjectFetcher;
 
 use crate::ppu::PPU;
 
-#[derive(Clone, Debug)]
-enum FetcherState {
+#[derive(Clone, Debug, Hash)]
+pub enum FetcherState {
     GetTileDelay,
     GetTile,
     GetTileDataLowDelay,
@@ -17,25 +17,27 @@ enum FetcherState {
     PushRow,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub struct FIFOItem {
     pub color: u8,
+    /// Only used in CGB mode, where each tile picks one of 8 palettes.
+    pub cgb_palette: u8,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Hash, PartialEq)]
 pub enum FetchingFor {
     BackgroundOrWindowFIFO,
     ObjectFIFO,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub struct Fetcher {
     pub fetching_for: FetchingFor,
 }
 
 // Background and Window use one of these based on bit 4 of lcd_control.
 // Sprites always use UnsignedFrom0x8000.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TileAddressingMode {
     UnsignedFrom0x8000,
     SignedFrom0x9000,
@@ -1,18 +1,31 @@
 use std::{collections::VecDeque, num::Wrapping};
 
 use crate::{
-    ppu::{LCDC_BACKGROUND_TILE_MAP_AREA_BIT, PPU, TILE_MAP_HORIZONTAL_TILE_COUNT},
+    ppu::{
+        LCDC_BACKGROUND_TILE_MAP_AREA_BIT, LCDC_WINDOW_TILE_MAP_AREA_BIT, PPU,
+        TILE_MAP_HORIZONTAL_TILE_COUNT,
+    },
     utils,
 };
 
 use super::{FIFOItem, Fetcher, FetcherState};
 
-#[derive(Clone, Debug)]
+// CGB tile attributes single bits of interest
+const TILE_Y_FLIP_BIT: u8 = 6;
+const TILE_X_FLIP_BIT: u8 = 5;
+const TILE_VRAM_BANK_BIT: u8 = 3;
+const TILE_CGB_PALETTE_MASK: u8 = 0b111;
+
+#[derive(Clone, Debug, Hash)]
 pub struct BackgroundOrWindowFetcher {
     state: FetcherState,
     pub fifo: VecDeque<FIFOItem>,
+    /// Set once the window has been reached on the current scanline.
+    pub is_fetching_window: bool,
     pub row_of_pixel_within_tile: u8,
     tile_id: u8,
+    /// Always 0 outside of CGB mode, so that DMG tiles are never flipped nor banked.
+    tile_attributes: u8,
     pub vram_tile_column: u8,
     tile_row_data: [u8; 8],
 }
@@ -22,16 +35,27 @@ impl BackgroundOrWindowFetcher {
         BackgroundOrWindowFetcher {
             state: FetcherState::GetTileDelay,
             fifo: VecDeque::new(),
+            is_fetching_window: false,
             row_of_pixel_within_tile: 0,
             tile_id: 0,
+            tile_attributes: 0,
             vram_tile_column: 0,
             tile_row_data: [0; 8],
         }
     }
 
+    pub fn state(&self) -> &FetcherState {
+        &self.state
+    }
+
+    pub fn tile_id(&self) -> u8 {
+        self.tile_id
+    }
+
     pub fn prepare_for_new_frame(&mut self) {
         self.state = FetcherState::GetTileDelay;
         self.fifo.clear();
+        self.is_fetching_window = false;
         self.row_of_pixel_within_tile = 0;
         self.vram_tile_column = 0;
         self.tile_row_data = [0; 8];
@@ -40,11 +64,54 @@ impl BackgroundOrWindowFetcher {
     pub fn prepare_for_new_row(&mut self) {
         self.state = FetcherState::GetTileDelay;
         self.fifo.clear();
+        self.is_fetching_window = false;
         self.row_of_pixel_within_tile = 0;
         self.vram_tile_column = 0;
         self.tile_row_data = [0; 8];
     }
 
+    pub fn start_fetching_window(&mut self) {
+        self.state = FetcherState::GetTileDelay;
+        self.fifo.clear();
+        self.is_fetching_window = true;
+        self.vram_tile_column = 0;
+        self.tile_row_data = [0; 8];
+    }
+
+    // The row data is ready once the fetcher is only waiting for room in the FIFO to push it.
+    pub fn is_row_ready(&self) -> bool {
+        matches!(self.state, FetcherState::PushRow)
+    }
+
+    fn is_attribute_set(&self, bit: u8) -> bool {
+        (self.tile_attributes >> bit) & 1 != 0
+    }
+
+    // Tile data comes from the VRAM bank picked by the tile attributes, and tiles can be flipped
+    // vertically by reading their rows bottom-up.
+    fn tile_data_bank_and_row<'a>(&self, ppu: &'a PPU) -> (&'a [u8], u8) {
+        let vram = if self.is_attribute_set(TILE_VRAM_BANK_BIT) {
+            &ppu.vram_bank_1[..]
+        } else {
+            &ppu.vram[..]
+        };
+        let row = self.pixel_row(ppu) % 8;
+        if self.is_attribute_set(TILE_Y_FLIP_BIT) {
+            (vram, 7 - row)
+        } else {
+            (vram, row)
+        }
+    }
+
+    // The window is not affected by scrolling, and has its own line counter.
+    fn pixel_row(&self, ppu: &PPU) -> u8 {
+        if self.is_fetching_window {
+            ppu.window_line_counter()
+        } else {
+            (ppu.read_ly() + ppu.scy).0
+        }
+    }
+
     pub fn tick(&mut self, ppu: &mut PPU) {
         match self.state {
             FetcherState::GetTileDelay => self.state = FetcherState::GetTile,
@@ -52,8 +119,17 @@ impl BackgroundOrWindowFetcher {
             FetcherState::GetTile => {
                 // NOTE: Because the following operations are done via Wrapping at u8, they
                 // automatically perform the necessary "mod 256"
-                let vram_pixel_row = (ppu.read_ly() + ppu.scy).0;
-                let vram_pixel_col = (Wrapping(self.vram_tile_column) * Wrapping(8) + ppu.scx).0;
+                let vram_pixel_row = self.pixel_row(ppu);
+                let vram_pixel_col = if self.is_fetching_window {
+                    (Wrapping(self.vram_tile_column) * Wrapping(8)).0
+                } else {
+                    (Wrapping(self.vram_tile_column) * Wrapping(8) + ppu.scx).0
+                };
+                let tile_map_area_bit = if self.is_fetching_window {
+                    LCDC_WINDOW_TILE_MAP_AREA_BIT
+                } else {
+                    LCDC_BACKGROUND_TILE_MAP_AREA_BIT
+                };
 
                 let tile_row = vram_pixel_row / 8;
                 let tile_col = vram_pixel_col / 8;
@@ -61,21 +137,33 @@ impl BackgroundOrWindowFetcher {
                 let tile_index_in_its_tile_map =
                     tile_row as usize * TILE_MAP_HORIZONTAL_TILE_COUNT + tile_col as usize;
 
-                // FIXME: more complex rules for the row base address
-                let vram_base_address =
-                    if utils::is_bit_set(&ppu.lcd_control, LCDC_BACKGROUND_TILE_MAP_AREA_BIT) {
-                        ppu.tile_map0_last_addressing_modes[tile_index_in_its_tile_map] =
-                            ppu.get_addressing_mode();
-                        0x1C00 // 0x9C00, but VRAM starts at 0x8000
-                    } else {
-                        ppu.tile_map1_last_addressing_modes[tile_index_in_its_tile_map] =
-                            ppu.get_addressing_mode();
-                        0x1800 // 0x9800, but VRAM starts at 0x8000
-                    };
+                // Remember how each tile got decoded, so that the tile map views can match it.
+                // Tile map 0 lives at 0x9800, and tile map 1 at 0x9C00.
+                let vram_base_address = if utils::is_bit_set(&ppu.lcd_control, tile_map_area_bit) {
+                    ppu.remember_addressing_mode(
+                        1,
+                        tile_index_in_its_tile_map,
+                        ppu.get_addressing_mode(),
+                    );
+                    0x1C00 // 0x9C00, but VRAM starts at 0x8000
+                } else {
+                    ppu.remember_addressing_mode(
+                        0,
+                        tile_index_in_its_tile_map,
+                        ppu.get_addressing_mode(),
+                    );
+                    0x1800 // 0x9800, but VRAM starts at 0x8000
+                };
 
                 let row_address = vram_base_address + ((tile_row as u16) << 5) + (tile_col as u16);
 
                 self.tile_id = ppu.vram[row_address as usize];
+                // In CGB mode, the attributes of the tile live at the same address in VRAM bank 1
+                self.tile_attributes = if ppu.is_cgb_mode {
+                    ppu.vram_bank_1[row_address as usize]
+                } else {
+                    0
+                };
                 self.state = FetcherState::GetTileDataLowDelay;
             }
 
@@ -84,11 +172,11 @@ impl BackgroundOrWindowFetcher {
             }
 
             FetcherState::GetTileDataLow => {
-                let ly = ppu.read_ly();
+                let (vram, row) = self.tile_data_bank_and_row(ppu);
                 Fetcher::read_tile_row(
-                    &ppu.vram,
+                    vram,
                     &ppu.get_addressing_mode(),
-                    (ly + ppu.scy).0,
+                    row,
                     self.tile_id,
                     false,
                     &mut self.tile_row_data,
@@ -101,15 +189,19 @@ impl BackgroundOrWindowFetcher {
             }
 
             FetcherState::GetTileDataHigh => {
-                let ly = ppu.read_ly();
+                let (vram, row) = self.tile_data_bank_and_row(ppu);
                 Fetcher::read_tile_row(
-                    &ppu.vram,
+                    vram,
                     &ppu.get_addressing_mode(),
-                    (ly + ppu.scy).0,
+                    row,
                     self.tile_id,
                     true,
                     &mut self.tile_row_data,
                 );
+                // Row data is sorted by increasing X, flipping just reverses it
+                if self.is_attribute_set(TILE_X_FLIP_BIT) {
+                    self.tile_row_data.reverse();
+                }
                 self.state = FetcherState::PushRow;
             }
 
@@ -118,7 +210,10 @@ impl BackgroundOrWindowFetcher {
                 if self.fifo.len() == 0 {
                     for i in 0..8 {
                         let color = self.tile_row_data[i];
-                        self.fifo.push_back(FIFOItem { color });
+                        self.fifo.push_back(FIFOItem {
+                            color,
+                            cgb_palette: self.tile_attributes & TILE_CGB_PALETTE_MASK,
+                        });
                     }
                     self.vram_tile_column += 1;
                     // clean up so that GetTileData can assume 0
@@ -1,15 +1,13 @@
-use std::{
-    cmp::{max, min},
-    collections::VecDeque,
-};
+use std::{collections::VecDeque, num::Wrapping};
 
 use crate::ppu::PPU;
 
 use super::{Fetcher, TileAddressingMode};
 
-#[derive(Clone, Debug)]
-enum FetcherState {
-    GetTileDelay,
+#[derive(Clone, Debug, Hash)]
+// Unlike background tiles, sprite fetches take 6 dots, pushing included, so there is no initial
+// delay.
+pub enum FetcherState {
     GetTile,
     GetTileDataLowDelay,
     GetTileDataLow,
@@ -18,7 +16,14 @@ enum FetcherState {
     PushRow,
 }
 
-#[derive(Clone, Debug)]
+// Sprite attributes single bits of interest
+const OBJECT_PRIORITY_BIT: u8 = 7;
+const OBJECT_Y_FLIP_BIT: u8 = 6;
+const OBJECT_X_FLIP_BIT: u8 = 5;
+const OBJECT_PALETTE_BIT: u8 = 4;
+const OBJECT_CGB_PALETTE_MASK: u8 = 0b111;
+
+#[derive(Clone, Debug, Hash)]
 pub struct Sprite {
     pub attributes: u8,
     pub tile_index: u8,
@@ -26,36 +31,73 @@ pub struct Sprite {
     pub y_screen_plus_16: u8,
 }
 
-#[derive(Clone, Debug)]
+impl Sprite {
+    fn is_attribute_set(&self, bit_position: u8) -> bool {
+        (self.attributes >> bit_position) & 1 != 0
+    }
+
+    // Returns the tile to read from (8x16 sprites span two consecutive tiles), and the row within
+    // that tile, accounting for vertical flipping.
+    fn tile_and_row(&self, ppu: &PPU) -> (u8, u8) {
+        let object_height = ppu.object_height();
+        // Sprites are not affected by scrolling, the row is relative to the top of the sprite.  OAM
+        // scan only selects sprites that intersect the current line, so this is in range.
+        let sprite_top_plus_16 = Wrapping(self.y_screen_plus_16);
+        let mut row = (ppu.read_ly() + Wrapping(16) - sprite_top_plus_16).0 % object_height;
+        if self.is_attribute_set(OBJECT_Y_FLIP_BIT) {
+            row = object_height - 1 - row;
+        }
+        let tile_index = if object_height == 16 {
+            // The lower bit of the tile index is ignored in 8x16 mode
+            (self.tile_index & 0xFE) | (row / 8)
+        } else {
+            self.tile_index
+        };
+        (tile_index, row % 8)
+    }
+}
+
+#[derive(Clone, Debug, Hash)]
 pub enum ObjectPalette {
     ObjectPalette0,
     ObjectPalette1,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub struct ObjectFIFOItem {
     pub color: u8,
     pub palette: ObjectPalette,
+    /// Only used in CGB mode, where each sprite picks one of 8 palettes.
+    pub cgb_palette: u8,
+    /// When set, background colors 1-3 are drawn over this pixel.
+    pub background_has_priority: bool,
 }
 
-#[derive(Clone, Debug)]
+pub const TRANSPARENT_PIXEL: ObjectFIFOItem = ObjectFIFOItem {
+    color: 0,
+    palette: ObjectPalette::ObjectPalette0,
+    cgb_palette: 0,
+    background_has_priority: false,
+};
+
+#[derive(Clone, Debug, Hash)]
 pub struct ObjectFetcher {
     state: FetcherState,
+    /// Pixels of the objects, the front of the FIFO is at `pixel_index_in_row`.
     pub fifo: VecDeque<ObjectFIFOItem>,
+    /// The sprite being fetched, if any.
     sprite: Option<Sprite>,
     pub pixel_index_in_row: u8,
     tile_row_data: [u8; 8],
+    /// Sprites on the current scanline that have not been fetched yet, sorted by priority (lowest X
+    /// first, then OAM order).
     pub selected_objects: VecDeque<Sprite>,
 }
 
-pub fn inclusive_ranges_overlap((s1, e1): (i16, i16), (s2, e2): (i16, i16)) -> bool {
-    max(s1, s2) <= min(e1, e2)
-}
-
 impl ObjectFetcher {
     pub fn new() -> Self {
         ObjectFetcher {
-            state: FetcherState::GetTileDelay,
+            state: FetcherState::GetTile,
             fifo: VecDeque::new(),
             sprite: None,
             pixel_index_in_row: 0,
@@ -64,53 +106,64 @@ impl ObjectFetcher {
         }
     }
 
+    pub fn state(&self) -> &FetcherState {
+        &self.state
+    }
+
+    pub fn sprite(&self) -> Option<&Sprite> {
+        self.sprite.as_ref()
+    }
+
     pub fn prepare_for_new_row(&mut self) {
-        self.state = FetcherState::GetTileDelay;
+        self.state = FetcherState::GetTile;
         self.fifo.clear();
+        self.sprite = None;
         self.tile_row_data = [0; 8];
         self.pixel_index_in_row = 0;
     }
 
     pub fn prepare_for_new_frame(&mut self) {
-        self.state = FetcherState::GetTileDelay;
+        self.state = FetcherState::GetTile;
         self.fifo.clear();
+        self.sprite = None;
         self.pixel_index_in_row = 0;
     }
 
+    // A sprite gets fetched when the pixel being drawn reaches its left edge.  While this is the
+    // case, the background FIFO must not be popped.
+    pub fn is_fetch_needed(&self) -> bool {
+        if self.sprite.is_some() {
+            return true;
+        }
+        match self.selected_objects.front() {
+            Some(sprite) => sprite.x_screen_plus_8 as i16 - 8 <= self.pixel_index_in_row as i16,
+            None => false,
+        }
+    }
+
     pub fn tick(&mut self, ppu: &mut PPU) {
         match self.state {
-            FetcherState::GetTileDelay => self.state = FetcherState::GetTile,
-
             FetcherState::GetTile => {
-                let current_x = self.pixel_index_in_row as i16;
-                let x_range = (current_x, current_x + 7);
-                let selected = &self.selected_objects;
-
-                // Technically we should only tick this when there is going to be a match
-                self.sprite = selected
-                    .iter()
-                    .find(|item| {
-                        let item_x_screen = item.x_screen_plus_8 as u16 as i16 - 8;
-                        inclusive_ranges_overlap(x_range, (item_x_screen, item_x_screen + 7))
-                    })
-                    .map(|i| i.clone());
-
+                // Sprites are sorted by X, so the next one to reach is at the front
+                self.sprite = self.selected_objects.pop_front();
                 self.state = FetcherState::GetTileDataLowDelay
             }
 
             FetcherState::GetTileDataLowDelay => self.state = FetcherState::GetTileDataLow,
 
             FetcherState::GetTileDataLow => {
-                let ly = ppu.read_ly();
                 match self.sprite.clone() {
-                    Some(sprite) => Fetcher::read_tile_row(
-                        &ppu.vram,
-                        &TileAddressingMode::UnsignedFrom0x8000,
-                        (ly + ppu.scy).0,
-                        sprite.tile_index,
-                        false,
-                        &mut self.tile_row_data,
-                    ),
+                    Some(sprite) => {
+                        let (tile_index, row) = sprite.tile_and_row(ppu);
+                        Fetcher::read_tile_row(
+                            &ppu.vram,
+                            &TileAddressingMode::UnsignedFrom0x8000,
+                            row,
+                            tile_index,
+                            false,
+                            &mut self.tile_row_data,
+                        )
+                    }
                     None => {
                         self.tile_row_data = [0; 8];
                     }
@@ -121,16 +174,22 @@ impl ObjectFetcher {
             FetcherState::GetTileDataHighDelay => self.state = FetcherState::GetTileDataHigh,
 
             FetcherState::GetTileDataHigh => {
-                let ly = ppu.read_ly();
                 match self.sprite.clone() {
-                    Some(sprite) => Fetcher::read_tile_row(
-                        &ppu.vram,
-                        &TileAddressingMode::UnsignedFrom0x8000,
-                        (ly + ppu.scy).0,
-                        sprite.tile_index,
-                        true,
-                        &mut self.tile_row_data,
-                    ),
+                    Some(sprite) => {
+                        let (tile_index, row) = sprite.tile_and_row(ppu);
+                        Fetcher::read_tile_row(
+                            &ppu.vram,
+                            &TileAddressingMode::UnsignedFrom0x8000,
+                            row,
+                            tile_index,
+                            true,
+                            &mut self.tile_row_data,
+                        );
+                        // Row data is sorted by increasing X, flipping just reverses it
+                        if sprite.is_attribute_set(OBJECT_X_FLIP_BIT) {
+                            self.tile_row_data.reverse();
+                        }
+                    }
                     None => {
                         self.tile_row_data = [0; 8];
                     }
@@ -139,31 +198,39 @@ impl ObjectFetcher {
             }
 
             FetcherState::PushRow => {
-                let obj_fifo_len = self.fifo.len();
+                let sprite = self.sprite.take();
+                // Sprites are fetched when the current pixel reaches their left edge, so their
+                // first column is at the front of the FIFO.  The exception is sprites hanging off
+                // the left edge of the screen (X < 8), whose hidden columns get skipped.
+                let hidden_columns = match &sprite {
+                    Some(sprite) => {
+                        let sprite_x_screen = sprite.x_screen_plus_8 as i16 - 8;
+                        (self.pixel_index_in_row as i16 - sprite_x_screen).clamp(0, 8) as usize
+                    }
+                    None => 0,
+                };
+                let visible_columns = 8 - hidden_columns;
+                // Pad with transparent pixels so that the sprite can be merged in.  Pixels past the
+                // right edge of the screen are simply never popped.
+                while self.fifo.len() < visible_columns {
+                    self.fifo.push_back(TRANSPARENT_PIXEL);
+                }
                 // Object FIFO pixels are merged with existing object FIFO pixels:
-                // Those with ID 0 are overwritten by latter ones, otherwise the existing one wins
-                for i in 0..8 {
-                    if i < obj_fifo_len {
-                        // Pixel merging following OBJ-to-OBJ priority
-                        let old_item = self.fifo[i].clone();
-                        if old_item.color == 0 {
-                            self.fifo[i] = ObjectFIFOItem {
-                                color: self.tile_row_data[i],
-                                palette: palette_for_sprite(self.sprite.as_ref()),
-                            };
-                        }
-                    } else {
-                        // No pixel to merge with, just push
-                        let color = self.tile_row_data[i];
-                        self.fifo.push_back(ObjectFIFOItem {
-                            color,
-                            palette: palette_for_sprite(self.sprite.as_ref()),
-                        });
+                // Those with ID 0 are overwritten by latter ones, otherwise the existing one wins.
+                // Since sprites are fetched by decreasing priority, this is the DMG priority rule.
+                for i in 0..visible_columns {
+                    if self.fifo[i].color == 0 {
+                        self.fifo[i] = ObjectFIFOItem {
+                            color: self.tile_row_data[hidden_columns + i],
+                            palette: palette_for_sprite(sprite.as_ref()),
+                            cgb_palette: cgb_palette_for_sprite(sprite.as_ref()),
+                            background_has_priority: background_has_priority(sprite.as_ref()),
+                        };
                     }
                 }
                 // clean up so that GetTileData can assume 0
                 self.tile_row_data = [0; 8];
-                self.state = FetcherState::GetTileDelay
+                self.state = FetcherState::GetTile
             }
         }
     }
@@ -171,11 +238,27 @@ impl ObjectFetcher {
 
 fn palette_for_sprite(sprite: Option<&Sprite>) -> ObjectPalette {
     match sprite {
-        Some(sprite) => match (sprite.attributes >> 4) & 1 {
-            0b0 => ObjectPalette::ObjectPalette0,
-            0b1 => ObjectPalette::ObjectPalette1,
-            _ => unreachable!(),
-        },
+        Some(sprite) => {
+            if sprite.is_attribute_set(OBJECT_PALETTE_BIT) {
+                ObjectPalette::ObjectPalette1
+            } else {
+                ObjectPalette::ObjectPalette0
+            }
+        }
         None => ObjectPalette::ObjectPalette0, // does not matter
     }
 }
+
+fn cgb_palette_for_sprite(sprite: Option<&Sprite>) -> u8 {
+    match sprite {
+        Some(sprite) => sprite.attributes & OBJECT_CGB_PALETTE_MASK,
+        None => 0, // does not matter
+    }
+}
+
+fn background_has_priority(sprite: Option<&Sprite>) -> bool {
+    match sprite {
+        Some(sprite) => sprite.is_attribute_set(OBJECT_PRIORITY_BIT),
+        None => false, // does not matter
+    }
+}
@@ -1,11 +1,15 @@
-use std::{collections::VecDeque, num::Wrapping};
+use std::{
+    collections::VecDeque,
+    hash::{Hash, Hasher},
+    num::Wrapping,
+};
 
 use crate::{
     cpu::interrupts::{Interrupts, STAT_INTERRUPT_BIT, VBLANK_INTERRUPT_BIT},
     pixel_fetcher::{
         background_or_window::BackgroundOrWindowFetcher,
         get_tile_index_in_palette,
-        object::{ObjectFetcher, ObjectPalette, Sprite},
+        object::{ObjectFetcher, ObjectPalette, Sprite, TRANSPARENT_PIXEL},
         Fetcher, FetchingFor, TileAddressingMode,
     },
     utils::{self},
@@ -16,10 +20,9 @@ const TILE_MAP1_VRAM_OFFSET: usize = 0x1C00;
 
 const OAM_SIZE: usize = 0xA0;
 const VRAM_SIZE: usize = 0x2000;
-const WRAM_SIZE: usize = 0x1000;
 
-const LCD_HORIZONTAL_PIXEL_COUNT: usize = 160;
-const LCD_VERTICAL_PIXEL_COUNT: usize = 144;
+pub const LCD_HORIZONTAL_PIXEL_COUNT: usize = 160;
+pub const LCD_VERTICAL_PIXEL_COUNT: usize = 144;
 
 pub const HORIZONTAL_PIXELS_PER_TILE: usize = 8;
 pub const VERTICAL_PIXELS_PER_TILE: usize = 8;
@@ -33,6 +36,8 @@ pub const TILE_PALETTE_VERTICAL_PIXELS: usize =
     TILE_PALETTE_VERTICAL_TILE_COUNT * VERTICAL_PIXELS_PER_TILE;
 pub const TILE_PALETTE_PIXELS_TOTAL: usize =
     TILE_PALETTE_HORIZONTAL_PIXELS * TILE_PALETTE_VERTICAL_PIXELS;
+const TILE_PALETTE_TILE_TOTAL: usize =
+    TILE_PALETTE_HORIZONTAL_TILE_COUNT * TILE_PALETTE_VERTICAL_TILE_COUNT;
 
 pub const TILE_MAP_HORIZONTAL_TILE_COUNT: usize = 32;
 pub const TILE_MAP_VERTICAL_TILE_COUNT: usize = 32;
@@ -44,26 +49,74 @@ const TILE_MAP_PIXELS_TOTAL: usize = TILE_MAP_HORIZONTAL_PIXELS * TILE_MAP_VERTI
 
 const PIXEL_DATA_SIZE: usize = 4; // 4-bytes for R, G, B, A
 
+const CGB_PALETTE_MEMORY_SIZE: usize = 64;
+
 // LCD control single bits of interest
-const _LCDC_BACKGROUND_AND_WINDOW_ENABLE_BIT: u8 = 0;
-const _LCDC_OBJECT_ENABLE_BIT: u8 = 1;
-const _LCDC_OBJECT_SIZE_BIT: u8 = 2;
+const LCDC_BACKGROUND_AND_WINDOW_ENABLE_BIT: u8 = 0;
+const LCDC_OBJECT_ENABLE_BIT: u8 = 1;
+const LCDC_OBJECT_SIZE_BIT: u8 = 2;
 pub const LCDC_BACKGROUND_TILE_MAP_AREA_BIT: u8 = 3;
 const LCDC_BACKGROUND_AND_WINDOW_TILE_AREA_BIT: u8 = 4;
-const _LCDC_WINDOW_ENABLE_BIT: u8 = 5;
-const _LCDC_WINDOW_TILE_MAP_AREA_BIT: u8 = 6;
+const LCDC_WINDOW_ENABLE_BIT: u8 = 5;
+pub const LCDC_WINDOW_TILE_MAP_AREA_BIT: u8 = 6;
 const LCDC_LCD_ENABLE_BIT: u8 = 7;
 
 // LCD status single bits of interest
+const STAT_MODE_MASK: u8 = 0b11;
+const STAT_READ_ONLY_MASK: u8 = 0b111;
 const LYC_EQUALS_LY_BIT: u8 = 2;
 const MODE_0_INTERRUPT_SELECT_BIT: u8 = 3;
 const MODE_1_INTERRUPT_SELECT_BIT: u8 = 4;
 const MODE_2_INTERRUPT_SELECT_BIT: u8 = 5;
 const LYC_EQUALS_LY_INTERRUPT_SELECT_BIT: u8 = 6;
 
-#[derive(Clone, Debug)]
+const OAM_SCAN_DOTS: u16 = 80;
+const LAST_SCANLINE: u8 = 153;
+const LAST_SCANLINE_LY_DOTS: u16 = 4;
+const DISCARDED_FETCH_DOTS: u16 = 6;
+
+// The palette used to show tiles in the debug views.  Raw shows color codes as they are, which
+// helps when the palettes are all white, as they are during boot.
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
+pub enum TilePaletteSelection {
+    Background,
+    Object0,
+    Object1,
+    Raw,
+}
+
+// Color codes 0 to 3 map to shades 0 to 3
+const IDENTITY_PALETTE: u8 = 0b11_10_01_00;
+
+// A cell of one of the two tile maps, as pointed at in the debugger.
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
+pub struct TileMapCell {
+    pub tile_map: u8,
+    pub column: u8,
+    pub row: u8,
+}
+
+impl TileMapCell {
+    pub fn index_in_tile_map(&self) -> usize {
+        self.row as usize * TILE_MAP_HORIZONTAL_TILE_COUNT + self.column as usize
+    }
+
+    // Where the tile id (and in CGB mode, the attributes) of this cell live in VRAM
+    pub fn vram_offset(&self) -> usize {
+        let tile_map_vram_offset = if self.tile_map == 0 {
+            TILE_MAP0_VRAM_OFFSET
+        } else {
+            TILE_MAP1_VRAM_OFFSET
+        };
+        tile_map_vram_offset + self.index_in_tile_map()
+    }
+}
+
+#[derive(Clone, Debug, Hash)]
 pub enum PPUState {
     OAMScan,
+    /// Holds how many background pixels are still to be discarded for fine scrolling.  SCX % 8 is
+    /// latched when entering mode 3, so later writes to SCX do not change it for this scanline.
     DrawingPixels(u8),
     HorizontalBlank,
     VerticalBlank,
@@ -74,24 +127,42 @@ pub struct PPU {
     /** PPU state **/
     drawn_pixels_on_current_row: u8,
     fix_ly_for_gb_doctor: bool,
+    /// Panic when a scanline does not finish drawing in time, rather than recovering from it.
+    is_strict: bool,
+    /// Enables the CGB-only hardware (VRAM bank 1, attributes, palettes).  Always off for DMG
+    /// games, which must not see any of it.
+    pub is_cgb_mode: bool,
     /// Because the STAT interrupt is triggered on a rising edge of the STAT line, we need to
     /// remember its previous value.
-    last_stat_line: u8,
+    last_stat_line: bool,
     scanline_dots: u16,
+    /// How many dots the last mode 3 lasted.  It depends on fine scrolling, the window and
+    /// sprites, and HBlank gets shorter to compensate.
+    mode_3_length: u16,
+    /// How many scanlines were still drawing when they should have ended, and had to be completed
+    /// by force.  This always points at a bug in the pixel pipeline.
+    stalled_scanline_count: u64,
+    /// How many frames made it to `front_buffer` through VBlank, lets headless runs wait for one.
+    completed_frame_count: u64,
     state: PPUState,
+    /// Set once LY has been equal to WY during the current frame, the window only shows up after.
+    is_window_y_reached: bool,
+    /// The row of the window to draw next.  It only advances on scanlines where the window was
+    /// drawn, so that the window picks up where it left off if it gets hidden for a few scanlines.
+    window_line_counter: u8,
 
     // Hardware registers
     pub background_palette_data: u8,
-    pub cgb_background_palette_data: Wrapping<u8>,
-    pub cgb_background_palette_spec: Wrapping<u8>,
+    pub cgb_background_palettes: CGBPaletteMemory,
+    pub cgb_object_palettes: CGBPaletteMemory,
     pub lcd_control: Wrapping<u8>,
-    pub lcd_status: Wrapping<u8>,
+    /// LCD status.  Made private so that writes go through `write_stat()`, which protects the bits
+    /// owned by the PPU.
+    lcd_status: Wrapping<u8>,
     pub lcd_y_compare: Wrapping<u8>,
     /// LCD Y-coordinate.  Made private to enforce the use of `read_ly()` which allows forcing LY's
     /// value when using GB Doctor.
     lcd_y_coord: Wrapping<u8>,
-    pub object_palette_data: Wrapping<u8>,
-    pub object_palette_spec: Wrapping<u8>,
     pub object_palette_0: u8,
     pub object_palette_1: u8,
     pub scx: Wrapping<u8>,
@@ -103,23 +174,44 @@ pub struct PPU {
     // Hardware banks
     pub object_attribute_memory: [u8; OAM_SIZE], // TODO: make private?
     pub vram: [u8; VRAM_SIZE],
-    wram_0: [u8; WRAM_SIZE],
-    wram_1: [u8; WRAM_SIZE],
-
-    // Rendered pixel surfaces
-    pub lcd_pixels: [u8; LCD_HORIZONTAL_PIXEL_COUNT * LCD_VERTICAL_PIXEL_COUNT * PIXEL_DATA_SIZE],
-    pub tile_map0_pixels: [u8; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
-    pub tile_map1_pixels: [u8; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
-    pub tile_palette_pixels: [u8; TILE_PALETTE_PIXELS_TOTAL * PIXEL_DATA_SIZE],
+    /// CGB only, holds more tile data, as well as the attributes of the tiles in the tile maps.
+    pub vram_bank_1: [u8; VRAM_SIZE],
+
+    // Rendered pixel surfaces, on the heap since they add up to more than a megabyte
+    /// The frame being drawn, dot by dot.  Made private so that the GUI shows `front_buffer`
+    /// instead, which only ever holds complete frames.
+    lcd_pixels: Box<[u8]>,
+    /// The last complete frame, updated when entering VBlank.
+    pub front_buffer: Box<[u8]>,
+    pub tile_map0_pixels: Box<[u8]>,
+    pub tile_map1_pixels: Box<[u8]>,
+    /// The tile maps without the outlines drawn over them
+    tile_map0_base_pixels: Box<[u8]>,
+    tile_map1_base_pixels: Box<[u8]>,
+    pub tile_palette_pixels: Box<[u8]>,
+    /// The same tiles through BGP, whatever the tile palette shows, for the tile maps to copy from
+    background_tile_pixels: Box<[u8]>,
 
     // Transient state saved for debug view purposes
     frame_scxs: [u8; LCD_VERTICAL_PIXEL_COUNT],
     frame_scxs_valid: [bool; LCD_VERTICAL_PIXEL_COUNT],
     frame_scys_at_scanline_0: [u8; LCD_HORIZONTAL_PIXEL_COUNT],
     frame_scys_first_scanline_valid: [bool; LCD_HORIZONTAL_PIXEL_COUNT],
-    // TODO: make this private? move it to pixel fetcher?
-    pub tile_map0_last_addressing_modes: [TileAddressingMode; TILE_MAP_TILE_TOTAL],
-    pub tile_map1_last_addressing_modes: [TileAddressingMode; TILE_MAP_TILE_TOTAL],
+    // TODO: move it to pixel fetcher?
+    tile_map0_last_addressing_modes: [TileAddressingMode; TILE_MAP_TILE_TOTAL],
+    tile_map1_last_addressing_modes: [TileAddressingMode; TILE_MAP_TILE_TOTAL],
+
+    // Dirty tracking for the debug views, so that only what changed since they were last rendered
+    // gets rendered again
+    dirty_tiles: [bool; TILE_PALETTE_TILE_TOTAL],
+    rendered_tile_palette: u8,
+    dirty_background_tiles: [bool; TILE_PALETTE_TILE_TOTAL],
+    rendered_background_palette: u8,
+    tile_map0_dirty_cells: [bool; TILE_MAP_TILE_TOTAL],
+    tile_map1_dirty_cells: [bool; TILE_MAP_TILE_TOTAL],
+    /// Tiles repainted in BGP colors since each tile map last copied from them
+    tile_map0_stale_tiles: [bool; TILE_PALETTE_TILE_TOTAL],
+    tile_map1_stale_tiles: [bool; TILE_PALETTE_TILE_TOTAL],
 }
 
 const BLACK: [u8; 4] = [0, 0, 0, 255];
@@ -127,6 +219,9 @@ const DARK_GRAY: [u8; 4] = [0x55, 0x55, 0x55, 255];
 const LIGHT_GRAY: [u8; 4] = [0xAA, 0xAA, 0xAA, 255];
 const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 255];
 
+const VIEWPORT_OUTLINE_COLOR: [u8; 4] = [255, 0, 0, 255];
+const WINDOW_OUTLINE_COLOR: [u8; 4] = [0, 0, 255, 255];
+
 pub fn pixel_code_to_rgba(pixel_code: u8, palette: u8) -> [u8; PIXEL_DATA_SIZE] {
     let pixel_shade = match pixel_code {
         0b00 => palette & 0b11,
@@ -144,31 +239,133 @@ pub fn pixel_code_to_rgba(pixel_code: u8, palette: u8) -> [u8; PIXEL_DATA_SIZE]
     }
 }
 
+// CGB colors are RGB555, each 5-bit channel gets scaled to the full 8-bit range.
+pub fn cgb_color_to_rgba(color: u16) -> [u8; PIXEL_DATA_SIZE] {
+    let scale = |channel: u16| {
+        let channel = (channel & 0x1F) as u8;
+        (channel << 3) | (channel >> 2)
+    };
+    [scale(color), scale(color >> 5), scale(color >> 10), 255]
+}
+
+// CGB palette memory is only reachable through an index register (BCPS/OCPS), which can
+// auto-increment after each write to the data register (BCPD/OCPD).  It holds 8 palettes of 4
+// colors, each color taking 2 bytes (little-endian).
+#[derive(Clone, Debug, Hash)]
+pub struct CGBPaletteMemory {
+    index: u8,
+    auto_increment: bool,
+    memory: [u8; CGB_PALETTE_MEMORY_SIZE],
+}
+
+impl CGBPaletteMemory {
+    pub fn new() -> Self {
+        CGBPaletteMemory {
+            index: 0,
+            auto_increment: false,
+            memory: [0; CGB_PALETTE_MEMORY_SIZE],
+        }
+    }
+
+    // Bit 6 is unused and reads as 1
+    pub fn read_spec(&self) -> Wrapping<u8> {
+        Wrapping(((self.auto_increment as u8) << 7) | 0x40 | self.index)
+    }
+
+    pub fn write_spec(&mut self, value: Wrapping<u8>) {
+        self.index = value.0 & 0x3F;
+        self.auto_increment = value.0 & 0x80 != 0;
+    }
+
+    pub fn read_data(&self) -> Wrapping<u8> {
+        Wrapping(self.memory[self.index as usize])
+    }
+
+    // Only writes increment the index, reads do not.
+    pub fn write_data(&mut self, value: Wrapping<u8>) {
+        self.memory[self.index as usize] = value.0;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    pub fn color_to_rgba(&self, palette: u8, pixel_code: u8) -> [u8; PIXEL_DATA_SIZE] {
+        let address = (palette as usize * 4 + pixel_code as usize) * 2;
+        cgb_color_to_rgba(u16::from_le_bytes([
+            self.memory[address],
+            self.memory[address + 1],
+        ]))
+    }
+}
+
 // Each pixel takes 4 bytes (R, G, B, A).  Each y results in 160 pixels.
 pub fn pixel_coordinates_in_rgba_slice(x: u8, y: u8) -> usize {
     (y as usize * LCD_HORIZONTAL_PIXEL_COUNT + x as usize) * PIXEL_DATA_SIZE
 }
 
+// Only the state the game can observe, or that decides what it observes later.  The debug views
+// and their bookkeeping are left out, they depend on what the GUI chose to render.
+impl Hash for PPU {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.drawn_pixels_on_current_row.hash(state);
+        self.is_cgb_mode.hash(state);
+        self.last_stat_line.hash(state);
+        self.scanline_dots.hash(state);
+        self.mode_3_length.hash(state);
+        self.completed_frame_count.hash(state);
+        self.state.hash(state);
+        self.is_window_y_reached.hash(state);
+        self.window_line_counter.hash(state);
+
+        self.background_palette_data.hash(state);
+        self.cgb_background_palettes.hash(state);
+        self.cgb_object_palettes.hash(state);
+        self.lcd_control.hash(state);
+        self.lcd_status.hash(state);
+        self.lcd_y_compare.hash(state);
+        self.lcd_y_coord.hash(state);
+        self.object_palette_0.hash(state);
+        self.object_palette_1.hash(state);
+        self.scx.hash(state);
+        self.scy.hash(state);
+        self.vram_bank.hash(state);
+        self.window_x7.hash(state);
+        self.window_y.hash(state);
+
+        self.object_attribute_memory.hash(state);
+        self.vram.hash(state);
+        self.vram_bank_1.hash(state);
+
+        self.lcd_pixels.hash(state);
+        self.front_buffer.hash(state);
+    }
+}
+
 impl PPU {
-    pub fn new(fix_ly: bool) -> Self {
+    pub fn new(fix_ly: bool, is_cgb_mode: bool, is_strict: bool) -> Self {
         PPU {
             drawn_pixels_on_current_row: 0,
             fix_ly_for_gb_doctor: fix_ly,
-            last_stat_line: 0,
+            is_strict,
+            is_cgb_mode,
+            last_stat_line: false,
             scanline_dots: 0,
+            mode_3_length: 0,
+            stalled_scanline_count: 0,
+            completed_frame_count: 0,
             state: PPUState::OAMScan,
+            is_window_y_reached: false,
+            window_line_counter: 0,
 
             background_palette_data: 0,
-            cgb_background_palette_spec: Wrapping(0),
-            cgb_background_palette_data: Wrapping(0),
+            cgb_background_palettes: CGBPaletteMemory::new(),
+            cgb_object_palettes: CGBPaletteMemory::new(),
             lcd_control: Wrapping(0),
             lcd_status: Wrapping(2), // initially set Mode 2
             lcd_y_compare: Wrapping(0),
             lcd_y_coord: Wrapping(0),
-            object_palette_data: Wrapping(0),
             object_palette_0: 0,
             object_palette_1: 0,
-            object_palette_spec: Wrapping(0),
             scx: Wrapping(0),
             scy: Wrapping(0),
             vram_bank: Wrapping(0),
@@ -177,15 +374,30 @@ impl PPU {
 
             object_attribute_memory: [0; OAM_SIZE],
             vram: [0; VRAM_SIZE],
-            wram_0: [0; WRAM_SIZE],
-            wram_1: [0; WRAM_SIZE],
-
-            lcd_pixels: [0; LCD_HORIZONTAL_PIXEL_COUNT
-                * LCD_VERTICAL_PIXEL_COUNT
-                * PIXEL_DATA_SIZE],
-            tile_map0_pixels: [0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
-            tile_map1_pixels: [0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE],
-            tile_palette_pixels: [0; TILE_PALETTE_PIXELS_TOTAL * PIXEL_DATA_SIZE],
+            vram_bank_1: [0; VRAM_SIZE],
+
+            lcd_pixels: vec![
+                0;
+                LCD_HORIZONTAL_PIXEL_COUNT * LCD_VERTICAL_PIXEL_COUNT * PIXEL_DATA_SIZE
+            ]
+            .into_boxed_slice(),
+            front_buffer: vec![
+                0;
+                LCD_HORIZONTAL_PIXEL_COUNT
+                    * LCD_VERTICAL_PIXEL_COUNT
+                    * PIXEL_DATA_SIZE
+            ]
+            .into_boxed_slice(),
+            tile_map0_pixels: vec![0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE].into_boxed_slice(),
+            tile_map1_pixels: vec![0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE].into_boxed_slice(),
+            tile_map0_base_pixels: vec![0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE]
+                .into_boxed_slice(),
+            tile_map1_base_pixels: vec![0; TILE_MAP_PIXELS_TOTAL * PIXEL_DATA_SIZE]
+                .into_boxed_slice(),
+            tile_palette_pixels: vec![0; TILE_PALETTE_PIXELS_TOTAL * PIXEL_DATA_SIZE]
+                .into_boxed_slice(),
+            background_tile_pixels: vec![0; TILE_PALETTE_PIXELS_TOTAL * PIXEL_DATA_SIZE]
+                .into_boxed_slice(),
 
             frame_scxs: [0; LCD_VERTICAL_PIXEL_COUNT],
             frame_scxs_valid: [true; LCD_VERTICAL_PIXEL_COUNT],
@@ -195,6 +407,15 @@ impl PPU {
                 TILE_MAP_TILE_TOTAL],
             tile_map1_last_addressing_modes: [TileAddressingMode::UnsignedFrom0x8000;
                 TILE_MAP_TILE_TOTAL],
+
+            dirty_tiles: [true; TILE_PALETTE_TILE_TOTAL],
+            rendered_tile_palette: 0,
+            dirty_background_tiles: [true; TILE_PALETTE_TILE_TOTAL],
+            rendered_background_palette: 0,
+            tile_map0_dirty_cells: [true; TILE_MAP_TILE_TOTAL],
+            tile_map1_dirty_cells: [true; TILE_MAP_TILE_TOTAL],
+            tile_map0_stale_tiles: [false; TILE_PALETTE_TILE_TOTAL],
+            tile_map1_stale_tiles: [false; TILE_PALETTE_TILE_TOTAL],
         }
     }
 
@@ -206,22 +427,96 @@ impl PPU {
         }
     }
 
+    pub fn is_background_and_window_enabled(&self) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_BACKGROUND_AND_WINDOW_ENABLE_BIT)
+    }
+
+    pub fn are_objects_enabled(&self) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_OBJECT_ENABLE_BIT)
+    }
+
+    // Objects are either 8x8 or 8x16 pixels
+    pub fn object_height(&self) -> u8 {
+        if utils::is_bit_set(&self.lcd_control, LCDC_OBJECT_SIZE_BIT) {
+            16
+        } else {
+            8
+        }
+    }
+
     pub fn is_lcd_ppu_on(&self) -> bool {
         utils::is_bit_set(&self.lcd_control, LCDC_LCD_ENABLE_BIT)
     }
 
-    pub fn increment_ly(&mut self, interrupts: &mut Interrupts) {
+    pub fn increment_ly(&mut self) {
         self.lcd_y_coord = self.lcd_y_coord + Wrapping(1);
+        self.compare_ly();
+    }
+
+    // Must be called whenever either LY or LYC changes, the coincidence flag is not latched.
+    fn compare_ly(&mut self) {
         if self.lcd_y_coord == self.lcd_y_compare {
             utils::set_bit(&mut self.lcd_status, LYC_EQUALS_LY_BIT);
-            if utils::is_bit_set(&self.lcd_status, LYC_EQUALS_LY_INTERRUPT_SELECT_BIT) {
-                interrupts.request(STAT_INTERRUPT_BIT);
-            }
         } else {
             utils::unset_bit(&mut self.lcd_status, LYC_EQUALS_LY_BIT);
         }
     }
 
+    pub fn window_line_counter(&self) -> u8 {
+        self.window_line_counter
+    }
+
+    pub fn mode(&self) -> u8 {
+        match self.state {
+            PPUState::HorizontalBlank => 0,
+            PPUState::VerticalBlank => 1,
+            PPUState::OAMScan => 2,
+            PPUState::DrawingPixels(_) => 3,
+        }
+    }
+
+    // The CPU cannot access VRAM while the PPU is drawing pixels out of it.
+    pub fn is_vram_accessible(&self) -> bool {
+        !self.is_lcd_ppu_on() || self.mode() != 3
+    }
+
+    // The CPU cannot access OAM while the PPU is scanning it or drawing sprites out of it.
+    pub fn is_oam_accessible(&self) -> bool {
+        !self.is_lcd_ppu_on() || self.mode() < 2
+    }
+
+    // The frame being drawn, only meant for debugging, see `front_buffer`.
+    pub fn lcd_pixels(&self) -> &[u8] {
+        &self.lcd_pixels
+    }
+
+    pub fn drawn_pixels_on_current_row(&self) -> u8 {
+        self.drawn_pixels_on_current_row
+    }
+
+    pub fn scanline_dots(&self) -> u16 {
+        self.scanline_dots
+    }
+
+    pub fn mode_3_length(&self) -> u16 {
+        self.mode_3_length
+    }
+
+    pub fn stalled_scanline_count(&self) -> u64 {
+        self.stalled_scanline_count
+    }
+
+    pub fn completed_frame_count(&self) -> u64 {
+        self.completed_frame_count
+    }
+
+    // WX holds the window's X position plus 7, so the window can never show when WX >= 167.
+    fn is_window_reached(&self, x: u8) -> bool {
+        utils::is_bit_set(&self.lcd_control, LCDC_WINDOW_ENABLE_BIT)
+            && self.is_window_y_reached
+            && x as u16 + 7 >= self.window_x7.0 as u16
+    }
+
     pub fn read_ly(&self) -> Wrapping<u8> {
         if self.fix_ly_for_gb_doctor {
             Wrapping(144)
@@ -230,96 +525,218 @@ impl PPU {
         }
     }
 
-    // TODO: Eventually we could update on the fly on writes
-    pub fn render_tile_palette(&mut self) {
-        for tile_palette_y in 0..TILE_PALETTE_VERTICAL_TILE_COUNT {
-            for tile_palette_x in 0..TILE_PALETTE_HORIZONTAL_TILE_COUNT {
-                let tile_data_from = (tile_palette_y * 16 + tile_palette_x) * 16;
-                let tile_data = &self.vram[tile_data_from..tile_data_from + 16];
-                for tile_pixel_y in 0..VERTICAL_PIXELS_PER_TILE {
-                    let row_data_from = tile_pixel_y * 2;
-                    let low_bits = tile_data[row_data_from];
-                    let high_bits = tile_data[row_data_from + 1];
-                    for tile_pixel_x in 0..HORIZONTAL_PIXELS_PER_TILE {
-                        let pixel_code = (((high_bits >> (7 - tile_pixel_x)) & 1) << 1)
-                            | ((low_bits >> (7 - tile_pixel_x)) & 1);
-                        let pixel_rgba =
-                            pixel_code_to_rgba(pixel_code, self.background_palette_data);
-                        let vram_pixel_x = tile_palette_x * 8 + tile_pixel_x;
-                        let vram_pixel_y = tile_palette_y * 8 + tile_pixel_y;
-                        let vram_pixels_from =
-                            (vram_pixel_y * TILE_PALETTE_HORIZONTAL_PIXELS + vram_pixel_x) * 4;
-                        self.tile_palette_pixels[vram_pixels_from..vram_pixels_from + 4]
-                            .copy_from_slice(&pixel_rgba);
-                    }
-                }
+    // Remembers how the fetcher decoded a tile map cell, so that the tile map views can match it.
+    pub fn remember_addressing_mode(
+        &mut self,
+        tile_map: u8,
+        tile_index_in_tile_map: usize,
+        addressing_mode: TileAddressingMode,
+    ) {
+        let (last_addressing_modes, dirty_cells) = if tile_map == 0 {
+            (
+                &mut self.tile_map0_last_addressing_modes,
+                &mut self.tile_map0_dirty_cells,
+            )
+        } else {
+            (
+                &mut self.tile_map1_last_addressing_modes,
+                &mut self.tile_map1_dirty_cells,
+            )
+        };
+        if last_addressing_modes[tile_index_in_tile_map] != addressing_mode {
+            last_addressing_modes[tile_index_in_tile_map] = addressing_mode;
+            dirty_cells[tile_index_in_tile_map] = true;
+        }
+    }
+
+    pub fn tile_palette(&self, selection: TilePaletteSelection) -> u8 {
+        match selection {
+            TilePaletteSelection::Background => self.background_palette_data,
+            TilePaletteSelection::Object0 => self.object_palette_0,
+            TilePaletteSelection::Object1 => self.object_palette_1,
+            TilePaletteSelection::Raw => IDENTITY_PALETTE,
+        }
+    }
+
+    // How the fetcher last decoded the tile id of this cell
+    pub fn tile_map_cell_addressing_mode(&self, cell: TileMapCell) -> TileAddressingMode {
+        if cell.tile_map == 0 {
+            self.tile_map0_last_addressing_modes[cell.index_in_tile_map()]
+        } else {
+            self.tile_map1_last_addressing_modes[cell.index_in_tile_map()]
+        }
+    }
+
+    // Only the tiles written to since the last call get repainted, unless the palette changed,
+    // since every tile goes through it.
+    pub fn render_tile_palette(&mut self, selection: TilePaletteSelection) {
+        let palette = self.tile_palette(selection);
+        if palette != self.rendered_tile_palette {
+            self.dirty_tiles = [true; TILE_PALETTE_TILE_TOTAL];
+            self.rendered_tile_palette = palette;
+        }
+        render_tiles(
+            &self.vram,
+            palette,
+            &mut self.dirty_tiles,
+            &mut self.tile_palette_pixels,
+        );
+    }
+
+    // The tile maps show the tiles the way the background would, so they get their own copy of the
+    // tiles in BGP colors, and the tile palette selection does not affect them.
+    fn render_background_tiles(&mut self) {
+        let palette = self.background_palette_data;
+        if palette != self.rendered_background_palette {
+            self.dirty_background_tiles = [true; TILE_PALETTE_TILE_TOTAL];
+            self.rendered_background_palette = palette;
+        }
+        for (tile_index, is_dirty) in self.dirty_background_tiles.iter().enumerate() {
+            if *is_dirty {
+                self.tile_map0_stale_tiles[tile_index] = true;
+                self.tile_map1_stale_tiles[tile_index] = true;
             }
         }
+        render_tiles(
+            &self.vram,
+            palette,
+            &mut self.dirty_background_tiles,
+            &mut self.background_tile_pixels,
+        );
+    }
+
+    // Which tile map the background uses, 0 for the one at 0x9800, 1 for the one at 0x9C00
+    pub fn background_tile_map(&self) -> u8 {
+        utils::is_bit_set(&self.lcd_control, LCDC_BACKGROUND_TILE_MAP_AREA_BIT) as u8
+    }
+
+    // Which tile map the window uses, 0 for the one at 0x9800, 1 for the one at 0x9C00
+    pub fn window_tile_map(&self) -> u8 {
+        utils::is_bit_set(&self.lcd_control, LCDC_WINDOW_TILE_MAP_AREA_BIT) as u8
     }
 
-    // NOTE: Assumes the tile palette has been rendered first
     pub fn render_tile_map0(&mut self) {
+        self.render_background_tiles();
         render_tile_map(
             &self.vram,
-            &self.tile_palette_pixels,
-            &mut self.tile_map0_pixels,
+            &self.background_tile_pixels,
+            &mut self.tile_map0_base_pixels,
             TILE_MAP0_VRAM_OFFSET,
             &self.tile_map0_last_addressing_modes,
+            &mut self.tile_map0_dirty_cells,
+            &mut self.tile_map0_stale_tiles,
+        );
+        self.tile_map0_pixels
+            .copy_from_slice(&self.tile_map0_base_pixels);
+        self.render_outlines(0);
+    }
+
+    pub fn render_tile_map1(&mut self) {
+        self.render_background_tiles();
+        render_tile_map(
+            &self.vram,
+            &self.background_tile_pixels,
+            &mut self.tile_map1_base_pixels,
+            TILE_MAP1_VRAM_OFFSET,
+            &self.tile_map1_last_addressing_modes,
+            &mut self.tile_map1_dirty_cells,
+            &mut self.tile_map1_stale_tiles,
         );
+        self.tile_map1_pixels
+            .copy_from_slice(&self.tile_map1_base_pixels);
+        self.render_outlines(1);
+    }
 
-        // Render the top and bottom SCY lines, where they haven't been messed with mid-frame
+    // Points of the tile map, before wrapping, outlining the area of the background that was on
+    // screen during the frame.  The left and right edges follow SCX scanline by scanline, and the
+    // top and bottom edges follow SCY pixel by pixel, as long as they were not messed with
+    // mid-frame.
+    fn viewport_outline(&self) -> Vec<(usize, usize)> {
+        let mut outline = Vec::new();
+        let scy_left = self.frame_scys_at_scanline_0[0] as usize;
+        for y in 0..LCD_VERTICAL_PIXEL_COUNT {
+            if self.frame_scxs_valid[y] {
+                let scx = self.frame_scxs[y] as usize;
+                outline.push((scx, scy_left + y));
+                outline.push((scx + LCD_HORIZONTAL_PIXEL_COUNT - 1, scy_left + y));
+            }
+        }
         let scx_top = self.frame_scxs[0] as usize;
-        let scx_bot = self.frame_scxs[LCD_VERTICAL_PIXEL_COUNT - 1] as usize;
-        for y in 0..LCD_HORIZONTAL_PIXEL_COUNT {
-            if self.frame_scys_first_scanline_valid[y] {
-                let scy = self.frame_scys_at_scanline_0[y] as usize;
-                let pixel_index =
-                    scy * TILE_MAP_HORIZONTAL_PIXELS + ((y + scx_top) % TILE_MAP_HORIZONTAL_PIXELS);
-                self.tile_map0_pixels[pixel_index * 4..(pixel_index + 1) * 4]
-                    .copy_from_slice(&[255, 0, 0, 255]);
-                let pixel_index = ((scy + LCD_VERTICAL_PIXEL_COUNT) % TILE_MAP_VERTICAL_PIXELS)
-                    * TILE_MAP_HORIZONTAL_PIXELS
-                    + ((y + scx_bot) % TILE_MAP_HORIZONTAL_PIXELS);
-                self.tile_map0_pixels[pixel_index * 4..(pixel_index + 1) * 4]
-                    .copy_from_slice(&[255, 255, 0, 255]);
+        let scx_bottom = self.frame_scxs[LCD_VERTICAL_PIXEL_COUNT - 1] as usize;
+        for x in 0..LCD_HORIZONTAL_PIXEL_COUNT {
+            if self.frame_scys_first_scanline_valid[x] {
+                let scy = self.frame_scys_at_scanline_0[x] as usize;
+                outline.push((scx_top + x, scy));
+                outline.push((scx_bottom + x, scy + LCD_VERTICAL_PIXEL_COUNT - 1));
             }
         }
+        outline
+    }
 
-        // Render the left and right SCY lines, where they haven't been messed with mid-frame
-        let scy_left = self.frame_scys_at_scanline_0[0] as usize;
-        let scy_right = self.frame_scys_at_scanline_0[LCD_HORIZONTAL_PIXEL_COUNT - 1] as usize;
-        for x in 0..LCD_VERTICAL_PIXEL_COUNT {
-            if self.frame_scxs_valid[x] {
-                let scx = self.frame_scxs[x] as usize;
-                let pixel_index =
-                    ((x + scy_left) % TILE_MAP_VERTICAL_PIXELS) * TILE_MAP_HORIZONTAL_PIXELS + scx;
-                self.tile_map0_pixels[pixel_index * 4..(pixel_index + 1) * 4]
-                    .copy_from_slice(&[0, 255, 0, 255]);
-                let pixel_index = ((x + scy_right) % TILE_MAP_VERTICAL_PIXELS)
-                    * TILE_MAP_HORIZONTAL_PIXELS
-                    + ((scx + LCD_HORIZONTAL_PIXEL_COUNT) % TILE_MAP_HORIZONTAL_PIXELS);
-                self.tile_map0_pixels[pixel_index * 4..(pixel_index + 1) * 4]
-                    .copy_from_slice(&[0, 255, 255, 255]);
-            }
+    // Points of the tile map outlining the area of the window on screen.  The window always shows
+    // its tile map from the top-left corner, and extends to the bottom-right of the screen.
+    fn window_outline(&self) -> Vec<(usize, usize)> {
+        let mut outline = Vec::new();
+        let window_x7 = self.window_x7.0 as usize;
+        let window_y = self.window_y.0 as usize;
+        if window_x7 >= LCD_HORIZONTAL_PIXEL_COUNT + 7 || window_y >= LCD_VERTICAL_PIXEL_COUNT {
+            return outline;
+        }
+        // When WX < 7, the leftmost columns of the window are off-screen
+        let left = 7_usize.saturating_sub(window_x7);
+        let right = LCD_HORIZONTAL_PIXEL_COUNT + 6 - window_x7;
+        let bottom = LCD_VERTICAL_PIXEL_COUNT - 1 - window_y;
+        for x in left..=right {
+            outline.push((x, 0));
+            outline.push((x, bottom));
+        }
+        for y in 0..=bottom {
+            outline.push((left, y));
+            outline.push((right, y));
         }
+        outline
     }
 
-    // NOTE: Assumes the tile palette has been rendered first
-    pub fn render_tile_map1(&mut self) {
-        render_tile_map(
-            &self.vram,
-            &self.tile_palette_pixels,
-            &mut self.tile_map1_pixels,
-            TILE_MAP1_VRAM_OFFSET,
-            &self.tile_map1_last_addressing_modes,
-        )
+    // Draws the viewport and window outlines over the tile maps they use.  They are drawn over a
+    // copy of the rendered tile map, so that they do not linger once they move.
+    fn render_outlines(&mut self, tile_map: u8) {
+        let mut outlines = Vec::new();
+        if self.background_tile_map() == tile_map {
+            outlines.push((self.viewport_outline(), VIEWPORT_OUTLINE_COLOR));
+        }
+        if utils::is_bit_set(&self.lcd_control, LCDC_WINDOW_ENABLE_BIT)
+            && self.window_tile_map() == tile_map
+        {
+            outlines.push((self.window_outline(), WINDOW_OUTLINE_COLOR));
+        }
+        let tile_map_pixels = if tile_map == 0 {
+            &mut self.tile_map0_pixels
+        } else {
+            &mut self.tile_map1_pixels
+        };
+        for (outline, rgba) in outlines {
+            for (x, y) in outline {
+                let pixel_index = (y % TILE_MAP_VERTICAL_PIXELS) * TILE_MAP_HORIZONTAL_PIXELS
+                    + (x % TILE_MAP_HORIZONTAL_PIXELS);
+                tile_map_pixels[pixel_index * PIXEL_DATA_SIZE..(pixel_index + 1) * PIXEL_DATA_SIZE]
+                    .copy_from_slice(&rgba);
+            }
+        }
     }
 
-    // TODO: Eventually we could update on the fly on writes
-    pub fn render(&mut self) {
-        self.render_tile_palette();
-        self.render_tile_map0();
-        // self.render_tile_map1();
+    pub fn render(
+        &mut self,
+        tile_palette_selection: TilePaletteSelection,
+        render_tile_map0: bool,
+        render_tile_map1: bool,
+    ) {
+        self.render_tile_palette(tile_palette_selection);
+        if render_tile_map0 {
+            self.render_tile_map0();
+        }
+        if render_tile_map1 {
+            self.render_tile_map1();
+        }
     }
 
     pub fn prepare_for_new_frame(
@@ -328,6 +745,9 @@ impl PPU {
         obj_fetcher: &mut ObjectFetcher,
     ) {
         self.lcd_y_coord = Wrapping(0);
+        self.compare_ly();
+        self.is_window_y_reached = false;
+        self.window_line_counter = 0;
 
         bgw_fetcher.prepare_for_new_frame();
         obj_fetcher.prepare_for_new_frame();
@@ -364,14 +784,14 @@ impl PPU {
         }
 
         self.scanline_dots += 1;
-        if self.scanline_dots > 456 {
-            panic!("Frame did not finish rendering in time, investigate.");
+        if self.scanline_dots == 456 && matches!(self.state, PPUState::DrawingPixels(_)) {
+            self.complete_stalled_scanline(bgw_fetcher, obj_fetcher, pixel_fetcher);
         }
 
         match self.state {
             // mode 2
             PPUState::OAMScan => {
-                if self.scanline_dots == 80 {
+                if self.scanline_dots == OAM_SCAN_DOTS {
                     let ly = self.read_ly().0 as usize;
 
                     // At the start of each scanline, remember SCX
@@ -379,11 +799,20 @@ impl PPU {
                         self.frame_scxs[ly] = self.scx.0;
                     }
 
+                    if ly == self.window_y.0 as usize {
+                        self.is_window_y_reached = true;
+                    }
+
                     let mut selected_objects = VecDeque::new();
-                    let object_size = 8; // TODO: this is either 8 or 16 depending on something
+                    let object_size = self.object_height() as i16;
                     let ly = ly as i16; // from now on it's convenient as a signed (yet >= 0)
-                    for object_offset in (0x00..0x9F).step_by(4) {
-                        if selected_objects.len() == 10 {
+
+                    // At most 10 sprites per scanline, and none when objects are disabled.  Sprites
+                    // fully off-screen horizontally (X = 0 or X >= 168) count towards the limit,
+                    // even though none of their pixels end up on screen.
+                    let max_selected_objects = if self.are_objects_enabled() { 10 } else { 0 };
+                    for object_offset in (0x00..OAM_SIZE).step_by(4) {
+                        if selected_objects.len() == max_selected_objects {
                             break;
                         }
                         let y_screen_plus_16 = self.object_attribute_memory[object_offset];
@@ -398,40 +827,66 @@ impl PPU {
                             });
                         }
                     }
+                    // When sprites overlap, the one with the lowest X wins.  The sort is stable, so
+                    // ties are broken by OAM order.
+                    selected_objects
+                        .make_contiguous()
+                        .sort_by_key(|sprite| sprite.x_screen_plus_8);
                     obj_fetcher.selected_objects = selected_objects;
                     self.switch_to_drawing_pixels(pixel_fetcher);
                 }
             }
 
             // mode 3
-            PPUState::DrawingPixels(dropped_pixels) => {
+            PPUState::DrawingPixels(pixels_to_discard) => {
                 if self.drawn_pixels_on_current_row as usize == LCD_HORIZONTAL_PIXEL_COUNT {
                     return;
                 }
 
+                // The first tile fetched on each scanline is thrown away, delaying everything
+                if self.scanline_dots <= OAM_SCAN_DOTS + DISCARDED_FETCH_DOTS {
+                    return;
+                }
+
                 obj_fetcher.pixel_index_in_row = self.drawn_pixels_on_current_row;
 
-                let bgw_fifo_len = bgw_fetcher.fifo.len();
-                let obj_fifo_len = obj_fetcher.fifo.len();
+                // Once the window is reached, it covers the rest of the scanline.  The fetcher
+                // starts over, discarding the background pixels it had already fetched.
+                if !bgw_fetcher.is_fetching_window
+                    && pixels_to_discard == 0
+                    && self.is_window_reached(self.drawn_pixels_on_current_row)
+                {
+                    bgw_fetcher.start_fetching_window();
+                }
 
-                let fetcher_state = &pixel_fetcher.fetching_for;
-                if obj_fifo_len == 0 && bgw_fifo_len != 0 {
-                    if *fetcher_state == FetchingFor::BackgroundOrWindowFIFO {
+                // Sprites are fetched as soon as the pixel being drawn reaches them, and the
+                // background FIFO is paused in the meantime.  The sprite fetch only starts once
+                // the background fetcher has its current row ready, so the stall lasts between 6
+                // and 11 dots depending on where the sprite sits relative to background tiles.
+                if obj_fetcher.is_fetch_needed() {
+                    if pixel_fetcher.fetching_for == FetchingFor::BackgroundOrWindowFIFO
+                        && bgw_fetcher.is_row_ready()
+                    {
                         pixel_fetcher.switch_to_object_fifo();
                     }
-                } else {
-                    if *fetcher_state == FetchingFor::ObjectFIFO {
-                        pixel_fetcher.switch_to_background_or_window_fifo();
-                    }
+                } else if pixel_fetcher.fetching_for == FetchingFor::ObjectFIFO {
+                    pixel_fetcher.switch_to_background_or_window_fifo();
                 }
+                let is_fetching_object = pixel_fetcher.fetching_for == FetchingFor::ObjectFIFO;
                 pixel_fetcher.tick(bgw_fetcher, obj_fetcher, self);
+                if is_fetching_object || obj_fetcher.is_fetch_needed() {
+                    return;
+                }
 
-                if !bgw_fetcher.fifo.is_empty() && !obj_fetcher.fifo.is_empty() {
-                    // To support fine scrolling, the first (scx % 8) pixels are dropped from FIFOs
-                    if dropped_pixels < self.scx.0 % 8 {
+                // The background FIFO drives the output, the object FIFO only holds pixels where
+                // sprites were fetched.
+                if !bgw_fetcher.fifo.is_empty() {
+                    // To support fine scrolling, the first (scx % 8) pixels are dropped from the
+                    // background FIFO, one per dot, which lengthens mode 3 accordingly.  Sprites
+                    // are not scrolled, so their FIFO is left alone.
+                    if pixels_to_discard > 0 {
                         bgw_fetcher.fifo.pop_front();
-                        obj_fetcher.fifo.pop_front();
-                        self.state = PPUState::DrawingPixels(dropped_pixels + 1);
+                        self.state = PPUState::DrawingPixels(pixels_to_discard - 1);
                         return;
                     }
 
@@ -443,16 +898,29 @@ impl PPU {
                     }
 
                     let bgw_pixel = bgw_fetcher.fifo.pop_front().unwrap();
-                    let obj_pixel = obj_fetcher.fifo.pop_front().unwrap();
+                    // On DMG, disabling the background and window blanks them to color 0, which can
+                    // also happen in the middle of a scanline.
+                    let bgw_color = if self.is_background_and_window_enabled() {
+                        bgw_pixel.color
+                    } else {
+                        0
+                    };
+                    // Pixels not covered by any sprite are transparent
+                    let obj_pixel = obj_fetcher.fifo.pop_front().unwrap_or(TRANSPARENT_PIXEL);
                     let pixel_x = self.drawn_pixels_on_current_row;
                     let pixel_y = self.read_ly().0;
 
                     let from = pixel_coordinates_in_rgba_slice(pixel_x, pixel_y);
-                    // Simulate pixel mixing
-                    let (selected_pixel, palette) = if obj_pixel.color == 0 {
-                        (bgw_pixel.color, self.background_palette_data)
+                    // Simulate pixel mixing.  Object color 0 is transparent, whichever palette the
+                    // sprite uses, since it is checked before going through the palette.  Sprites
+                    // with the priority attribute only show over background color 0.
+                    // Objects can also be disabled in the middle of a scanline.
+                    let background_wins = !self.are_objects_enabled()
+                        || obj_pixel.color == 0
+                        || (obj_pixel.background_has_priority && bgw_color != 0);
+                    let (selected_pixel, palette) = if background_wins {
+                        (bgw_color, self.background_palette_data)
                     } else {
-                        // FIXME: need to choose between OBJ palettes based on attribute
                         (
                             obj_pixel.color,
                             match obj_pixel.palette {
@@ -461,7 +929,16 @@ impl PPU {
                             },
                         )
                     };
-                    let rgba = pixel_code_to_rgba(selected_pixel, palette);
+                    // In CGB mode, each tile and sprite picks one of 8 palettes of their own
+                    let rgba = if !self.is_cgb_mode {
+                        pixel_code_to_rgba(selected_pixel, palette)
+                    } else if background_wins {
+                        self.cgb_background_palettes
+                            .color_to_rgba(bgw_pixel.cgb_palette, bgw_color)
+                    } else {
+                        self.cgb_object_palettes
+                            .color_to_rgba(obj_pixel.cgb_palette, obj_pixel.color)
+                    };
                     self.lcd_pixels[from..from + 4].copy_from_slice(&rgba);
                     self.drawn_pixels_on_current_row += 1;
 
@@ -475,7 +952,10 @@ impl PPU {
             PPUState::HorizontalBlank => {
                 if self.scanline_dots == 456 {
                     self.scanline_dots = 0;
-                    self.increment_ly(interrupts);
+                    if bgw_fetcher.is_fetching_window {
+                        self.window_line_counter += 1;
+                    }
+                    self.increment_ly();
                     if self.read_ly().0 as usize == LCD_VERTICAL_PIXEL_COUNT {
                         self.switch_to_vertical_blank(interrupts)
                     } else {
@@ -486,55 +966,215 @@ impl PPU {
 
             // mode 1
             PPUState::VerticalBlank => {
+                // LY only reads 153 for the first few dots of the last scanline, then it already
+                // reads 0 (and gets compared to LYC as such) for the rest of the scanline.
+                if self.lcd_y_coord.0 == LAST_SCANLINE
+                    && self.scanline_dots == LAST_SCANLINE_LY_DOTS
+                {
+                    self.lcd_y_coord = Wrapping(0);
+                    self.compare_ly();
+                }
                 if self.scanline_dots == 456 {
                     self.scanline_dots = 0;
-                    self.increment_ly(interrupts);
-                    if self.read_ly().0 == 153 {
+                    // During VBlank, LY can only be 0 on the last scanline
+                    if self.lcd_y_coord.0 == 0 {
                         self.prepare_for_new_frame(bgw_fetcher, obj_fetcher);
                         self.switch_to_oam_scan(bgw_fetcher, obj_fetcher)
+                    } else {
+                        self.increment_ly();
                     }
                 }
             }
         }
 
-        // STAT interrupt check
-        let stat_line = (self.lcd_status.0 >> 3) & 0xF;
-        if self.last_stat_line == 0 && stat_line != 0 {
+        self.update_stat_line(interrupts);
+    }
+
+    // Mode 3 should always be over by the end of the scanline, if it is not, the pixel pipeline got
+    // stuck.  Rather than bringing the whole emulator down, the rest of the scanline gets filled
+    // with background color 0, so that the frame still completes.
+    fn complete_stalled_scanline(
+        &mut self,
+        bgw_fetcher: &BackgroundOrWindowFetcher,
+        obj_fetcher: &ObjectFetcher,
+        pixel_fetcher: &Fetcher,
+    ) {
+        let ly = self.lcd_y_coord.0;
+        let diagnostics = format!(
+            "LY={} drawn_pixels={} fetching_for={:?} bgw_state={:?} bgw_fifo={} obj_state={:?} \
+             obj_fifo={} pending_objects={}",
+            ly,
+            self.drawn_pixels_on_current_row,
+            pixel_fetcher.fetching_for,
+            bgw_fetcher.state(),
+            bgw_fetcher.fifo.len(),
+            obj_fetcher.state(),
+            obj_fetcher.fifo.len(),
+            obj_fetcher.selected_objects.len(),
+        );
+        if self.is_strict {
+            panic!("Scanline did not finish drawing in time: {}", diagnostics);
+        }
+        // A stuck pipeline tends to stay stuck, so the following ones are only counted
+        if self.stalled_scanline_count == 0 {
+            println!(
+                "WARNING: Scanline did not finish drawing in time: {}",
+                diagnostics
+            );
+        }
+        self.stalled_scanline_count += 1;
+
+        let rgba = if self.is_cgb_mode {
+            self.cgb_background_palettes.color_to_rgba(0, 0)
+        } else {
+            pixel_code_to_rgba(0, self.background_palette_data)
+        };
+        for pixel_x in self.drawn_pixels_on_current_row..LCD_HORIZONTAL_PIXEL_COUNT as u8 {
+            let from = pixel_coordinates_in_rgba_slice(pixel_x, ly);
+            self.lcd_pixels[from..from + 4].copy_from_slice(&rgba);
+        }
+        self.drawn_pixels_on_current_row = LCD_HORIZONTAL_PIXEL_COUNT as u8;
+        self.switch_to_horizontal_blank();
+    }
+
+    fn update_stat_line(&mut self, interrupts: &mut Interrupts) {
+        let stat_line = self.is_lcd_ppu_on() && self.stat_line();
+        if !self.last_stat_line && stat_line {
             interrupts.request(STAT_INTERRUPT_BIT);
         }
         self.last_stat_line = stat_line;
     }
 
-    pub fn read_vram(&self, address: Wrapping<u16>) -> Wrapping<u8> {
-        Wrapping(self.vram[address.0 as usize])
+    // The STAT line is high whenever one of the enabled sources has its condition met.  Since the
+    // interrupt is only requested on a rising edge, a source becoming true while another one
+    // already holds the line high does not trigger a new interrupt (the so-called "STAT blocking").
+    fn stat_line(&self) -> bool {
+        let source_is_active = |select_bit: u8, condition: bool| {
+            condition && utils::is_bit_set(&self.lcd_status, select_bit)
+        };
+        source_is_active(
+            MODE_0_INTERRUPT_SELECT_BIT,
+            matches!(self.state, PPUState::HorizontalBlank),
+        ) || source_is_active(
+            MODE_1_INTERRUPT_SELECT_BIT,
+            matches!(self.state, PPUState::VerticalBlank),
+        ) || source_is_active(
+            MODE_2_INTERRUPT_SELECT_BIT,
+            matches!(self.state, PPUState::OAMScan),
+        ) || source_is_active(
+            LYC_EQUALS_LY_INTERRUPT_SELECT_BIT,
+            utils::is_bit_set(&self.lcd_status, LYC_EQUALS_LY_BIT),
+        )
     }
 
-    pub fn read_wram_0(&self, address: Wrapping<u16>) -> Wrapping<u8> {
-        Wrapping(self.wram_0[address.0 as usize])
+    // The CPU accesses the VRAM bank selected with bit 0 of VBK, which only matters on CGB.
+    fn is_vram_bank_1_selected(&self) -> bool {
+        self.is_cgb_mode && self.vram_bank.0 & 1 == 1
     }
 
-    pub fn read_wram_1(&self, address: Wrapping<u16>) -> Wrapping<u8> {
-        Wrapping(self.wram_1[address.0 as usize])
+    pub fn read_vram(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        if self.is_vram_bank_1_selected() {
+            Wrapping(self.vram_bank_1[address.0 as usize])
+        } else {
+            Wrapping(self.vram[address.0 as usize])
+        }
     }
 
     pub fn read_lcdc(&self) -> Wrapping<u8> {
         self.lcd_control
     }
 
+    // Bit 7 is unused and always reads as 1
+    pub fn read_stat(&self) -> Wrapping<u8> {
+        self.lcd_status | Wrapping(0x80)
+    }
+
     pub fn write_vram(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
-        self.vram[address.0 as usize] = value.0;
+        let address = address.0 as usize;
+        if self.is_vram_bank_1_selected() {
+            self.vram_bank_1[address] = value.0;
+            return;
+        }
+        if self.vram[address] == value.0 {
+            return;
+        }
+        self.vram[address] = value.0;
+        // Only bank 0 shows in the debug views, each tile takes 16 bytes
+        match address {
+            0..TILE_MAP0_VRAM_OFFSET => {
+                self.dirty_tiles[address / 16] = true;
+                self.dirty_background_tiles[address / 16] = true;
+            }
+            TILE_MAP0_VRAM_OFFSET..TILE_MAP1_VRAM_OFFSET => {
+                self.tile_map0_dirty_cells[address - TILE_MAP0_VRAM_OFFSET] = true
+            }
+            _ => self.tile_map1_dirty_cells[address - TILE_MAP1_VRAM_OFFSET] = true,
+        }
     }
 
-    pub fn write_wram_0(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
-        self.wram_0[address.0 as usize] = value.0;
+    pub fn write_lcdc(
+        &mut self,
+        value: Wrapping<u8>,
+        bgw_fetcher: &mut BackgroundOrWindowFetcher,
+        obj_fetcher: &mut ObjectFetcher,
+        interrupts: &mut Interrupts,
+    ) {
+        let was_on = self.is_lcd_ppu_on();
+        self.lcd_control = value;
+        match (was_on, self.is_lcd_ppu_on()) {
+            (true, false) => self.turn_off(),
+            (false, true) => self.turn_on(bgw_fetcher, obj_fetcher),
+            _ => {}
+        }
+        // Turning the LCD off pulls the STAT line low, turning it back on may raise it right away.
+        self.compare_ly();
+        self.update_stat_line(interrupts);
     }
 
-    pub fn write_wram_1(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
-        self.wram_1[address.0 as usize] = value.0;
+    // While off, the PPU sits at the start of scanline 0 in mode 0, and the screen is blank.
+    fn turn_off(&mut self) {
+        self.lcd_y_coord = Wrapping(0);
+        self.scanline_dots = 0;
+        self.switch_to(PPUState::HorizontalBlank);
+        for pixel in self.lcd_pixels.chunks_exact_mut(PIXEL_DATA_SIZE) {
+            pixel.copy_from_slice(&WHITE);
+        }
+        // The screen goes blank right away, there is no frame to wait for
+        self.front_buffer.copy_from_slice(&self.lcd_pixels);
     }
 
-    pub fn write_lcdc(&mut self, value: Wrapping<u8>) {
-        self.lcd_control = value;
+    // Turning the LCD back on starts a fresh frame.
+    // FIXME: the first frame after turning the LCD on is slightly shorter on hardware
+    fn turn_on(
+        &mut self,
+        bgw_fetcher: &mut BackgroundOrWindowFetcher,
+        obj_fetcher: &mut ObjectFetcher,
+    ) {
+        self.scanline_dots = 0;
+        self.prepare_for_new_frame(bgw_fetcher, obj_fetcher);
+        self.switch_to_oam_scan(bgw_fetcher, obj_fetcher);
+    }
+
+    // The mode and the coincidence flag are owned by the PPU, only the interrupt selection bits can
+    // be written.  Enabling a source whose condition already holds raises the STAT line.
+    pub fn write_stat(&mut self, value: Wrapping<u8>, interrupts: &mut Interrupts) {
+        self.lcd_status =
+            Wrapping((value.0 & !STAT_READ_ONLY_MASK) | (self.lcd_status.0 & STAT_READ_ONLY_MASK));
+        self.update_stat_line(interrupts);
+    }
+
+    // Games reprogram LYC mid-frame for raster effects, so the comparison must happen immediately
+    // rather than on the next LY change.
+    pub fn write_lyc(&mut self, value: Wrapping<u8>, interrupts: &mut Interrupts) {
+        self.lcd_y_compare = value;
+        self.compare_ly();
+        self.update_stat_line(interrupts);
+    }
+
+    // The current mode can be read from the lower two bits of STAT.
+    fn switch_to(&mut self, state: PPUState) {
+        self.state = state;
+        self.lcd_status = Wrapping((self.lcd_status.0 & !STAT_MODE_MASK) | self.mode());
     }
 
     fn switch_to_oam_scan(
@@ -545,52 +1185,83 @@ impl PPU {
         self.drawn_pixels_on_current_row = 0;
         bgw_fetcher.prepare_for_new_row();
         obj_fetcher.prepare_for_new_row();
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping((machine.ppu().lcd_status.0 & 0xFC) | 2);
-        utils::unset_bit(&mut self.lcd_status, MODE_0_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_1_INTERRUPT_SELECT_BIT);
-        utils::set_bit(&mut self.lcd_status, MODE_2_INTERRUPT_SELECT_BIT);
-        self.state = PPUState::OAMScan;
+        self.switch_to(PPUState::OAMScan);
     }
 
     fn switch_to_drawing_pixels(&mut self, pixel_fetcher: &mut Fetcher) {
         pixel_fetcher.switch_to_background_or_window_fifo();
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping((machine.ppu().lcd_status.0 & 0xFC) | 3);
-        self.state = PPUState::DrawingPixels(0);
+        self.switch_to(PPUState::DrawingPixels(self.scx.0 % 8));
     }
 
     fn switch_to_horizontal_blank(&mut self) {
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping(machine.ppu().lcd_status.0 & 0xFC);
-        utils::set_bit(&mut self.lcd_status, MODE_0_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_1_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_2_INTERRUPT_SELECT_BIT);
-        self.state = PPUState::HorizontalBlank;
+        self.mode_3_length = self.scanline_dots - OAM_SCAN_DOTS;
+        self.switch_to(PPUState::HorizontalBlank);
     }
 
     fn switch_to_vertical_blank(&mut self, interrupts: &mut Interrupts) {
-        // Disabled because it locks LCD for Dr. Mario:
-        // machine.ppu_mut().lcd_status = Wrapping((machine.ppu().lcd_status.0 & 0xFC) | 1);
-        utils::unset_bit(&mut self.lcd_status, MODE_0_INTERRUPT_SELECT_BIT);
-        utils::set_bit(&mut self.lcd_status, MODE_1_INTERRUPT_SELECT_BIT);
-        utils::unset_bit(&mut self.lcd_status, MODE_2_INTERRUPT_SELECT_BIT);
+        self.front_buffer.copy_from_slice(&self.lcd_pixels);
+        self.completed_frame_count += 1;
         interrupts.request(VBLANK_INTERRUPT_BIT);
-        self.state = PPUState::VerticalBlank
+        self.switch_to(PPUState::VerticalBlank)
+    }
+}
+
+// Paints the tiles marked dirty, laid out as in the tile palette.
+fn render_tiles(
+    vram: &[u8],
+    palette: u8,
+    dirty_tiles: &mut [bool; TILE_PALETTE_TILE_TOTAL],
+    pixels: &mut [u8],
+) {
+    for tile_palette_y in 0..TILE_PALETTE_VERTICAL_TILE_COUNT {
+        for tile_palette_x in 0..TILE_PALETTE_HORIZONTAL_TILE_COUNT {
+            let tile_index = tile_palette_y * TILE_PALETTE_HORIZONTAL_TILE_COUNT + tile_palette_x;
+            if !dirty_tiles[tile_index] {
+                continue;
+            }
+            dirty_tiles[tile_index] = false;
+            let tile_data_from = tile_index * 16;
+            let tile_data = &vram[tile_data_from..tile_data_from + 16];
+            for tile_pixel_y in 0..VERTICAL_PIXELS_PER_TILE {
+                let row_data_from = tile_pixel_y * 2;
+                let low_bits = tile_data[row_data_from];
+                let high_bits = tile_data[row_data_from + 1];
+                for tile_pixel_x in 0..HORIZONTAL_PIXELS_PER_TILE {
+                    let pixel_code = (((high_bits >> (7 - tile_pixel_x)) & 1) << 1)
+                        | ((low_bits >> (7 - tile_pixel_x)) & 1);
+                    let pixel_rgba = pixel_code_to_rgba(pixel_code, palette);
+                    let vram_pixel_x = tile_palette_x * 8 + tile_pixel_x;
+                    let vram_pixel_y = tile_palette_y * 8 + tile_pixel_y;
+                    let vram_pixels_from =
+                        (vram_pixel_y * TILE_PALETTE_HORIZONTAL_PIXELS + vram_pixel_x) * 4;
+                    pixels[vram_pixels_from..vram_pixels_from + 4].copy_from_slice(&pixel_rgba);
+                }
+            }
+        }
     }
 }
 
+// Only copies the cells that changed, or whose tile got repainted, since the last call.
 fn render_tile_map(
     vram: &[u8],
     tile_palette_pixels: &[u8],
     tile_map_pixels: &mut [u8],
     tile_map_vram_offset: usize,
     tile_map_last_addressing_modes: &[TileAddressingMode; TILE_MAP_TILE_TOTAL],
+    dirty_cells: &mut [bool; TILE_MAP_TILE_TOTAL],
+    stale_tiles: &mut [bool; TILE_PALETTE_TILE_TOTAL],
 ) {
     for tile_map_y in 0..TILE_MAP_VERTICAL_TILE_COUNT {
         for tile_map_x in 0..TILE_MAP_HORIZONTAL_TILE_COUNT {
             let tile_map_index = (tile_map_y << 5) | tile_map_x;
             let tile_id = vram[tile_map_vram_offset + tile_map_index];
+            let addressing_mode = tile_map_last_addressing_modes[tile_map_index];
+            let tile_index_in_palette =
+                get_tile_index_in_palette(tile_id, &addressing_mode) as usize;
+            if !dirty_cells[tile_map_index] && !stale_tiles[tile_index_in_palette] {
+                continue;
+            }
+            dirty_cells[tile_map_index] = false;
             // Because tiles have already been rendered as pixels in the tile palette, here we
             // can just copy slices of lines for the 8 lines of the tile.
             for tile_pixel_y in 0..VERTICAL_PIXELS_PER_TILE {
@@ -609,11 +1280,6 @@ fn render_tile_map(
                     + pixels_to_skip_to_reach_tile_map_x;
                 let bytes_to_skip = pixels_to_skip * PIXEL_DATA_SIZE;
 
-                let tile_index_in_tile_map =
-                    tile_map_y * TILE_MAP_HORIZONTAL_TILE_COUNT + tile_map_x;
-                let addressing_mode = tile_map_last_addressing_modes[tile_index_in_tile_map];
-                let tile_index_in_palette =
-                    get_tile_index_in_palette(tile_id, &addressing_mode) as usize;
                 let palette_tile_y = tile_index_in_palette / TILE_PALETTE_HORIZONTAL_TILE_COUNT;
                 let palette_tile_x = tile_index_in_palette % TILE_PALETTE_HORIZONTAL_TILE_COUNT;
                 let palette_tiles_to_skip = palette_tile_y * TILE_PALETTE_HORIZONTAL_TILE_COUNT;
@@ -632,4 +1298,1058 @@ fn render_tile_map(
             }
         }
     }
+    *stale_tiles = [false; TILE_PALETTE_TILE_TOTAL];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application_state::{ConsoleMode, ROMInformation},
+        instructions::asm::*,
+        machine::{
+            tests::{machine_with_cartridge, run_until},
+            Machine,
+        },
+    };
+
+    // A machine with the LCD on, spinning at 0x104.
+    fn machine_with_lcd_on() -> Machine {
+        machine_with_lcd_on_as(ConsoleMode::Dmg)
+    }
+
+    fn machine_with_lcd_on_as(console_mode: ConsoleMode) -> Machine {
+        let rom_information = ROMInformation {
+            console_mode,
+            ..ROMInformation::new()
+        };
+        let program = program![ld_a_u8(0x91), ldh_u8_a(0x40), jr(-2)];
+        let mut machine = machine_with_cartridge(&program, rom_information);
+        run_until(&mut machine, 0x104);
+        machine
+    }
+
+    // Only the PPU moves, the CPU stays put.
+    fn tick_ppu(machine: &mut Machine) {
+        machine.ppu.ticks(
+            &mut machine.background_window_fetcher,
+            &mut machine.interrupts,
+            &mut machine.object_fetcher,
+            &mut machine.pixel_fetcher,
+            1,
+        );
+    }
+
+    // Runs the PPU dot by dot, a frame at most, until `condition` holds.
+    fn tick_ppu_until(machine: &mut Machine, condition: impl Fn(&Machine) -> bool) {
+        for _ in 0..154 * 456 {
+            if condition(machine) {
+                return;
+            }
+            tick_ppu(machine);
+        }
+        panic!("Never happened within a frame");
+    }
+
+    // Counts the STAT interrupts requested over a frame, checking that each one comes from a rising
+    // edge of the line combining the sources selected in `stat`.
+    fn stat_interrupts_over_a_frame(stat: u8, lyc: u8) -> usize {
+        let mut machine = machine_with_lcd_on();
+        machine.write_u8(Wrapping(0xFF45), Wrapping(lyc));
+        machine.write_u8(Wrapping(0xFF41), Wrapping(stat));
+        tick_ppu_until(&mut machine, |machine| {
+            machine.ppu.read_ly().0 == 0
+                && machine.ppu.mode() == 2
+                && machine.ppu.scanline_dots() == 0
+        });
+        let stat_line = |machine: &Machine| {
+            let mode = machine.ppu.mode();
+            (0..3).any(|source| mode == source && stat & (0x08 << source) != 0)
+                || stat & 0x40 != 0 && machine.ppu.read_ly().0 == lyc
+        };
+        let mut was_high = stat_line(&machine);
+        machine.interrupts.interrupt_flag = Wrapping(0);
+        let mut count = 0;
+        for dot in 0..154 * 456 {
+            tick_ppu(&mut machine);
+            let is_high = stat_line(&machine);
+            let is_requested = machine.interrupts.interrupt_flag.0 & (1 << STAT_INTERRUPT_BIT) != 0;
+            assert_eq!(
+                is_requested,
+                is_high && !was_high,
+                "STAT {:#04x}, dot {}",
+                stat,
+                dot
+            );
+            machine.interrupts.interrupt_flag = Wrapping(0);
+            count += is_requested as usize;
+            was_high = is_high;
+        }
+        count
+    }
+
+    #[test]
+    fn stat_interrupts_fire_on_rising_edges_of_the_combined_line() {
+        // The selection bits, from mode 0 (bit 3) to LYC=LY (bit 6), and the interrupts per frame
+        // with LYC=10.  A source rising while another one holds the line high is blocked, e.g.
+        // mode 2 right after an H-Blank, V-Blank right after the H-Blank of line 143, or the
+        // H-Blank of line 10 while LYC=LY.
+        let expected = [
+            (0x00, 0),
+            (0x08, 144),
+            (0x10, 1),
+            (0x18, 144),
+            (0x20, 144),
+            (0x28, 145),
+            (0x30, 144),
+            (0x38, 144),
+            (0x40, 1),
+            (0x48, 143),
+            (0x50, 2),
+            (0x58, 143),
+            (0x60, 143),
+            (0x68, 144),
+            (0x70, 143),
+            (0x78, 143),
+        ];
+        for (stat, interrupts) in expected {
+            assert_eq!(
+                stat_interrupts_over_a_frame(stat, 10),
+                interrupts,
+                "STAT {:#04x}",
+                stat
+            );
+        }
+    }
+
+    // A machine with the LCD on and sprites enabled, where every palette maps color codes to the
+    // same shades.  VRAM is all zeros, so the background is color 0 all over.
+    fn machine_drawing_sprites() -> Machine {
+        let mut machine = machine_with_lcd_on();
+        machine.ppu.lcd_control |= 1 << LCDC_OBJECT_ENABLE_BIT;
+        machine.ppu.background_palette_data = IDENTITY_PALETTE;
+        machine.ppu.object_palette_0 = IDENTITY_PALETTE;
+        machine.ppu.object_palette_1 = IDENTITY_PALETTE;
+        machine
+    }
+
+    // Writes the color codes of tile `tile` in the 0x8000 addressing mode to `vram`, either bank,
+    // each row from left to right.
+    fn write_tile(vram: &mut [u8], tile: usize, rows: [[u8; 8]; 8]) {
+        for (row, colors) in rows.iter().enumerate() {
+            let (mut low, mut high) = (0, 0);
+            for (column, color) in colors.iter().enumerate() {
+                low |= (color & 1) << (7 - column);
+                high |= (color >> 1) << (7 - column);
+            }
+            vram[tile * 16 + row * 2] = low;
+            vram[tile * 16 + row * 2 + 1] = high;
+        }
+    }
+
+    fn write_sprite(
+        ppu: &mut PPU,
+        slot: usize,
+        x_screen_plus_8: u8,
+        y_screen_plus_16: u8,
+        tile: u8,
+        attributes: u8,
+    ) {
+        ppu.object_attribute_memory[slot * 4..slot * 4 + 4].copy_from_slice(&[
+            y_screen_plus_16,
+            x_screen_plus_8,
+            tile,
+            attributes,
+        ]);
+    }
+
+    // Draws line `ly` of a new frame, and returns its shades from left to right.
+    fn shades_on_line(machine: &mut Machine, ly: u8) -> Vec<u8> {
+        tick_ppu_until(machine, |machine| machine.ppu.mode() == 1);
+        shades_on_next_line(machine, ly)
+    }
+
+    // Same, going on with the current frame.
+    fn shades_on_next_line(machine: &mut Machine, ly: u8) -> Vec<u8> {
+        let shades = [0, 1, 2, 3].map(|shade| pixel_code_to_rgba(shade, IDENTITY_PALETTE));
+        colors_on_next_line(machine, ly, shades)
+    }
+
+    // Draws line `ly` of the current frame, and tells which of `colors` each pixel is.
+    fn colors_on_next_line(
+        machine: &mut Machine,
+        ly: u8,
+        colors: [[u8; PIXEL_DATA_SIZE]; 4],
+    ) -> Vec<u8> {
+        tick_ppu_until(machine, |machine| {
+            machine.ppu.read_ly().0 == ly && machine.ppu.mode() == 0
+        });
+        let from = pixel_coordinates_in_rgba_slice(0, ly);
+        machine.ppu.lcd_pixels()[from..from + LCD_HORIZONTAL_PIXEL_COUNT * PIXEL_DATA_SIZE]
+            .chunks(PIXEL_DATA_SIZE)
+            .map(|rgba| {
+                (0..4)
+                    .find(|color| colors[*color as usize] == rgba)
+                    .expect("Not one of the expected colors")
+            })
+            .collect()
+    }
+
+    // White, red, green and blue
+    const CGB_COLORS: [u16; 4] = [0x7FFF, 0x001F, 0x03E0, 0x7C00];
+
+    // A CGB machine with the LCD on and sprites enabled, where the first background and object
+    // palettes map color codes 0 to 3 to `CGB_COLORS`.
+    fn cgb_machine_drawing_sprites() -> Machine {
+        let mut machine = machine_with_lcd_on_as(ConsoleMode::Cgb);
+        machine.ppu.lcd_control |= 1 << LCDC_OBJECT_ENABLE_BIT;
+        for palettes in [
+            &mut machine.ppu.cgb_background_palettes,
+            &mut machine.ppu.cgb_object_palettes,
+        ] {
+            palettes.write_spec(Wrapping(0x80));
+            for byte in CGB_COLORS.iter().flat_map(|color| color.to_le_bytes()) {
+                palettes.write_data(Wrapping(byte));
+            }
+        }
+        machine
+    }
+
+    // Draws line `ly` of a new frame, and returns its colors as indices in `CGB_COLORS`.
+    fn cgb_colors_on_line(machine: &mut Machine, ly: u8) -> Vec<u8> {
+        tick_ppu_until(machine, |machine| machine.ppu.mode() == 1);
+        colors_on_next_line(machine, ly, CGB_COLORS.map(cgb_color_to_rgba))
+    }
+
+    // Every row differs from the others, and from itself reversed.
+    const ASYMMETRIC_TILE: [[u8; 8]; 8] = [
+        [0, 1, 2, 3, 0, 1, 2, 3],
+        [1, 2, 3, 0, 1, 2, 3, 1],
+        [2, 3, 0, 1, 2, 3, 1, 2],
+        [3, 0, 1, 2, 3, 1, 2, 3],
+        [0, 1, 2, 3, 1, 2, 3, 0],
+        [1, 2, 3, 1, 2, 3, 0, 1],
+        [2, 3, 1, 2, 3, 0, 1, 2],
+        [3, 1, 2, 3, 0, 1, 2, 3],
+    ];
+
+    fn reversed(mut row: [u8; 8]) -> [u8; 8] {
+        row.reverse();
+        row
+    }
+
+    #[test]
+    fn sprites_flip_along_each_axis() {
+        // Row 2 of the sprite is on line 40, it is row 5 when flipped vertically
+        for (attributes, expected) in [
+            (0x00, ASYMMETRIC_TILE[2]),
+            (0x20, reversed(ASYMMETRIC_TILE[2])),
+            (0x40, ASYMMETRIC_TILE[5]),
+            (0x60, reversed(ASYMMETRIC_TILE[5])),
+        ] {
+            let mut machine = machine_drawing_sprites();
+            write_tile(&mut machine.ppu.vram, 1, ASYMMETRIC_TILE);
+            write_sprite(&mut machine.ppu, 0, 8 + 20, 16 + 38, 1, attributes);
+            let shades = shades_on_line(&mut machine, 40);
+            assert_eq!(shades[20..28], expected, "attributes 0x{:02X}", attributes);
+            assert!(shades[..20].iter().all(|shade| *shade == 0));
+            assert!(shades[28..].iter().all(|shade| *shade == 0));
+        }
+    }
+
+    #[test]
+    fn tall_sprites_flip_across_both_tiles() {
+        let mut machine = machine_drawing_sprites();
+        machine.ppu.lcd_control |= 1 << LCDC_OBJECT_SIZE_BIT;
+        let mut bottom_tile = [[0; 8]; 8];
+        bottom_tile[6] = [3, 3, 2, 2, 1, 1, 0, 0];
+        write_tile(&mut machine.ppu.vram, 2, [[0; 8]; 8]);
+        write_tile(&mut machine.ppu.vram, 3, bottom_tile);
+        // Row 1 of the flipped sprite is row 14, that is row 6 of the bottom tile.  The lower bit
+        // of the tile index is ignored.
+        write_sprite(&mut machine.ppu, 0, 8, 16 + 49, 3, 0x40);
+        assert_eq!(
+            shades_on_line(&mut machine, 50)[..8],
+            [3, 3, 2, 2, 1, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn sprites_use_the_palette_they_select() {
+        // OBP0 reverses the shades, OBP1 maps everything to shade 2.  Color 0 stays transparent
+        // either way, and shows the background, which is all color 1.
+        for (attributes, expected) in [
+            (0x00, [1, 2, 1, 0, 1, 2, 1, 0]),
+            (0x10, [1, 2, 2, 2, 1, 2, 2, 2]),
+        ] {
+            let mut machine = machine_drawing_sprites();
+            machine.ppu.object_palette_0 = 0b00_01_10_11;
+            machine.ppu.object_palette_1 = 0b10_10_10_10;
+            write_tile(&mut machine.ppu.vram, 0, [[1; 8]; 8]);
+            write_tile(&mut machine.ppu.vram, 1, [[0, 1, 2, 3, 0, 1, 2, 3]; 8]);
+            write_sprite(&mut machine.ppu, 0, 8 + 100, 16 + 30, 1, attributes);
+            let shades = shades_on_line(&mut machine, 30);
+            assert_eq!(
+                shades[100..108],
+                expected,
+                "attributes 0x{:02X}",
+                attributes
+            );
+            assert!(shades[..100].iter().all(|shade| *shade == 1));
+            assert!(shades[108..].iter().all(|shade| *shade == 1));
+        }
+    }
+
+    #[test]
+    fn sprites_behind_the_background_only_show_over_color_0() {
+        // Priority attribute, background color, and the shade drawn where the sprite is opaque
+        for (attributes, background_color, expected) in
+            [(0x00, 0, 3), (0x00, 2, 3), (0x80, 0, 3), (0x80, 2, 2)]
+        {
+            let mut machine = machine_drawing_sprites();
+            write_tile(&mut machine.ppu.vram, 0, [[background_color; 8]; 8]);
+            // Only the left half of the sprite is opaque
+            write_tile(&mut machine.ppu.vram, 1, [[3, 3, 3, 3, 0, 0, 0, 0]; 8]);
+            write_sprite(&mut machine.ppu, 0, 8 + 60, 16 + 70, 1, attributes);
+            let shades = shades_on_line(&mut machine, 70);
+            let drawn = [[expected; 4], [background_color; 4]].concat();
+            assert_eq!(
+                shades[60..68],
+                drawn[..],
+                "attributes 0x{:02X}, background color {}",
+                attributes,
+                background_color
+            );
+        }
+    }
+
+    #[test]
+    fn sprites_are_not_scrolled() {
+        let mut machine = machine_drawing_sprites();
+        machine.ppu.scy = Wrapping(32);
+        write_tile(&mut machine.ppu.vram, 1, ASYMMETRIC_TILE);
+        // The top of the sprite is on line 24, whatever SCY is
+        write_sprite(&mut machine.ppu, 0, 8, 40, 1, 0x00);
+        for ly in 24..32 {
+            let shades = shades_on_line(&mut machine, ly);
+            assert_eq!(
+                shades[..8],
+                ASYMMETRIC_TILE[ly as usize - 24],
+                "line {}",
+                ly
+            );
+        }
+    }
+
+    // The X and tile index of the sprites OAM scan selected for line `ly`, before any of them gets
+    // fetched.
+    fn selected_sprites(machine: &mut Machine, ly: u8) -> Vec<(u8, u8)> {
+        tick_ppu_until(machine, |machine| machine.ppu.mode() == 1);
+        tick_ppu_until(machine, |machine| {
+            machine.ppu.read_ly().0 == ly && machine.ppu.mode() == 3
+        });
+        machine
+            .object_fetcher
+            .selected_objects
+            .iter()
+            .map(|sprite| (sprite.x_screen_plus_8, sprite.tile_index))
+            .collect()
+    }
+
+    #[test]
+    fn oam_scan_reaches_the_last_slot() {
+        let mut machine = machine_drawing_sprites();
+        write_sprite(&mut machine.ppu, 39, 8 + 30, 16 + 50, 39, 0x00);
+        assert_eq!(selected_sprites(&mut machine, 50), [(8 + 30, 39)]);
+    }
+
+    #[test]
+    fn oam_scan_selects_at_most_10_sprites() {
+        let mut machine = machine_drawing_sprites();
+        for slot in 0..40 {
+            write_sprite(
+                &mut machine.ppu,
+                slot,
+                8 + 100 - slot as u8,
+                16 + 50,
+                slot as u8,
+                0x00,
+            );
+        }
+        // The first 10 in OAM order, even though the later ones are further left
+        let first_slots = (0..10)
+            .rev()
+            .map(|slot| (8 + 100 - slot, slot))
+            .collect::<Vec<_>>();
+        assert_eq!(selected_sprites(&mut machine, 50), first_slots);
+    }
+
+    #[test]
+    fn overlapping_sprites_are_drawn_by_x_then_oam_order() {
+        let mut machine = machine_drawing_sprites();
+        write_tile(&mut machine.ppu.vram, 1, [[1; 8]; 8]);
+        write_tile(&mut machine.ppu.vram, 2, [[2; 8]; 8]);
+        // The sprite further left wins, even though it comes later in OAM
+        write_sprite(&mut machine.ppu, 0, 8 + 16, 16 + 60, 2, 0x00);
+        write_sprite(&mut machine.ppu, 1, 8 + 12, 16 + 60, 1, 0x00);
+        // At the same X, the first one in OAM wins
+        write_sprite(&mut machine.ppu, 2, 8 + 40, 16 + 60, 2, 0x00);
+        write_sprite(&mut machine.ppu, 3, 8 + 40, 16 + 60, 1, 0x00);
+        let shades = shades_on_line(&mut machine, 60);
+        assert_eq!(shades[12..24], [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(shades[40..48], [2; 8]);
+    }
+
+    #[test]
+    fn disabling_objects_hides_sprites_from_the_next_line() {
+        let mut machine = machine_drawing_sprites();
+        write_tile(&mut machine.ppu.vram, 0, ASYMMETRIC_TILE);
+        write_tile(&mut machine.ppu.vram, 1, [[3; 8]; 8]);
+        write_sprite(&mut machine.ppu, 0, 8 + 80, 16 + 60, 1, 0x00);
+        let background = |ly: usize| ASYMMETRIC_TILE[ly % 8].repeat(20);
+        let shades = shades_on_line(&mut machine, 60);
+        assert_eq!(shades[80..88], [3; 8]);
+        assert_eq!(shades[..80], background(60)[..80]);
+
+        machine.ppu.lcd_control &= !(1 << LCDC_OBJECT_ENABLE_BIT);
+        assert_eq!(shades_on_next_line(&mut machine, 61), background(61));
+
+        machine.ppu.lcd_control |= 1 << LCDC_OBJECT_ENABLE_BIT;
+        let shades = shades_on_next_line(&mut machine, 62);
+        assert_eq!(shades[80..88], [3; 8]);
+        assert_eq!(shades[88..], background(62)[88..]);
+    }
+
+    #[test]
+    fn sprites_are_clipped_at_the_screen_edges() {
+        let row = ASYMMETRIC_TILE[1];
+        // OAM X, then where the visible columns of the sprite are drawn
+        for (x_screen_plus_8, columns, pixels) in [
+            (3, 5..8, 0..3),
+            (8, 0..8, 0..8),
+            (160, 0..8, 152..160),
+            (167, 0..1, 159..160),
+        ] {
+            let mut machine = machine_drawing_sprites();
+            write_tile(&mut machine.ppu.vram, 1, ASYMMETRIC_TILE);
+            write_sprite(&mut machine.ppu, 0, x_screen_plus_8, 16 + 39, 1, 0x00);
+            let shades = shades_on_line(&mut machine, 40);
+            let mut expected = vec![0; LCD_HORIZONTAL_PIXEL_COUNT];
+            expected[pixels].copy_from_slice(&row[columns]);
+            assert_eq!(shades, expected, "X = {}", x_screen_plus_8);
+        }
+    }
+
+    #[test]
+    fn hidden_sprites_count_towards_the_limit() {
+        let mut machine = machine_drawing_sprites();
+        write_tile(&mut machine.ppu.vram, 1, [[3; 8]; 8]);
+        for slot in 0..10 {
+            let x_screen_plus_8 = if slot % 2 == 0 { 0 } else { 168 };
+            write_sprite(&mut machine.ppu, slot, x_screen_plus_8, 16 + 40, 1, 0x00);
+        }
+        write_sprite(&mut machine.ppu, 10, 8 + 50, 16 + 40, 1, 0x00);
+        assert!(shades_on_line(&mut machine, 40)
+            .iter()
+            .all(|shade| *shade == 0));
+    }
+
+    #[test]
+    fn the_window_line_counter_skips_lines_without_the_window() {
+        let mut machine = machine_drawing_sprites();
+        // The background is color 0, the window uses the other tile map, where all cells are tile 1
+        write_tile(&mut machine.ppu.vram, 1, ASYMMETRIC_TILE);
+        machine.ppu.vram[TILE_MAP1_VRAM_OFFSET..TILE_MAP1_VRAM_OFFSET + 0x400].fill(1);
+        machine.ppu.lcd_control |= 1 << LCDC_WINDOW_ENABLE_BIT | 1 << LCDC_WINDOW_TILE_MAP_AREA_BIT;
+        machine.ppu.window_y = Wrapping(0);
+        machine.ppu.window_x7 = Wrapping(7);
+        let window_row = |row: usize| ASYMMETRIC_TILE[row % 8].repeat(20);
+        assert_eq!(shades_on_line(&mut machine, 9), window_row(9));
+        assert_eq!(machine.ppu.window_line_counter(), 9);
+
+        // Pushed past the right edge, the window does not show up, and its rows are not used up
+        machine.ppu.window_x7 = Wrapping(167);
+        for ly in 10..15 {
+            assert_eq!(
+                shades_on_next_line(&mut machine, ly),
+                [0; 160],
+                "line {}",
+                ly
+            );
+            assert_eq!(machine.ppu.window_line_counter(), 10, "line {}", ly);
+        }
+
+        machine.ppu.window_x7 = Wrapping(7);
+        assert_eq!(shades_on_next_line(&mut machine, 15), window_row(10));
+        assert_eq!(shades_on_next_line(&mut machine, 16), window_row(11));
+    }
+
+    #[test]
+    fn disabling_the_background_draws_color_0_under_sprites() {
+        let mut machine = machine_drawing_sprites();
+        // Color 0 is black through BGP
+        machine.ppu.background_palette_data = 0b00_01_10_11;
+        write_tile(&mut machine.ppu.vram, 0, ASYMMETRIC_TILE);
+        write_tile(&mut machine.ppu.vram, 1, [[2; 8]; 8]);
+        write_sprite(&mut machine.ppu, 0, 8 + 40, 16 + 30, 1, 0x00);
+        let background = ASYMMETRIC_TILE[30 % 8].map(|color| 3 - color).repeat(20);
+        assert_eq!(shades_on_line(&mut machine, 30)[..40], background[..40]);
+
+        machine.ppu.lcd_control &= !(1 << LCDC_BACKGROUND_AND_WINDOW_ENABLE_BIT);
+        let shades = shades_on_next_line(&mut machine, 31);
+        assert_eq!(shades[40..48], [2; 8]);
+        for (x, shade) in shades.iter().enumerate() {
+            if !(40..48).contains(&x) {
+                assert_eq!(*shade, 3, "pixel {}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn each_tile_map_remembers_how_its_cells_were_decoded() {
+        let mut machine = machine_drawing_sprites();
+        let addressing_mode = |machine: &Machine, tile_map: u8, row: u8| {
+            machine.ppu.tile_map_cell_addressing_mode(TileMapCell {
+                tile_map,
+                column: 0,
+                row,
+            })
+        };
+        // The background on the second tile map, with signed tile ids
+        machine.ppu.lcd_control |= 1 << LCDC_BACKGROUND_TILE_MAP_AREA_BIT;
+        machine.ppu.lcd_control &= !(1 << LCDC_BACKGROUND_AND_WINDOW_TILE_AREA_BIT);
+        shades_on_line(&mut machine, 143);
+        assert_eq!(
+            addressing_mode(&machine, 1, 0),
+            TileAddressingMode::SignedFrom0x9000
+        );
+        assert_eq!(
+            addressing_mode(&machine, 0, 0),
+            TileAddressingMode::UnsignedFrom0x8000
+        );
+
+        // The window from line 64 on, on the first tile map
+        machine.ppu.lcd_control |= 1 << LCDC_WINDOW_ENABLE_BIT;
+        machine.ppu.lcd_control &= !(1 << LCDC_WINDOW_TILE_MAP_AREA_BIT);
+        machine.ppu.window_y = Wrapping(64);
+        machine.ppu.window_x7 = Wrapping(7);
+        shades_on_line(&mut machine, 143);
+        assert_eq!(
+            addressing_mode(&machine, 0, 0),
+            TileAddressingMode::SignedFrom0x9000
+        );
+        // Cells below what the window showed were left alone
+        assert_eq!(
+            addressing_mode(&machine, 0, 10),
+            TileAddressingMode::UnsignedFrom0x8000
+        );
+    }
+
+    #[test]
+    fn scx_scrolls_pixel_by_pixel() {
+        let pattern = [[0, 1, 2, 3, 0, 1, 2, 3], [3, 3, 2, 2, 1, 1, 0, 0]].concat();
+        let mut unscrolled_mode_3_length = 0;
+        for scx in 0..8 {
+            let mut machine = machine_drawing_sprites();
+            write_tile(
+                &mut machine.ppu.vram,
+                1,
+                [pattern[..8].try_into().unwrap(); 8],
+            );
+            write_tile(
+                &mut machine.ppu.vram,
+                2,
+                [pattern[8..].try_into().unwrap(); 8],
+            );
+            for column in 0..32 {
+                machine.ppu.vram[TILE_MAP0_VRAM_OFFSET + column] = 1 + column as u8 % 2;
+            }
+            machine.ppu.scx = Wrapping(scx);
+            let shades = shades_on_line(&mut machine, 0);
+            assert_eq!(shades[0], pattern[scx as usize], "SCX = {}", scx);
+            for (x, shade) in shades.iter().enumerate() {
+                assert_eq!(*shade, pattern[(x + scx as usize) % 16], "SCX = {}", scx);
+            }
+            // Each discarded pixel takes a dot
+            if scx == 0 {
+                unscrolled_mode_3_length = machine.ppu.mode_3_length();
+            }
+            assert_eq!(
+                machine.ppu.mode_3_length(),
+                unscrolled_mode_3_length + scx as u16
+            );
+        }
+    }
+
+    #[test]
+    fn lines_without_sprites_are_drawn_in_time() {
+        let mut machine = machine_drawing_sprites();
+        tick_ppu_until(&mut machine, |machine| machine.ppu.read_ly().0 == 20);
+        assert_eq!(machine.ppu.scanline_dots(), 0);
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 0);
+        assert_eq!(machine.ppu.read_ly().0, 20);
+        assert_eq!(machine.ppu.drawn_pixels_on_current_row(), 160);
+        assert!(machine.ppu.scanline_dots() < 456);
+        assert_eq!(machine.ppu.stalled_scanline_count(), 0);
+    }
+
+    #[test]
+    fn stalled_scanlines_are_completed_and_counted() {
+        let mut machine = machine_drawing_sprites();
+        for ly in [20, 30] {
+            tick_ppu_until(&mut machine, |machine| {
+                machine.ppu.read_ly().0 == ly && machine.ppu.mode() == 3
+            });
+            // Starve the pipeline of background pixels until the scanline is over
+            while machine.ppu.read_ly().0 == ly {
+                machine.background_window_fetcher.fifo.clear();
+                tick_ppu(&mut machine);
+            }
+        }
+        assert_eq!(machine.ppu.stalled_scanline_count(), 2);
+        // The rest of the frame is drawn as usual
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 1);
+        assert_eq!(machine.ppu.read_ly().0, 144);
+        assert_eq!(machine.ppu.stalled_scanline_count(), 2);
+    }
+
+    // Draws line `ly` of a new frame, and returns how long mode 3 and the whole line lasted.
+    fn line_timings(machine: &mut Machine, ly: u8) -> (u16, u16) {
+        tick_ppu_until(machine, |machine| machine.ppu.mode() == 1);
+        tick_ppu_until(machine, |machine| {
+            machine.ppu.read_ly().0 == ly && machine.ppu.mode() == 2
+        });
+        let mut line_dots = 0;
+        while machine.ppu.read_ly().0 == ly {
+            tick_ppu(machine);
+            line_dots += 1;
+        }
+        (machine.ppu.mode_3_length(), line_dots)
+    }
+
+    #[test]
+    fn sprites_lengthen_mode_3_at_the_expense_of_h_blank() {
+        let mut machine = machine_drawing_sprites();
+        let (no_sprite_length, line_dots) = line_timings(&mut machine, 70);
+        assert_eq!(no_sprite_length, 172);
+        assert_eq!(line_dots, 456);
+
+        for slot in 0..10 {
+            write_sprite(
+                &mut machine.ppu,
+                slot,
+                8 + 15 * slot as u8,
+                16 + 70,
+                0,
+                0x00,
+            );
+        }
+        let (ten_sprites_length, line_dots) = line_timings(&mut machine, 70);
+        // Each sprite stalls the background for 6 to 11 dots
+        assert!(ten_sprites_length >= no_sprite_length + 10 * 6);
+        assert!(ten_sprites_length <= no_sprite_length + 10 * 11);
+        assert_eq!(line_dots, 456);
+    }
+
+    #[test]
+    fn vbk_selects_the_vram_bank_in_cgb_mode() {
+        let mut machine = machine_with_lcd_on_as(ConsoleMode::Cgb);
+        machine.write_u8(Wrapping(0xFF40), Wrapping(0x00));
+        for (bank, value) in [(0, 0x11), (1, 0x22)] {
+            machine.write_u8(Wrapping(0xFF4F), Wrapping(bank));
+            machine.write_u8(Wrapping(0x9800), Wrapping(value));
+        }
+        for (bank, value) in [(0, 0x11), (1, 0x22), (0xFE, 0x11), (0xFF, 0x22)] {
+            machine.write_u8(Wrapping(0xFF4F), Wrapping(bank));
+            assert_eq!(machine.read_u8(Wrapping(0x9800)).0, value, "VBK {}", bank);
+        }
+        assert_eq!(machine.ppu.vram[0x1800], 0x11);
+        assert_eq!(machine.ppu.vram_bank_1[0x1800], 0x22);
+
+        // DMG games only ever see the first bank
+        let mut machine = machine_with_lcd_on();
+        machine.write_u8(Wrapping(0xFF40), Wrapping(0x00));
+        machine.write_u8(Wrapping(0xFF4F), Wrapping(1));
+        machine.write_u8(Wrapping(0x9800), Wrapping(0x33));
+        assert_eq!(machine.ppu.vram[0x1800], 0x33);
+        assert_eq!(machine.ppu.vram_bank_1[0x1800], 0x00);
+    }
+
+    #[test]
+    fn cgb_tile_attributes_flip_tiles_and_pick_their_bank() {
+        let other_bank_tile = ASYMMETRIC_TILE.map(|row| row.map(|color| 3 - color));
+        // Row 2 of the tiles is on line 2, it is row 5 when flipped vertically
+        for (attributes, expected) in [
+            (0x00, ASYMMETRIC_TILE[2]),
+            (0x20, reversed(ASYMMETRIC_TILE[2])),
+            (0x40, ASYMMETRIC_TILE[5]),
+            (0x08, other_bank_tile[2]),
+            (0x68, reversed(other_bank_tile[5])),
+        ] {
+            let mut machine = cgb_machine_drawing_sprites();
+            write_tile(&mut machine.ppu.vram, 1, ASYMMETRIC_TILE);
+            write_tile(&mut machine.ppu.vram_bank_1, 1, other_bank_tile);
+            machine.ppu.vram[TILE_MAP0_VRAM_OFFSET..][..0x400].fill(1);
+            machine.ppu.vram_bank_1[TILE_MAP0_VRAM_OFFSET..][..0x400].fill(attributes);
+            let colors = cgb_colors_on_line(&mut machine, 2);
+            assert_eq!(colors[..8], expected, "attributes 0x{:02X}", attributes);
+        }
+    }
+
+    #[test]
+    fn cgb_palettes_fill_up_through_auto_increment() {
+        let mut machine = machine_with_lcd_on_as(ConsoleMode::Cgb);
+        // BCPS and BCPD, then OCPS and OCPD
+        for (spec, data) in [(0xFF68, 0xFF69), (0xFF6A, 0xFF6B)] {
+            machine.write_u8(Wrapping(spec), Wrapping(0x80));
+            for index in 0..64 {
+                machine.write_u8(Wrapping(data), Wrapping((index * 3) ^ data as u8));
+            }
+            // The index wrapped around, bit 6 reads as 1
+            assert_eq!(machine.read_u8(Wrapping(spec)), Wrapping(0xC0));
+            for index in 0..64 {
+                machine.write_u8(Wrapping(spec), Wrapping(index));
+                assert_eq!(
+                    machine.read_u8(Wrapping(data)).0,
+                    (index * 3) ^ data as u8,
+                    "0x{:04X}, index {}",
+                    data,
+                    index
+                );
+                // Reads do not increment the index
+                assert_eq!(machine.read_u8(Wrapping(spec)).0, 0x40 | index);
+            }
+        }
+    }
+
+    #[test]
+    fn cgb_colors_are_scaled_to_8_bits_per_channel() {
+        assert_eq!(cgb_color_to_rgba(0x0000), [0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(cgb_color_to_rgba(0x7FFF), [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(cgb_color_to_rgba(0x7C1F), [0xFF, 0x00, 0xFF, 0xFF]);
+        // Red 16, green 10, blue 0
+        assert_eq!(cgb_color_to_rgba(0x0150), [0x84, 0x52, 0x00, 0xFF]);
+
+        let mut machine = cgb_machine_drawing_sprites();
+        // Color 3 of background palette 2, and color 1 of object palette 5
+        machine.write_u8(Wrapping(0xFF68), Wrapping(0x80 | (2 * 8 + 3 * 2)));
+        machine.write_u8(Wrapping(0xFF69), Wrapping(0x50));
+        machine.write_u8(Wrapping(0xFF69), Wrapping(0x01));
+        machine.write_u8(Wrapping(0xFF6A), Wrapping(0x80 | (5 * 8 + 2)));
+        machine.write_u8(Wrapping(0xFF6B), Wrapping(0x1F));
+        machine.write_u8(Wrapping(0xFF6B), Wrapping(0x7C));
+        write_tile(&mut machine.ppu.vram, 1, [[3; 8]; 8]);
+        write_tile(&mut machine.ppu.vram, 2, [[1; 8]; 8]);
+        machine.ppu.vram[TILE_MAP0_VRAM_OFFSET..][..0x400].fill(1);
+        machine.ppu.vram_bank_1[TILE_MAP0_VRAM_OFFSET..][..0x400].fill(2);
+        write_sprite(&mut machine.ppu, 0, 8 + 80, 16, 2, 5);
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 1);
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 0);
+        let pixel = |x: u8| {
+            let from = pixel_coordinates_in_rgba_slice(x, 0);
+            machine.ppu.lcd_pixels()[from..from + PIXEL_DATA_SIZE].to_vec()
+        };
+        assert_eq!(pixel(0), [0x84, 0x52, 0x00, 0xFF]);
+        assert_eq!(pixel(80), [0xFF, 0x00, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn tile_palette_selection_does_not_recolor_the_tile_maps() {
+        let mut ppu = PPU::new(false, false, false);
+        ppu.background_palette_data = IDENTITY_PALETTE;
+        ppu.object_palette_0 = 0b00_00_11_00;
+        // Tile 0 is all color 1, and fills both tile maps
+        for row in 0..8 {
+            ppu.write_vram(Wrapping(row * 2), Wrapping(0xFF));
+        }
+        ppu.render(TilePaletteSelection::Object0, true, true);
+        assert_eq!(
+            ppu.tile_palette_pixels[..4],
+            pixel_code_to_rgba(1, 0b00_00_11_00)
+        );
+        assert_eq!(
+            ppu.tile_map0_base_pixels[..4],
+            pixel_code_to_rgba(1, IDENTITY_PALETTE)
+        );
+        assert_eq!(
+            ppu.tile_map1_base_pixels[..4],
+            pixel_code_to_rgba(1, IDENTITY_PALETTE)
+        );
+
+        // Changing BGP repaints the tile maps, but not a tile palette shown through OBP0
+        ppu.background_palette_data = 0b11_11_11_11;
+        ppu.render(TilePaletteSelection::Object0, true, false);
+        assert_eq!(
+            ppu.tile_palette_pixels[..4],
+            pixel_code_to_rgba(1, 0b00_00_11_00)
+        );
+        assert_eq!(
+            ppu.tile_map0_base_pixels[..4],
+            pixel_code_to_rgba(1, 0b11_11_11_11)
+        );
+    }
+
+    #[test]
+    fn frames_without_vram_writes_repaint_no_tiles() {
+        let mut machine = machine_with_lcd_on();
+        machine.run_frame();
+        machine
+            .ppu
+            .render(TilePaletteSelection::Background, true, true);
+        // Anything repainted from now on shows up as something else
+        let ppu = &mut machine.ppu;
+        ppu.tile_palette_pixels.fill(0x42);
+        ppu.tile_map0_base_pixels.fill(0x42);
+        ppu.tile_map1_base_pixels.fill(0x42);
+        machine.run_frame();
+        machine
+            .ppu
+            .render(TilePaletteSelection::Background, true, true);
+        let repainted = |pixels: &[u8]| {
+            pixels
+                .chunks(PIXEL_DATA_SIZE)
+                .filter(|rgba| *rgba != [0x42; PIXEL_DATA_SIZE])
+                .count()
+        };
+        assert_eq!(repainted(&machine.ppu.tile_palette_pixels), 0);
+        assert_eq!(repainted(&machine.ppu.tile_map0_base_pixels), 0);
+        assert_eq!(repainted(&machine.ppu.tile_map1_base_pixels), 0);
+
+        // A write only repaints the tile it lands in
+        machine.ppu.write_vram(Wrapping(16), Wrapping(0xFF));
+        machine
+            .ppu
+            .render(TilePaletteSelection::Background, false, false);
+        assert_eq!(repainted(&machine.ppu.tile_palette_pixels), PIXELS_PER_TILE);
+    }
+
+    #[test]
+    fn outlines_wrap_around_the_tile_map() {
+        let mut machine = machine_with_lcd_on();
+        for (register, value) in [(0xFF42, 250), (0xFF43, 252), (0xFF4A, 50), (0xFF4B, 107)] {
+            machine.write_u8(Wrapping(register), Wrapping(value));
+        }
+        machine.write_u8(Wrapping(0xFF40), Wrapping(0xB1));
+        machine.run_frame();
+        machine.run_frame();
+        machine.ppu.render_tile_map0();
+        let pixel = |x: usize, y: usize| {
+            let from = (y * TILE_MAP_HORIZONTAL_PIXELS + x) * PIXEL_DATA_SIZE;
+            machine.ppu.tile_map0_pixels[from..from + PIXEL_DATA_SIZE].to_vec()
+        };
+        // The viewport spans 252..=155 horizontally, and 250..=137 vertically
+        for (x, y) in [
+            (252, 250),
+            (155, 250),
+            (252, 137),
+            (155, 137),
+            (0, 250),
+            (252, 0),
+        ] {
+            assert_eq!(pixel(x, y), VIEWPORT_OUTLINE_COLOR, "({}, {})", x, y);
+        }
+        for (x, y) in [(251, 250), (156, 250), (253, 251), (154, 136)] {
+            assert_ne!(pixel(x, y), VIEWPORT_OUTLINE_COLOR, "({}, {})", x, y);
+        }
+        // The window shows from the corner of its tile map, down to the bottom-right of the screen
+        for (x, y) in [(0, 0), (59, 0), (0, 93), (59, 93)] {
+            assert_eq!(pixel(x, y), WINDOW_OUTLINE_COLOR, "({}, {})", x, y);
+        }
+        assert_ne!(pixel(60, 94), WINDOW_OUTLINE_COLOR);
+    }
+
+    #[test]
+    fn stat_reads_the_mode_throughout_a_frame() {
+        let mut machine = machine_with_lcd_on();
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 1);
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 2);
+        let mut modes = vec![];
+        for _ in 0..154 * 456 {
+            let mode = machine.read_u8(Wrapping(0xFF41)).0 & 0b11;
+            if modes.last() != Some(&mode) {
+                modes.push(mode);
+            }
+            tick_ppu(&mut machine);
+        }
+        let expected = [[2, 3, 0].repeat(144), vec![1]].concat();
+        assert_eq!(modes, expected);
+    }
+
+    #[test]
+    fn the_front_buffer_only_changes_at_v_blank() {
+        let mut machine = machine_with_lcd_on();
+        machine.write_u8(Wrapping(0xFF47), Wrapping(0xE4));
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 1);
+        let completed_frame = machine.ppu.front_buffer.clone();
+        assert_eq!(machine.ppu.lcd_pixels(), &completed_frame[..]);
+
+        // Color 0 goes from white to black for the next frame
+        machine.write_u8(Wrapping(0xFF47), Wrapping(0xE7));
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 2);
+        for _ in 0..100 {
+            tick_ppu(&mut machine);
+        }
+        assert_eq!(machine.ppu.front_buffer, completed_frame);
+        tick_ppu_until(&mut machine, |machine| machine.ppu.read_ly().0 == 100);
+        assert_eq!(machine.ppu.front_buffer, completed_frame);
+        assert_ne!(machine.ppu.lcd_pixels(), &completed_frame[..]);
+
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 1);
+        assert_ne!(machine.ppu.front_buffer, completed_frame);
+        assert_eq!(machine.ppu.lcd_pixels(), &machine.ppu.front_buffer[..]);
+    }
+
+    #[test]
+    fn writes_to_ly_are_ignored() {
+        let mut machine = machine_with_lcd_on();
+        tick_ppu_until(&mut machine, |machine| machine.ppu.read_ly().0 == 50);
+        tick_ppu(&mut machine);
+        for value in [0x00, 0x32, 0x99] {
+            machine.write_u8(Wrapping(0xFF44), Wrapping(value));
+            assert_eq!(machine.ppu.read_ly(), Wrapping(50));
+            assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(50));
+            assert_eq!(machine.ppu.scanline_dots(), 1);
+        }
+        for _ in 1..456 {
+            tick_ppu(&mut machine);
+        }
+        assert_eq!(machine.ppu.read_ly(), Wrapping(51));
+    }
+
+    #[test]
+    fn turning_the_lcd_off_and_on_restarts_the_frame() {
+        let mut machine = machine_with_lcd_on();
+        tick_ppu_until(&mut machine, |machine| {
+            machine.ppu.read_ly().0 == 100 && machine.ppu.mode() == 3
+        });
+        machine.write_u8(Wrapping(0xFF40), Wrapping(0x11));
+        assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(0));
+        assert_eq!(machine.read_u8(Wrapping(0xFF41)).0 & 0b11, 0);
+        assert!(machine
+            .ppu
+            .front_buffer
+            .chunks(PIXEL_DATA_SIZE)
+            .all(|pixel| pixel == WHITE));
+        // Nothing moves while off
+        for _ in 0..1000 {
+            tick_ppu(&mut machine);
+        }
+        assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(0));
+        assert_eq!(machine.read_u8(Wrapping(0xFF41)).0 & 0b11, 0);
+
+        machine.write_u8(Wrapping(0xFF40), Wrapping(0x91));
+        assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(0));
+        assert_eq!(machine.read_u8(Wrapping(0xFF41)).0 & 0b11, 2);
+        assert_eq!(machine.ppu.scanline_dots(), 0);
+        for _ in 0..OAM_SCAN_DOTS {
+            tick_ppu(&mut machine);
+        }
+        assert_eq!(machine.read_u8(Wrapping(0xFF41)).0 & 0b11, 3);
+        for _ in OAM_SCAN_DOTS..456 {
+            tick_ppu(&mut machine);
+        }
+        assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(1));
+    }
+
+    #[test]
+    fn the_cpu_is_locked_out_of_vram_and_oam_while_the_ppu_uses_them() {
+        let mut machine = machine_with_lcd_on();
+        // VRAM is only locked during mode 3, OAM during modes 2 and 3
+        for (mode, is_vram_locked, is_oam_locked) in [
+            (0, false, false),
+            (1, false, false),
+            (2, false, true),
+            (3, true, true),
+        ] {
+            tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == mode);
+            machine.ppu.vram[0x10] = 0x42;
+            machine.ppu.object_attribute_memory[0x10] = 0x42;
+            for (address, is_locked) in [(0x8010, is_vram_locked), (0xFE10, is_oam_locked)] {
+                let address = Wrapping(address);
+                machine.write_u8(address, Wrapping(0x99));
+                let (read, stored) = if is_locked {
+                    (0xFF, 0x42)
+                } else {
+                    (0x99, 0x99)
+                };
+                assert_eq!(
+                    machine.read_u8(address).0,
+                    read,
+                    "0x{:04X}, mode {}",
+                    address.0,
+                    mode
+                );
+                assert_eq!(
+                    machine.read_u8_raw(address).0,
+                    stored,
+                    "0x{:04X}, mode {}",
+                    address.0,
+                    mode
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stat_writes_keep_the_bits_owned_by_the_ppu() {
+        let mut machine = machine_with_lcd_on();
+        tick_ppu_until(&mut machine, |machine| machine.ppu.mode() == 3);
+        machine.write_u8(Wrapping(0xFF41), Wrapping(0x00));
+        assert_eq!(machine.read_u8(Wrapping(0xFF41)).0 & 0x83, 0x83);
+        let stat = machine.read_u8(Wrapping(0xFF41)).0;
+        machine.write_u8(Wrapping(0xFF41), Wrapping(0x78));
+        assert_eq!(machine.read_u8(Wrapping(0xFF41)).0, stat & 0x87 | 0x78);
+    }
+
+    #[test]
+    fn writing_lyc_equal_to_ly_requests_a_stat_interrupt() {
+        let mut machine = machine_with_lcd_on();
+        machine.write_u8(Wrapping(0xFF45), Wrapping(0xFF));
+        machine.write_u8(Wrapping(0xFF41), Wrapping(0x40));
+        tick_ppu_until(&mut machine, |machine| machine.ppu.read_ly().0 == 42);
+        machine.interrupts.interrupt_flag = Wrapping(0);
+        machine.write_u8(Wrapping(0xFF45), Wrapping(42));
+        assert_ne!(machine.read_u8(Wrapping(0xFF41)).0 & 0b100, 0);
+        assert_eq!(machine.interrupts.interrupt_flag.0, 1 << STAT_INTERRUPT_BIT);
+        // Writing it again while the line is still high does not request another one
+        machine.interrupts.interrupt_flag = Wrapping(0);
+        machine.write_u8(Wrapping(0xFF45), Wrapping(42));
+        assert_eq!(machine.interrupts.interrupt_flag.0, 0);
+        // Moving LYC away drops the coincidence flag
+        machine.write_u8(Wrapping(0xFF45), Wrapping(43));
+        assert_eq!(machine.read_u8(Wrapping(0xFF41)).0 & 0b100, 0);
+        assert_eq!(machine.interrupts.interrupt_flag.0, 0);
+    }
+
+    #[test]
+    fn ly_reads_zero_for_most_of_the_last_scanline() {
+        let mut machine = machine_with_lcd_on();
+        // Interrupts on LY=LYC=0
+        machine.write_u8(Wrapping(0xFF41), Wrapping(0x40));
+        tick_ppu_until(&mut machine, |machine| {
+            machine.ppu.read_ly().0 == LAST_SCANLINE
+        });
+        machine.interrupts.interrupt_flag = Wrapping(0);
+        for dot in 0..456 {
+            assert_eq!(machine.ppu.scanline_dots(), dot);
+            assert_eq!(machine.ppu.mode(), 1);
+            let ly = machine.read_u8(Wrapping(0xFF44)).0;
+            let stat = machine.read_u8(Wrapping(0xFF41)).0;
+            let is_stat_interrupt_requested =
+                machine.interrupts.interrupt_flag.0 & (1 << STAT_INTERRUPT_BIT) != 0;
+            if dot < LAST_SCANLINE_LY_DOTS {
+                assert_eq!(ly, LAST_SCANLINE, "dot {}", dot);
+                assert_eq!(stat & 0b100, 0, "dot {}", dot);
+                assert!(!is_stat_interrupt_requested, "dot {}", dot);
+            } else {
+                assert_eq!(ly, 0, "dot {}", dot);
+                assert_ne!(stat & 0b100, 0, "dot {}", dot);
+                assert!(is_stat_interrupt_requested, "dot {}", dot);
+            }
+            tick_ppu(&mut machine);
+        }
+        // The next frame starts with LY still 0
+        assert_eq!(machine.ppu.mode(), 2);
+        assert_eq!(machine.read_u8(Wrapping(0xFF44)), Wrapping(0));
+    }
 }
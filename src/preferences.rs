@@ -0,0 +1,63 @@
+use std::{fs, io, path::Path};
+
+// Choices made in the GUI that should survive restarts.  They are saved as they change, one
+// `name = value` per line.
+#[derive(Clone, Debug)]
+pub struct Preferences {
+    pub is_input_display_shown: bool,
+}
+
+impl Preferences {
+    pub fn new() -> Self {
+        Preferences {
+            is_input_display_shown: false,
+        }
+    }
+
+    // A missing file is not an error, the defaults are used instead.
+    pub fn load(path: &str) -> Result<Self, io::Error> {
+        if !Path::new(path).exists() {
+            return Ok(Preferences::new());
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(Preferences::parse(&contents))
+    }
+
+    // Lines that cannot be understood are skipped, leaving the default value.
+    pub fn parse(contents: &str) -> Self {
+        let mut preferences = Preferences::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_input_display(line) {
+                Some(is_input_display_shown) => {
+                    preferences.is_input_display_shown = is_input_display_shown
+                }
+                None => println!(
+                    "[WARNING] Ignoring preference on line {}: {}",
+                    line_number + 1,
+                    line
+                ),
+            }
+        }
+        preferences
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), io::Error> {
+        fs::write(
+            path,
+            format!("input_display = {}\n", self.is_input_display_shown),
+        )
+    }
+}
+
+// Only one preference for now.
+fn parse_input_display(line: &str) -> Option<bool> {
+    let (name, value) = line.split_once('=')?;
+    if name.trim() != "input_display" {
+        return None;
+    }
+    value.trim().parse().ok()
+}
@@ -1,9 +1,7 @@
 use core::fmt;
 use std::num::Wrapping;
 
-use crate::machine::Machine;
-
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug, Hash, PartialEq)]
 pub enum R8 {
     A,
     B,
@@ -56,7 +54,7 @@ impl Flag {
     }
 }
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct Registers {
     pub af: Wrapping<u16>,
     pub bc: Wrapping<u16>,
@@ -95,8 +93,9 @@ impl Registers {
         self
     }
 
+    // The lower nibble of F does not exist in hardware, it always reads as 0.
     fn write_f(&mut self, f: Wrapping<u8>) -> &mut Self {
-        self.af = u16_from_u8s(self.read_a(), f);
+        self.af = u16_from_u8s(self.read_a(), f & Wrapping(0xF0));
         self
     }
 
@@ -201,7 +200,7 @@ impl Registers {
 
     pub fn write_r16(&mut self, r16: &R16, value: Wrapping<u16>) -> &mut Self {
         match r16 {
-            R16::AF => self.af = value,
+            R16::AF => self.af = value & Wrapping(0xFFF0),
             R16::BC => self.bc = value,
             R16::DE => self.de = value,
             R16::HL => self.hl = value,
@@ -244,15 +243,18 @@ impl Registers {
     }
 }
 
-impl Machine {
-    // Used very frequently
-    pub fn read_r8(&self, r8: &R8) -> Wrapping<u8> {
-        self.registers().read_r8(r8)
-    }
-
-    // Used very frequently
-    pub fn write_r8(&mut self, r8: &R8, value: Wrapping<u8>) -> &mut Self {
-        self.registers_mut().write_r8(r8, value);
-        self
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_low_nibble_of_f_reads_as_zero() {
+        let mut registers = Registers::new();
+        registers.write_r8(&R8::F, Wrapping(0xFF));
+        assert_eq!(registers.read_f(), Wrapping(0xF0));
+        registers.write_r16(&R16::AF, Wrapping(0x12FF));
+        assert_eq!(registers.af, Wrapping(0x12F0));
+        registers.znhc(false, false, false, false);
+        assert_eq!(registers.af, Wrapping(0x1200));
     }
 }
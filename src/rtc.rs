@@ -0,0 +1,264 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Values written to the MBC3 RAM bank register to map an RTC register at 0xA000-0xBFFF
+const RTC_SECONDS: u8 = 0x08;
+const RTC_MINUTES: u8 = 0x09;
+const RTC_HOURS: u8 = 0x0A;
+const RTC_DAY_LOW: u8 = 0x0B;
+const RTC_DAY_HIGH: u8 = 0x0C;
+
+// DH single bits of interest
+const DH_DAY_BIT_8: u8 = 0;
+const DH_HALT_BIT: u8 = 6;
+const DH_DAY_CARRY_BIT: u8 = 7;
+
+// Only the bits backed by the counters, unused bits read back as 0
+const SECONDS_MASK: u8 = 0x3F;
+const MINUTES_MASK: u8 = 0x3F;
+const HOURS_MASK: u8 = 0x1F;
+const DAY_MASK: u16 = 0x1FF;
+
+// Save files end with the live and latched registers, 4 bytes each, then a 64-bit UNIX timestamp.
+// This is the format most emulators agree on.
+pub const RTC_SAVE_SIZE: usize = 48;
+const RTC_SAVE_REGISTERS_SIZE: usize = 20;
+
+// The RTC has its own 32768Hz crystal, but it ticks in step with the CPU clock
+const T_CYCLES_PER_SECOND: u32 = 4_194_304;
+
+#[derive(Clone, Debug, Default, Hash, PartialEq)]
+struct RTCRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u16,
+    is_halted: bool,
+    day_carry: bool,
+}
+
+impl RTCRegisters {
+    // Counters that were written out of range keep counting up to their mask before wrapping to 0,
+    // without carrying into the next counter.
+    fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & SECONDS_MASK;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & MINUTES_MASK;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & HOURS_MASK;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.day = (self.day + 1) & DAY_MASK;
+        if self.day == 0 {
+            // Sticks until the game clears it
+            self.day_carry = true;
+        }
+    }
+
+    fn day_high(&self) -> u8 {
+        ((self.day >> 8) as u8) << DH_DAY_BIT_8
+            | (self.is_halted as u8) << DH_HALT_BIT
+            | (self.day_carry as u8) << DH_DAY_CARRY_BIT
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            RTC_SECONDS => self.seconds,
+            RTC_MINUTES => self.minutes,
+            RTC_HOURS => self.hours,
+            RTC_DAY_LOW => self.day as u8,
+            RTC_DAY_HIGH => self.day_high(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            RTC_SECONDS => self.seconds = value & SECONDS_MASK,
+            RTC_MINUTES => self.minutes = value & MINUTES_MASK,
+            RTC_HOURS => self.hours = value & HOURS_MASK,
+            RTC_DAY_LOW => self.day = (self.day & 0x100) | value as u16,
+            RTC_DAY_HIGH => {
+                self.day = (self.day & 0xFF) | (((value >> DH_DAY_BIT_8) & 1) as u16) << 8;
+                self.is_halted = (value >> DH_HALT_BIT) & 1 != 0;
+                self.day_carry = (value >> DH_DAY_CARRY_BIT) & 1 != 0;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn to_save_bytes(&self) -> Vec<u8> {
+        (RTC_SECONDS..=RTC_DAY_HIGH)
+            .flat_map(|register| (self.read(register) as u32).to_le_bytes())
+            .collect()
+    }
+
+    fn from_save_bytes(bytes: &[u8]) -> Self {
+        let mut registers = RTCRegisters::default();
+        for (register, chunk) in (RTC_SECONDS..=RTC_DAY_HIGH).zip(bytes.chunks_exact(4)) {
+            registers.write(register, chunk[0]);
+        }
+        registers
+    }
+}
+
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// The MBC3 real-time clock.  It counts emulated time rather than host time, so that runs are
+// deterministic, and only catches up with host time for while the emulator was closed.  Games read
+// a snapshot of the counters, taken when they latch the clock.
+#[derive(Clone, Debug, Hash)]
+pub struct RTC {
+    live: RTCRegisters,
+    latched: RTCRegisters,
+    /// Time elapsed since the last full second, carried over to the next one
+    sub_second_t_cycles: u32,
+    /// The last value written to the latch register, latching happens on a 0 then 1 sequence.
+    last_latch_write: u8,
+}
+
+impl RTC {
+    pub fn new() -> Self {
+        RTC {
+            live: RTCRegisters::default(),
+            latched: RTCRegisters::default(),
+            sub_second_t_cycles: 0,
+            last_latch_write: 0xFF,
+        }
+    }
+
+    pub fn is_register(register: u8) -> bool {
+        (RTC_SECONDS..=RTC_DAY_HIGH).contains(&register)
+    }
+
+    pub fn ticks(&mut self, t_cycles: u8) {
+        if self.live.is_halted {
+            return;
+        }
+        self.sub_second_t_cycles += t_cycles as u32;
+        if self.sub_second_t_cycles >= T_CYCLES_PER_SECOND {
+            self.sub_second_t_cycles -= T_CYCLES_PER_SECOND;
+            self.live.tick_second();
+        }
+    }
+
+    // Moves the live counters forward, as if `seconds` had passed while the clock was running.
+    fn advance_seconds(&mut self, seconds: u64) {
+        for _ in 0..seconds {
+            self.live.tick_second();
+        }
+    }
+
+    // Writing 0x00 then 0x01 to 0x6000-0x7FFF copies the live counters to the latched ones.
+    pub fn write_latch(&mut self, value: u8) {
+        if self.last_latch_write == 0x00 && value == 0x01 {
+            self.latched = self.live.clone();
+        }
+        self.last_latch_write = value;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched.read(register)
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        if register == RTC_SECONDS {
+            // Writing the seconds restarts the current second
+            self.sub_second_t_cycles = 0;
+        }
+        self.live.write(register, value);
+        self.latched.write(register, value);
+    }
+
+    pub fn to_save_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.live.to_save_bytes();
+        bytes.extend(self.latched.to_save_bytes());
+        bytes.extend(unix_time_now().to_le_bytes());
+        bytes
+    }
+
+    // The clock kept running while the emulator was closed, unless it was halted.
+    pub fn load_save_bytes(&mut self, bytes: &[u8]) {
+        let (live, rest) = bytes.split_at(RTC_SAVE_REGISTERS_SIZE);
+        let (latched, timestamp) = rest.split_at(RTC_SAVE_REGISTERS_SIZE);
+        self.live = RTCRegisters::from_save_bytes(live);
+        self.latched = RTCRegisters::from_save_bytes(latched);
+        self.sub_second_t_cycles = 0;
+        let saved_at = u64::from_le_bytes(timestamp.try_into().unwrap());
+        let seconds_since_save = unix_time_now().saturating_sub(saved_at);
+        if !self.live.is_halted {
+            self.advance_seconds(seconds_since_save);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers_at(hours: u8, minutes: u8, seconds: u8, day: u16) -> RTCRegisters {
+        RTCRegisters {
+            seconds,
+            minutes,
+            hours,
+            day,
+            ..RTCRegisters::default()
+        }
+    }
+
+    #[test]
+    fn seconds_carry_into_minutes_and_hours() {
+        let mut registers = registers_at(5, 59, 59, 0);
+        registers.tick_second();
+        assert_eq!(registers, registers_at(6, 0, 0, 0));
+    }
+
+    #[test]
+    fn the_last_second_of_a_day_carries_into_the_day_counter() {
+        let mut registers = registers_at(23, 59, 59, 0xFF);
+        registers.tick_second();
+        assert_eq!(registers, registers_at(0, 0, 0, 0x100));
+        assert_eq!(registers.read(RTC_DAY_LOW), 0x00);
+        assert_eq!(registers.read(RTC_DAY_HIGH), 1 << DH_DAY_BIT_8);
+    }
+
+    #[test]
+    fn the_day_counter_overflows_into_the_carry_bit() {
+        let mut registers = registers_at(23, 59, 59, DAY_MASK);
+        registers.tick_second();
+        assert_eq!(registers.day, 0);
+        assert!(registers.day_carry);
+        assert_eq!(registers.read(RTC_DAY_HIGH), 1 << DH_DAY_CARRY_BIT);
+        // The carry sticks, until the game clears it
+        registers.tick_second();
+        assert!(registers.day_carry);
+        registers.write(RTC_DAY_HIGH, 0);
+        assert!(!registers.day_carry);
+    }
+
+    #[test]
+    fn a_halted_clock_does_not_count() {
+        let mut rtc = RTC::new();
+        rtc.write(RTC_DAY_HIGH, 1 << DH_HALT_BIT);
+        for _ in 0..2 * T_CYCLES_PER_SECOND / 128 {
+            rtc.ticks(128);
+        }
+        assert_eq!(rtc.live.seconds, 0);
+        rtc.write(RTC_DAY_HIGH, 0);
+        for _ in 0..2 * T_CYCLES_PER_SECOND / 128 {
+            rtc.ticks(128);
+        }
+        assert_eq!(rtc.live.seconds, 2);
+    }
+}
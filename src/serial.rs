@@ -0,0 +1,251 @@
+use std::{
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    num::Wrapping,
+};
+
+use crate::cpu::interrupts::{Interrupts, SERIAL_INTERRUPT_BIT};
+
+const SERIAL_DATA_ADDRESS: u16 = 0xFF01;
+const SERIAL_CONTROL_ADDRESS: u16 = 0xFF02;
+
+// Serial control single bits of interest
+const SC_CLOCK_SELECT_BIT: u8 = 0;
+const SC_TRANSFER_ENABLE_BIT: u8 = 7;
+
+// With the internal clock, bits are shifted at 8192Hz.
+const DOTS_PER_SHIFTED_BIT: u16 = 512;
+
+// Only the end of what was printed is kept, test ROMs print their verdict last.  The machine gets
+// copied for every instruction when keeping history, so this must stay small.
+const CONSOLE_OUTPUT_CAPACITY: usize = 4096;
+
+// Whatever sits at the other end of the link cable.
+#[derive(Clone, Debug, Hash)]
+pub enum SerialPeer {
+    // Nothing is plugged in, the line floats high and every incoming bit reads as 1.
+    Disconnected,
+}
+
+impl SerialPeer {
+    fn exchange_bit(&mut self, _outgoing: bool) -> bool {
+        match self {
+            SerialPeer::Disconnected => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Serial {
+    pub serial_data: Wrapping<u8>,
+    pub serial_control: Wrapping<u8>,
+    peer: SerialPeer,
+    shifted_bits: u8,
+    shift_dots: u16,
+    /// Test ROMs like Blargg's print their results over the link cable, one byte per transfer.
+    /// When set, outgoing bytes are kept in `console_output` and printed.  It is left out of
+    /// the hash, the game cannot read it back.
+    pub is_console_enabled: bool,
+    console_output: String,
+}
+
+impl Hash for Serial {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.serial_data.hash(state);
+        self.serial_control.hash(state);
+        self.peer.hash(state);
+        self.shifted_bits.hash(state);
+        self.shift_dots.hash(state);
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            serial_data: Wrapping(0),
+            serial_control: Wrapping(0),
+            peer: SerialPeer::Disconnected,
+            shifted_bits: 0,
+            shift_dots: 0,
+            is_console_enabled: false,
+            console_output: String::new(),
+        }
+    }
+
+    pub fn console_output(&self) -> &str {
+        &self.console_output
+    }
+
+    // The byte is taken as the transfer starts, the transfer itself then goes on as usual, and
+    // completes with 0xFF shifted in since nothing is plugged in.
+    fn capture_console_byte(&mut self) {
+        let byte = self.serial_data.0 as char;
+        self.console_output.push(byte);
+        if self.console_output.len() > CONSOLE_OUTPUT_CAPACITY {
+            let excess = self.console_output.len() - CONSOLE_OUTPUT_CAPACITY;
+            let start = (excess..=self.console_output.len())
+                .find(|index| self.console_output.is_char_boundary(*index))
+                .unwrap();
+            self.console_output.drain(..start);
+        }
+        print!("{}", byte);
+        // Test ROMs print one character at a time, and may never print a newline
+        let _ = io::stdout().flush();
+    }
+
+    fn is_bit_set(&self, bit: u8) -> bool {
+        (self.serial_control.0 >> bit) & 1 != 0
+    }
+
+    // Only transfers clocked by this side make progress, when the clock is external we would be
+    // waiting for the peer forever.
+    fn is_transferring(&self) -> bool {
+        self.is_bit_set(SC_TRANSFER_ENABLE_BIT) && self.is_bit_set(SC_CLOCK_SELECT_BIT)
+    }
+
+    pub fn tick(&mut self, interrupts: &mut Interrupts) {
+        if !self.is_transferring() {
+            return;
+        }
+
+        self.shift_dots += 1;
+        if self.shift_dots < DOTS_PER_SHIFTED_BIT {
+            return;
+        }
+        self.shift_dots = 0;
+
+        // Bits go out most significant first, while incoming bits are shifted in from the right.
+        let outgoing = (self.serial_data.0 >> 7) != 0;
+        let incoming = self.peer.exchange_bit(outgoing);
+        self.serial_data = Wrapping((self.serial_data.0 << 1) | incoming as u8);
+        self.shifted_bits += 1;
+
+        if self.shifted_bits == 8 {
+            self.shifted_bits = 0;
+            self.serial_control &= !(1 << SC_TRANSFER_ENABLE_BIT);
+            interrupts.request(SERIAL_INTERRUPT_BIT);
+        }
+    }
+
+    pub fn ticks(&mut self, interrupts: &mut Interrupts, dots: u8) {
+        for _ in 0..dots {
+            self.tick(interrupts);
+        }
+    }
+
+    pub fn read_u8(&self, address: Wrapping<u16>) -> Wrapping<u8> {
+        match address.0 {
+            SERIAL_DATA_ADDRESS => self.serial_data,
+            SERIAL_CONTROL_ADDRESS => self.serial_control,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_u8(&mut self, address: Wrapping<u16>, value: Wrapping<u8>) {
+        match address.0 {
+            SERIAL_DATA_ADDRESS => self.serial_data = value,
+            SERIAL_CONTROL_ADDRESS => {
+                let was_transfer_requested = self.is_bit_set(SC_TRANSFER_ENABLE_BIT);
+                self.serial_control = value;
+                // Starting a transfer always starts from the first bit
+                self.shifted_bits = 0;
+                self.shift_dots = 0;
+                // Writing SC again while the transfer goes on does not send another byte
+                if self.is_console_enabled && !was_transfer_requested && self.is_transferring() {
+                    self.capture_console_byte();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(serial: &mut Serial, address: u16, value: u8) {
+        serial.write_u8(Wrapping(address), Wrapping(value));
+    }
+
+    // Sends a byte the way test ROMs do, and lets the transfer complete.
+    fn send(serial: &mut Serial, interrupts: &mut Interrupts, byte: u8) {
+        write(serial, SERIAL_DATA_ADDRESS, byte);
+        write(serial, SERIAL_CONTROL_ADDRESS, 0x81);
+        for _ in 0..8 * DOTS_PER_SHIFTED_BIT {
+            serial.tick(interrupts);
+        }
+    }
+
+    #[test]
+    fn transfers_with_the_internal_clock_are_captured() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::new();
+        serial.is_console_enabled = true;
+        for byte in b"Passed" {
+            send(&mut serial, &mut interrupts, *byte);
+        }
+        assert_eq!(serial.console_output(), "Passed");
+        // Nothing plugged in, the transfer completes with 0xFF shifted in
+        assert_eq!(serial.serial_data, Wrapping(0xFF));
+        assert_eq!(serial.serial_control.0 & 0x80, 0);
+        assert_ne!(interrupts.interrupt_flag.0 & (1 << SERIAL_INTERRUPT_BIT), 0);
+    }
+
+    #[test]
+    fn a_transfer_takes_4096_dots() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::new();
+        write(&mut serial, SERIAL_DATA_ADDRESS, 0x42);
+        write(&mut serial, SERIAL_CONTROL_ADDRESS, 0x81);
+        for dot in 1..=4096 {
+            serial.tick(&mut interrupts);
+            let is_requested = interrupts.interrupt_flag.0 & (1 << SERIAL_INTERRUPT_BIT) != 0;
+            assert_eq!(is_requested, dot == 4096, "dot {}", dot);
+        }
+        assert_eq!(
+            serial.read_u8(Wrapping(SERIAL_DATA_ADDRESS)),
+            Wrapping(0xFF)
+        );
+        assert_eq!(
+            serial.read_u8(Wrapping(SERIAL_CONTROL_ADDRESS)),
+            Wrapping(0x01)
+        );
+    }
+
+    #[test]
+    fn only_starting_a_transfer_captures() {
+        let mut serial = Serial::new();
+        serial.is_console_enabled = true;
+        write(&mut serial, SERIAL_DATA_ADDRESS, b'A');
+        // External clock, the peer would be the one to clock the transfer
+        write(&mut serial, SERIAL_CONTROL_ADDRESS, 0x80);
+        assert_eq!(serial.console_output(), "");
+        write(&mut serial, SERIAL_CONTROL_ADDRESS, 0x00);
+        write(&mut serial, SERIAL_CONTROL_ADDRESS, 0x81);
+        // Rewriting SC mid-transfer does not send the byte again
+        write(&mut serial, SERIAL_CONTROL_ADDRESS, 0x81);
+        assert_eq!(serial.console_output(), "A");
+    }
+
+    #[test]
+    fn nothing_is_captured_unless_enabled() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::new();
+        send(&mut serial, &mut interrupts, b'A');
+        assert_eq!(serial.console_output(), "");
+    }
+
+    #[test]
+    fn console_output_keeps_the_most_recent_bytes() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::new();
+        serial.is_console_enabled = true;
+        for _ in 0..CONSOLE_OUTPUT_CAPACITY {
+            send(&mut serial, &mut interrupts, b'.');
+        }
+        send(&mut serial, &mut interrupts, 0xE9);
+        assert_eq!(serial.console_output().chars().last(), Some('\u{E9}'));
+        assert!(serial.console_output().len() <= CONSOLE_OUTPUT_CAPACITY);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, io, num::Wrapping};
+
+use crate::machine::Machine;
+
+// Labels from a RGBDS .sym file, keyed by (bank, address).
+#[derive(Clone, Debug)]
+pub struct SymbolTable {
+    symbols: HashMap<(u16, u16), String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &String) -> Result<Self, io::Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(SymbolTable::parse(&contents))
+    }
+
+    // Lines look like `01:4ABC FuncName`, and `;` starts a comment.  Malformed lines are skipped.
+    pub fn parse(contents: &str) -> Self {
+        let mut symbols = HashMap::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = match line.split_once(';') {
+                Some((before_comment, _)) => before_comment,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_line(line) {
+                Some((bank, address, name)) => {
+                    symbols.insert((bank, address), String::from(name));
+                }
+                None => println!(
+                    "[WARNING] Ignoring malformed symbol on line {}: {}",
+                    line_number + 1,
+                    line
+                ),
+            }
+        }
+        SymbolTable { symbols }
+    }
+
+    // Only 0x4000-0x7FFF is banked on the ROM side, so that is the only range where we need to know
+    // which bank is currently mapped.  Other ranges are looked up in their default bank (RGBDS puts
+    // WRAMX in bank 1).
+    pub fn lookup(&self, machine: &Machine, address: Wrapping<u16>) -> Option<&str> {
+        let banks: &[u16] = match address.0 {
+            0x4000..=0x7FFF => &[machine.current_rom_bank()],
+            _ => &[0, 1],
+        };
+        banks
+            .iter()
+            .find_map(|bank| self.symbols.get(&(*bank, address.0)))
+            .map(|name| name.as_str())
+    }
+}
+
+fn parse_line(line: &str) -> Option<(u16, u16, &str)> {
+    let (location, name) = line.split_once(char::is_whitespace)?;
+    let (bank, address) = location.split_once(':')?;
+    let bank = u16::from_str_radix(bank, 16).ok()?;
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((bank, address, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application_state::{MapperType, ROMInformation},
+        machine::tests::machine_with_cartridge,
+    };
+
+    #[test]
+    fn parses_labels_and_skips_comments_and_malformed_lines() {
+        let symbols = SymbolTable::parse(
+            "\
+; File generated by rgblink
+00:0150 Start
+01:4ABC FuncName ; trailing comment
+00:C000 wBuffer
+  02:7FFF   Padded.local  
+
+4ABC NoBank
+01:ZZZZ NotHex
+01:4000
+",
+        );
+        let mut labels: Vec<_> = symbols.symbols.into_iter().collect();
+        labels.sort();
+        assert_eq!(
+            labels,
+            [
+                ((0x00, 0x0150), String::from("Start")),
+                ((0x00, 0xC000), String::from("wBuffer")),
+                ((0x01, 0x4ABC), String::from("FuncName")),
+                ((0x02, 0x7FFF), String::from("Padded.local")),
+            ]
+        );
+    }
+
+    #[test]
+    fn banked_labels_follow_the_mapped_rom_bank() {
+        let symbols = SymbolTable::parse("00:0150 Start\n01:4000 InBank1\n02:4000 InBank2\n");
+        let rom_information = ROMInformation {
+            mapper_type: MapperType::MBC1,
+            rom_banks: 4,
+            ..ROMInformation::new()
+        };
+        let mut machine = machine_with_cartridge(&[], rom_information);
+        assert_eq!(symbols.lookup(&machine, Wrapping(0x0150)), Some("Start"));
+        assert_eq!(symbols.lookup(&machine, Wrapping(0x4000)), Some("InBank1"));
+        machine.write_u8(Wrapping(0x2000), Wrapping(0x02));
+        assert_eq!(symbols.lookup(&machine, Wrapping(0x4000)), Some("InBank2"));
+        assert_eq!(symbols.lookup(&machine, Wrapping(0x4001)), None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::num::Wrapping;
+use std::{hash::Hasher, num::Wrapping};
 
 pub fn is_bit_set(value: &Wrapping<u8>, bit_position: u8) -> bool {
     (value.0 & (1 << bit_position)) != 0
@@ -27,3 +27,39 @@ pub fn set_bit(value: &mut Wrapping<u8>, bit_position: u8) {
 pub fn unset_bit(value: &mut Wrapping<u8>, bit_position: u8) {
     *value = compute_unset_bit(value, bit_position)
 }
+
+// FNV-1a, unlike the standard library hasher it is stable across Rust versions, so that frame
+// hashes can be written down and compared later.
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1aHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+// Lets `#[derive(Hash)]` types be hashed with FNV-1a.  The bytes derived impls feed it are only
+// stable for a given build, so these hashes are meant to be compared within a run.
+#[derive(Clone, Debug)]
+pub struct Fnv1aHasher {
+    hash: u64,
+}
+
+impl Fnv1aHasher {
+    pub fn new() -> Self {
+        Fnv1aHasher {
+            hash: 0xCBF2_9CE4_8422_2325,
+        }
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
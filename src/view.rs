@@ -4,12 +4,21 @@ use iced::advanced::image;
 use iced::border::Radius;
 use iced::widget::container;
 use iced::widget::image::FilterMethod;
-use iced::{alignment, widget, Border, Color};
+use iced::{alignment, widget, Border, Color, Element};
 use iced_aw::{grid_row, Grid};
 
 use crate::application_state::ApplicationState;
+use crate::inputs::Buttons;
 use crate::message::Message;
-use crate::ppu::{TILE_PALETTE_HORIZONTAL_PIXELS, TILE_PALETTE_VERTICAL_PIXELS};
+use crate::pixel_fetcher::get_tile_index_in_palette;
+use crate::ppu::{
+    TileMapCell, TilePaletteSelection, HORIZONTAL_PIXELS_PER_TILE, LCD_HORIZONTAL_PIXEL_COUNT,
+    LCD_VERTICAL_PIXEL_COUNT, PPU, TILE_MAP_HORIZONTAL_TILE_COUNT, TILE_MAP_VERTICAL_TILE_COUNT,
+    TILE_PALETTE_HORIZONTAL_PIXELS, TILE_PALETTE_VERTICAL_PIXELS, VERTICAL_PIXELS_PER_TILE,
+};
+
+const TILE_MAP_ZOOM_FACTOR: usize = 2;
+const BEAM_COLOR: [u8; 4] = [255, 0, 255, 255];
 
 impl ApplicationState {
     pub fn view(app: &ApplicationState) -> Grid<Message> {
@@ -32,11 +41,16 @@ impl ApplicationState {
                 })
             });
 
+        let lcd_pixels = if app.is_lcd_in_progress_shown {
+            lcd_pixels_with_beam(machine.ppu())
+        } else {
+            machine.ppu().front_buffer.to_vec()
+        };
         let lcd = widget::Container::new(
             widget::Image::new(image::Handle::from_rgba(
                 160,
                 144,
-                image::Bytes::copy_from_slice(&machine.ppu().lcd_pixels),
+                image::Bytes::from(lcd_pixels),
             ))
             .content_fit(iced::ContentFit::Fill)
             .filter_method(FilterMethod::Nearest)
@@ -45,6 +59,30 @@ impl ApplicationState {
         )
         .width(480)
         .height(432);
+        let lcd_buffer_toggle = widget::button(widget::text(if app.is_lcd_in_progress_shown {
+            "Show last frame"
+        } else {
+            "Show frame in progress"
+        }))
+        .on_press(Message::ToggleLCDInProgress);
+        let is_input_display_shown = app.preferences.is_input_display_shown;
+        let input_display_toggle = widget::button(widget::text(if is_input_display_shown {
+            "Hide inputs"
+        } else {
+            "Show inputs"
+        }))
+        .on_press(Message::ToggleInputDisplay);
+        let mut lcd = widget::Column::new()
+            .push(
+                widget::Row::new()
+                    .spacing(4)
+                    .push(lcd_buffer_toggle)
+                    .push(input_display_toggle),
+            )
+            .push(lcd);
+        if is_input_display_shown {
+            lcd = lcd.push(input_display(&machine.inputs.buttons()));
+        }
 
         let tile_palette_zoom_factor = 2;
         let wanted_width = (TILE_PALETTE_HORIZONTAL_PIXELS * tile_palette_zoom_factor) as u16;
@@ -62,37 +100,268 @@ impl ApplicationState {
         )
         .width(wanted_width)
         .height(wanted_height);
+        let tile_palette = widget::Column::new()
+            .push(tile_palette_selector(app.tile_palette_selection))
+            .push(tile_palette);
 
-        let tile_map0 = widget::Container::new(
-            widget::Image::new(image::Handle::from_rgba(
-                256,
-                256,
-                image::Bytes::copy_from_slice(&machine.ppu().tile_map0_pixels),
-            ))
-            .content_fit(iced::ContentFit::Fill)
-            .filter_method(FilterMethod::Nearest)
-            .width(512)
-            .height(512),
-        )
-        .width(512)
-        .height(512);
+        let tile_map0 = tile_map_view(
+            machine.ppu(),
+            0,
+            &machine.ppu().tile_map0_pixels,
+            app.is_tile_map0_shown,
+            app.hovered_tile_map_cell,
+        );
+        let tile_map1 = tile_map_view(
+            machine.ppu(),
+            1,
+            &machine.ppu().tile_map1_pixels,
+            app.is_tile_map1_shown,
+            app.hovered_tile_map_cell,
+        );
+
+        grid = grid.push(grid_row![debugger, lcd, tile_palette]);
+        grid = grid.push(grid_row![tile_map0, tile_map1]);
+        grid.into()
+    }
+}
+
+// Labels the tile map with what currently uses it, pressing its number toggles its rendering.
+fn tile_map_view<'a>(
+    ppu: &PPU,
+    tile_map: u8,
+    tile_map_pixels: &[u8],
+    is_shown: bool,
+    hovered_cell: Option<TileMapCell>,
+) -> widget::Column<'a, Message> {
+    let mut users = Vec::new();
+    if ppu.background_tile_map() == tile_map {
+        users.push("background");
+    }
+    if ppu.window_tile_map() == tile_map {
+        users.push("window");
+    }
+    let label = format!(
+        "Tile map {} (0x{:04X}): {} [{} to {}]",
+        tile_map,
+        if tile_map == 0 { 0x9800 } else { 0x9C00 },
+        if users.is_empty() {
+            String::from("unused")
+        } else {
+            users.join(", ")
+        },
+        tile_map,
+        if is_shown { "hide" } else { "show" },
+    );
 
-        let tile_map1 = widget::Container::new(
+    let pixels: Element<'a, Message> = if is_shown {
+        widget::mouse_area(
             widget::Image::new(image::Handle::from_rgba(
                 256,
                 256,
-                image::Bytes::copy_from_slice(&machine.ppu().tile_map1_pixels),
+                image::Bytes::copy_from_slice(tile_map_pixels),
             ))
             .content_fit(iced::ContentFit::Fill)
             .filter_method(FilterMethod::Nearest)
             .width(512)
             .height(512),
         )
-        .width(512)
-        .height(512);
+        .on_move(move |position| {
+            Message::HoverTileMapCell(tile_map_cell_at(tile_map, position.x, position.y))
+        })
+        .on_exit(Message::LeaveTileMap)
+        .into()
+    } else {
+        widget::text("Hidden").into()
+    };
 
-        grid = grid.push(grid_row![debugger, lcd, tile_palette]);
-        grid = grid.push(grid_row![tile_map0, tile_map1]);
-        grid.into()
+    let info = match hovered_cell {
+        Some(cell) if cell.tile_map == tile_map => tile_map_cell_info(ppu, cell),
+        _ => String::new(),
+    };
+
+    widget::Column::new()
+        .push(widget::text(label))
+        .push(widget::Container::new(pixels).width(512).height(512))
+        .push(widget::text(info))
+}
+
+// The frame being drawn, with the pixels of the current scanline not drawn yet marked, so that one
+// can tell where the beam is.
+fn lcd_pixels_with_beam(ppu: &PPU) -> Vec<u8> {
+    let mut pixels = ppu.lcd_pixels().to_vec();
+    let ly = ppu.read_ly().0 as usize;
+    if ly < LCD_VERTICAL_PIXEL_COUNT {
+        let row_from = ly * LCD_HORIZONTAL_PIXEL_COUNT;
+        let drawn = ppu.drawn_pixels_on_current_row() as usize;
+        for x in drawn..LCD_HORIZONTAL_PIXEL_COUNT {
+            let from = (row_from + x) * 4;
+            pixels[from..from + 4].copy_from_slice(&BEAM_COLOR);
+        }
+    }
+    pixels
+}
+
+// The tile map views are zoomed in, so each cell takes 16x16 pixels of the widget.
+fn tile_map_cell_at(tile_map: u8, x: f32, y: f32) -> TileMapCell {
+    let cell_width = (HORIZONTAL_PIXELS_PER_TILE * TILE_MAP_ZOOM_FACTOR) as f32;
+    let cell_height = (VERTICAL_PIXELS_PER_TILE * TILE_MAP_ZOOM_FACTOR) as f32;
+    let last_column = (TILE_MAP_HORIZONTAL_TILE_COUNT - 1) as f32;
+    let last_row = (TILE_MAP_VERTICAL_TILE_COUNT - 1) as f32;
+    TileMapCell {
+        tile_map,
+        column: (x / cell_width).clamp(0.0, last_column) as u8,
+        row: (y / cell_height).clamp(0.0, last_row) as u8,
+    }
+}
+
+fn tile_map_cell_info(ppu: &PPU, cell: TileMapCell) -> String {
+    let vram_offset = cell.vram_offset();
+    let tile_id = ppu.vram[vram_offset];
+    let addressing_mode = ppu.tile_map_cell_addressing_mode(cell);
+    let tile_data_address =
+        0x8000 + get_tile_index_in_palette(tile_id, &addressing_mode) as usize * 16;
+    let mut info = format!(
+        "({}, {}) at 0x{:04X}: tile 0x{:02X} {:?}, data at 0x{:04X}",
+        cell.column,
+        cell.row,
+        0x8000 + vram_offset,
+        tile_id,
+        addressing_mode,
+        tile_data_address,
+    );
+    if ppu.is_cgb_mode {
+        info += &format!(", attributes 0x{:02X}", ppu.vram_bank_1[vram_offset]);
+    }
+    info
+}
+
+// Laid out like on the console: the D-pad, then B and A, then Select and Start.
+fn input_display_buttons(buttons: &Buttons) -> [(&'static str, bool); 8] {
+    [
+        ("<", buttons.left),
+        ("^", buttons.up),
+        ("v", buttons.down),
+        (">", buttons.right),
+        ("B", buttons.b),
+        ("A", buttons.a),
+        ("Select", buttons.select),
+        ("Start", buttons.start),
+    ]
+}
+
+// The buttons the game sees, pressed ones filled in, like the input displays of speedruns.
+fn input_display<'a>(buttons: &Buttons) -> widget::Row<'a, Message> {
+    input_display_buttons(buttons).into_iter().fold(
+        widget::Row::new().spacing(4),
+        |row, (label, is_pressed)| {
+            row.push(
+                widget::Container::new(widget::text(label))
+                    .padding([2, 6])
+                    .style(move |_theme| {
+                        let style = container::Style::default().border(Border {
+                            color: Color::BLACK,
+                            width: 1.0,
+                            ..Border::default()
+                        });
+                        if is_pressed {
+                            container::Style {
+                                text_color: Some(Color::WHITE),
+                                ..style.background(Color::BLACK)
+                            }
+                        } else {
+                            style
+                        }
+                    }),
+            )
+        },
+    )
+}
+
+// One button per palette, the selected one is disabled.
+fn tile_palette_selector<'a>(selected: TilePaletteSelection) -> widget::Row<'a, Message> {
+    [
+        (TilePaletteSelection::Background, "BGP"),
+        (TilePaletteSelection::Object0, "OBP0"),
+        (TilePaletteSelection::Object1, "OBP1"),
+        (TilePaletteSelection::Raw, "Raw"),
+    ]
+    .into_iter()
+    .fold(widget::Row::new().spacing(4), |row, (selection, label)| {
+        let button = widget::button(widget::text(label));
+        row.push(if selection == selected {
+            button
+        } else {
+            button.on_press(Message::SelectTilePalette(selection))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::interrupts::Interrupts,
+        inputs::{Button, InputSource, Inputs},
+        pixel_fetcher::TileAddressingMode,
+    };
+
+    fn highlighted(inputs: &Inputs) -> Vec<&'static str> {
+        input_display_buttons(&inputs.buttons())
+            .into_iter()
+            .filter(|(_, is_pressed)| *is_pressed)
+            .map(|(label, _)| label)
+            .collect()
+    }
+
+    #[test]
+    fn input_display_highlights_what_the_game_sees() {
+        let mut inputs = Inputs::new();
+        let mut interrupts = Interrupts::new();
+        assert!(highlighted(&inputs).is_empty());
+        inputs.set_button(InputSource::Keyboard, Button::Up, true, &mut interrupts);
+        inputs.set_button(InputSource::Keyboard, Button::Start, true, &mut interrupts);
+        inputs.set_button(InputSource::Gamepad, Button::B, true, &mut interrupts);
+        assert_eq!(highlighted(&inputs), ["^", "B", "Start"]);
+        // Turbo buttons go on and off with the frames
+        inputs.set_turbo_button(InputSource::Keyboard, Button::A, true, &mut interrupts);
+        assert_eq!(highlighted(&inputs), ["^", "B", "A", "Start"]);
+        inputs.advance_frame(&mut interrupts);
+        assert_eq!(highlighted(&inputs), ["^", "B", "Start"]);
+        // A movie being played back replaces the player's buttons
+        let movie_buttons = Buttons {
+            left: true,
+            select: true,
+            ..Buttons::default()
+        };
+        inputs.set_overridden_buttons(Some(movie_buttons), &mut interrupts);
+        assert_eq!(highlighted(&inputs), ["<", "Select"]);
+    }
+
+    #[test]
+    fn hovering_picks_the_cell_under_the_zoomed_in_pixel() {
+        let cell = |x, y| {
+            let cell = tile_map_cell_at(1, x, y);
+            assert_eq!(cell.tile_map, 1);
+            (cell.column, cell.row)
+        };
+        assert_eq!(cell(0.0, 0.0), (0, 0));
+        assert_eq!(cell(15.9, 15.9), (0, 0));
+        assert_eq!(cell(16.0, 47.5), (1, 2));
+        assert_eq!(cell(511.9, 496.0), (31, 31));
+        // The edges of the widget stay within the tile map
+        assert_eq!(cell(-1.0, 512.0), (0, 31));
+        assert_eq!(cell(600.0, -0.5), (31, 0));
+    }
+
+    #[test]
+    fn hovered_cells_show_where_their_tile_comes_from() {
+        let mut ppu = PPU::new(false, false, false);
+        let cell = tile_map_cell_at(0, 20.0, 20.0);
+        ppu.vram[cell.vram_offset()] = 0x01;
+        ppu.remember_addressing_mode(0, 33, TileAddressingMode::SignedFrom0x9000);
+        assert_eq!(
+            tile_map_cell_info(&ppu, cell),
+            "(1, 1) at 0x9821: tile 0x01 SignedFrom0x9000, data at 0x9010"
+        );
     }
 }
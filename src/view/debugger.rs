@@ -1,4 +1,7 @@
+mod callstack;
+mod fetcher;
 mod instructions;
+mod joypad;
 mod lcd;
 mod registers;
 mod stack;
@@ -10,15 +13,28 @@ use crate::{application_state::ApplicationState, message::Message};
 pub fn view(app: &ApplicationState) -> Column<Message> {
     let machine = app.current_machine_immut();
     let instructions = instructions::view(app);
+    let status = widget::text(format!("CPU: {}", machine.cpu().status()));
+    let cartridge = widget::text(format!(
+        "Cartridge: {} ({:?})",
+        machine.rom_information.title, machine.rom_information.mapper_type
+    ));
     let registers = registers::view(&machine.registers());
-    let stack = stack::view(machine);
+    let stack = stack::view(machine, &app.symbols);
+    let callstack = callstack::view(machine, &app.symbols);
     let lcd = lcd::view(machine);
+    let fetcher = fetcher::view(machine, app.is_fetcher_panel_expanded);
+    let joypad = joypad::view(machine, app.is_joypad_panel_expanded);
 
     widget::Column::new()
         .width(450)
         .height(520)
         .push(instructions)
+        .push(cartridge)
+        .push(status)
         .push(registers)
         .push(stack)
+        .push(callstack)
         .push(lcd)
+        .push(fetcher)
+        .push(joypad)
 }
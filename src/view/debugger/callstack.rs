@@ -0,0 +1,33 @@
+use std::num::Wrapping;
+
+use iced::widget;
+use iced_aw::{grid_row, Grid};
+
+use crate::{machine::Machine, message::Message, symbols::SymbolTable};
+
+// Only the innermost frames fit in the debugger panel
+const SHOWN_FRAMES: usize = 4;
+
+fn display_address(machine: &Machine, symbols: &SymbolTable, address: Wrapping<u16>) -> String {
+    match symbols.lookup(machine, address) {
+        Some(label) => format!("0x{:04X} {}", address, label),
+        None => format!("0x{:04X}", address),
+    }
+}
+
+pub fn view<'a>(machine: &Machine, symbols: &SymbolTable) -> Grid<'a, Message> {
+    let mut callstack_grid = Grid::new().column_spacing(5);
+    callstack_grid = callstack_grid.push(grid_row![widget::text("Call stack:")]);
+
+    for frame in machine.call_stack.frames().iter().rev().take(SHOWN_FRAMES) {
+        callstack_grid = callstack_grid.push(grid_row![
+            widget::text(display_address(machine, symbols, frame.target)),
+            widget::text(format!(
+                "from {}",
+                display_address(machine, symbols, frame.call_site)
+            )),
+        ]);
+    }
+
+    callstack_grid
+}
@@ -0,0 +1,125 @@
+use iced::{
+    widget::{self, container},
+    Border, Color,
+};
+use iced_aw::{grid_row, Grid};
+
+use crate::{
+    machine::Machine,
+    message::Message,
+    pixel_fetcher::object::ObjectPalette,
+    ppu::{pixel_code_to_rgba, PPU},
+};
+
+// Only the pixels about to be popped are shown
+const SHOWN_FIFO_ENTRIES: usize = 8;
+const FIFO_ENTRY_SIZE: f32 = 10.0;
+
+// A square of the given color, or an empty outline for transparent pixels.
+fn fifo_entry<'a>(rgba: Option<[u8; 4]>) -> widget::Container<'a, Message> {
+    widget::Container::new(widget::Space::new(FIFO_ENTRY_SIZE, FIFO_ENTRY_SIZE)).style(
+        move |_theme| {
+            let style = container::Style::default().border(Border {
+                color: Color::BLACK,
+                width: 1.0,
+                ..Border::default()
+            });
+            match rgba {
+                Some([r, g, b, _]) => style.background(Color::from_rgb8(r, g, b)),
+                None => style,
+            }
+        },
+    )
+}
+
+fn fifo_row<'a>(entries: impl Iterator<Item = Option<[u8; 4]>>) -> widget::Row<'a, Message> {
+    entries
+        .take(SHOWN_FIFO_ENTRIES)
+        .fold(widget::Row::new().spacing(2), |row, rgba| {
+            row.push(fifo_entry(rgba))
+        })
+}
+
+fn object_palette(ppu: &PPU, palette: &ObjectPalette) -> u8 {
+    match palette {
+        ObjectPalette::ObjectPalette0 => ppu.object_palette_0,
+        ObjectPalette::ObjectPalette1 => ppu.object_palette_1,
+    }
+}
+
+pub fn view<'a>(machine: &Machine, is_expanded: bool) -> widget::Column<'a, Message> {
+    let header = widget::button(widget::text(if is_expanded {
+        "Hide fetcher"
+    } else {
+        "Show fetcher"
+    }))
+    .on_press(Message::ToggleFetcherPanel);
+    let mut column = widget::Column::new().push(header);
+    if !is_expanded {
+        return column;
+    }
+
+    let ppu = machine.ppu();
+    let bgw_fetcher = &machine.background_window_fetcher;
+    let obj_fetcher = &machine.object_fetcher;
+
+    let mut grid = Grid::new().column_spacing(5);
+    grid = grid.push(grid_row![
+        widget::text("Fetching for"),
+        widget::text(format!("{:?}", machine.pixel_fetcher.fetching_for)),
+    ]);
+    grid = grid.push(grid_row![
+        widget::text("Scanline"),
+        widget::text(format!(
+            "dot {}, {} pixels drawn",
+            ppu.scanline_dots(),
+            ppu.drawn_pixels_on_current_row()
+        )),
+    ]);
+    grid = grid.push(grid_row![
+        widget::text("BG/Win"),
+        widget::text(format!(
+            "{:?}, tile 0x{:02X}, column {}, row {}",
+            bgw_fetcher.state(),
+            bgw_fetcher.tile_id(),
+            bgw_fetcher.vram_tile_column,
+            bgw_fetcher.row_of_pixel_within_tile
+        )),
+    ]);
+    grid = grid.push(grid_row![
+        widget::text(format!("FIFO ({})", bgw_fetcher.fifo.len())),
+        fifo_row(
+            bgw_fetcher
+                .fifo
+                .iter()
+                .map(|item| Some(pixel_code_to_rgba(item.color, ppu.background_palette_data)))
+        ),
+    ]);
+    grid = grid.push(grid_row![
+        widget::text("Object"),
+        widget::text(match obj_fetcher.sprite() {
+            Some(sprite) => format!(
+                "{:?}, tile 0x{:02X}, {} pending",
+                obj_fetcher.state(),
+                sprite.tile_index,
+                obj_fetcher.selected_objects.len()
+            ),
+            None => format!(
+                "{:?}, {} pending",
+                obj_fetcher.state(),
+                obj_fetcher.selected_objects.len()
+            ),
+        }),
+    ]);
+    grid = grid.push(grid_row![
+        widget::text(format!("FIFO ({})", obj_fetcher.fifo.len())),
+        fifo_row(obj_fetcher.fifo.iter().map(|item| {
+            // Color 0 is transparent, whichever palette the sprite uses
+            (item.color != 0)
+                .then(|| pixel_code_to_rgba(item.color, object_palette(ppu, &item.palette)))
+        })),
+    ]);
+
+    column = column.push(grid);
+    column
+}
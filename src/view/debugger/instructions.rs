@@ -1,7 +1,25 @@
 use iced::{widget, Color, Theme};
 use iced_aw::{grid_row, Grid};
 
-use crate::{application_state::ApplicationState, memory::Memory, message::Message};
+use crate::{
+    application_state::ApplicationState, instructions::decode::DecodedInstruction,
+    machine::Machine, message::Message,
+};
+
+// Appends the label of the jump target, if there is one
+fn display_with_target_label(
+    app: &ApplicationState,
+    machine: &Machine,
+    instr: &DecodedInstruction,
+) -> String {
+    let target_label = instr
+        .jump_target()
+        .and_then(|target| app.symbols.lookup(machine, target));
+    match target_label {
+        Some(label) => format!("{} ; {}", instr, label),
+        None => format!("{}", instr),
+    }
+}
 
 pub fn view(app: &ApplicationState) -> Grid<Message> {
     let mut instructions_grid = Grid::new().column_spacing(5).padding(2);
@@ -11,36 +29,42 @@ pub fn view(app: &ApplicationState) -> Grid<Message> {
     };
 
     for old in app.snaps.asc_iter().take(history_size) {
-        let instr = Memory::decode_instruction_at(old, old.registers().pc);
+        let instr = app
+            .instruction_cache
+            .decode_instruction_at(old, old.registers().pc);
         let row = grid_row![
             widget::text(app.display_breakpoint(instr.address)).style(history_style),
             widget::text(""),
             widget::text(format!("{:04X}", instr.address)).style(history_style),
             widget::text(format!("{}", instr.display_raw())).style(history_style),
-            widget::text(format!("{}", instr)).style(history_style)
+            widget::text(display_with_target_label(app, old, &instr)).style(history_style)
         ];
         instructions_grid = instructions_grid.push(row);
     }
 
     let machine = app.current_machine_immut();
     let pc = machine.registers().pc;
-    let instrs = Memory::decode_instructions_at(machine, pc, 10);
+    let instrs = app
+        .instruction_cache
+        .decode_instructions_at(machine, pc, 10);
 
-    instructions_grid = instructions_grid.push(grid_row![
-        widget::text(app.display_breakpoint(instrs[0].address)),
-        widget::text("→"),
-        widget::text(format!("{:04X}", instrs[0].address)),
-        widget::text(format!("{}", instrs[0].display_raw())),
-        widget::text(format!("{}", instrs[0]))
-    ]);
-
-    for instr in instrs.iter().skip(1) {
+    // The first instruction is the one at PC
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Some(label) = app.symbols.lookup(machine, instr.address) {
+            instructions_grid = instructions_grid.push(grid_row![
+                widget::text(""),
+                widget::text(""),
+                widget::text(""),
+                widget::text(""),
+                widget::text(format!("{}:", label))
+            ]);
+        }
         instructions_grid = instructions_grid.push(grid_row![
             widget::text(app.display_breakpoint(instr.address)),
-            widget::text(""),
+            widget::text(if index == 0 { "→" } else { "" }),
             widget::text(format!("{:04X}", instr.address)),
             widget::text(format!("{}", instr.display_raw())),
-            widget::text(format!("{}", instr))
+            widget::text(display_with_target_label(app, machine, instr))
         ]);
     }
 
@@ -0,0 +1,66 @@
+use iced::widget;
+use iced_aw::{grid_row, Grid};
+
+use crate::{machine::Machine, message::Message};
+
+fn bit(is_set: bool) -> widget::Text<'static> {
+    widget::text(format!("{}", is_set as u8))
+}
+
+// What the game sees of the joypad, to tell key mapping problems apart from P1 or game bugs.
+pub fn view<'a>(machine: &Machine, is_expanded: bool) -> widget::Column<'a, Message> {
+    let header = widget::button(widget::text(if is_expanded {
+        "Hide joypad"
+    } else {
+        "Show joypad"
+    }))
+    .on_press(Message::ToggleJoypadPanel);
+    let mut column = widget::Column::new().push(header);
+    if !is_expanded {
+        return column;
+    }
+
+    let inputs = &machine.inputs;
+    let buttons = inputs.buttons();
+
+    let mut grid = Grid::new().column_spacing(5);
+    grid = grid.push(grid_row![
+        widget::text("P1"),
+        widget::text(""),
+        widget::text("Dir"),
+        widget::text("Act"),
+        widget::text(""),
+        widget::text("R"),
+        widget::text("L"),
+        widget::text("U"),
+        widget::text("D"),
+        widget::text("A"),
+        widget::text("B"),
+        widget::text("Se"),
+        widget::text("St"),
+        widget::text(""),
+        widget::text("IRQ"),
+        widget::text("Prev"),
+    ]);
+    grid = grid.push(grid_row![
+        widget::text(format!("{:02X}", inputs.read())),
+        widget::text(""),
+        bit(inputs.are_directions_selected()),
+        bit(inputs.are_actions_selected()),
+        widget::text(""),
+        bit(buttons.right),
+        bit(buttons.left),
+        bit(buttons.up),
+        bit(buttons.down),
+        bit(buttons.a),
+        bit(buttons.b),
+        bit(buttons.select),
+        bit(buttons.start),
+        widget::text(""),
+        bit(inputs.is_interrupt_requested_this_frame()),
+        bit(inputs.was_interrupt_requested_last_frame()),
+    ]);
+
+    column = column.push(grid);
+    column
+}
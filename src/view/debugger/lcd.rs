@@ -29,6 +29,14 @@ pub fn view(machine: &Machine) -> Grid<Message> {
 
     let mut lcdc_grid = Grid::new();
     lcdc_grid = lcdc_grid.push(grid_row![widget::text("LCDC"), lcdc_grid_right]);
+    lcdc_grid = lcdc_grid.push(grid_row![
+        widget::text("Mode 3"),
+        widget::text(format!("{} dots", machine.ppu().mode_3_length())),
+    ]);
+    lcdc_grid = lcdc_grid.push(grid_row![
+        widget::text("Stalled scanlines"),
+        widget::text(format!("{}", machine.ppu().stalled_scanline_count())),
+    ]);
 
     lcdc_grid
 }
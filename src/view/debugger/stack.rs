@@ -6,9 +6,11 @@ use std::{
 use iced::widget;
 use iced_aw::{grid_row, Grid};
 
-use crate::{machine::Machine, message::Message};
+use crate::{
+    instructions::type_def::Immediate16, machine::Machine, message::Message, symbols::SymbolTable,
+};
 
-pub fn view(machine: &Machine) -> Grid<Message> {
+pub fn view<'a>(machine: &Machine, symbols: &SymbolTable) -> Grid<'a, Message> {
     let mut stack_grid = Grid::new();
     stack_grid = stack_grid.push(grid_row![widget::text("Stack:")]);
 
@@ -20,9 +22,18 @@ pub fn view(machine: &Machine) -> Grid<Message> {
     );
 
     for stack_addr in stack_top..=stack_until {
+        // Words on the stack are often return addresses, show their label if they have one
+        let mut label = String::new();
+        if (stack_addr - stack_top) % 2 == 0 {
+            let word = Immediate16::from_memory(machine, Wrapping(stack_addr)).as_u16();
+            if let Some(symbol) = symbols.lookup(machine, word) {
+                label = format!("-> {}", symbol);
+            }
+        }
         stack_grid = stack_grid.push(grid_row![
             widget::text(format!("0x{:04X}:", stack_addr)),
-            widget::text(format!("{:02X}", machine.read_u8(Wrapping(stack_addr)))),
+            widget::text(format!("{:02X}", machine.read_u8_raw(Wrapping(stack_addr)))),
+            widget::text(label),
         ]);
     }
 